@@ -62,6 +62,9 @@ pub struct Config {
     pub insecure_registries: Option<Vec<String>>,
     /// The directory kubelet should watch for new plugin sockets
     pub plugins_dir: PathBuf,
+    /// The CIDR from which pods are given their own IP addresses, if
+    /// the provider supports it
+    pub pod_cidr: Option<String>,
 }
 /// The configuration for the Kubelet server.
 #[derive(Clone, Debug)]
@@ -74,6 +77,10 @@ pub struct ServerConfig {
     pub cert_file: PathBuf,
     /// Path to kubelet TLS private key.
     pub private_key_file: PathBuf,
+    /// Path to the certificates of the CAs that clients' certificates are
+    /// verified against. Only clients that authenticate with such a
+    /// certificate may exec into or attach to containers.
+    pub client_ca_file: Option<PathBuf>,
 }
 
 #[derive(Debug, Default, serde::Deserialize)]
@@ -115,12 +122,16 @@ struct ConfigBuilder {
     pub server_tls_cert_file: Option<PathBuf>,
     #[serde(default, rename = "tlsPrivateKeyFile")]
     pub server_tls_private_key_file: Option<PathBuf>,
+    #[serde(default, rename = "clientCAFile")]
+    pub server_client_ca_file: Option<PathBuf>,
     #[serde(default, rename = "allowLocalModules")]
     pub allow_local_modules: Option<bool>,
     #[serde(default, rename = "insecureRegistries")]
     pub insecure_registries: Option<Vec<String>>,
     #[serde(default, rename = "pluginsDir")]
     pub plugins_dir: Option<PathBuf>,
+    #[serde(default, rename = "podCIDR")]
+    pub pod_cidr: Option<String>,
 }
 
 struct ConfigBuilderFallbacks {
@@ -156,6 +167,7 @@ impl Config {
             allow_local_modules: false,
             insecure_registries: None,
             plugins_dir,
+            pod_cidr: None,
             server_config: ServerConfig {
                 addr: match preferred_ip_family {
                     IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
@@ -164,6 +176,7 @@ impl Config {
                 port: DEFAULT_PORT,
                 cert_file,
                 private_key_file,
+                client_ca_file: None,
             },
         })
     }
@@ -278,10 +291,12 @@ impl ConfigBuilder {
             allow_local_modules: opts.allow_local_modules,
             insecure_registries: opts.insecure_registries.map(parse_comma_separated),
             plugins_dir: opts.plugins_dir,
+            pod_cidr: opts.pod_cidr,
             server_addr: ok_result_of(opts.addr),
             server_port: ok_result_of(opts.port),
             server_tls_cert_file: opts.cert_file,
             server_tls_private_key_file: opts.private_key_file,
+            server_client_ca_file: opts.client_ca_file,
         }
     }
 
@@ -316,9 +331,11 @@ impl ConfigBuilder {
             allow_local_modules: other.allow_local_modules.or(self.allow_local_modules),
             insecure_registries: other.insecure_registries.or(self.insecure_registries),
             plugins_dir: other.plugins_dir.or(self.plugins_dir),
+            pod_cidr: other.pod_cidr.or(self.pod_cidr),
             server_tls_private_key_file: other
                 .server_tls_private_key_file
                 .or(self.server_tls_private_key_file),
+            server_client_ca_file: other.server_client_ca_file.or(self.server_client_ca_file),
         }
     }
 
@@ -368,9 +385,11 @@ impl ConfigBuilder {
             allow_local_modules: self.allow_local_modules.unwrap_or(false),
             insecure_registries: self.insecure_registries,
             plugins_dir,
+            pod_cidr: self.pod_cidr,
             server_config: ServerConfig {
                 cert_file: server_tls_cert_file,
                 private_key_file: server_tls_private_key_file,
+                client_ca_file: self.server_client_ca_file,
                 addr: server_addr,
                 port: server_port,
             },
@@ -445,6 +464,13 @@ pub struct Opts {
     )]
     private_key_file: Option<PathBuf>,
 
+    #[structopt(
+        long = "client-ca-file",
+        env = "KRUSTLET_CLIENT_CA_FILE",
+        help = "The path to the certificates of the CAs that clients' certificates are verified against. Exec and attach requests are refused unless the client authenticates with such a certificate"
+    )]
+    client_ca_file: Option<PathBuf>,
+
     #[structopt(
         short = "n",
         long = "node-ip",
@@ -518,6 +544,13 @@ pub struct Opts {
         help = "Registries that should be accessed over HTTP instead of HTTPS (comma separated)"
     )]
     insecure_registries: Option<String>,
+
+    #[structopt(
+        long = "pod-cidr",
+        env = "KRUSTLET_POD_CIDR",
+        help = "The CIDR to give pods their own IP addresses from, if the provider supports it. The node must route the CIDR to itself"
+    )]
+    pod_cidr: Option<String>,
 }
 
 fn default_hostname() -> anyhow::Result<String> {
@@ -653,13 +686,15 @@ mod test {
             "nodeName": "krusty-node",
             "tlsCertificateFile": "/my/secure/cert.pfx",
             "tlsPrivateKeyFile": "/the/key",
+            "clientCAFile": "/the/client/ca.crt",
             "bootstrapFile": "/the/bootstrap/file.txt",
             "allowLocalModules": true,
             "insecureRegistries": [
                 "local",
                 "dev"
             ],
-            "pluginsDir": "/some/plugins"
+            "pluginsDir": "/some/plugins",
+            "podCIDR": "10.244.1.0/24"
         }"#,
         );
         let config = config_builder.unwrap().build(fallbacks()).unwrap();
@@ -673,6 +708,10 @@ mod test {
             config.server_config.private_key_file.to_string_lossy(),
             "/the/key"
         );
+        assert_eq!(
+            config.server_config.client_ca_file.as_deref(),
+            Some(Path::new("/the/client/ca.crt"))
+        );
         assert_eq!(
             config.bootstrap_file.to_string_lossy(),
             "/the/bootstrap/file.txt"
@@ -689,6 +728,7 @@ mod test {
         assert_eq!(&config.insecure_registries.clone().unwrap()[0], "local");
         assert_eq!(&config.insecure_registries.unwrap()[1], "dev");
         assert_eq!(&config.plugins_dir.to_string_lossy(), "/some/plugins");
+        assert_eq!(config.pod_cidr.as_deref(), Some("10.244.1.0/24"));
     }
 
     #[test]
@@ -749,6 +789,8 @@ mod test {
         assert_eq!(format!("{}", config.node_ip), "4.4.4.4");
         assert_eq!(config.allow_local_modules, false);
         assert_eq!(config.insecure_registries, None);
+        assert_eq!(config.pod_cidr, None);
+        assert_eq!(config.server_config.client_ca_file, None);
         assert_eq!(config.node_labels.len(), 0);
         assert_eq!(
             &config.plugins_dir.to_string_lossy(),
@@ -30,6 +30,7 @@ mod test {
             hostname: "nope".to_owned(),
             insecure_registries: None,
             plugins_dir: std::path::PathBuf::from("/nope"),
+            pod_cidr: None,
             max_pods: 0,
             node_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
             node_labels: std::collections::HashMap::new(),
@@ -39,6 +40,7 @@ mod test {
                 port: 0,
                 cert_file: std::path::PathBuf::from("/nope"),
                 private_key_file: std::path::PathBuf::from("/nope"),
+                client_ca_file: None,
             },
         }
     }
@@ -1,8 +1,13 @@
 use std::io::SeekFrom;
+use std::time::Duration;
 
+use futures::future::BoxFuture;
 use tokio::io::{AsyncRead, AsyncSeek, AsyncSeekExt};
+use tokio::sync::mpsc;
+use tracing::debug;
 
 use crate::container::ContainerMap;
+use crate::exec::{AttachedOutput, Sender as ExecSender};
 use crate::handle::StopHandler;
 use crate::log::{stream, HandleFactory, Sender};
 
@@ -13,6 +18,12 @@ use crate::log::{stream, HandleFactory, Sender};
 pub struct Handle<H, F> {
     handle: H,
     handle_factory: F,
+    /// Where input from attached clients is sent, if the process has stdin
+    stdin: Option<mpsc::Sender<Vec<u8>>>,
+    /// Whether stdin should be closed once the first attached client detaches
+    stdin_once: bool,
+    /// Where the process's output is copied to attached clients, if it can be
+    output: Option<AttachedOutput>,
 }
 
 impl<H, F> std::fmt::Debug for Handle<H, F> {
@@ -28,15 +39,98 @@ impl<H: StopHandler, F> Handle<H, F> {
         Self {
             handle,
             handle_factory,
+            stdin: None,
+            stdin_once: false,
+            output: None,
         }
     }
 
+    /// Accept input for the process's stdin from attached clients, sending it
+    /// on `stdin`. If `once` is set, stdin is closed when the first client
+    /// to attach detaches, otherwise it stays open until the process exits.
+    pub fn with_stdin(mut self, stdin: mpsc::Sender<Vec<u8>>, once: bool) -> Self {
+        self.stdin = Some(stdin);
+        self.stdin_once = once;
+        self
+    }
+
+    /// Let clients attach to the process's output, which the provider copies
+    /// to `output` while the process runs.
+    pub fn with_attached_output(mut self, output: AttachedOutput) -> Self {
+        self.output = Some(output);
+        self
+    }
+
+    /// Sends the process's output to an attached client until it exits, and
+    /// forwards any input from the client to its stdin until the client
+    /// detaches.
+    pub(crate) fn attach(
+        &mut self,
+        input: Option<mpsc::Receiver<Vec<u8>>>,
+        output: ExecSender,
+    ) -> anyhow::Result<()> {
+        if input.is_some() && self.stdin.is_none() {
+            anyhow::bail!("container does not have stdin open");
+        }
+        self.output
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("container output cannot be attached to"))?
+            .attach(output)?;
+        let mut input = match input {
+            Some(input) => input,
+            None => return Ok(()),
+        };
+        let stdin = if self.stdin_once {
+            self.stdin.take()
+        } else {
+            self.stdin.clone()
+        }
+        .ok_or_else(|| anyhow::anyhow!("container does not have stdin open"))?;
+        tokio::spawn(async move {
+            while let Some(data) = input.recv().await {
+                if stdin.send(data).await.is_err() {
+                    debug!("Process stdin closed, detaching client.");
+                    break;
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// The handle to the running process, for providers to operate on it.
+    pub fn handle(&self) -> &H {
+        &self.handle
+    }
+
     /// Signal the running instance to stop. Use [`Handle::wait`] to wait for the process to
     /// exit. This uses the underlying [`StopHandler`] implementation passed to the constructor
     pub async fn stop(&mut self) -> anyhow::Result<()> {
+        self.stdin.take();
         self.handle.stop().await
     }
 
+    /// Ask the running instance to stop, closing its stdin, and stop it if it
+    /// is still running after `grace_period`. This uses the underlying
+    /// [`StopHandler`] implementation passed to the constructor
+    pub async fn stop_gracefully(&mut self, grace_period: Duration) -> anyhow::Result<()>
+    where
+        H: Send,
+    {
+        self.stdin.take();
+        self.handle.stop_gracefully(grace_period).await
+    }
+
+    /// Start stopping the running instance as [`Handle::stop_gracefully`]
+    /// does, returning a future that finishes stopping it without borrowing
+    /// this handle, if the underlying [`StopHandler`] can provide one.
+    pub(crate) fn stop_gracefully_detached(
+        &mut self,
+        grace_period: Duration,
+    ) -> Option<BoxFuture<'static, anyhow::Result<()>>> {
+        self.stdin.take();
+        self.handle.stop_gracefully_detached(grace_period)
+    }
+
     /// Streams output from the running process into the given sender.
     /// Optionally tails the output and/or continues to watch the file and stream changes.
     pub(crate) async fn output<R>(&mut self, sender: Sender) -> anyhow::Result<()>
@@ -46,7 +140,7 @@ impl<H: StopHandler, F> Handle<H, F> {
     {
         let mut handle = self.handle_factory.new_handle();
         handle.seek(SeekFrom::Start(0)).await?;
-        tokio::spawn(stream(handle, sender));
+        tokio::spawn(stream(handle, sender, self.handle_factory.format()));
         Ok(())
     }
 
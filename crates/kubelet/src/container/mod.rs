@@ -10,7 +10,10 @@ pub mod state;
 mod status;
 
 pub use handle::{Handle, HandleMap};
-pub use status::{make_initial_container_status, patch_container_status, Status};
+pub use status::{
+    make_initial_container_status, patch_container_readiness, patch_container_restart_count,
+    patch_container_status, Status,
+};
 
 /// Specifies how the store should check for module updates
 #[derive(PartialEq, Debug, Clone, Copy)]
@@ -100,6 +103,9 @@ pub type ContainerMap<V> = std::collections::HashMap<ContainerKey, V>;
 
 /// Provides methods for accessing `ContainerMap` elements by name.
 pub trait ContainerMapByName<V> {
+    /// Gets a reference to the value associated with the container with the
+    /// given name.
+    fn get_by_name(&self, name: String) -> Option<&V>;
     /// Gets a mutable reference to the value associated with the container
     /// with the given name.
     fn get_mut_by_name(&mut self, name: String) -> Option<&mut V>;
@@ -108,6 +114,12 @@ pub trait ContainerMapByName<V> {
 }
 
 impl<V> ContainerMapByName<V> for ContainerMap<V> {
+    fn get_by_name(&self, name: String) -> Option<&V> {
+        let app_key = ContainerKey::App(name.clone());
+        self.get(&app_key)
+            .or_else(|| self.get(&ContainerKey::Init(name)))
+    }
+
     fn get_mut_by_name(&mut self, name: String) -> Option<&mut V> {
         // TODO: borrow checker objected to any of the more natural forms
         let app_key = ContainerKey::App(name.clone());
@@ -200,6 +212,45 @@ impl Container {
         self.0.resources.as_ref()
     }
 
+    /// Get the CPU limit of container in cores, if one is set.
+    pub fn cpu_limit(&self) -> anyhow::Result<Option<f64>> {
+        self.limit(crate::resources::CPU)
+            .map(crate::resources::parse_quantity)
+            .transpose()
+    }
+
+    /// Get the memory limit of container in bytes, if one is set.
+    pub fn memory_limit(&self) -> anyhow::Result<Option<u64>> {
+        self.limit(crate::resources::MEMORY)
+            .map(crate::resources::parse_bytes)
+            .transpose()
+    }
+
+    /// Get the ephemeral storage limit of container in bytes, if one is set.
+    pub fn ephemeral_storage_limit(&self) -> anyhow::Result<Option<u64>> {
+        self.limit(crate::resources::EPHEMERAL_STORAGE)
+            .map(crate::resources::parse_bytes)
+            .transpose()
+    }
+
+    pub(crate) fn limit(
+        &self,
+        resource: &str,
+    ) -> Option<&k8s_openapi::apimachinery::pkg::api::resource::Quantity> {
+        self.resources()
+            .and_then(|r| r.limits.as_ref())
+            .and_then(|limits| limits.get(resource))
+    }
+
+    pub(crate) fn request(
+        &self,
+        resource: &str,
+    ) -> Option<&k8s_openapi::apimachinery::pkg::api::resource::Quantity> {
+        self.resources()
+            .and_then(|r| r.requests.as_ref())
+            .and_then(|requests| requests.get(resource))
+    }
+
     /// Get security context of container.
     pub fn security_context(&self) -> Option<&k8s_openapi::api::core::v1::SecurityContext> {
         self.0.security_context.as_ref()
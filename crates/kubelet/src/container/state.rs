@@ -68,6 +68,10 @@ pub async fn run_to_completion<S: ObjectState<Manifest = Container, Status = Sta
         }
     });
 
+    // Whether the container has already reported that it terminated, in which
+    // case its status (and exit code) shouldn't be replaced if it errors out
+    let mut reported_terminated = false;
+
     loop {
         debug!(
             "Pod {} container {} entering state {:?}",
@@ -79,6 +83,7 @@ pub async fn run_to_completion<S: ObjectState<Manifest = Container, Status = Sta
 
         match state.status(&mut container_state, &latest_container).await {
             Ok(status) => {
+                reported_terminated = matches!(status, Status::Terminated { .. });
                 match patch_container_status(&api, &latest_pod, &container_name, &status).await {
                     Ok(_) => (),
                     Err(e) => {
@@ -129,14 +134,18 @@ pub async fn run_to_completion<S: ObjectState<Manifest = Container, Status = Sta
                         "Pod {} container {} state machine exited with error: {:?}",
                         &pod_name, container_name, e
                     );
-                    let status = Status::Terminated {
-                        timestamp: Utc::now(),
-                        message: format!("Container exited with error: {:?}.", e),
-                        failed: true,
-                    };
-                    patch_container_status(&api, &latest_pod, &container_name, &status)
-                        .await
-                        .unwrap();
+                    if !reported_terminated {
+                        let status = Status::Terminated {
+                            timestamp: Utc::now(),
+                            message: format!("Container exited with error: {:?}.", e),
+                            failed: true,
+                            exit_code: None,
+                            reason: None,
+                        };
+                        patch_container_status(&api, &latest_pod, &container_name, &status)
+                            .await
+                            .unwrap();
+                    }
 
                     break result;
                 }
@@ -3,7 +3,7 @@ use crate::pod::Pod;
 use chrono::{DateTime, Utc};
 use k8s_openapi::api::core::v1::{
     ContainerState, ContainerStateRunning, ContainerStateTerminated, ContainerStateWaiting,
-    ContainerStatus as KubeContainerStatus, Pod as KubePod,
+    ContainerStatus as KubeContainerStatus, Pod as KubePod, PodCondition,
 };
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
 use tracing::{debug, warn};
@@ -21,6 +21,8 @@ pub enum Status {
         timestamp: DateTime<Utc>,
         /// A human readable string describing the why it is in a waiting status
         message: String,
+        /// A brief CamelCase reason for waiting, such as `CrashLoopBackOff`
+        reason: Option<String>,
     },
     /// The container is running
     Running {
@@ -35,6 +37,11 @@ pub enum Status {
         message: String,
         /// Should be set to true if the process exited with an error
         failed: bool,
+        /// The exit code of the process, if it exited with one. Containers
+        /// without an exit code report 1 if they failed and 0 otherwise
+        exit_code: Option<i32>,
+        /// A brief CamelCase reason for terminating, such as `OOMKilled`
+        reason: Option<String>,
     },
 }
 
@@ -44,6 +51,17 @@ impl Status {
         Status::Waiting {
             timestamp: Utc::now(),
             message: message.to_string(),
+            reason: None,
+        }
+    }
+
+    /// Create `Status::Waiting` from a reason, such as
+    /// `CreateContainerConfigError`, and message.
+    pub fn waiting_with_reason(reason: &str, message: &str) -> Self {
+        Status::Waiting {
+            timestamp: Utc::now(),
+            message: message.to_string(),
+            reason: Some(reason.to_string()),
         }
     }
 
@@ -60,6 +78,32 @@ impl Status {
             timestamp: Utc::now(),
             message: message.to_string(),
             failed,
+            exit_code: None,
+            reason: None,
+        }
+    }
+
+    /// Create `Status::Terminated` from a reason, such as `OOMKilled`,
+    /// message and failed `bool`.
+    pub fn terminated_with_reason(reason: &str, message: &str, failed: bool) -> Self {
+        Status::Terminated {
+            timestamp: Utc::now(),
+            message: message.to_string(),
+            failed,
+            exit_code: None,
+            reason: Some(reason.to_string()),
+        }
+    }
+
+    /// Create `Status::Terminated` for a process that exited with `exit_code`.
+    /// Any non-zero exit code is treated as a failure.
+    pub fn exited(message: &str, exit_code: i32) -> Self {
+        Status::Terminated {
+            timestamp: Utc::now(),
+            message: message.to_string(),
+            failed: exit_code != 0,
+            exit_code: Some(exit_code),
+            reason: None,
         }
     }
 
@@ -67,10 +111,12 @@ impl Status {
     pub fn to_kubernetes(&self, container_name: &str) -> KubeContainerStatus {
         let mut state = ContainerState::default();
         match self {
-            Self::Waiting { message, .. } => {
+            Self::Waiting {
+                message, reason, ..
+            } => {
                 state.waiting.replace(ContainerStateWaiting {
                     message: Some(message.clone()),
-                    ..Default::default()
+                    reason: reason.clone(),
                 });
             }
             Self::Running { timestamp } => {
@@ -82,11 +128,14 @@ impl Status {
                 timestamp,
                 message,
                 failed,
+                exit_code,
+                reason,
             } => {
                 state.terminated.replace(ContainerStateTerminated {
                     finished_at: Some(Time(*timestamp)),
                     message: Some(message.clone()),
-                    exit_code: *failed as i32,
+                    exit_code: exit_code.unwrap_or(*failed as i32),
+                    reason: reason.clone(),
                     ..Default::default()
                 });
             }
@@ -95,11 +144,11 @@ impl Status {
         KubeContainerStatus {
             state: Some(state),
             name: container_name.to_string(),
-            // Right now we don't have a way to probe, so just set to ready if
-            // in a running state
+            // Containers are ready if running, unless their probes say
+            // otherwise (see `patch_container_readiness`)
             ready,
-            // This is always true if startupProbe is not defined. When we
-            // handle probes, this should be updated accordingly
+            // This is always true if startupProbe is not defined. Containers
+            // with one only start once it has succeeded
             started: Some(true),
             // The rest of the items in status (see docs here:
             // https://kubernetes.io/docs/reference/generated/kubernetes-api/v1.17/#containerstatus-v1-core)
@@ -119,7 +168,16 @@ pub async fn patch_container_status(
 ) -> anyhow::Result<()> {
     match pod.find_container(&key) {
         Some(container) => {
-            let kube_status = status.to_kubernetes(container.name());
+            let mut kube_status = status.to_kubernetes(container.name());
+            // Containers with probes are only ready, or started, once their
+            // probes have succeeded
+            if container.startup_probe().is_some() {
+                kube_status.started = Some(false);
+                kube_status.ready = false;
+            }
+            if container.readiness_probe().is_some() {
+                kube_status.ready = false;
+            }
 
             let patches = match pod.container_status_index(&key) {
                 Some(idx) => {
@@ -140,7 +198,7 @@ pub async fn patch_container_status(
                         }),
                         json_patch::PatchOperation::Replace(json_patch::ReplaceOperation {
                             path: format!("{}/started", path_prefix),
-                            value: serde_json::json!(true),
+                            value: serde_json::json!(kube_status.started),
                         }),
                     ]
                 }
@@ -181,6 +239,116 @@ pub async fn patch_container_status(
     }
 }
 
+/// Patch whether a single container has started and is ready, as determined by
+/// its probes, along with the pod's `ContainersReady` and `Ready` conditions.
+///
+/// The container's status must already have been reported with
+/// [patch_container_status].
+pub async fn patch_container_readiness(
+    client: &kube::Api<KubePod>,
+    pod: &Pod,
+    key: &ContainerKey,
+    started: bool,
+    ready: bool,
+) -> anyhow::Result<()> {
+    let idx = pod.container_status_index(key).ok_or_else(|| {
+        anyhow::anyhow!(
+            "Pod {} has no status for container {}.",
+            pod.name(),
+            key.name()
+        )
+    })?;
+    let path_prefix = if key.is_init() {
+        format!("/status/initContainerStatuses/{}", idx)
+    } else {
+        format!("/status/containerStatuses/{}", idx)
+    };
+    let patch = json_patch::Patch(vec![
+        json_patch::PatchOperation::Replace(json_patch::ReplaceOperation {
+            path: format!("{}/started", path_prefix),
+            value: serde_json::json!(started),
+        }),
+        json_patch::PatchOperation::Replace(json_patch::ReplaceOperation {
+            path: format!("{}/ready", path_prefix),
+            value: serde_json::json!(ready),
+        }),
+    ]);
+    let params = kube::api::PatchParams::default();
+    debug!(
+        "Patching container readiness {} {}: '{:?}'",
+        pod.name(),
+        key.name(),
+        patch
+    );
+    let patched = client
+        .patch_status(pod.name(), &params, &kube::api::Patch::<()>::Json(patch))
+        .await?;
+
+    // The pod is ready once all of its app containers are
+    let pod_ready = patched
+        .status
+        .iter()
+        .flat_map(|status| status.container_statuses.iter().flatten())
+        .all(|status| status.ready);
+    let status = if pod_ready { "True" } else { "False" };
+    let now = Time(Utc::now());
+    let conditions: Vec<_> = ["ContainersReady", "Ready"]
+        .iter()
+        .map(|type_| PodCondition {
+            type_: type_.to_string(),
+            status: status.to_string(),
+            last_transition_time: Some(now.clone()),
+            ..Default::default()
+        })
+        .collect();
+    let patch = serde_json::json!({ "status": { "conditions": conditions } });
+    client
+        .patch_status(pod.name(), &params, &kube::api::Patch::Strategic(patch))
+        .await?;
+    Ok(())
+}
+
+/// Patch the number of times a single container has been restarted.
+///
+/// The container's status must already have been reported with
+/// [patch_container_status].
+pub async fn patch_container_restart_count(
+    client: &kube::Api<KubePod>,
+    pod: &Pod,
+    key: &ContainerKey,
+    restart_count: i32,
+) -> anyhow::Result<()> {
+    let idx = pod.container_status_index(key).ok_or_else(|| {
+        anyhow::anyhow!(
+            "Pod {} has no status for container {}.",
+            pod.name(),
+            key.name()
+        )
+    })?;
+    let path = if key.is_init() {
+        format!("/status/initContainerStatuses/{}/restartCount", idx)
+    } else {
+        format!("/status/containerStatuses/{}/restartCount", idx)
+    };
+    let patch = json_patch::Patch(vec![json_patch::PatchOperation::Replace(
+        json_patch::ReplaceOperation {
+            path,
+            value: serde_json::json!(restart_count),
+        },
+    )]);
+    let params = kube::api::PatchParams::default();
+    debug!(
+        "Patching container restart count {} {}: '{:?}'",
+        pod.name(),
+        key.name(),
+        patch
+    );
+    client
+        .patch_status(pod.name(), &params, &kube::api::Patch::<()>::Json(patch))
+        .await?;
+    Ok(())
+}
+
 /// Create inital container status for registering pod.
 pub fn make_initial_container_status(container: &Container) -> KubeContainerStatus {
     let state = ContainerState {
@@ -198,3 +366,60 @@ pub fn make_initial_container_status(container: &Container) -> KubeContainerStat
         ..Default::default()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn exit_code(status: &Status) -> i32 {
+        status
+            .to_kubernetes("test")
+            .state
+            .unwrap()
+            .terminated
+            .unwrap()
+            .exit_code
+    }
+
+    #[test]
+    fn terminated_without_exit_code_reports_failure() {
+        assert_eq!(exit_code(&Status::terminated("done", false)), 0);
+        assert_eq!(exit_code(&Status::terminated("oops", true)), 1);
+    }
+
+    #[test]
+    fn exited_reports_exit_code() {
+        let status = Status::exited("exited", 42);
+        assert!(matches!(status, Status::Terminated { failed: true, .. }));
+        assert_eq!(exit_code(&status), 42);
+
+        let status = Status::exited("exited", 0);
+        assert!(matches!(status, Status::Terminated { failed: false, .. }));
+        assert_eq!(exit_code(&status), 0);
+    }
+
+    #[test]
+    fn terminated_reports_reason() {
+        let status = Status::terminated_with_reason("OOMKilled", "out of memory", true)
+            .to_kubernetes("test");
+        let terminated = status.state.unwrap().terminated.unwrap();
+        assert_eq!(terminated.reason.as_deref(), Some("OOMKilled"));
+        assert_eq!(terminated.message.as_deref(), Some("out of memory"));
+        assert_eq!(terminated.exit_code, 1);
+
+        let status = Status::terminated("done", false).to_kubernetes("test");
+        assert_eq!(status.state.unwrap().terminated.unwrap().reason, None);
+    }
+
+    #[test]
+    fn waiting_reports_reason() {
+        let status = Status::waiting_with_reason("CreateContainerConfigError", "missing key")
+            .to_kubernetes("test");
+        let waiting = status.state.unwrap().waiting.unwrap();
+        assert_eq!(
+            waiting.reason.as_deref(),
+            Some("CreateContainerConfigError")
+        );
+        assert_eq!(waiting.message.as_deref(), Some("missing key"));
+    }
+}
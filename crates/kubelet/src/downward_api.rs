@@ -0,0 +1,212 @@
+//! Information about a pod and its containers that is exposed to them through
+//! the Downward API, in environment variables and `downwardAPI` volumes.
+use std::collections::HashMap;
+
+use k8s_openapi::api::core::v1::{Node, ResourceFieldSelector};
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use kube::api::Api;
+use tracing::info;
+
+use crate::container::Container;
+use crate::pod::Pod;
+use crate::resources::{parse_quantity, CPU, EPHEMERAL_STORAGE, MEMORY};
+
+/// Build the map of allowable field_ref values.
+///
+/// The Downward API only supports a small selection of fields. This
+/// provides those fields.
+pub(crate) fn field_map(pod: &Pod) -> HashMap<String, String> {
+    let mut map: HashMap<String, String> = HashMap::new();
+    map.insert("metadata.name".into(), pod.name().to_owned());
+    map.insert("metadata.namespace".into(), pod.namespace().to_owned());
+    map.insert(
+        "metadata.uid".into(),
+        pod.uid().unwrap_or_default().to_owned(),
+    );
+    map.insert(
+        "spec.nodeName".into(),
+        pod.node_name().unwrap_or_default().to_owned(),
+    );
+    map.insert(
+        "spec.serviceAccountName".into(),
+        pod.service_account_name().unwrap_or_default().to_owned(),
+    );
+    map.insert(
+        "status.hostIP".into(),
+        pod.host_ip().unwrap_or_default().to_owned(),
+    );
+    // Nodes only have the one address
+    map.insert(
+        "status.hostIPs".into(),
+        pod.host_ip().unwrap_or_default().to_owned(),
+    );
+    map.insert(
+        "status.podIP".into(),
+        pod.pod_ip().unwrap_or_default().to_owned(),
+    );
+    map.insert("status.podIPs".into(), pod.pod_ips().join(","));
+    pod.labels().iter().for_each(|(k, v)| {
+        info!("adding {} to labels", k);
+        map.insert(format!("metadata.labels.{}", k), v.clone());
+        map.insert(format!("metadata.labels['{}']", k), v.clone());
+    });
+    pod.annotations().iter().for_each(|(k, v)| {
+        map.insert(format!("metadata.annotations.{}", k), v.clone());
+        map.insert(format!("metadata.annotations['{}']", k), v.clone());
+    });
+    map
+}
+
+/// Resolve a `resourceFieldRef`, such as `limits.memory`, of `container` in
+/// units of its divisor, rounded up.
+///
+/// As in Kubernetes, a limit that the container doesn't set is the
+/// allocatable amount of the resource on the pod's node, and a request that
+/// it doesn't set is zero.
+pub(crate) async fn resource_field_value(
+    selector: &ResourceFieldSelector,
+    container: &Container,
+    pod: &Pod,
+    client: &kube::Client,
+) -> anyhow::Result<String> {
+    let (kind, resource) = match selector.resource.split_once('.') {
+        Some((kind, resource)) if [CPU, MEMORY, EPHEMERAL_STORAGE].contains(&resource) => {
+            (kind, resource)
+        }
+        _ => anyhow::bail!("unsupported resource {}", selector.resource),
+    };
+    let quantity = match kind {
+        "limits" => match container.limit(resource) {
+            Some(limit) => limit.clone(),
+            None => node_allocatable(pod, client, resource).await?,
+        },
+        "requests" => container
+            .request(resource)
+            .cloned()
+            .unwrap_or_else(|| Quantity("0".to_string())),
+        _ => anyhow::bail!("unsupported resource {}", selector.resource),
+    };
+    in_units_of(&quantity, selector.divisor.as_ref())
+}
+
+async fn node_allocatable(
+    pod: &Pod,
+    client: &kube::Client,
+    resource: &str,
+) -> anyhow::Result<Quantity> {
+    let node_name = pod
+        .node_name()
+        .ok_or_else(|| anyhow::anyhow!("pod {} has not been scheduled", pod.name()))?;
+    let node = Api::<Node>::all(client.clone()).get(node_name).await?;
+    node.status
+        .and_then(|status| status.allocatable)
+        .and_then(|mut allocatable| allocatable.remove(resource))
+        .ok_or_else(|| anyhow::anyhow!("node {} has no allocatable {}", node_name, resource))
+}
+
+/// Divide a quantity by a divisor, which defaults to 1, rounding up.
+fn in_units_of(quantity: &Quantity, divisor: Option<&Quantity>) -> anyhow::Result<String> {
+    let divisor = match divisor {
+        Some(divisor) => parse_quantity(divisor)?,
+        None => 1.0,
+    };
+    if divisor <= 0.0 {
+        anyhow::bail!("divisor must be greater than zero");
+    }
+    let units = parse_quantity(quantity)? / divisor;
+    // Quantities like 100m aren't exact as floats, so a quotient that is
+    // whole but for rounding errors must not be rounded up
+    let whole = units.round();
+    let units = if (units - whole).abs() < 1e-9 * whole.abs().max(1.0) {
+        whole
+    } else {
+        units.ceil()
+    };
+    Ok((units as i64).to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn quantity(value: &str) -> Quantity {
+        Quantity(value.to_string())
+    }
+
+    #[test]
+    fn test_in_units_of_rounds_up() {
+        assert_eq!(in_units_of(&quantity("250m"), None).unwrap(), "1");
+        assert_eq!(
+            in_units_of(&quantity("100m"), Some(&quantity("1m"))).unwrap(),
+            "100"
+        );
+        assert_eq!(
+            in_units_of(&quantity("64Mi"), Some(&quantity("1Mi"))).unwrap(),
+            "64"
+        );
+        assert_eq!(
+            in_units_of(&quantity("1G"), Some(&quantity("1Mi"))).unwrap(),
+            "954"
+        );
+        assert!(in_units_of(&quantity("1"), Some(&quantity("0"))).is_err());
+    }
+
+    fn pod() -> Pod {
+        serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "test", "uid": "1234" },
+            "spec": {
+                "nodeName": "krustlet",
+                "containers": [{
+                    "name": "app",
+                    "resources": {
+                        "limits": { "cpu": "500m", "memory": "128Mi" },
+                        "requests": { "cpu": "250m" },
+                    },
+                }],
+            },
+            "status": { "hostIP": "10.0.0.1", "podIPs": [{ "ip": "10.244.1.2" }] },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_field_map() {
+        let fields = field_map(&pod());
+        assert_eq!(fields["metadata.uid"], "1234");
+        assert_eq!(fields["spec.nodeName"], "krustlet");
+        assert_eq!(fields["status.hostIPs"], "10.0.0.1");
+        assert_eq!(fields["status.podIPs"], "10.244.1.2");
+    }
+
+    #[tokio::test]
+    async fn test_resource_field_value() {
+        let pod = pod();
+        let container = pod.containers().remove(0);
+        // Never used, as the container sets the limits and requests asked for
+        let config = kube::Config::new("http://127.0.0.1:1".parse().unwrap());
+        let client = kube::Client::try_from(config).unwrap();
+        let selector = |resource: &str, divisor: &str| ResourceFieldSelector {
+            resource: resource.to_string(),
+            divisor: Some(quantity(divisor)),
+            container_name: None,
+        };
+        let value = |selector| {
+            let (container, pod, client) = (&container, &pod, &client);
+            async move {
+                resource_field_value(&selector, container, pod, client)
+                    .await
+                    .unwrap()
+            }
+        };
+        assert_eq!(value(selector("limits.cpu", "1m")).await, "500");
+        assert_eq!(value(selector("limits.memory", "1Mi")).await, "128");
+        assert_eq!(value(selector("requests.cpu", "1m")).await, "250");
+        assert_eq!(value(selector("requests.memory", "1")).await, "0");
+        assert!(
+            resource_field_value(&selector("limits.gpu", "1"), &container, &pod, &client)
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,153 @@
+//! `exec` contains types for streaming the output of commands run in a workload
+//! back to the client that requested them, and the output of a running workload
+//! to the clients attached to it.
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::mpsc;
+
+/// The output stream that a command wrote some data to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stream {
+    /// The command's stdout.
+    Stdout,
+    /// The command's stderr.
+    Stderr,
+}
+
+/// Sender for streaming the output of a command to the client.
+#[derive(Clone, Debug)]
+pub struct Sender {
+    sender: mpsc::Sender<(Stream, Vec<u8>)>,
+}
+
+impl Sender {
+    /// Create new `Sender` from a channel the client's output is read from.
+    pub fn new(sender: mpsc::Sender<(Stream, Vec<u8>)>) -> Self {
+        Sender { sender }
+    }
+
+    /// Async send output written to `stream` to the client.
+    pub async fn send(&self, stream: Stream, data: Vec<u8>) -> anyhow::Result<()> {
+        self.sender
+            .send((stream, data))
+            .await
+            .map_err(|_| anyhow::anyhow!("client disconnected"))
+    }
+
+    /// Send output written to `stream` to the client, blocking the current
+    /// thread until there is room for it. This must not be called from an
+    /// async context.
+    pub fn blocking_send(&self, stream: Stream, data: Vec<u8>) -> anyhow::Result<()> {
+        self.sender
+            .blocking_send((stream, data))
+            .map_err(|_| anyhow::anyhow!("client disconnected"))
+    }
+}
+
+/// The clients attached to the output of a running process. Providers send a
+/// copy of everything the process writes to stdout or stderr here, and each
+/// attached client is disconnected once the process exits.
+#[derive(Clone, Debug)]
+pub struct AttachedOutput {
+    /// The attached clients, or `None` once the process has exited
+    clients: Arc<Mutex<Option<Vec<Sender>>>>,
+}
+
+impl Default for AttachedOutput {
+    fn default() -> Self {
+        AttachedOutput {
+            clients: Arc::new(Mutex::new(Some(Vec::new()))),
+        }
+    }
+}
+
+impl AttachedOutput {
+    /// Create new `AttachedOutput` for a running process, with no clients attached.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send the process's output to `client` from now until it exits.
+    pub fn attach(&self, client: Sender) -> anyhow::Result<()> {
+        match self.clients.lock().unwrap().as_mut() {
+            Some(clients) => {
+                clients.push(client);
+                Ok(())
+            }
+            None => Err(anyhow::anyhow!("process has exited")),
+        }
+    }
+
+    /// Send output written to `stream` to every attached client, blocking the
+    /// current thread until each has room for it. Clients that have detached
+    /// are dropped. This must not be called from an async context.
+    pub fn blocking_send(&self, stream: Stream, data: &[u8]) {
+        let clients = match self.clients.lock().unwrap().as_ref() {
+            Some(clients) if !clients.is_empty() => clients.clone(),
+            _ => return,
+        };
+        let mut detached = false;
+        for client in clients {
+            detached |= client.blocking_send(stream, data.to_vec()).is_err();
+        }
+        if detached {
+            if let Some(clients) = self.clients.lock().unwrap().as_mut() {
+                clients.retain(|client| !client.sender.is_closed());
+            }
+        }
+    }
+
+    /// Disconnect every attached client because the process has exited, and
+    /// refuse any more.
+    pub fn close(&self) {
+        self.clients.lock().unwrap().take();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn attached_clients_receive_output_until_closed() {
+        let output = AttachedOutput::new();
+        let (sender, mut client) = mpsc::channel(8);
+        output.attach(Sender::new(sender)).unwrap();
+
+        let sending = output.clone();
+        tokio::task::spawn_blocking(move || {
+            sending.blocking_send(Stream::Stdout, b"hello");
+            sending.blocking_send(Stream::Stderr, b"oh no");
+            sending.close();
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            client.recv().await,
+            Some((Stream::Stdout, b"hello".to_vec()))
+        );
+        assert_eq!(
+            client.recv().await,
+            Some((Stream::Stderr, b"oh no".to_vec()))
+        );
+        assert_eq!(client.recv().await, None);
+
+        let (sender, _client) = mpsc::channel(8);
+        assert!(output.attach(Sender::new(sender)).is_err());
+    }
+
+    #[tokio::test]
+    async fn detached_clients_are_dropped() {
+        let output = AttachedOutput::new();
+        let (sender, client) = mpsc::channel(8);
+        output.attach(Sender::new(sender)).unwrap();
+        drop(client);
+
+        let sending = output.clone();
+        tokio::task::spawn_blocking(move || sending.blocking_send(Stream::Stdout, b"hello"))
+            .await
+            .unwrap();
+        assert!(output.clients.lock().unwrap().as_ref().unwrap().is_empty());
+    }
+}
@@ -1,3 +1,7 @@
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+
 /// A [`StopHandler`] is used to handle stopping running processes.
 #[async_trait::async_trait]
 pub trait StopHandler {
@@ -7,6 +11,30 @@ pub trait StopHandler {
     /// underlying handle to complete. Instead they should call wait() to wait for anything running
     /// to stop.
     async fn stop(&mut self) -> anyhow::Result<()>;
+    /// Asks anything running under the implementor to shut down by itself, and
+    /// stops it as [`StopHandler::stop`] does if it is still running after
+    /// `grace_period`.
+    ///
+    /// Implementors that have no way to ask for a graceful shutdown can keep
+    /// the default, which stops immediately.
+    async fn stop_gracefully(&mut self, grace_period: Duration) -> anyhow::Result<()> {
+        let _ = grace_period;
+        self.stop().await
+    }
+    /// Starts stopping anything running under the implementor as
+    /// [`StopHandler::stop_gracefully`] does, returning a future that finishes
+    /// stopping it without borrowing the implementor, so that callers don't
+    /// have to hold on to it for the whole grace period.
+    ///
+    /// Implementors that can't do so can keep the default, which returns
+    /// `None`, and are then stopped with `stop_gracefully`.
+    fn stop_gracefully_detached(
+        &mut self,
+        grace_period: Duration,
+    ) -> Option<BoxFuture<'static, anyhow::Result<()>>> {
+        let _ = grace_period;
+        None
+    }
     /// Wait for the implementor to stop anything it considers in the running state.
     async fn wait(&mut self) -> anyhow::Result<()>;
 }
@@ -209,13 +209,15 @@ mod test {
     use crate::container::Container;
     use crate::plugin_watcher::PluginRegistry;
     use crate::pod::{Pod, Status};
+    use crate::test_util::fake_api_server;
     use k8s_openapi::api::core::v1::{
-        Container as KubeContainer, EnvVar, EnvVarSource, ObjectFieldSelector, Pod as KubePod,
-        PodSpec, PodStatus,
+        ConfigMapEnvSource, ConfigMapKeySelector, Container as KubeContainer, EnvFromSource,
+        EnvVar, EnvVarSource, ObjectFieldSelector, Pod as KubePod, PodSpec, PodStatus,
+        ResourceFieldSelector, SecretEnvSource, SecretKeySelector,
     };
     use krator::ObjectState;
     use kube::api::ObjectMeta;
-    use std::collections::BTreeMap;
+    use std::collections::{BTreeMap, HashMap};
     use tokio::sync::RwLock;
 
     fn mock_client() -> kube::Client {
@@ -225,6 +227,80 @@ mod test {
         .unwrap()
     }
 
+    /// The objects that the fake API server serves in the env var tests.
+    fn env_objects() -> Vec<(&'static str, serde_json::Value)> {
+        vec![
+            (
+                "/api/v1/namespaces/default/configmaps/payments",
+                serde_json::json!({
+                    "apiVersion": "v1",
+                    "kind": "ConfigMap",
+                    "metadata": { "name": "payments", "namespace": "default" },
+                    "data": { "endpoint": "https://payments.example.com" },
+                }),
+            ),
+            (
+                "/api/v1/namespaces/default/secrets/credentials",
+                serde_json::json!({
+                    "apiVersion": "v1",
+                    "kind": "Secret",
+                    "metadata": { "name": "credentials", "namespace": "default" },
+                    // "hunter2"
+                    "data": { "password": "aHVudGVyMg==" },
+                }),
+            ),
+        ]
+    }
+
+    fn config_map_key(name: &str, key: &str, optional: bool) -> EnvVarSource {
+        EnvVarSource {
+            config_map_key_ref: Some(ConfigMapKeySelector {
+                name: Some(name.into()),
+                key: key.into(),
+                optional: Some(optional),
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn secret_key(name: &str, key: &str, optional: bool) -> EnvVarSource {
+        EnvVarSource {
+            secret_key_ref: Some(SecretKeySelector {
+                name: Some(name.into()),
+                key: key.into(),
+                optional: Some(optional),
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn env_container(env: Vec<(&str, EnvVarSource)>, env_from: Vec<EnvFromSource>) -> Container {
+        Container::new(&KubeContainer {
+            env: Some(
+                env.into_iter()
+                    .map(|(name, source)| EnvVar {
+                        name: name.into(),
+                        value_from: Some(source),
+                        ..Default::default()
+                    })
+                    .collect(),
+            ),
+            env_from: Some(env_from),
+            ..Default::default()
+        })
+    }
+
+    fn env_pod() -> Pod {
+        Pod::from(KubePod {
+            metadata: ObjectMeta {
+                name: Some("my-name".to_string()),
+                namespace: Some("default".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+
     struct MockProvider;
 
     struct ProviderState;
@@ -372,7 +448,9 @@ mod test {
                 ..Default::default()
             }),
         });
-        let env = MockProvider::env_vars(&container, &pod, &mock_client()).await;
+        let env = MockProvider::env_vars(&container, &pod, &mock_client())
+            .await
+            .unwrap();
 
         assert_eq!(
             "value",
@@ -397,4 +475,215 @@ mod test {
         assert_eq!("10.21.77.2", env.get("POD_IP").expect("pod_ip").as_str());
         assert_eq!("10.21.77.1", env.get("HOST_IP").expect("host_ip").as_str());
     }
+
+    #[tokio::test]
+    async fn test_env_vars_fail_on_unresolved_refs() {
+        let container = Container::new(&KubeContainer {
+            env: Some(vec![EnvVar {
+                name: "API_KEY".into(),
+                value_from: Some(EnvVarSource {
+                    config_map_key_ref: Some(ConfigMapKeySelector {
+                        name: Some("payments".into()),
+                        key: "api-key".into(),
+                        optional: Some(true),
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        });
+        let pod = Pod::from(KubePod {
+            metadata: ObjectMeta {
+                name: Some("my-name".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        // Even optional references fail if the API server can't say whether
+        // the ConfigMap exists
+        let e = MockProvider::env_vars(&container, &pod, &mock_client())
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("unable to fetch configmap"));
+    }
+
+    #[tokio::test]
+    async fn test_env_vars_fail_on_unresolvable_resource_fields() {
+        fn resource_field(container_name: &str, resource: &str) -> Container {
+            Container::new(&KubeContainer {
+                name: "app".into(),
+                env: Some(vec![EnvVar {
+                    name: "LIMIT".into(),
+                    value_from: Some(EnvVarSource {
+                        resource_field_ref: Some(ResourceFieldSelector {
+                            container_name: Some(container_name.into()),
+                            resource: resource.into(),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            })
+        }
+        let pod = Pod::from(KubePod {
+            metadata: ObjectMeta {
+                name: Some("my-name".to_string()),
+                ..Default::default()
+            },
+            spec: Some(PodSpec {
+                containers: vec![KubeContainer {
+                    name: "app".into(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+
+        let container = resource_field("sidecar", "requests.cpu");
+        let e = MockProvider::env_vars(&container, &pod, &mock_client())
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("no container named sidecar"));
+
+        let container = resource_field("app", "requests.gpu");
+        let e = MockProvider::env_vars(&container, &pod, &mock_client())
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("unsupported resource requests.gpu"));
+
+        // Requests default to zero, as in Kubernetes
+        let container = resource_field("app", "requests.cpu");
+        let env = MockProvider::env_vars(&container, &pod, &mock_client())
+            .await
+            .unwrap();
+        assert_eq!(env["LIMIT"], "0");
+    }
+
+    #[tokio::test]
+    async fn test_env_vars_fail_on_missing_sources() {
+        let (client, _) = fake_api_server(env_objects()).await;
+        let pod = env_pod();
+
+        let cases = vec![
+            (
+                env_container(
+                    vec![("ENDPOINT", config_map_key("billing", "endpoint", false))],
+                    vec![],
+                ),
+                "configmap \"billing\" not found",
+            ),
+            (
+                env_container(
+                    vec![("PASSWORD", secret_key("tokens", "password", false))],
+                    vec![],
+                ),
+                "secret \"tokens\" not found",
+            ),
+            (
+                env_container(
+                    vec![("TIMEOUT", config_map_key("payments", "timeout", false))],
+                    vec![],
+                ),
+                "couldn't find key timeout in ConfigMap default/payments",
+            ),
+            (
+                env_container(
+                    vec![("TOKEN", secret_key("credentials", "token", false))],
+                    vec![],
+                ),
+                "couldn't find key token in Secret default/credentials",
+            ),
+            (
+                env_container(
+                    vec![],
+                    vec![EnvFromSource {
+                        config_map_ref: Some(ConfigMapEnvSource {
+                            name: Some("billing".into()),
+                            optional: None,
+                        }),
+                        ..Default::default()
+                    }],
+                ),
+                "configmap \"billing\" not found",
+            ),
+            (
+                env_container(
+                    vec![],
+                    vec![EnvFromSource {
+                        secret_ref: Some(SecretEnvSource {
+                            name: Some("tokens".into()),
+                            optional: Some(false),
+                        }),
+                        ..Default::default()
+                    }],
+                ),
+                "secret \"tokens\" not found",
+            ),
+        ];
+        for (container, expected) in cases {
+            let e = MockProvider::env_vars(&container, &pod, &client)
+                .await
+                .unwrap_err();
+            assert!(
+                e.to_string().contains(expected),
+                "expected {:?} to contain {:?}",
+                e.to_string(),
+                expected
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_env_vars_skip_missing_optional_sources() {
+        let (client, _) = fake_api_server(env_objects()).await;
+        let container = env_container(
+            vec![
+                ("ENDPOINT", config_map_key("payments", "endpoint", false)),
+                ("PASSWORD", secret_key("credentials", "password", false)),
+                ("BILLING", config_map_key("billing", "endpoint", true)),
+                ("TIMEOUT", config_map_key("payments", "timeout", true)),
+                ("TOKEN", secret_key("tokens", "token", true)),
+                ("USERNAME", secret_key("credentials", "username", true)),
+            ],
+            vec![
+                EnvFromSource {
+                    prefix: Some("PAYMENTS_".into()),
+                    config_map_ref: Some(ConfigMapEnvSource {
+                        name: Some("payments".into()),
+                        optional: None,
+                    }),
+                    ..Default::default()
+                },
+                EnvFromSource {
+                    config_map_ref: Some(ConfigMapEnvSource {
+                        name: Some("billing".into()),
+                        optional: Some(true),
+                    }),
+                    secret_ref: Some(SecretEnvSource {
+                        name: Some("tokens".into()),
+                        optional: Some(true),
+                    }),
+                    ..Default::default()
+                },
+            ],
+        );
+
+        let env = MockProvider::env_vars(&container, &env_pod(), &client)
+            .await
+            .unwrap();
+
+        let expected: HashMap<String, String> = vec![
+            ("ENDPOINT", "https://payments.example.com"),
+            ("PAYMENTS_endpoint", "https://payments.example.com"),
+            ("PASSWORD", "hunter2"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        .collect();
+        assert_eq!(env, expected);
+    }
 }
@@ -77,6 +77,7 @@
 
 mod bootstrapping;
 mod config_interpreter;
+mod downward_api;
 mod kubelet;
 mod operator;
 
@@ -94,16 +95,23 @@ pub(crate) mod grpc_sock;
 #[cfg(target_family = "windows")]
 #[allow(dead_code, clippy::all)]
 pub(crate) mod mio_uds_windows;
+#[cfg(test)]
+pub(crate) mod test_util;
 
 pub mod backoff;
 pub mod config;
 pub mod container;
+pub mod exec;
 pub mod handle;
+pub mod lifecycle;
 pub mod log;
 pub mod node;
 pub mod plugin_watcher;
 pub mod pod;
+pub mod probe;
 pub mod provider;
+pub mod resources;
+pub mod runtime_class;
 pub mod secret;
 pub mod state;
 pub mod store;
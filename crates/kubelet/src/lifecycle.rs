@@ -0,0 +1,131 @@
+//! `lifecycle` runs the `postStart` and `preStop` hooks of containers.
+use k8s_openapi::api::core::v1::Handler as KubeHandler;
+
+use crate::container::Container;
+use crate::probe::{Exec, Handler};
+
+/// The lifecycle hooks of a container.
+#[derive(Clone, Debug, Default)]
+pub struct Hooks {
+    post_start: Option<Handler>,
+    pre_stop: Option<Handler>,
+}
+
+impl Hooks {
+    /// Creates the hooks configured in `container`'s manifest. HTTP hooks
+    /// without a host connect to `pod_ip`.
+    pub fn new(container: &Container, pod_ip: &str) -> anyhow::Result<Self> {
+        let lifecycle = match container.lifecycle() {
+            Some(lifecycle) => lifecycle,
+            None => return Ok(Hooks::default()),
+        };
+        let hook = |hook: Option<&KubeHandler>, kind: &str| {
+            hook.map(|hook| {
+                if hook.tcp_socket.is_some() {
+                    anyhow::bail!("TCP hooks are not supported");
+                }
+                Handler::new(
+                    hook.exec.as_ref(),
+                    hook.http_get.as_ref(),
+                    None,
+                    container,
+                    pod_ip,
+                )
+            })
+            .transpose()
+            .map_err(|e| anyhow::anyhow!("invalid {} hook: {}", kind, e))
+        };
+        Ok(Hooks {
+            post_start: hook(lifecycle.post_start.as_ref(), "postStart")?,
+            pre_stop: hook(lifecycle.pre_stop.as_ref(), "preStop")?,
+        })
+    }
+
+    /// Whether the container has a `preStop` hook.
+    pub fn has_pre_stop(&self) -> bool {
+        self.pre_stop.is_some()
+    }
+
+    /// Runs the `postStart` hook, if there is one, once the container has
+    /// started. If it fails, the container should be stopped.
+    pub async fn post_start(&self, exec: &dyn Exec) -> anyhow::Result<()> {
+        match &self.post_start {
+            Some(hook) => hook
+                .check(exec)
+                .await
+                .map_err(|e| anyhow::anyhow!("postStart hook failed: {}", e)),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the `preStop` hook, if there is one, before the container is
+    /// stopped. The container should be stopped whether or not it succeeds.
+    pub async fn pre_stop(&self, exec: &dyn Exec) -> anyhow::Result<()> {
+        match &self.pre_stop {
+            Some(hook) => hook
+                .check(exec)
+                .await
+                .map_err(|e| anyhow::anyhow!("preStop hook failed: {}", e)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct ExitCode(i32);
+
+    #[async_trait::async_trait]
+    impl Exec for ExitCode {
+        async fn exec(&self, _command: Vec<String>) -> anyhow::Result<i32> {
+            Ok(self.0)
+        }
+    }
+
+    fn container(lifecycle: serde_json::Value) -> Container {
+        Container::new(
+            &serde_json::from_value(serde_json::json!({
+                "name": "test",
+                "lifecycle": lifecycle,
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_hooks_report_failures() {
+        let hooks = Hooks::new(
+            &container(serde_json::json!({
+                "postStart": { "exec": { "command": ["warm-up"] } },
+                "preStop": { "exec": { "command": ["flush"] } },
+            })),
+            "10.244.1.2",
+        )
+        .unwrap();
+        assert!(hooks.has_pre_stop());
+        assert!(hooks.post_start(&ExitCode(0)).await.is_ok());
+        let e = hooks.pre_stop(&ExitCode(3)).await.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "preStop hook failed: command exited with code 3"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_missing_hooks_succeed() {
+        let hooks = Hooks::new(&container(serde_json::json!({})), "10.244.1.2").unwrap();
+        assert!(!hooks.has_pre_stop());
+        assert!(hooks.post_start(&ExitCode(1)).await.is_ok());
+        assert!(hooks.pre_stop(&ExitCode(1)).await.is_ok());
+    }
+
+    #[test]
+    fn test_tcp_hooks_are_rejected() {
+        let container = container(serde_json::json!({
+            "preStop": { "tcpSocket": { "port": 80 } },
+        }));
+        assert!(Hooks::new(&container, "10.244.1.2").is_err());
+    }
+}
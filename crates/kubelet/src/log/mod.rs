@@ -85,28 +85,119 @@ impl Sender {
     }
 }
 
+/// The format that a container's log is stored in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// The log holds the container's output exactly as it was written.
+    Plain,
+    /// The log holds the container's output in the CRI logging format, one
+    /// `<timestamp> <stream> <tag> <content>` record per line, where the tag
+    /// is `P` for part of a line and `F` for the end of a line.
+    Cri,
+}
+
+/// Reads a container's output from a log, one line at a time.
+struct LogLines<R> {
+    reader: tokio::io::BufReader<R>,
+    format: Format,
+    /// the parts of a CRI log line that have been read so far, by stream
+    partial: std::collections::HashMap<String, Vec<u8>>,
+}
+
+impl<R: AsyncRead + std::marker::Unpin> LogLines<R> {
+    fn new(handle: R, format: Format) -> Self {
+        LogLines {
+            reader: tokio::io::BufReader::new(handle),
+            format,
+            partial: std::collections::HashMap::new(),
+        }
+    }
+
+    /// Returns the next line of output, or `None` if the end of the log has
+    /// been reached. Parts of a line that hasn't been fully written yet are
+    /// held on to until the rest of it is read from the same stream.
+    async fn next_line(&mut self) -> std::io::Result<Option<String>> {
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if self.reader.read_until(b'\n', &mut line).await? == 0 {
+                return Ok(None);
+            }
+            if line.last() == Some(&b'\n') {
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+            }
+            if self.format == Format::Plain {
+                return Ok(Some(String::from_utf8_lossy(&line).into_owned()));
+            }
+            match parse_cri_line(&line) {
+                Some((stream, true, content)) => self
+                    .partial
+                    .entry(stream.to_owned())
+                    .or_default()
+                    .extend_from_slice(content),
+                Some((stream, false, content)) => {
+                    // A partial line may have been cut anywhere, so it is only
+                    // decoded once the whole line has been read
+                    let mut full = self.partial.remove(stream).unwrap_or_default();
+                    full.extend_from_slice(content);
+                    return Ok(Some(String::from_utf8_lossy(&full).into_owned()));
+                }
+                // Pass through anything that wasn't written by the runtime
+                None => return Ok(Some(String::from_utf8_lossy(&line).into_owned())),
+            }
+        }
+    }
+}
+
+/// Splits a CRI log line into its stream, whether it is a partial line and
+/// its content.
+fn parse_cri_line(line: &[u8]) -> Option<(&str, bool, &[u8])> {
+    let mut fields = line.splitn(4, |b| *b == b' ');
+    let _timestamp = fields.next()?;
+    let stream = std::str::from_utf8(fields.next()?).ok()?;
+    let partial = match fields.next()? {
+        b"P" => true,
+        b"F" => false,
+        _ => return None,
+    };
+    Some((stream, partial, fields.next().unwrap_or_default()))
+}
+
+/// Read to the end of the log, keeping the last `n` lines.
+async fn last_lines<R: AsyncRead + std::marker::Unpin>(
+    lines: &mut LogLines<R>,
+    n: usize,
+) -> std::io::Result<std::collections::VecDeque<String>> {
+    let mut line_buf = std::collections::VecDeque::with_capacity(n);
+    while let Some(line) = lines.next_line().await? {
+        if line_buf.len() == n {
+            line_buf.pop_front();
+        }
+        if n > 0 {
+            line_buf.push_back(line);
+        }
+    }
+    Ok(line_buf)
+}
+
 /// Stream last `n` lines.
 async fn tail<R: AsyncRead + std::marker::Unpin>(
-    lines: &mut tokio::io::Lines<tokio::io::BufReader<R>>,
+    lines: &mut LogLines<R>,
     sender: &mut Sender,
     n: usize,
 ) -> Result<(), SendError> {
-    let mut line_buf = std::collections::VecDeque::with_capacity(n);
-
-    while let Some(line) = match lines.next_line().await {
-        Ok(line) => line,
+    let line_buf = match last_lines(lines, n).await {
+        Ok(line_buf) => line_buf,
         Err(e) => {
             let err = format!("Error reading from log: {:?}", e);
             error!("{}", &err);
             sender.send(err).await?;
             return Err(e.into());
         }
-    } {
-        if line_buf.len() == n {
-            line_buf.pop_front();
-        }
-        line_buf.push_back(line);
-    }
+    };
 
     for mut line in line_buf {
         line.push('\n');
@@ -117,7 +208,7 @@ async fn tail<R: AsyncRead + std::marker::Unpin>(
 
 /// Stream log to end.
 async fn stream_to_end<R: AsyncRead + std::marker::Unpin>(
-    lines: &mut tokio::io::Lines<tokio::io::BufReader<R>>,
+    lines: &mut LogLines<R>,
     sender: &mut Sender,
 ) -> Result<(), SendError> {
     while let Some(mut line) = match lines.next_line().await {
@@ -135,13 +226,14 @@ async fn stream_to_end<R: AsyncRead + std::marker::Unpin>(
     Ok(())
 }
 
-/// Future that streams logs from provided `AsyncRead` to provided `Sender`.
+/// Future that streams logs in the given format from provided `AsyncRead` to
+/// provided `Sender`.
 pub async fn stream<R: AsyncRead + std::marker::Unpin>(
     handle: R,
     mut sender: Sender,
+    format: Format,
 ) -> anyhow::Result<()> {
-    let buf = tokio::io::BufReader::new(handle);
-    let mut lines = buf.lines();
+    let mut lines = LogLines::new(handle, format);
 
     if let Some(n) = sender.tail() {
         match tail(&mut lines, &mut sender, n).await {
@@ -172,6 +264,17 @@ pub async fn stream<R: AsyncRead + std::marker::Unpin>(
     Ok(())
 }
 
+/// Read the last `n` lines of output from a log in the given format, such as
+/// to report why a container failed.
+pub async fn read_tail<R: AsyncRead + std::marker::Unpin>(
+    handle: R,
+    format: Format,
+    n: usize,
+) -> std::io::Result<Vec<String>> {
+    let mut lines = LogLines::new(handle, format);
+    Ok(last_lines(&mut lines, n).await?.into())
+}
+
 // TODO: Both providers make a handle containing a tempfile. If this is a common pattern,
 // it might make sense to provide that implementation here. This would add `tempfile` as a
 // dependency of `kubelet`.
@@ -179,4 +282,95 @@ pub async fn stream<R: AsyncRead + std::marker::Unpin>(
 pub trait HandleFactory<R>: Sync + Send {
     /// Create new log reader.
     fn new_handle(&self) -> R;
+
+    /// The format of the log read by the readers. Defaults to [`Format::Plain`].
+    fn format(&self) -> Format {
+        Format::Plain
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn read_all(log: &str, format: Format) -> Vec<String> {
+        let mut lines = LogLines::new(log.as_bytes(), format);
+        let mut output = vec![];
+        while let Some(line) = lines.next_line().await.unwrap() {
+            output.push(line);
+        }
+        output
+    }
+
+    #[tokio::test]
+    async fn plain_logs_are_read_as_is() {
+        let log = "2021-03-01T00:00:00.000000000Z stdout F hello\nworld\n";
+        assert_eq!(
+            read_all(log, Format::Plain).await,
+            vec!["2021-03-01T00:00:00.000000000Z stdout F hello", "world"]
+        );
+    }
+
+    #[tokio::test]
+    async fn cri_logs_are_decoded() {
+        let log = "2021-03-01T00:00:00.000000000Z stdout F hello world\n\
+                   2021-03-01T00:00:01.000000000Z stderr F oh no\n\
+                   2021-03-01T00:00:02.000000000Z stdout F \n";
+        assert_eq!(
+            read_all(log, Format::Cri).await,
+            vec!["hello world", "oh no", ""]
+        );
+    }
+
+    #[tokio::test]
+    async fn cri_partial_lines_are_joined() {
+        let log = "2021-03-01T00:00:00.000000000Z stdout P hello \n\
+                   2021-03-01T00:00:01.000000000Z stdout F world\n\
+                   2021-03-01T00:00:02.000000000Z stdout P unfinished\n";
+        assert_eq!(read_all(log, Format::Cri).await, vec!["hello world"]);
+    }
+
+    #[tokio::test]
+    async fn cri_partial_lines_are_joined_per_stream() {
+        let log = "2021-03-01T00:00:00.000000000Z stdout P hello \n\
+                   2021-03-01T00:00:01.000000000Z stderr P oh \n\
+                   2021-03-01T00:00:02.000000000Z stdout F world\n\
+                   2021-03-01T00:00:03.000000000Z stderr F no\n";
+        assert_eq!(
+            read_all(log, Format::Cri).await,
+            vec!["hello world", "oh no"]
+        );
+    }
+
+    #[tokio::test]
+    async fn cri_partial_lines_may_split_characters() {
+        let mut log = b"2021-03-01T00:00:00.000000000Z stdout P caf\xc3\n".to_vec();
+        log.extend_from_slice(b"2021-03-01T00:00:01.000000000Z stdout F \xa9\n");
+        let mut lines = LogLines::new(log.as_slice(), Format::Cri);
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "caf\u{e9}");
+        assert!(lines.next_line().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn read_tail_keeps_last_lines() {
+        let log = "2021-03-01T00:00:00.000000000Z stdout F one\n\
+                   2021-03-01T00:00:01.000000000Z stderr F two\n\
+                   2021-03-01T00:00:02.000000000Z stdout F three\n";
+        assert_eq!(
+            read_tail(log.as_bytes(), Format::Cri, 2).await.unwrap(),
+            vec!["two", "three"]
+        );
+        assert!(read_tail(log.as_bytes(), Format::Cri, 0)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn cri_logs_pass_through_other_lines() {
+        assert_eq!(
+            read_all("not a cri line\n", Format::Cri).await,
+            vec!["not a cri line"]
+        );
+    }
 }
@@ -2,7 +2,7 @@
 //! nodes operating within the cluster.
 use crate::config::Config;
 use crate::container::Status as ContainerStatus;
-use crate::pod::{Phase, Pod};
+use crate::pod::{Phase, Pod, PodKey};
 use crate::provider::Provider;
 use chrono::prelude::*;
 use futures::{StreamExt, TryStreamExt};
@@ -14,8 +14,10 @@ use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
 use kube::api::{Api, ListParams, ObjectMeta, PatchParams, PostParams};
 use kube::error::ErrorResponse;
 use kube::Error;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashSet};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
 use tracing::{debug, error, info, warn};
 
 const KUBELET_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -70,8 +72,17 @@ pub async fn create<P: Provider>(client: &kube::Client, config: &Config, provide
 
     match retry!(node_client.get(&config.node_name).await, times: 4, break_on: &Error::Api(ErrorResponse { code: 404, .. }))
     {
-        Ok(_) => {
+        Ok(node) => {
             debug!("Node already exists, skipping node creation");
+            let handlers = provider.runtime_handlers();
+            if let Err(e) =
+                retry!(update_handler_labels(&node_client, &node, &handlers).await, times: 4)
+            {
+                error!(
+                    "Exhausted retries updating runtime handler labels: {}. Not retrying.",
+                    e
+                );
+            }
             return;
         }
         Err(Error::Api(ErrorResponse { code: 404, .. })) => (),
@@ -95,6 +106,9 @@ pub async fn create<P: Provider>(client: &kube::Client, config: &Config, provide
     );
 
     node_labels_definition(P::ARCH, &config, &mut builder);
+    for handler in provider.runtime_handlers() {
+        builder.add_label(&crate::runtime_class::handler_label(&handler), "true");
+    }
 
     // TODO Do we want to detect this?
     builder.add_capacity("cpu", "4");
@@ -152,6 +166,38 @@ pub async fn create<P: Provider>(client: &kube::Client, config: &Config, provide
     info!("Successfully created node '{}'", &config.node_name);
 }
 
+/// Label an existing node with the runtime handlers the provider supports
+/// now, removing the labels of handlers it no longer supports, as a node
+/// that's created with them would have been.
+async fn update_handler_labels(
+    node_client: &Api<KubeNode>,
+    node: &KubeNode,
+    handlers: &[String],
+) -> Result<KubeNode, Error> {
+    let mut labels: serde_json::Map<String, serde_json::Value> = node
+        .metadata
+        .labels
+        .iter()
+        .flatten()
+        .filter(|(label, _)| label.starts_with(crate::runtime_class::HANDLER_LABEL_PREFIX))
+        .map(|(label, _)| (label.clone(), serde_json::Value::Null))
+        .collect();
+    for handler in handlers {
+        labels.insert(
+            crate::runtime_class::handler_label(handler),
+            serde_json::Value::from("true"),
+        );
+    }
+    let patch = serde_json::json!({ "metadata": { "labels": labels } });
+    node_client
+        .patch(
+            node.metadata.name.as_deref().unwrap_or_default(),
+            &PatchParams::default(),
+            &kube::api::Patch::Strategic(patch),
+        )
+        .await
+}
+
 /// Fetch the uid of a node by name.
 pub async fn uid(client: &kube::Client, node_name: &str) -> anyhow::Result<String> {
     let node_client: Api<KubeNode> = Api::all(client.clone());
@@ -179,7 +225,12 @@ pub async fn drain(client: &kube::Client, node_name: &str) -> anyhow::Result<()>
     Ok(())
 }
 
-/// Fetches list of pods on this node and deletes them.
+/// How much longer than its grace period a pod is given to be deleted while
+/// evicting, to allow for the Kubernetes API and the provider.
+const EVICTION_TIMEOUT_MARGIN: Duration = Duration::from_secs(10);
+
+/// Fetches list of pods on this node and deletes them, waiting for them to be
+/// deleted for up to their termination grace periods.
 pub async fn evict_pods(client: &kube::Client, node_name: &str) -> anyhow::Result<()> {
     let pod_client: Api<KubePod> = Api::all(client.clone());
     let node_selector = format!("spec.nodeName={}", node_name);
@@ -196,6 +247,9 @@ pub async fn evict_pods(client: &kube::Client, node_name: &str) -> anyhow::Resul
 
     info!("Evicting {} pods.", pods.len());
 
+    // The pods being deleted, which are all given their grace periods at once
+    let mut evicting = HashSet::new();
+    let mut deadline = Instant::now();
     for pod in pods {
         let pod = Pod::from(pod);
         if pod.is_daemonset() {
@@ -215,7 +269,9 @@ pub async fn evict_pods(client: &kube::Client, node_name: &str) -> anyhow::Resul
                             ContainerStatus::Terminated {
                                 timestamp: Utc::now(),
                                 message: "Evicted on node shutdown".to_string(),
-                                failed: false
+                                failed: false,
+                                exit_code: None,
+                                reason: None,
                             }.to_kubernetes(container.name())
                         }).collect::<Vec<KubeContainerStatus>>()
                     }
@@ -231,8 +287,14 @@ pub async fn evict_pods(client: &kube::Client, node_name: &str) -> anyhow::Resul
             info!("Marked static pod as terminated.");
             continue;
         } else {
-            match evict_pod(&client, pod.name(), pod.namespace(), &mut stream).await {
-                Ok(_) => (),
+            match evict_pod(client, &pod).await {
+                Ok(true) => {
+                    evicting.insert(PodKey::from(&pod));
+                    deadline = deadline.max(
+                        Instant::now() + pod.termination_grace_period() + EVICTION_TIMEOUT_MARGIN,
+                    );
+                }
+                Ok(false) => (),
                 Err(e) => {
                     // Absorb the error and attempt to delete other pods with best effort.
                     error!("Error evicting pod: {:?}", e)
@@ -240,6 +302,19 @@ pub async fn evict_pods(client: &kube::Client, node_name: &str) -> anyhow::Resul
             }
         }
     }
+
+    if !evicting.is_empty() {
+        info!("Waiting for {} pods to be evicted.", evicting.len());
+        match tokio::time::timeout_at(deadline, wait_for_deletion(&mut stream, &mut evicting)).await
+        {
+            Ok(result) => result?,
+            Err(_) => warn!(
+                "{} pods were not evicted within their grace periods: {:?}",
+                evicting.len(),
+                evicting
+            ),
+        }
+    }
     Ok(())
 }
 
@@ -250,31 +325,38 @@ type PodStream = std::pin::Pin<
     >,
 >;
 
-async fn evict_pod(
-    client: &kube::Client,
-    name: &str,
-    namespace: &str,
+/// Deletes the pod, returning whether its deletion is pending because it has a
+/// grace period.
+async fn evict_pod(client: &kube::Client, pod: &Pod) -> anyhow::Result<bool> {
+    let ns_client: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+    info!(
+        "Evicting namespace '{}' pod '{}'",
+        pod.namespace(),
+        pod.name()
+    );
+    let params = Default::default();
+    let response = ns_client.delete(pod.name(), &params).await?;
+    if response.is_right() {
+        info!("Pod '{}' evicted.", pod.name());
+    }
+    Ok(response.is_left())
+}
+
+/// Waits until all of the `pods` have been deleted, removing them as they are.
+async fn wait_for_deletion(
     stream: &mut PodStream,
+    pods: &mut HashSet<PodKey>,
 ) -> anyhow::Result<()> {
-    let ns_client: Api<KubePod> = Api::namespaced(client.clone(), namespace);
-    info!("Evicting namespace '{}' pod '{}'", namespace, name);
-    let params = Default::default();
-    let response = ns_client.delete(name, &params).await?;
-
-    if response.is_left() {
-        // TODO Timeout?
-        info!("Waiting for pod '{}' eviction.", name);
-        while let Some(event) = stream.try_next().await? {
-            if let kube::api::WatchEvent::Deleted(s) = event {
-                let pod = Pod::from(s);
-                if name == pod.name() && namespace == pod.namespace() {
-                    info!("Pod '{}' evicted.", name);
+    while let Some(event) = stream.try_next().await? {
+        if let kube::api::WatchEvent::Deleted(s) = event {
+            let pod = Pod::from(s);
+            if pods.remove(&PodKey::from(&pod)) {
+                info!("Pod '{}' evicted.", pod.name());
+                if pods.is_empty() {
                     break;
                 }
             }
         }
-    } else {
-        info!("Pod '{}' evicted.", name);
     }
     Ok(())
 }
@@ -726,6 +808,7 @@ impl Default for Node {
 mod test {
     use super::*;
     use crate::config::{Config, ServerConfig};
+    use crate::test_util::fake_api_server;
     use std::collections::HashMap;
     use std::net::{IpAddr, Ipv4Addr};
     use std::path::PathBuf;
@@ -757,12 +840,14 @@ mod test {
                 port: 8080,
                 cert_file: PathBuf::new(),
                 private_key_file: PathBuf::new(),
+                client_ca_file: None,
             },
             bootstrap_file: "doesnt/matter".into(),
             allow_local_modules: false,
             insecure_registries: None,
             data_dir: PathBuf::new(),
             plugins_dir: PathBuf::new(),
+            pod_cidr: None,
             node_labels,
             max_pods: 110,
         };
@@ -779,4 +864,58 @@ mod test {
         assert!(!result.get("beta.kubernetes.io/os").unwrap().eq("managed"));
         assert!(result.get("beta.kubernetes.io/os").unwrap().eq("linux"));
     }
+
+    #[tokio::test]
+    async fn test_update_handler_labels() {
+        let (client, requests) = fake_api_server(vec![(
+            "/api/v1/nodes/krustlet",
+            serde_json::json!({
+                "apiVersion": "v1",
+                "kind": "Node",
+                "metadata": { "name": "krustlet" },
+            }),
+        )])
+        .await;
+
+        let mut labels = BTreeMap::new();
+        labels.insert("kubernetes.io/os".to_owned(), "linux".to_owned());
+        labels.insert(
+            crate::runtime_class::handler_label("wasmtime"),
+            "true".to_owned(),
+        );
+        labels.insert(
+            crate::runtime_class::handler_label("retired"),
+            "true".to_owned(),
+        );
+        let node = KubeNode {
+            metadata: ObjectMeta {
+                name: Some("krustlet".to_owned()),
+                labels: Some(labels),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let handlers = vec!["wasmtime".to_owned(), "wasmtime-simd".to_owned()];
+        update_handler_labels(&Api::all(client), &node, &handlers)
+            .await
+            .unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        let (method, path, patch) = &requests[0];
+        assert_eq!(method, http::Method::PATCH);
+        assert_eq!(path, "/api/v1/nodes/krustlet");
+        assert_eq!(
+            patch,
+            &serde_json::json!({
+                "metadata": {
+                    "labels": {
+                        "runtime-handler.krustlet.dev/wasmtime": "true",
+                        "runtime-handler.krustlet.dev/wasmtime-simd": "true",
+                        "runtime-handler.krustlet.dev/retired": null,
+                    }
+                }
+            })
+        );
+    }
 }
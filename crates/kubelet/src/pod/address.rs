@@ -0,0 +1,155 @@
+//! Allocation of pod IP addresses from a node's pod CIDR.
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use super::PodKey;
+
+/// A pool of pod IP addresses in a CIDR, such as the one set with
+/// [`crate::node::Builder::set_pod_cidr`].
+///
+/// The network address and the first address after it, which is
+/// conventionally the gateway, are never allocated, nor is the broadcast
+/// address of an IPv4 CIDR.
+#[derive(Debug)]
+pub struct AddressPool {
+    network: IpAddr,
+    prefix_len: u8,
+    allocated: HashMap<PodKey, IpAddr>,
+}
+
+impl AddressPool {
+    /// Create an empty pool of the addresses in `cidr`, e.g. `10.244.1.0/24`.
+    pub fn new(cidr: &str) -> anyhow::Result<Self> {
+        let (address, prefix_len) = cidr
+            .split_once('/')
+            .ok_or_else(|| anyhow::anyhow!("pod CIDR {} has no prefix length", cidr))?;
+        let address: IpAddr = address
+            .parse()
+            .map_err(|e| anyhow::anyhow!("pod CIDR {} has an invalid address: {}", cidr, e))?;
+        let prefix_len: u8 = prefix_len.parse().map_err(|e| {
+            anyhow::anyhow!("pod CIDR {} has an invalid prefix length: {}", cidr, e)
+        })?;
+        if prefix_len > bits(&address) {
+            anyhow::bail!("pod CIDR {} has an invalid prefix length", cidr);
+        }
+        Ok(AddressPool {
+            network: from_bits(
+                &address,
+                to_bits(&address) & !host_mask(&address, prefix_len),
+            ),
+            prefix_len,
+            allocated: HashMap::new(),
+        })
+    }
+
+    /// The CIDR that addresses are allocated from.
+    pub fn cidr(&self) -> String {
+        format!("{}/{}", self.network, self.prefix_len)
+    }
+
+    /// Allocate an address for the pod, or return the address it has already
+    /// been allocated.
+    pub fn allocate(&mut self, key: &PodKey) -> anyhow::Result<IpAddr> {
+        if let Some(address) = self.allocated.get(key) {
+            return Ok(*address);
+        }
+        let network = to_bits(&self.network);
+        let mut last = host_mask(&self.network, self.prefix_len);
+        if self.network.is_ipv4() {
+            last = last.saturating_sub(1);
+        }
+        let address = (2..=last)
+            .map(|host| from_bits(&self.network, network | host))
+            .find(|address| !self.allocated.values().any(|a| a == address))
+            .ok_or_else(|| anyhow::anyhow!("no addresses are left in pod CIDR {}", self.cidr()))?;
+        self.allocated.insert(key.clone(), address);
+        Ok(address)
+    }
+
+    /// Return the pod's address to the pool.
+    pub fn release(&mut self, key: &PodKey) {
+        self.allocated.remove(key);
+    }
+}
+
+fn bits(address: &IpAddr) -> u8 {
+    match address {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn host_mask(address: &IpAddr, prefix_len: u8) -> u128 {
+    let host_bits = bits(address) - prefix_len;
+    if host_bits == 128 {
+        u128::MAX
+    } else {
+        (1 << host_bits) - 1
+    }
+}
+
+fn to_bits(address: &IpAddr) -> u128 {
+    match address {
+        IpAddr::V4(address) => u32::from(*address) as u128,
+        IpAddr::V6(address) => u128::from(*address),
+    }
+}
+
+fn from_bits(like: &IpAddr, bits: u128) -> IpAddr {
+    match like {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from(bits as u32)),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(bits)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_allocate_skips_network_and_gateway() {
+        let mut pool = AddressPool::new("10.244.1.7/24").unwrap();
+        assert_eq!(pool.cidr(), "10.244.1.0/24");
+        let address = pool.allocate(&PodKey::new("default", "a")).unwrap();
+        assert_eq!(address, "10.244.1.2".parse::<IpAddr>().unwrap());
+        let address = pool.allocate(&PodKey::new("default", "b")).unwrap();
+        assert_eq!(address, "10.244.1.3".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_allocate_is_stable_and_release_reuses() {
+        let mut pool = AddressPool::new("10.244.1.0/24").unwrap();
+        let a = PodKey::new("default", "a");
+        let b = PodKey::new("default", "b");
+        let first = pool.allocate(&a).unwrap();
+        assert_eq!(pool.allocate(&a).unwrap(), first);
+        pool.release(&a);
+        pool.allocate(&b).unwrap();
+        assert_eq!(
+            pool.allocate(&a).unwrap(),
+            "10.244.1.3".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_allocate_exhausted() {
+        // Only .2 is usable in a /30
+        let mut pool = AddressPool::new("10.244.1.0/30").unwrap();
+        pool.allocate(&PodKey::new("default", "a")).unwrap();
+        assert!(pool.allocate(&PodKey::new("default", "b")).is_err());
+    }
+
+    #[test]
+    fn test_allocate_ipv6() {
+        let mut pool = AddressPool::new("fd00:10:244::/64").unwrap();
+        let address = pool.allocate(&PodKey::new("default", "a")).unwrap();
+        assert_eq!(address, "fd00:10:244::2".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_invalid_cidrs() {
+        assert!(AddressPool::new("10.244.1.0").is_err());
+        assert!(AddressPool::new("10.244.1/24").is_err());
+        assert!(AddressPool::new("10.244.1.0/33").is_err());
+    }
+}
@@ -0,0 +1,66 @@
+//! Events recorded about pods, which are shown by `kubectl describe pod`.
+
+use super::Pod;
+use chrono::Utc;
+use k8s_openapi::api::core::v1::{Event, EventSource, ObjectReference};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+use kube::api::{Api, PostParams};
+use kube::Resource;
+use tracing::debug;
+
+/// Whether an event is expected or a problem.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventType {
+    /// Part of the pod's normal lifecycle.
+    Normal,
+    /// Something went wrong.
+    Warning,
+}
+
+/// Record an event about the pod, or one of its containers if
+/// `container_name` is given.
+pub async fn record_event(
+    client: &kube::Client,
+    pod: &Pod,
+    container_name: Option<&str>,
+    type_: EventType,
+    reason: &str,
+    message: &str,
+) -> anyhow::Result<()> {
+    let now = Utc::now();
+    let kube_pod = pod.as_kube_pod();
+    let event = Event {
+        metadata: ObjectMeta {
+            name: Some(format!("{}.{:x}", pod.name(), now.timestamp_nanos())),
+            namespace: Some(pod.namespace().to_string()),
+            ..Default::default()
+        },
+        involved_object: ObjectReference {
+            api_version: Some("v1".to_string()),
+            kind: Some("Pod".to_string()),
+            name: Some(pod.name().to_string()),
+            namespace: Some(pod.namespace().to_string()),
+            uid: kube_pod.meta().uid.clone(),
+            resource_version: kube_pod.meta().resource_version.clone(),
+            field_path: container_name.map(|name| format!("spec.containers{{{}}}", name)),
+        },
+        type_: Some(format!("{:?}", type_)),
+        reason: Some(reason.to_string()),
+        message: Some(message.to_string()),
+        count: Some(1),
+        first_timestamp: Some(Time(now)),
+        last_timestamp: Some(Time(now)),
+        source: Some(EventSource {
+            component: Some("kubelet".to_string()),
+            host: kube_pod
+                .spec
+                .as_ref()
+                .and_then(|spec| spec.node_name.clone()),
+        }),
+        ..Default::default()
+    };
+    debug!("Recording event for Pod {}: {:?}", pod.name(), event);
+    let api: Api<Event> = Api::namespaced(client.clone(), pod.namespace());
+    api.create(&PostParams::default(), &event).await?;
+    Ok(())
+}
@@ -1,12 +1,14 @@
 use std::collections::HashMap;
+use std::time::Duration;
 
 use tokio::io::{AsyncRead, AsyncSeek};
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, RwLock};
 use tracing::{debug, error, info};
 
 use crate::container::{
     ContainerKey, ContainerMapByName, Handle as ContainerHandle, HandleMap as ContainerHandleMap,
 };
+use crate::exec::Sender as ExecSender;
 use crate::handle::StopHandler;
 use crate::log::{HandleFactory, Sender};
 use crate::pod::Pod;
@@ -74,6 +76,55 @@ impl<H: StopHandler, F> Handle<H, F> {
         handle.output(sender).await
     }
 
+    /// Sends the output of the specified container to an attached client until
+    /// it exits, and forwards any input from the client to its stdin until the
+    /// client detaches.
+    pub async fn attach(
+        &self,
+        container_name: &str,
+        input: Option<mpsc::Receiver<Vec<u8>>>,
+        output: ExecSender,
+    ) -> anyhow::Result<()> {
+        let mut handles = self.container_handles.write().await;
+        let handle = handles
+            .get_mut_by_name(container_name.to_owned())
+            .ok_or_else(|| ProviderError::ContainerNotFound {
+                pod_name: self.pod.name().to_owned(),
+                container_name: container_name.to_owned(),
+            })?;
+        handle.attach(input, output)
+    }
+
+    /// Calls `f` with the handle of the specified container and returns its result.
+    pub async fn with_container_handle<T>(
+        &self,
+        container_name: &str,
+        f: impl FnOnce(&ContainerHandle<H, F>) -> T,
+    ) -> anyhow::Result<T> {
+        let handles = self.container_handles.read().await;
+        let handle = handles
+            .get_by_name(container_name.to_owned())
+            .ok_or_else(|| ProviderError::ContainerNotFound {
+                pod_name: self.pod.name().to_owned(),
+                container_name: container_name.to_owned(),
+            })?;
+        Ok(f(handle))
+    }
+
+    /// Signal a single container of the pod to stop, e.g. because it failed its
+    /// liveness probe.
+    pub async fn stop_container(&self, key: &ContainerKey) -> anyhow::Result<()> {
+        let mut handles = self.container_handles.write().await;
+        let handle = handles
+            .get_mut(key)
+            .ok_or_else(|| ProviderError::ContainerNotFound {
+                pod_name: self.pod.name().to_owned(),
+                container_name: key.name(),
+            })?;
+        info!("Stopping container: {}", key);
+        handle.stop().await
+    }
+
     /// Signal the pod and all its running containers to stop and wait for them
     /// to complete.
     pub async fn stop(&self) -> anyhow::Result<()> {
@@ -93,6 +144,35 @@ impl<H: StopHandler, F> Handle<H, F> {
         Ok(())
     }
 
+    /// Ask the pod's running containers to stop, and stop any that are still
+    /// running after `grace_period`, as Kubernetes does when a pod is deleted.
+    /// The containers are only locked while they are asked to stop, unless
+    /// their [`StopHandler`] can't stop them without being held on to.
+    pub async fn stop_gracefully(&self, grace_period: Duration) -> anyhow::Result<()>
+    where
+        H: Send,
+    {
+        let mut stops = vec![];
+        {
+            let mut handles = self.container_handles.write().await;
+            for (key, handle) in handles.iter_mut() {
+                info!("Stopping container gracefully: {}", key);
+                match handle.stop_gracefully_detached(grace_period) {
+                    Some(stop) => stops.push((key.clone(), stop)),
+                    None => {
+                        let result = handle.stop_gracefully(grace_period).await;
+                        log_stopped(key, result);
+                    }
+                }
+            }
+        }
+        let stops = stops
+            .into_iter()
+            .map(|(key, stop)| async move { log_stopped(&key, stop.await) });
+        futures::future::join_all(stops).await;
+        Ok(())
+    }
+
     /// Wait for all containers in the pod to complete
     pub async fn wait(&mut self) -> anyhow::Result<()> {
         let mut handles = self.container_handles.write().await;
@@ -103,6 +183,13 @@ impl<H: StopHandler, F> Handle<H, F> {
     }
 }
 
+fn log_stopped(key: &ContainerKey, result: anyhow::Result<()>) {
+    match result {
+        Ok(_) => debug!("Successfully stopped container {}", key),
+        Err(e) => error!("Error while trying to stop pod {}: {:?}", key, e),
+    }
+}
+
 /// Generates a unique human readable key for storing a handle to a pod in a
 /// hash. This is a convenience wrapper around [pod_key].
 #[deprecated(
@@ -123,3 +210,82 @@ pub fn key_from_pod(pod: &Pod) -> String {
 pub fn pod_key<N: AsRef<str>, T: AsRef<str>>(namespace: N, pod_name: T) -> String {
     format!("{}:{}", namespace.as_ref(), pod_name.as_ref())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::future::BoxFuture;
+    use k8s_openapi::api::core::v1::Pod as KubePod;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::{oneshot, Notify};
+
+    /// Stops once `release` is notified, telling `started` when it starts.
+    struct SlowStop {
+        started: Mutex<Option<oneshot::Sender<()>>>,
+        release: Arc<Notify>,
+    }
+
+    #[async_trait::async_trait]
+    impl StopHandler for SlowStop {
+        async fn stop(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn stop_gracefully_detached(
+            &mut self,
+            _grace_period: Duration,
+        ) -> Option<BoxFuture<'static, anyhow::Result<()>>> {
+            if let Some(started) = self.started.lock().unwrap().take() {
+                started.send(()).ok();
+            }
+            let release = self.release.clone();
+            Some(Box::pin(async move {
+                release.notified().await;
+                Ok(())
+            }))
+        }
+
+        async fn wait(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn containers_are_not_locked_for_the_grace_period() {
+        let (started, stopping) = oneshot::channel();
+        let release = Arc::new(Notify::new());
+        let mut handles = HashMap::new();
+        handles.insert(
+            ContainerKey::App("app".to_owned()),
+            ContainerHandle::new(
+                SlowStop {
+                    started: Mutex::new(Some(started)),
+                    release: release.clone(),
+                },
+                (),
+            ),
+        );
+        let kube_pod: KubePod = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "test" },
+            "spec": { "containers": [{ "name": "app" }] }
+        }))
+        .unwrap();
+        let handle = Arc::new(Handle::new(handles, Pod::from(kube_pod), None));
+
+        let stop = tokio::spawn({
+            let handle = handle.clone();
+            async move { handle.stop_gracefully(Duration::from_secs(30)).await }
+        });
+        stopping.await.unwrap();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            handle.with_container_handle("app", |_| ()),
+        )
+        .await
+        .expect("container handles were locked during the grace period")
+        .unwrap();
+
+        release.notify_one();
+        stop.await.unwrap().unwrap();
+    }
+}
@@ -1,7 +1,10 @@
 //! `pod` is a collection of utilities surrounding the Kubernetes pod API.
+pub mod address;
+mod event;
 mod handle;
 pub mod state;
 mod status;
+pub use event::{record_event, EventType};
 // Ignore deprecated here as this is just a reexport
 #[allow(deprecated)]
 pub use handle::{key_from_pod, pod_key, Handle};
@@ -51,6 +54,16 @@ impl Pod {
             .unwrap_or("default")
     }
 
+    /// Get the pod's UID
+    pub fn uid(&self) -> Option<&str> {
+        self.kube_pod.metadata.uid.as_deref()
+    }
+
+    /// Get the name of the node the pod is scheduled to
+    pub fn node_name(&self) -> Option<&str> {
+        self.kube_pod.spec.as_ref()?.node_name.as_deref()
+    }
+
     /// Get the pod's node_selector map
     pub fn node_selector(&self) -> Option<&std::collections::BTreeMap<String, String>> {
         self.kube_pod.spec.as_ref()?.node_selector.as_ref()
@@ -62,6 +75,50 @@ impl Pod {
         spec.service_account_name.as_deref()
     }
 
+    /// Get the name of the pod's RuntimeClass
+    pub fn runtime_class_name(&self) -> Option<&str> {
+        let spec = self.kube_pod.spec.as_ref()?;
+        spec.runtime_class_name.as_deref()
+    }
+
+    /// Get the pod's security context
+    pub fn security_context(&self) -> Option<&k8s_openapi::api::core::v1::PodSecurityContext> {
+        let spec = self.kube_pod.spec.as_ref()?;
+        spec.security_context.as_ref()
+    }
+
+    /// Get the pod's restart policy, which defaults to `Always`
+    pub fn restart_policy(&self) -> RestartPolicy {
+        let policy = self
+            .kube_pod
+            .spec
+            .as_ref()
+            .and_then(|s| s.restart_policy.as_deref());
+        match policy {
+            Some("OnFailure") => RestartPolicy::OnFailure,
+            Some("Never") => RestartPolicy::Never,
+            _ => RestartPolicy::Always,
+        }
+    }
+
+    /// Get how long the pod's containers are given to shut down gracefully
+    /// when it is deleted, which defaults to 30 seconds. A grace period given
+    /// when deleting the pod overrides the one in its spec.
+    pub fn termination_grace_period(&self) -> std::time::Duration {
+        let seconds = self
+            .kube_pod
+            .meta()
+            .deletion_grace_period_seconds
+            .or_else(|| {
+                self.kube_pod
+                    .spec
+                    .as_ref()
+                    .and_then(|s| s.termination_grace_period_seconds)
+            })
+            .unwrap_or(DEFAULT_TERMINATION_GRACE_PERIOD_SECONDS);
+        std::time::Duration::from_secs(seconds.max(0) as u64)
+    }
+
     /// Get the pod volumes
     pub fn volumes(&self) -> Option<&Vec<KubeVolume>> {
         let spec = self.kube_pod.spec.as_ref()?;
@@ -80,6 +137,30 @@ impl Pod {
         status.pod_ip.as_deref()
     }
 
+    /// Get all of the pod's ips, falling back to its ip
+    pub fn pod_ips(&self) -> Vec<&str> {
+        let ips: Vec<&str> = self
+            .kube_pod
+            .status
+            .iter()
+            .flat_map(|status| status.pod_ips.iter().flatten())
+            .filter_map(|ip| ip.ip.as_deref())
+            .collect();
+        if ips.is_empty() {
+            self.pod_ip().into_iter().collect()
+        } else {
+            ips
+        }
+    }
+
+    /// Set the pod's ip and host ip, so that they can be used before the
+    /// pod's status has been updated with them
+    pub fn set_ips(&mut self, pod_ip: &str, host_ip: &str) {
+        let status = self.kube_pod.status.get_or_insert_with(Default::default);
+        status.pod_ip = Some(pod_ip.to_string());
+        status.host_ip = Some(host_ip.to_string());
+    }
+
     /// Get an iterator over the pod's labels
     pub fn labels(&self) -> &std::collections::BTreeMap<String, String> {
         self.kube_pod.meta().labels.as_ref().unwrap_or(&EMPTY_MAP)
@@ -253,6 +334,29 @@ impl std::convert::From<Pod> for KubePod {
     }
 }
 
+/// Specifies which of a pod's containers are restarted when they exit
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum RestartPolicy {
+    /// Restart containers whenever they exit
+    Always,
+    /// Restart containers only if they exit with an error
+    OnFailure,
+    /// Never restart containers
+    Never,
+}
+
+impl RestartPolicy {
+    /// Whether a container that exited, with an error if `failed`, should be
+    /// restarted
+    pub fn should_restart(&self, failed: bool) -> bool {
+        match self {
+            Self::Always => true,
+            Self::OnFailure => failed,
+            Self::Never => false,
+        }
+    }
+}
+
 /// PodKey is a unique human readable key for storing a handle to a pod in a hash.
 #[derive(Hash, Ord, Eq, PartialOrd, PartialEq, Debug, Clone, Default)]
 pub struct PodKey {
@@ -317,7 +421,76 @@ impl From<&KubePod> for PodKey {
     }
 }
 
+/// The grace period of pods that don't set `terminationGracePeriodSeconds`.
+const DEFAULT_TERMINATION_GRACE_PERIOD_SECONDS: i64 = 30;
+
 lazy_static::lazy_static! {
     static ref EMPTY_MAP: std::collections::BTreeMap<String, String> = std::collections::BTreeMap::new();
     static ref EMPTY_VEC: Vec<KubeContainer> = Vec::new();
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pod_with_restart_policy(policy: Option<&str>) -> Pod {
+        let kube_pod: KubePod = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "test" },
+            "spec": {
+                "containers": [],
+                "restartPolicy": policy,
+            }
+        }))
+        .unwrap();
+        Pod::from(kube_pod)
+    }
+
+    #[test]
+    fn test_restart_policy_defaults_to_always() {
+        let pod = pod_with_restart_policy(None);
+        assert_eq!(pod.restart_policy(), RestartPolicy::Always);
+        let pod = pod_with_restart_policy(Some("OnFailure"));
+        assert_eq!(pod.restart_policy(), RestartPolicy::OnFailure);
+        let pod = pod_with_restart_policy(Some("Never"));
+        assert_eq!(pod.restart_policy(), RestartPolicy::Never);
+    }
+
+    #[test]
+    fn test_termination_grace_period() {
+        let pod = pod_with_restart_policy(None);
+        assert_eq!(
+            pod.termination_grace_period(),
+            std::time::Duration::from_secs(30)
+        );
+
+        let mut kube_pod = pod.into_kube_pod();
+        kube_pod
+            .spec
+            .as_mut()
+            .unwrap()
+            .termination_grace_period_seconds = Some(5);
+        let pod = Pod::from(kube_pod);
+        assert_eq!(
+            pod.termination_grace_period(),
+            std::time::Duration::from_secs(5)
+        );
+
+        let mut kube_pod = pod.into_kube_pod();
+        kube_pod.metadata.deletion_grace_period_seconds = Some(0);
+        let pod = Pod::from(kube_pod);
+        assert_eq!(
+            pod.termination_grace_period(),
+            std::time::Duration::from_secs(0)
+        );
+    }
+
+    #[test]
+    fn test_should_restart() {
+        assert!(RestartPolicy::Always.should_restart(false));
+        assert!(RestartPolicy::Always.should_restart(true));
+        assert!(!RestartPolicy::OnFailure.should_restart(false));
+        assert!(RestartPolicy::OnFailure.should_restart(true));
+        assert!(!RestartPolicy::Never.should_restart(false));
+        assert!(!RestartPolicy::Never.should_restart(true));
+    }
+}
@@ -192,6 +192,18 @@ impl StatusBuilder {
         self
     }
 
+    /// Set Pod IP.
+    pub fn pod_ip(mut self, pod_ip: &str) -> StatusBuilder {
+        self.0.pod_ip = Some(pod_ip.to_string());
+        self
+    }
+
+    /// Set the IP of the host the Pod is running on.
+    pub fn host_ip(mut self, host_ip: &str) -> StatusBuilder {
+        self.0.host_ip = Some(host_ip.to_string());
+        self
+    }
+
     /// Set Pod conditions.
     pub fn conditions(mut self, conditions: Vec<KubePodCondition>) -> StatusBuilder {
         self.0.conditions = Some(conditions);
@@ -260,6 +272,15 @@ impl ObjectStatus for Status {
             status.insert("conditions".to_string(), serde_json::json!(s));
         };
 
+        if let Some(s) = self.0.pod_ip.clone() {
+            status.insert("podIPs".to_string(), serde_json::json!([{ "ip": s }]));
+            status.insert("podIP".to_string(), serde_json::Value::String(s));
+        };
+
+        if let Some(s) = self.0.host_ip.clone() {
+            status.insert("hostIP".to_string(), serde_json::Value::String(s));
+        };
+
         serde_json::json!(
             {
                 "metadata": {
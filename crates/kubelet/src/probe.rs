@@ -0,0 +1,516 @@
+//! `probe` runs the liveness, readiness and startup probes of containers on
+//! the schedule configured in their manifests.
+use std::net::Ipv6Addr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::{ExecAction, HTTPGetAction, Probe as KubeProbe, TCPSocketAction};
+use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
+use tokio::sync::mpsc;
+use tokio::time::Interval;
+use tracing::debug;
+
+use crate::container::Container;
+
+/// Runs the commands of exec probes in a running container.
+#[async_trait::async_trait]
+pub trait Exec: Send + Sync + 'static {
+    /// Runs `command` in the container and returns its exit code.
+    async fn exec(&self, command: Vec<String>) -> anyhow::Result<i32>;
+}
+
+/// A change in the health of a container, as determined by its probes.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProbeEvent {
+    /// The container's startup probe succeeded, or it has none.
+    Started,
+    /// The container became ready or unready to serve requests.
+    Ready(bool),
+    /// The container failed its startup or liveness probe, and should be
+    /// restarted. No more events are sent after this one.
+    Unhealthy(String),
+}
+
+/// The action a probe or lifecycle hook takes in a container.
+#[derive(Clone, Debug)]
+pub(crate) enum Handler {
+    Exec(Vec<String>),
+    HttpGet {
+        url: String,
+        headers: Vec<(String, String)>,
+    },
+    TcpSocket {
+        host: String,
+        port: u16,
+    },
+}
+
+impl Handler {
+    /// Creates the handler of a probe or hook, which must have exactly one of
+    /// an exec, HTTP or TCP action.
+    pub(crate) fn new(
+        exec: Option<&ExecAction>,
+        http_get: Option<&HTTPGetAction>,
+        tcp_socket: Option<&TCPSocketAction>,
+        container: &Container,
+        pod_ip: &str,
+    ) -> anyhow::Result<Self> {
+        if let Some(exec) = exec {
+            let command = exec.command.clone().unwrap_or_default();
+            if command.is_empty() {
+                anyhow::bail!("exec probe has no command");
+            }
+            Ok(Handler::Exec(command))
+        } else if let Some(http_get) = http_get {
+            let scheme = match http_get.scheme.as_deref() {
+                None | Some("HTTP") => "http",
+                Some("HTTPS") => "https",
+                Some(other) => anyhow::bail!("unrecognized HTTP probe scheme {}", other),
+            };
+            let host = url_host(http_get.host.as_deref().unwrap_or(pod_ip));
+            let port = resolve_port(&http_get.port, container)?;
+            let path = http_get.path.as_deref().unwrap_or("/");
+            let path = path.strip_prefix('/').unwrap_or(path);
+            let headers = http_get
+                .http_headers
+                .iter()
+                .flatten()
+                .map(|header| (header.name.clone(), header.value.clone()))
+                .collect();
+            Ok(Handler::HttpGet {
+                url: format!("{}://{}:{}/{}", scheme, host, port, path),
+                headers,
+            })
+        } else if let Some(tcp_socket) = tcp_socket {
+            Ok(Handler::TcpSocket {
+                host: tcp_socket.host.as_deref().unwrap_or(pod_ip).to_string(),
+                port: resolve_port(&tcp_socket.port, container)?,
+            })
+        } else {
+            anyhow::bail!("no exec, httpGet or tcpSocket action")
+        }
+    }
+
+    /// Runs the action, returning why it failed if it did.
+    pub(crate) async fn check(&self, exec: &dyn Exec) -> Result<(), String> {
+        match self {
+            Handler::Exec(command) => match exec.exec(command.clone()).await {
+                Ok(0) => Ok(()),
+                Ok(code) => Err(format!("command exited with code {}", code)),
+                Err(e) => Err(format!("command failed: {}", e)),
+            },
+            Handler::HttpGet { url, headers } => {
+                let mut request = HTTP_CLIENT.get(url);
+                for (name, value) in headers {
+                    request = request.header(name.as_str(), value.as_str());
+                }
+                let response = request
+                    .send()
+                    .await
+                    .map_err(|e| format!("GET {} failed: {}", url, e))?;
+                let status = response.status();
+                if status.is_success() || status.is_redirection() {
+                    Ok(())
+                } else {
+                    Err(format!("GET {} returned {}", url, status))
+                }
+            }
+            Handler::TcpSocket { host, port } => {
+                tokio::net::TcpStream::connect((host.as_str(), *port))
+                    .await
+                    .map(|_| ())
+                    .map_err(|e| format!("connecting to {}:{} failed: {}", host, port, e))
+            }
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    /// The client that HTTP probes and hooks are made with. As in the Kubernetes
+    /// kubelet, it doesn't follow redirects or verify the certificates of HTTPS
+    /// endpoints.
+    static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .danger_accept_invalid_certs(true)
+        .build()
+        .expect("unable to build HTTP probe client");
+}
+
+/// Brackets IPv6 addresses for use as the host of a URL.
+fn url_host(host: &str) -> String {
+    match host.parse::<Ipv6Addr>() {
+        Ok(_) => format!("[{}]", host),
+        Err(_) => host.to_string(),
+    }
+}
+
+/// Resolves a probe's port, which may be the name of one of the container's
+/// ports.
+fn resolve_port(port: &IntOrString, container: &Container) -> anyhow::Result<u16> {
+    let number = match port {
+        IntOrString::Int(number) => *number,
+        IntOrString::String(name) => container
+            .ports()
+            .iter()
+            .flatten()
+            .find(|port| port.name.as_deref() == Some(name.as_str()))
+            .map(|port| port.container_port)
+            .ok_or_else(|| anyhow::anyhow!("container has no port named {}", name))?,
+    };
+    std::convert::TryFrom::try_from(number)
+        .map_err(|_| anyhow::anyhow!("probe port {} is out of range", number))
+}
+
+/// A probe of a container and its schedule.
+#[derive(Clone, Debug)]
+struct Probe {
+    handler: Handler,
+    initial_delay: Duration,
+    period: Duration,
+    timeout: Duration,
+    success_threshold: i32,
+    failure_threshold: i32,
+}
+
+impl Probe {
+    fn new(probe: &KubeProbe, container: &Container, pod_ip: &str) -> anyhow::Result<Self> {
+        let seconds = |value: Option<i32>, default: i32| {
+            Duration::from_secs(value.unwrap_or(default).max(0) as u64)
+        };
+        Ok(Probe {
+            handler: Handler::new(
+                probe.exec.as_ref(),
+                probe.http_get.as_ref(),
+                probe.tcp_socket.as_ref(),
+                container,
+                pod_ip,
+            )?,
+            initial_delay: seconds(probe.initial_delay_seconds, 0),
+            period: seconds(probe.period_seconds, 10).max(Duration::from_secs(1)),
+            timeout: seconds(probe.timeout_seconds, 1).max(Duration::from_secs(1)),
+            success_threshold: probe.success_threshold.unwrap_or(1).max(1),
+            failure_threshold: probe.failure_threshold.unwrap_or(3).max(1),
+        })
+    }
+
+    async fn check(&self, exec: &dyn Exec) -> Result<(), String> {
+        match tokio::time::timeout(self.timeout, self.handler.check(exec)).await {
+            Ok(result) => result,
+            Err(_) => Err(format!("probe timed out after {:?}", self.timeout)),
+        }
+    }
+
+    /// Waits for the initial delay, then returns the schedule to probe on.
+    async fn schedule(&self) -> Interval {
+        tokio::time::sleep(self.initial_delay).await;
+        tokio::time::interval(self.period)
+    }
+
+    /// Probes the container on `schedule` until the result differs from
+    /// `healthy` for the threshold number of times in a row, then returns that
+    /// result. If `healthy` is `None`, returns whichever threshold is met
+    /// first.
+    async fn next_change(
+        &self,
+        exec: &dyn Exec,
+        schedule: &mut Interval,
+        healthy: Option<bool>,
+    ) -> Result<(), String> {
+        let mut successes = 0;
+        let mut failures = 0;
+        loop {
+            schedule.tick().await;
+            match self.check(exec).await {
+                Ok(()) => {
+                    failures = 0;
+                    successes += 1;
+                    if healthy != Some(true) && successes >= self.success_threshold {
+                        return Ok(());
+                    }
+                }
+                Err(e) => {
+                    debug!("Probe failed: {}", e);
+                    successes = 0;
+                    failures += 1;
+                    if healthy != Some(false) && failures >= self.failure_threshold {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The probes of a container.
+#[derive(Clone, Debug)]
+pub struct Probes {
+    startup: Option<Probe>,
+    liveness: Option<Probe>,
+    readiness: Option<Probe>,
+}
+
+impl Probes {
+    /// Creates the probes configured in `container`'s manifest. HTTP and TCP
+    /// probes without a host connect to `pod_ip`.
+    pub fn new(container: &Container, pod_ip: &str) -> anyhow::Result<Self> {
+        let probe = |probe: Option<&KubeProbe>, kind: &str| {
+            probe
+                .map(|probe| Probe::new(probe, container, pod_ip))
+                .transpose()
+                .map_err(|e| anyhow::anyhow!("invalid {} probe: {}", kind, e))
+        };
+        Ok(Probes {
+            startup: probe(container.startup_probe(), "startup")?,
+            liveness: probe(container.liveness_probe(), "liveness")?,
+            readiness: probe(container.readiness_probe(), "readiness")?,
+        })
+    }
+
+    /// Whether the container has no probes.
+    pub fn is_empty(&self) -> bool {
+        self.startup.is_none() && self.liveness.is_none() && self.readiness.is_none()
+    }
+
+    /// Runs the probes, sending changes in the container's health to `events`,
+    /// until the container is unhealthy or `events` is closed.
+    ///
+    /// As in Kubernetes, the liveness and readiness probes only start once the
+    /// startup probe has succeeded, and a container without a readiness probe
+    /// is ready once it has started.
+    pub async fn run(self, exec: Arc<dyn Exec>, events: mpsc::Sender<ProbeEvent>) {
+        if let Some(startup) = &self.startup {
+            let mut schedule = startup.schedule().await;
+            if let Err(e) = startup.next_change(&*exec, &mut schedule, None).await {
+                let message = format!("Startup probe failed: {}", e);
+                events.send(ProbeEvent::Unhealthy(message)).await.ok();
+                return;
+            }
+        }
+        if events.send(ProbeEvent::Started).await.is_err() {
+            return;
+        }
+
+        let liveness = async {
+            match &self.liveness {
+                Some(liveness) => {
+                    let mut schedule = liveness.schedule().await;
+                    match liveness
+                        .next_change(&*exec, &mut schedule, Some(true))
+                        .await
+                    {
+                        Err(e) => format!("Liveness probe failed: {}", e),
+                        Ok(()) => unreachable!("healthy liveness probes never change"),
+                    }
+                }
+                None => futures::future::pending().await,
+            }
+        };
+        let readiness = async {
+            match &self.readiness {
+                Some(readiness) => {
+                    let mut schedule = readiness.schedule().await;
+                    let mut ready = false;
+                    loop {
+                        ready = readiness
+                            .next_change(&*exec, &mut schedule, Some(ready))
+                            .await
+                            .is_ok();
+                        if events.send(ProbeEvent::Ready(ready)).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+                None => {
+                    events.send(ProbeEvent::Ready(true)).await.ok();
+                    futures::future::pending::<()>().await
+                }
+            }
+        };
+
+        tokio::select! {
+            message = liveness => {
+                events.send(ProbeEvent::Unhealthy(message)).await.ok();
+            }
+            _ = readiness => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Exits with the codes it was created with in turn, then with the last one.
+    struct ExitCodes {
+        codes: Vec<i32>,
+        calls: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl Exec for ExitCodes {
+        async fn exec(&self, _command: Vec<String>) -> anyhow::Result<i32> {
+            let call = self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(self.codes[call.min(self.codes.len() - 1)])
+        }
+    }
+
+    fn exec_probes(probes: serde_json::Value) -> Probes {
+        let mut container = serde_json::json!({ "name": "test" });
+        for (kind, probe) in probes.as_object().unwrap() {
+            let mut probe = probe.clone();
+            probe["exec"] = serde_json::json!({ "command": ["check"] });
+            probe["periodSeconds"] = serde_json::json!(1);
+            container[kind] = probe;
+        }
+        let container = Container::new(&serde_json::from_value(container).unwrap());
+        Probes::new(&container, "10.244.1.2").unwrap()
+    }
+
+    async fn events(probes: Probes, codes: Vec<i32>, count: usize) -> Vec<ProbeEvent> {
+        let exec = Arc::new(ExitCodes {
+            codes,
+            calls: AtomicUsize::new(0),
+        });
+        let (tx, mut rx) = mpsc::channel(8);
+        tokio::spawn(probes.run(exec, tx));
+        let mut events = vec![];
+        while events.len() < count {
+            match rx.recv().await {
+                Some(event) => events.push(event),
+                None => break,
+            }
+        }
+        events
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_no_probes_is_started_and_ready() {
+        let probes = exec_probes(serde_json::json!({}));
+        assert!(probes.is_empty());
+        assert_eq!(
+            events(probes, vec![0], 2).await,
+            vec![ProbeEvent::Started, ProbeEvent::Ready(true)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_readiness_follows_thresholds() {
+        let probes = exec_probes(serde_json::json!({
+            "readinessProbe": { "failureThreshold": 2 }
+        }));
+        assert_eq!(
+            events(probes, vec![1, 0, 1, 0, 1, 1], 3).await,
+            vec![
+                ProbeEvent::Started,
+                ProbeEvent::Ready(true),
+                ProbeEvent::Ready(false),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_liveness_failure_is_unhealthy() {
+        let probes = exec_probes(serde_json::json!({ "livenessProbe": {} }));
+        assert_eq!(
+            events(probes, vec![0, 1, 1, 1], 3).await,
+            vec![
+                ProbeEvent::Started,
+                ProbeEvent::Ready(true),
+                ProbeEvent::Unhealthy(
+                    "Liveness probe failed: command exited with code 1".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_startup_failure_is_unhealthy() {
+        let probes = exec_probes(serde_json::json!({
+            "startupProbe": { "failureThreshold": 1 },
+            "livenessProbe": {},
+        }));
+        assert_eq!(
+            events(probes, vec![2], 1).await,
+            vec![ProbeEvent::Unhealthy(
+                "Startup probe failed: command exited with code 2".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_named_ports_are_resolved() {
+        let container = Container::new(
+            &serde_json::from_value(serde_json::json!({
+                "name": "test",
+                "ports": [{ "name": "http", "containerPort": 8080 }],
+                "readinessProbe": { "httpGet": { "port": "http", "path": "healthz" } },
+                "livenessProbe": { "tcpSocket": { "port": 9090, "host": "fd00::1" } },
+            }))
+            .unwrap(),
+        );
+        let probes = Probes::new(&container, "10.244.1.2").unwrap();
+        match probes.readiness.unwrap().handler {
+            Handler::HttpGet { url, .. } => assert_eq!(url, "http://10.244.1.2:8080/healthz"),
+            other => panic!("unexpected handler {:?}", other),
+        }
+        match probes.liveness.unwrap().handler {
+            Handler::TcpSocket { host, port } => {
+                assert_eq!((host.as_str(), port), ("fd00::1", 9090))
+            }
+            other => panic!("unexpected handler {:?}", other),
+        }
+
+        let container = Container::new(
+            &serde_json::from_value(serde_json::json!({
+                "name": "test",
+                "readinessProbe": { "httpGet": { "port": "http" } },
+            }))
+            .unwrap(),
+        );
+        assert!(Probes::new(&container, "10.244.1.2").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_https_probes_accept_self_signed_certificates() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_rustls::rustls::{Certificate, NoClientAuth, PrivateKey, ServerConfig};
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config
+            .set_single_cert(
+                vec![Certificate(cert.serialize_der().unwrap())],
+                PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = acceptor.accept(stream).await.unwrap();
+                let mut request = [0; 1024];
+                assert!(stream.read(&mut request).await.unwrap() > 0);
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                    .await
+                    .unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+
+        let handler = Handler::HttpGet {
+            url: format!("https://127.0.0.1:{}/healthz", port),
+            headers: vec![],
+        };
+        let exec = ExitCodes {
+            codes: vec![0],
+            calls: AtomicUsize::new(0),
+        };
+        // The same client is used for every check
+        assert_eq!(handler.check(&exec).await, Ok(()));
+        assert_eq!(handler.check(&exec).await, Ok(()));
+    }
+}
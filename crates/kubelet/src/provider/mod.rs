@@ -6,9 +6,10 @@ use k8s_openapi::api::core::v1::{ConfigMap, EnvVarSource, Secret};
 use kube::api::Api;
 use std::sync::Arc;
 use thiserror::Error;
-use tracing::{error, info};
+use tracing::debug;
 
 use crate::container::Container;
+use crate::downward_api::{field_map, resource_field_value};
 use crate::log::Sender;
 use crate::node::Builder;
 use crate::plugin_watcher::PluginRegistry;
@@ -106,6 +107,13 @@ pub trait Provider: Sized + Send + Sync + 'static {
         Ok(())
     }
 
+    /// The RuntimeClass handlers that the provider can run pods with, besides
+    /// its default one for pods without a runtime class. They are advertised
+    /// with node labels named by [`crate::runtime_class::handler_label`].
+    fn runtime_handlers(&self) -> Vec<String> {
+        Vec::new()
+    }
+
     /// Hook to allow provider to introduced shared state into Pod state.
     // TODO: Is there a way to provide a default implementation of this if Self::PodState: Default?
     async fn initialize_pod_state(&self, pod: &Pod) -> anyhow::Result<Self::PodState>;
@@ -121,9 +129,82 @@ pub trait Provider: Sized + Send + Sync + 'static {
 
     /// Execute a given command on a workload and then return the result.
     ///
+    /// The default implementation runs the command, split on whitespace, in the
+    /// pod's first container with [`Provider::exec_in_container`], and returns
+    /// its output as lines. Commands that exit with a non-zero code fail.
+    #[deprecated(
+        since = "0.7.0",
+        note = "Please use Provider::exec_in_container, which streams the command's output and reports its exit code. This method will be removed in 0.8"
+    )]
+    async fn exec(&self, pod: Pod, command: String) -> anyhow::Result<Vec<String>> {
+        let container = pod
+            .containers()
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("pod {} has no containers", pod.name()))?;
+        let command = command.split_whitespace().map(str::to_owned).collect();
+        // TODO: ~magic~ number
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(8);
+        let exec = self.exec_in_container(
+            pod.namespace().to_owned(),
+            pod.name().to_owned(),
+            container.name().to_owned(),
+            command,
+            crate::exec::Sender::new(sender),
+        );
+        tokio::pin!(exec);
+
+        // The output has to be read as the command runs, or it could block
+        let mut output = Vec::new();
+        let exit_code = loop {
+            tokio::select! {
+                exit_code = &mut exec => break exit_code?,
+                Some((_, data)) = receiver.recv() => output.extend(data),
+            }
+        };
+        while let Ok((_, data)) = receiver.try_recv() {
+            output.extend(data);
+        }
+        if exit_code != 0 {
+            anyhow::bail!("command terminated with non-zero exit code: {}", exit_code);
+        }
+        Ok(String::from_utf8_lossy(&output)
+            .lines()
+            .map(str::to_owned)
+            .collect())
+    }
+
+    /// Execute a given command in a container of a workload, streaming its
+    /// output to `output`, and return its exit code once it has completed.
+    ///
+    /// The default implementation of this returns a message that this feature is
+    /// not available. Override this only when there is an implementation.
+    async fn exec_in_container(
+        &self,
+        _namespace: String,
+        _pod: String,
+        _container: String,
+        _command: Vec<String>,
+        _output: crate::exec::Sender,
+    ) -> anyhow::Result<i32> {
+        Err(NotImplementedError.into())
+    }
+
+    /// Attach a client to a running workload. The workload's output is sent to
+    /// `output` until it exits, and if the client asked to write to its stdin,
+    /// input from the client is received on `stdin` until the client detaches.
+    /// This returns as soon as the client is attached.
+    ///
     /// The default implementation of this returns a message that this feature is
     /// not available. Override this only when there is an implementation.
-    async fn exec(&self, _pod: Pod, _command: String) -> anyhow::Result<Vec<String>> {
+    async fn attach(
+        &self,
+        _namespace: String,
+        _pod: String,
+        _container: String,
+        _stdin: Option<tokio::sync::mpsc::Receiver<Vec<u8>>>,
+        _output: crate::exec::Sender,
+    ) -> anyhow::Result<()> {
         Err(NotImplementedError.into())
     }
 
@@ -148,30 +229,8 @@ pub trait Provider: Sized + Send + Sync + 'static {
         container: &Container,
         pod: &Pod,
         client: &kube::Client,
-    ) -> HashMap<String, String> {
-        let mut env = HashMap::new();
-        let vars = match container.env().as_ref() {
-            Some(e) => e,
-            None => return env,
-        };
-
-        for env_var in vars.clone().into_iter() {
-            let key = env_var.name;
-            let value = match env_var.value {
-                Some(v) => v,
-                None => {
-                    on_missing_env_value(
-                        env_var.value_from,
-                        client,
-                        pod.namespace(),
-                        &field_map(pod),
-                    )
-                    .await
-                }
-            };
-            env.insert(key, value);
-        }
-        env
+    ) -> anyhow::Result<HashMap<String, String>> {
+        env_vars(container, pod, client).await
     }
 }
 
@@ -182,15 +241,20 @@ pub trait Provider: Sized + Send + Sync + 'static {
 /// custom Downward API fields.
 ///
 /// It is safe to call from within your own providers.
+///
+/// As in Kubernetes, this fails if a ConfigMap, Secret or key that a variable
+/// refers to doesn't exist, unless the reference is optional, in which case
+/// the variable isn't set. The container should then not be started.
 pub async fn env_vars(
     container: &Container,
     pod: &Pod,
     client: &kube::Client,
-) -> HashMap<String, String> {
-    let mut env = HashMap::new();
+) -> anyhow::Result<HashMap<String, String>> {
+    // Variables set in env take precedence over those from envFrom
+    let mut env = env_from_vars(container, client, pod.namespace()).await?;
     let vars = match container.env().as_ref() {
         Some(e) => e,
-        None => return env,
+        None => return Ok(env),
     };
 
     for env_var in vars.clone().into_iter() {
@@ -198,115 +262,169 @@ pub async fn env_vars(
         let value = match env_var.value {
             Some(v) => v,
             None => {
-                on_missing_env_value(env_var.value_from, client, pod.namespace(), &field_map(pod))
-                    .await
+                match on_missing_env_value(
+                    env_var.value_from,
+                    client,
+                    container,
+                    pod,
+                    &field_map(pod),
+                )
+                .await?
+                {
+                    Some(v) => v,
+                    None => continue,
+                }
             }
         };
         env.insert(key, value);
     }
-    env
+    Ok(env)
+}
+
+/// Resolve the environment variables of all of a container's `envFrom`
+/// sources. Where sources define the same variable, the last one wins.
+async fn env_from_vars(
+    container: &Container,
+    client: &kube::Client,
+    ns: &str,
+) -> anyhow::Result<HashMap<String, String>> {
+    let mut env = HashMap::new();
+    for source in container.env_from().iter().flatten() {
+        let prefix = source.prefix.as_deref().unwrap_or_default();
+        if let Some(cfgref) = source.config_map_ref.as_ref() {
+            let name = cfgref.name.as_deref().unwrap_or_default();
+            let api = Api::<ConfigMap>::namespaced(client.clone(), ns);
+            if let Some(cfgmap) = get_env_source(&api, "configmap", name, cfgref.optional).await? {
+                env.extend(
+                    cfgmap
+                        .data
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|(k, v)| (format!("{}{}", prefix, k), v)),
+                );
+            }
+        }
+        if let Some(secref) = source.secret_ref.as_ref() {
+            let name = secref.name.as_deref().unwrap_or_default();
+            let api = Api::<Secret>::namespaced(client.clone(), ns);
+            if let Some(secret) = get_env_source(&api, "secret", name, secref.optional).await? {
+                env.extend(secret.data.unwrap_or_default().into_iter().map(|(k, v)| {
+                    (
+                        format!("{}{}", prefix, k),
+                        String::from_utf8(v.0).unwrap_or_default(),
+                    )
+                }));
+            }
+        }
+    }
+    Ok(env)
+}
+
+/// Fetch a ConfigMap or Secret that environment variables refer to. Returns
+/// `None` if it doesn't exist but the reference to it is optional.
+async fn get_env_source<K>(
+    api: &Api<K>,
+    kind: &str,
+    name: &str,
+    optional: Option<bool>,
+) -> anyhow::Result<Option<K>>
+where
+    K: Clone + serde::de::DeserializeOwned + std::fmt::Debug,
+{
+    match api.get(name).await {
+        Ok(source) => Ok(Some(source)),
+        Err(kube::Error::Api(response)) if response.code == 404 => {
+            if optional == Some(true) {
+                debug!("Optional {} {} does not exist", kind, name);
+                Ok(None)
+            } else {
+                anyhow::bail!("{} {:?} not found", kind, name)
+            }
+        }
+        Err(e) => anyhow::bail!("unable to fetch {} {:?}: {}", kind, name, e),
+    }
 }
 
 /// Called when an env var does not have a value associated with.
 ///
-/// This follows the env_var_source to get the value
+/// This follows the env_var_source to get the value. Returns `None` if the
+/// variable shouldn't be set, because it refers to a missing optional key.
 #[doc(hidden)]
 async fn on_missing_env_value(
     env_var_source: Option<EnvVarSource>,
     client: &kube::Client,
-    ns: &str,
+    container: &Container,
+    pod: &Pod,
     fields: &HashMap<String, String>,
-) -> String {
+) -> anyhow::Result<Option<String>> {
+    let ns = pod.namespace();
     let env_src = match env_var_source {
         Some(env_src) => env_src,
-        None => return String::new(),
+        None => return Ok(Some(String::new())),
     };
 
     // ConfigMaps
     if let Some(cfkey) = env_src.config_map_key_ref.as_ref() {
         let name = cfkey.name.as_deref().unwrap_or_default();
-        match Api::<ConfigMap>::namespaced(client.clone(), ns)
-            .get(name)
-            .await
-        {
-            Ok(cfgmap) => {
-                // I am not totally clear on what the outcome should
-                // be of a cfgmap key miss. So for now just return an
-                // empty default.
-                return cfgmap
-                    .data
-                    .unwrap_or_default()
-                    .get(&cfkey.key)
-                    .cloned()
-                    .unwrap_or_default();
-            }
-            Err(e) => {
-                error!("Error fetching config map {}: {}", name, e);
-                return "".to_string();
-            }
-        }
+        let api = Api::<ConfigMap>::namespaced(client.clone(), ns);
+        let cfgmap = match get_env_source(&api, "configmap", name, cfkey.optional).await? {
+            Some(cfgmap) => cfgmap,
+            None => return Ok(None),
+        };
+        return match cfgmap.data.unwrap_or_default().remove(&cfkey.key) {
+            Some(value) => Ok(Some(value)),
+            None if cfkey.optional == Some(true) => Ok(None),
+            None => anyhow::bail!(
+                "couldn't find key {} in ConfigMap {}/{}",
+                cfkey.key,
+                ns,
+                name
+            ),
+        };
     }
     // Secrets
     if let Some(seckey) = env_src.secret_key_ref.as_ref() {
         let name = seckey.name.as_deref().unwrap_or_default();
-        match Api::<Secret>::namespaced(client.clone(), ns)
-            .get(name)
-            .await
-        {
-            Ok(secret) => {
-                // I am not totally clear on what the outcome should
-                // be of a secret key miss. So for now just return an
-                // empty default.
-                return secret
-                    .data
-                    .unwrap_or_default()
-                    .remove(&seckey.key)
-                    .map(|s| String::from_utf8(s.0).unwrap_or_default())
-                    .unwrap_or_default();
-            }
-            Err(e) => {
-                error!("Error fetching secret {}: {}", name, e);
-                return String::new();
-            }
-        }
+        let api = Api::<Secret>::namespaced(client.clone(), ns);
+        let secret = match get_env_source(&api, "secret", name, seckey.optional).await? {
+            Some(secret) => secret,
+            None => return Ok(None),
+        };
+        return match secret.data.unwrap_or_default().remove(&seckey.key) {
+            Some(value) => Ok(Some(String::from_utf8(value.0).unwrap_or_default())),
+            None if seckey.optional == Some(true) => Ok(None),
+            None => anyhow::bail!("couldn't find key {} in Secret {}/{}", seckey.key, ns, name),
+        };
     }
     // Downward API (Field Refs)
     if let Some(cfkey) = env_src.field_ref.as_ref() {
-        return fields.get(&cfkey.field_path).cloned().unwrap_or_default();
+        return Ok(Some(
+            fields.get(&cfkey.field_path).cloned().unwrap_or_default(),
+        ));
+    }
+    // Downward API (Resource Fields)
+    if let Some(resource_ref) = env_src.resource_field_ref.as_ref() {
+        let container = match resource_ref.container_name.as_deref() {
+            Some(name) if name != container.name() => pod
+                .all_containers()
+                .into_iter()
+                .find(|c| c.name() == name)
+                .ok_or_else(|| anyhow::anyhow!("no container named {}", name))?,
+            _ => container.clone(),
+        };
+        let value = resource_field_value(resource_ref, &container, pod, client)
+            .await
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "couldn't resolve resource field {}: {}",
+                    resource_ref.resource,
+                    e
+                )
+            })?;
+        return Ok(Some(value));
     }
-    // Reource Fields (Not implementable just yet... need more of a model.)
-
-    String::new()
-}
 
-/// Build the map of allowable field_ref values.
-///
-/// The Downward API only supports a small selection of fields. This
-/// provides those fields.
-fn field_map(pod: &Pod) -> HashMap<String, String> {
-    let mut map: HashMap<String, String> = HashMap::new();
-    map.insert("metadata.name".into(), pod.name().to_owned());
-    map.insert("metadata.namespace".into(), pod.namespace().to_owned());
-    map.insert(
-        "spec.serviceAccountName".into(),
-        pod.service_account_name().unwrap_or_default().to_owned(),
-    );
-    map.insert(
-        "status.hostIP".into(),
-        pod.host_ip().unwrap_or_default().to_owned(),
-    );
-    map.insert(
-        "status.podIP".into(),
-        pod.pod_ip().unwrap_or_default().to_owned(),
-    );
-    pod.labels().iter().for_each(|(k, v)| {
-        info!("adding {} to labels", k);
-        map.insert(format!("metadata.labels.{}", k), v.clone());
-    });
-    pod.annotations().iter().for_each(|(k, v)| {
-        map.insert(format!("metadata.annotations.{}", k), v.clone());
-    });
-    map
+    Ok(Some(String::new()))
 }
 
 /// A Provider error
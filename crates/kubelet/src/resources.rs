@@ -0,0 +1,106 @@
+//! Helpers for interpreting Kubernetes resource quantities such as those found
+//! in a container's `resources.limits` and `resources.requests`.
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+
+/// The name of the CPU resource.
+pub const CPU: &str = "cpu";
+/// The name of the memory resource.
+pub const MEMORY: &str = "memory";
+/// The name of the ephemeral storage resource.
+pub const EPHEMERAL_STORAGE: &str = "ephemeral-storage";
+
+/// Parses a Kubernetes resource quantity into its numeric value.
+///
+/// Supports the binary SI suffixes (`Ki`, `Mi`, `Gi`, `Ti`, `Pi`, `Ei`), the
+/// decimal SI suffixes (`n`, `u`, `m`, `k`, `M`, `G`, `T`, `P`, `E`) and
+/// decimal exponents (e.g. `1e3`), as described in the
+/// [Kubernetes API reference](https://kubernetes.io/docs/reference/kubernetes-api/common-definitions/quantity/).
+pub fn parse_quantity(quantity: &Quantity) -> anyhow::Result<f64> {
+    let value = quantity.0.trim();
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '+' || c == '-'))
+        .unwrap_or(value.len());
+    let (number, suffix) = value.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| anyhow::anyhow!("invalid quantity {:?}", value))?;
+    let multiplier = match suffix {
+        "" => 1.0,
+        "Ki" => 1024f64,
+        "Mi" => 1024f64.powi(2),
+        "Gi" => 1024f64.powi(3),
+        "Ti" => 1024f64.powi(4),
+        "Pi" => 1024f64.powi(5),
+        "Ei" => 1024f64.powi(6),
+        "n" => 1e-9,
+        "u" => 1e-6,
+        "m" => 1e-3,
+        "k" => 1e3,
+        "M" => 1e6,
+        "G" => 1e9,
+        "T" => 1e12,
+        "P" => 1e15,
+        "E" => 1e18,
+        exponent if exponent.starts_with('e') || exponent.starts_with('E') => {
+            let exponent: i32 = exponent[1..]
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid quantity {:?}", value))?;
+            10f64.powi(exponent)
+        }
+        other => anyhow::bail!("invalid quantity suffix {:?} in {:?}", other, value),
+    };
+    Ok(number * multiplier)
+}
+
+/// Parses a Kubernetes resource quantity into a whole number of bytes,
+/// rounding any fractional part up.
+pub fn parse_bytes(quantity: &Quantity) -> anyhow::Result<u64> {
+    let bytes = parse_quantity(quantity)?;
+    if bytes < 0.0 {
+        anyhow::bail!("quantity {:?} must not be negative", quantity.0);
+    }
+    Ok(bytes.ceil() as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn quantity(value: &str) -> Quantity {
+        Quantity(value.to_string())
+    }
+
+    #[test]
+    fn can_parse_plain_numbers() {
+        assert_eq!(parse_quantity(&quantity("128")).unwrap(), 128.0);
+        assert_eq!(parse_quantity(&quantity("0.5")).unwrap(), 0.5);
+    }
+
+    #[test]
+    fn can_parse_binary_suffixes() {
+        assert_eq!(parse_bytes(&quantity("1Ki")).unwrap(), 1024);
+        assert_eq!(parse_bytes(&quantity("64Mi")).unwrap(), 64 * 1024 * 1024);
+        assert_eq!(parse_bytes(&quantity("1Gi")).unwrap(), 1024 * 1024 * 1024);
+    }
+
+    #[test]
+    fn can_parse_decimal_suffixes() {
+        assert_eq!(parse_bytes(&quantity("128M")).unwrap(), 128_000_000);
+        assert_eq!(parse_bytes(&quantity("1k")).unwrap(), 1000);
+        assert_eq!(parse_quantity(&quantity("250m")).unwrap(), 0.25);
+    }
+
+    #[test]
+    fn can_parse_exponents() {
+        assert_eq!(parse_bytes(&quantity("129e6")).unwrap(), 129_000_000);
+        assert_eq!(parse_bytes(&quantity("1E3")).unwrap(), 1000);
+    }
+
+    #[test]
+    fn rejects_invalid_quantities() {
+        assert!(parse_quantity(&quantity("")).is_err());
+        assert!(parse_quantity(&quantity("12XB")).is_err());
+        assert!(parse_quantity(&quantity("Mi")).is_err());
+        assert!(parse_bytes(&quantity("-1Mi")).is_err());
+    }
+}
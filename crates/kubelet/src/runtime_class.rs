@@ -0,0 +1,80 @@
+//! Support for pods that choose how they are run with a
+//! [RuntimeClass](https://kubernetes.io/docs/concepts/containers/runtime-class/).
+//!
+//! A provider can run pods with any of the handlers it returns from
+//! [`Provider::runtime_handlers`](crate::provider::Provider::runtime_handlers),
+//! besides its default one for pods without a runtime class. Each handler is
+//! advertised with a node label, which a RuntimeClass's
+//! `scheduling.nodeSelector` can select so that its pods are only scheduled
+//! to nodes that can run them.
+use k8s_openapi::api::node::v1::RuntimeClass;
+use kube::api::Api;
+
+use crate::pod::Pod;
+
+/// The prefix of the node labels advertising the handlers a node supports
+pub const HANDLER_LABEL_PREFIX: &str = "runtime-handler.krustlet.dev/";
+
+/// The node label advertising that the node can run pods with `handler`.
+pub fn handler_label(handler: &str) -> String {
+    format!("{}{}", HANDLER_LABEL_PREFIX, handler)
+}
+
+/// Resolves the handler of the pod's RuntimeClass, which must be one of
+/// `handlers`. Returns `None` for pods without a runtime class, which are run
+/// by the provider's default handler.
+pub async fn resolve_handler(
+    pod: &Pod,
+    client: &kube::Client,
+    handlers: &[String],
+) -> anyhow::Result<Option<String>> {
+    let name = match pod.runtime_class_name() {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    let runtime_class = Api::<RuntimeClass>::all(client.clone())
+        .get(name)
+        .await
+        .map_err(|e| anyhow::anyhow!("unable to fetch RuntimeClass {:?}: {}", name, e))?;
+    check_handler(name, runtime_class.handler, handlers).map(Some)
+}
+
+fn check_handler(name: &str, handler: String, handlers: &[String]) -> anyhow::Result<String> {
+    if !handlers.contains(&handler) {
+        anyhow::bail!(
+            "handler {:?} of RuntimeClass {:?} is not supported by this node",
+            handler,
+            name
+        );
+    }
+    Ok(handler)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[tokio::test]
+    async fn test_pods_without_runtime_class_use_default_handler() {
+        let pod: Pod = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "test" },
+            "spec": { "containers": [] },
+        }))
+        .unwrap();
+        // Never used, as there is no RuntimeClass to fetch
+        let config = kube::Config::new("http://127.0.0.1:1".parse().unwrap());
+        let client = kube::Client::try_from(config).unwrap();
+        assert_eq!(resolve_handler(&pod, &client, &[]).await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_check_handler() {
+        let handlers = vec!["fast".to_string()];
+        assert_eq!(
+            check_handler("speedy", "fast".to_string(), &handlers).unwrap(),
+            "fast"
+        );
+        assert!(check_handler("slow", "slow".to_string(), &handlers).is_err());
+    }
+}
@@ -48,9 +48,26 @@ pub trait GenericProviderState: 'static + Send + Sync {
     fn plugin_registry(&self) -> Option<std::sync::Arc<PluginRegistry>> {
         None
     }
+    /// The RuntimeClass handlers that pods can be run with, which should be
+    /// the same as the provider's
+    /// [`runtime_handlers`](crate::provider::Provider::runtime_handlers).
+    fn runtime_handlers(&self) -> Vec<String> {
+        Vec::new()
+    }
     /// Stops the specified pod. This typically involves tearing down a
     /// runtime or other execution environment.
     async fn stop(&self, pod: &crate::pod::Pod) -> anyhow::Result<()>;
+    /// Stops the specified pod, giving it up to `grace_period` to shut down
+    /// by itself first. Providers whose workloads can't be asked to shut down
+    /// can keep the default, which calls `stop`.
+    async fn stop_gracefully(
+        &self,
+        pod: &crate::pod::Pod,
+        grace_period: std::time::Duration,
+    ) -> anyhow::Result<()> {
+        let _ = grace_period;
+        self.stop(pod).await
+    }
 }
 
 /// Exposes pod state in a way that can be consumed by
@@ -64,6 +81,12 @@ pub trait GenericPodState: ObjectState<Manifest = Pod, Status = PodStatus> {
     /// the provider's execution environment. Typically your
     /// implementation can just move the volumes map into a member field.
     async fn set_volumes(&mut self, volumes: HashMap<String, crate::volume::Ref>);
+    /// Stores the handler of the pod's RuntimeClass, or `None` if it is run
+    /// by the provider's default handler. Providers without any RuntimeClass
+    /// handlers can keep the default, which ignores it.
+    async fn set_runtime_handler(&mut self, handler: Option<String>) {
+        let _ = handler;
+    }
     /// Backs off (waits) after an error of the specified kind.
     async fn backoff(&mut self, sequence: BackoffSequence);
     /// Resets the backoff time for the specified kind of error.
@@ -5,7 +5,7 @@ use tracing::{debug, error, info};
 
 use super::error::Error;
 use super::image_pull::ImagePull;
-use super::GenericProvider;
+use super::{GenericPodState, GenericProvider, GenericProviderState};
 
 /// The Kubelet is aware of the Pod.
 pub struct Registered<P: GenericProvider> {
@@ -30,8 +30,8 @@ impl<P: GenericProvider> Default for Registered<P> {
 impl<P: GenericProvider> State<P::PodState> for Registered<P> {
     async fn next(
         self: Box<Self>,
-        _provider_state: SharedState<P::ProviderState>,
-        _pod_state: &mut P::PodState,
+        provider_state: SharedState<P::ProviderState>,
+        pod_state: &mut P::PodState,
         pod: Manifest<Pod>,
     ) -> Transition<P::PodState> {
         let pod = pod.latest();
@@ -45,6 +45,18 @@ impl<P: GenericProvider> State<P::PodState> for Registered<P> {
                 return Transition::next(self, next);
             }
         }
+        let (client, handlers) = {
+            let provider_state = provider_state.read().await;
+            (provider_state.client(), provider_state.runtime_handlers())
+        };
+        match crate::runtime_class::resolve_handler(&pod, &client, &handlers).await {
+            Ok(handler) => pod_state.set_runtime_handler(handler).await,
+            Err(e) => {
+                error!("{:?}", e);
+                let next = Error::<P>::new(e.to_string());
+                return Transition::next(self, next);
+            }
+        }
         info!("Pod registered: {}", pod.name());
         let next = ImagePull::<P>::default();
         Transition::next(self, next)
@@ -36,7 +36,9 @@ impl<P: GenericProvider> State<P::PodState> for Terminated<P> {
         // TODO: In original code, pod key was stored in state rather than
         // re-derived.  Is this important e.g. could pod mutate in ways
         // that invalidate the key assigned on startup?
-        let stop_result = state_reader.stop(&pod).await;
+        let stop_result = state_reader
+            .stop_gracefully(&pod, pod.termination_grace_period())
+            .await;
         Transition::Complete(stop_result)
     }
 
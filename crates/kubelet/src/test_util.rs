@@ -0,0 +1,68 @@
+//! Helpers shared by the crate's tests.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+/// A request that a fake API server received: its method, path and JSON
+/// body, which is null if it had none.
+pub(crate) type Request = (http::Method, String, serde_json::Value);
+
+/// Starts an API server that serves the given objects by path, whatever the
+/// method, and answers everything else with a 404. Returns a client for it
+/// and the requests it has received.
+pub(crate) async fn fake_api_server(
+    objects: Vec<(&str, serde_json::Value)>,
+) -> (kube::Client, Arc<Mutex<Vec<Request>>>) {
+    let objects: Arc<HashMap<String, String>> = Arc::new(
+        objects
+            .into_iter()
+            .map(|(path, object)| (path.to_owned(), object.to_string()))
+            .collect(),
+    );
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let recorded = requests.clone();
+    let make_svc = hyper::service::make_service_fn(move |_| {
+        let objects = objects.clone();
+        let recorded = recorded.clone();
+        async move {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(
+                move |req: hyper::Request<hyper::Body>| {
+                    let objects = objects.clone();
+                    let recorded = recorded.clone();
+                    async move {
+                        let method = req.method().clone();
+                        let path = req.uri().path().to_owned();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let body = serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null);
+                        recorded.lock().unwrap().push((method, path.clone(), body));
+                        let response = match objects.get(&path) {
+                            Some(object) => hyper::Response::new(hyper::Body::from(object.clone())),
+                            None => {
+                                let status = serde_json::json!({
+                                    "kind": "Status",
+                                    "apiVersion": "v1",
+                                    "status": "Failure",
+                                    "message": format!("{} not found", path),
+                                    "reason": "NotFound",
+                                    "code": 404,
+                                });
+                                hyper::Response::builder()
+                                    .status(404)
+                                    .body(status.to_string().into())
+                                    .unwrap()
+                            }
+                        };
+                        Ok::<_, std::convert::Infallible>(response)
+                    }
+                },
+            ))
+        }
+    });
+    let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+    let url = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+    let client =
+        kube::Client::try_from(kube::Config::new(reqwest::Url::parse(&url).unwrap())).unwrap();
+    (client, requests)
+}
@@ -0,0 +1,156 @@
+use std::path::{Component, Path};
+
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::{DownwardAPIVolumeFile, DownwardAPIVolumeSource, Pod as KubePod};
+use kube::api::ListParams;
+use kube_runtime::watcher::{watcher, Event};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use super::*;
+use crate::downward_api::{field_map, resource_field_value};
+
+pub(crate) async fn populate(
+    source: &DownwardAPIVolumeSource,
+    pod: &Pod,
+    client: &kube::Client,
+    path: &Path,
+) -> anyhow::Result<VolumeType> {
+    tokio::fs::create_dir_all(path).await?;
+    for item in source.items.iter().flatten() {
+        let value = match (&item.field_ref, &item.resource_field_ref) {
+            (Some(field_ref), _) => field_value(pod, &field_ref.field_path)?,
+            (None, Some(resource_ref)) => {
+                let container_name = resource_ref.container_name.as_deref().ok_or_else(|| {
+                    anyhow::anyhow!("resource field of {} has no container name", item.path)
+                })?;
+                let container = pod
+                    .all_containers()
+                    .into_iter()
+                    .find(|c| c.name() == container_name)
+                    .ok_or_else(|| anyhow::anyhow!("no container named {}", container_name))?;
+                resource_field_value(resource_ref, &container, pod, client).await?
+            }
+            (None, None) => anyhow::bail!("{} has no field or resource field", item.path),
+        };
+        write(path, item, &value).await?;
+    }
+    Ok(VolumeType::DownwardApi)
+}
+
+/// Watches the pod for changes to its labels and annotations, rewriting the
+/// files of the fields that have changed. The task runs until it is aborted.
+pub(crate) fn refresh(
+    source: DownwardAPIVolumeSource,
+    pod: &Pod,
+    client: &kube::Client,
+    path: PathBuf,
+) -> JoinHandle<()> {
+    let api: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+    let params = ListParams::default().fields(&format!("metadata.name={}", pod.name()));
+    tokio::spawn(async move {
+        let mut events = watcher(api, params).boxed();
+        while let Some(event) = events.next().await {
+            let pods = match event {
+                Ok(Event::Applied(pod)) => vec![pod],
+                Ok(Event::Restarted(pods)) => pods,
+                Ok(Event::Deleted(_)) => continue,
+                Err(e) => {
+                    warn!("Error watching pod for downward API volume: {}", e);
+                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            for pod in pods.into_iter().map(Pod::from) {
+                for item in source.items.iter().flatten() {
+                    let field_path = match &item.field_ref {
+                        Some(field_ref) => &field_ref.field_path,
+                        // Resources can't change while the pod is running
+                        None => continue,
+                    };
+                    let result = match field_value(&pod, field_path) {
+                        Ok(value) => write(&path, item, &value).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = result {
+                        warn!("Unable to refresh downward API file {}: {}", item.path, e);
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Resolves a `fieldRef`. Whole label and annotation maps are written one
+/// `key="value"` pair per line, sorted by key.
+fn field_value(pod: &Pod, field_path: &str) -> anyhow::Result<String> {
+    let map = match field_path {
+        "metadata.labels" => Some(pod.labels()),
+        "metadata.annotations" => Some(pod.annotations()),
+        _ => None,
+    };
+    if let Some(map) = map {
+        return Ok(map
+            .iter()
+            .map(|(k, v)| format!("{}={:?}", k, v))
+            .collect::<Vec<_>>()
+            .join("\n"));
+    }
+    field_map(pod)
+        .remove(field_path)
+        .ok_or_else(|| anyhow::anyhow!("unsupported field {}", field_path))
+}
+
+/// Writes an item's file if its content has changed, replacing it atomically
+/// so that it is never seen half written.
+async fn write(dir: &Path, item: &DownwardAPIVolumeFile, value: &str) -> anyhow::Result<()> {
+    let relative = Path::new(&item.path);
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        anyhow::bail!("{} is not a relative path", item.path);
+    }
+    let file_path = dir.join(relative);
+    if let Ok(current) = tokio::fs::read(&file_path).await {
+        if current == value.as_bytes() {
+            return Ok(());
+        }
+    }
+    if let Some(parent) = file_path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let mut temp_path = file_path.clone().into_os_string();
+    temp_path.push(".tmp");
+    tokio::fs::write(&temp_path, value).await?;
+    tokio::fs::rename(&temp_path, &file_path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_label_and_annotation_files() {
+        let pod: Pod = serde_json::from_value(serde_json::json!({
+            "metadata": {
+                "name": "test",
+                "labels": { "tier": "web", "app": "hello" },
+                "annotations": { "note": "say \"hi\"" },
+            },
+            "spec": { "containers": [] },
+        }))
+        .unwrap();
+        assert_eq!(
+            field_value(&pod, "metadata.labels").unwrap(),
+            "app=\"hello\"\ntier=\"web\""
+        );
+        assert_eq!(
+            field_value(&pod, "metadata.annotations").unwrap(),
+            "note=\"say \\\"hi\\\"\""
+        );
+        assert_eq!(field_value(&pod, "metadata.name").unwrap(), "test");
+        assert!(field_value(&pod, "metadata.generation").is_err());
+    }
+}
@@ -15,6 +15,7 @@ use crate::plugin_watcher::PluginRegistry;
 use crate::pod::Pod;
 
 mod configmap;
+mod downwardapi;
 mod hostpath;
 mod persistentvolumeclaim;
 mod secret;
@@ -30,16 +31,22 @@ pub enum VolumeType {
     PersistentVolumeClaim,
     /// hostpath volume
     HostPath,
+    /// downward API volume
+    DownwardApi,
+    /// scratch space that containers write to outside of their volumes
+    Scratch,
 }
 
 /// A smart wrapper around the location of a volume on the host system. If this
-/// is a ConfigMap or Secret volume, dropping this reference will clean up the
-/// temporary volume. [AsRef] and [std::ops::Deref] are implemented for this
+/// is a ConfigMap, Secret, downward API or scratch volume, dropping this
+/// reference will clean up the temporary volume. [AsRef] and [std::ops::Deref] are implemented for this
 /// type so you can still use it like a normal PathBuf
 #[derive(Debug)]
 pub struct Ref {
     host_path: PathBuf,
     volume_type: VolumeType,
+    /// keeps the volume's content up to date until dropped
+    refresh: Option<tokio::task::JoinHandle<()>>,
 }
 
 impl Ref {
@@ -61,7 +68,10 @@ impl Ref {
                 host_path.push(&v.name);
                 let pr = plugin_registry.clone();
                 async move {
-                    let volume_type = configure(v, pod.namespace(), client, pr, &host_path).await?;
+                    let volume_type = configure(v, pod, client, pr, &host_path).await?;
+                    let refresh = v.downward_api.as_ref().map(|source| {
+                        downwardapi::refresh(source.clone(), pod, client, host_path.clone())
+                    });
                     Ok((
                         v.name.to_owned(),
                         // Every other volume type should mount to the given
@@ -71,10 +81,12 @@ impl Ref {
                             Some(hostpath) => Ref {
                                 host_path: PathBuf::from(&hostpath.path),
                                 volume_type,
+                                refresh,
                             },
                             None => Ref {
                                 host_path,
                                 volume_type,
+                                refresh,
                             },
                         },
                     ))
@@ -89,6 +101,18 @@ impl Ref {
         }
     }
 
+    /// Creates an empty directory at `host_path` for a pod's containers to use
+    /// as scratch space, such as for a writable root file system. The
+    /// directory and everything in it is removed when the reference is dropped.
+    pub async fn scratch(host_path: PathBuf) -> anyhow::Result<Self> {
+        tokio::fs::create_dir_all(&host_path).await?;
+        Ok(Ref {
+            host_path,
+            volume_type: VolumeType::Scratch,
+            refresh: None,
+        })
+    }
+
     /// Unmounts any volumes mounted to the pod. Usually called when dropping
     /// the pod out of scope.
     pub async fn unmount_volumes_from_pod(
@@ -133,7 +157,16 @@ impl Deref for Ref {
 
 impl Drop for Ref {
     fn drop(&mut self) {
-        if matches!(self.volume_type, VolumeType::ConfigMap | VolumeType::Secret) {
+        if let Some(refresh) = self.refresh.take() {
+            refresh.abort();
+        }
+        if matches!(
+            self.volume_type,
+            VolumeType::ConfigMap
+                | VolumeType::Secret
+                | VolumeType::DownwardApi
+                | VolumeType::Scratch
+        ) {
             // TODO: Currently there is no way to do this async (though there is
             // an async destructors proposal)
             debug!(
@@ -185,11 +218,12 @@ impl From<Option<String>> for ItemMount {
 /// individually
 async fn configure(
     vol: &KubeVolume,
-    namespace: &str,
+    pod: &Pod,
     client: &kube::Client,
     plugin_registry: Option<Arc<PluginRegistry>>,
     path: &Path,
 ) -> anyhow::Result<VolumeType> {
+    let namespace = pod.namespace();
     if let Some(cm) = &vol.config_map {
         let name = &cm
             .name
@@ -210,9 +244,11 @@ async fn configure(
         persistentvolumeclaim::populate(pvc_source, client, namespace, plugin_registry, path).await
     } else if let Some(hp) = &vol.host_path {
         hostpath::populate(hp).await
+    } else if let Some(da) = &vol.downward_api {
+        downwardapi::populate(da, pod, client, path).await
     } else {
         Err(anyhow::anyhow!(
-            "Unsupported volume type. Currently supported types: ConfigMap, Secret, PersistentVolumeClaim, and HostPath"
+            "Unsupported volume type. Currently supported types: ConfigMap, Secret, PersistentVolumeClaim, HostPath, and DownwardAPI"
         ))
     }
 }
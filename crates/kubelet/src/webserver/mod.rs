@@ -1,27 +1,191 @@
 //! Server is an HTTP(S) server for answering Kubelet callbacks.
 //!
-//! Logs and exec calls are the main things that a server should handle.
+//! Logs, exec and attach calls are the main things that a server should handle.
+//! Exec and attach stream over either a WebSocket or a SPDY connection, which
+//! the server takes over from HTTP once it has agreed to upgrade it. As they
+//! give access to containers, only clients that authenticate with a client
+//! certificate may make them.
+
+mod spdy;
 
 use crate::config::ServerConfig;
+use crate::exec::{Sender as ExecSender, Stream};
 use crate::log::{Options, Sender};
 use crate::provider::{NotImplementedError, Provider};
+use anyhow::Context;
+use futures::future::BoxFuture;
+use futures::{SinkExt, StreamExt};
+use http::header::{HeaderMap, HeaderValue, CONNECTION, SEC_WEBSOCKET_PROTOCOL, UPGRADE};
 use http::status::StatusCode;
-use http::Response;
+use http::{Request, Response};
+use hyper::upgrade::OnUpgrade;
 use hyper::Body;
 use std::convert::Infallible;
-use std::sync::Arc;
-use tracing::{debug, error};
-use warp::Filter;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, NoClientAuth, RootCertStore, ServerConfig as TlsConfig,
+    Session,
+};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tower::Service;
+use tracing::{debug, error, warn};
+use warp::filters::BoxedFilter;
+use warp::ws::{Message, WebSocket, Ws};
+use warp::{Filter, Reply};
 
 const PING: &str = "this is the Krustlet HTTP server";
 
+/// The Kubernetes streaming protocols supported for attaching to and running
+/// commands in containers. Both send each stream on its own channel, but only
+/// v4 reports errors as a JSON `Status`.
+const STREAM_PROTOCOLS: &[&str] = &[V4_STREAM_PROTOCOL, "channel.k8s.io"];
+const V4_STREAM_PROTOCOL: &str = "v4.channel.k8s.io";
+/// The header that SPDY clients request streaming protocols with.
+const STREAM_PROTOCOL_HEADER: &str = "X-Stream-Protocol-Version";
+/// The channel that carries input for the container's stdin.
+const STDIN_CHANNEL: u8 = 0;
+/// The channel that carries output from stdout.
+const STDOUT_CHANNEL: u8 = 1;
+/// The channel that carries output from stderr.
+const STDERR_CHANNEL: u8 = 2;
+/// The channel that carries the outcome of a command.
+const ERROR_CHANNEL: u8 = 3;
+/// The channel that carries the size of the client's terminal.
+const RESIZE_CHANNEL: u8 = 4;
+
 /// Start the Krustlet HTTP(S) server
 ///
 /// This is a primitive implementation of an HTTP provider for the internal API.
+/// The server accepts connections itself, rather than through warp, so that it
+/// can tell which clients authenticated and take over connections upgraded to
+/// SPDY.
 pub(crate) async fn start<T: Provider>(
     provider: Arc<T>,
     config: &ServerConfig,
 ) -> anyhow::Result<()> {
+    let routes = routes(provider);
+    if config.client_ca_file.is_none() {
+        warn!("No client CA file is configured, so exec and attach requests will be refused");
+    }
+    let tls = TlsAcceptor::from(Arc::new(tls_config(config)?));
+    let listener = TcpListener::bind((config.addr, config.port)).await?;
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("Error accepting connection: {}", e);
+                continue;
+            }
+        };
+        let tls = tls.clone();
+        let routes = routes.clone();
+        tokio::spawn(async move {
+            match tls.accept(stream).await {
+                Ok(stream) => {
+                    let routes = match authenticated(&stream) {
+                        true => routes.authenticated,
+                        false => routes.anonymous,
+                    };
+                    serve_connection(stream, routes).await
+                }
+                Err(e) => debug!("Error negotiating TLS: {}", e),
+            }
+        });
+    }
+}
+
+/// Whether the client authenticated with a certificate. Certificates are
+/// only accepted once they have been verified against the client CAs.
+fn authenticated<IO>(stream: &TlsStream<IO>) -> bool {
+    let (_, session) = stream.get_ref();
+    matches!(session.get_peer_certificates(), Some(certs) if !certs.is_empty())
+}
+
+/// Reads the server's certificate and private key, which may be a PKCS8 or
+/// an RSA key, and the CAs that client certificates are verified against.
+/// Clients need not authenticate, as only some requests require them to.
+fn tls_config(config: &ServerConfig) -> anyhow::Result<TlsConfig> {
+    let cert = std::fs::read(&config.cert_file)
+        .with_context(|| format!("unable to read {}", config.cert_file.display()))?;
+    let certs = pemfile::certs(&mut cert.as_slice())
+        .map_err(|_| anyhow::anyhow!("unable to parse {}", config.cert_file.display()))?;
+    let key = std::fs::read(&config.private_key_file)
+        .with_context(|| format!("unable to read {}", config.private_key_file.display()))?;
+    let key = pemfile::pkcs8_private_keys(&mut key.as_slice())
+        .ok()
+        .filter(|keys| !keys.is_empty())
+        .or_else(|| pemfile::rsa_private_keys(&mut key.as_slice()).ok())
+        .and_then(|mut keys| keys.pop())
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "{} contains no private key",
+                config.private_key_file.display()
+            )
+        })?;
+
+    let mut tls = match &config.client_ca_file {
+        Some(client_ca_file) => {
+            let cas = std::fs::read(client_ca_file)
+                .with_context(|| format!("unable to read {}", client_ca_file.display()))?;
+            let mut roots = RootCertStore::empty();
+            match roots.add_pem_file(&mut cas.as_slice()) {
+                Ok((added, _)) if added > 0 => (),
+                _ => anyhow::bail!("{} contains no CA certificates", client_ca_file.display()),
+            }
+            TlsConfig::new(AllowAnyAnonymousOrAuthenticatedClient::new(roots))
+        }
+        None => TlsConfig::new(NoClientAuth::new()),
+    };
+    tls.set_single_cert(certs, key)?;
+    tls.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    Ok(tls)
+}
+
+/// The pending upgrade of a connection that a client asked to switch to SPDY.
+/// The server takes over the connection once it has agreed to the upgrade.
+#[derive(Clone)]
+struct SpdyUpgrade(Arc<Mutex<Option<OnUpgrade>>>);
+
+/// Serves the requests made over a connection. Requests to upgrade to SPDY
+/// are given the means to take over the connection, as they can't get it from
+/// the routes themselves.
+async fn serve_connection<IO>(io: IO, routes: BoxedFilter<(Response<Body>,)>)
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let routes = warp::service(routes);
+    let service = hyper::service::service_fn(move |mut request: Request<Body>| {
+        if wants_upgrade(request.headers(), spdy::UPGRADE) {
+            let upgrade = hyper::upgrade::on(&mut request);
+            request
+                .extensions_mut()
+                .insert(SpdyUpgrade(Arc::new(Mutex::new(Some(upgrade)))));
+        }
+        routes.clone().call(request)
+    });
+    if let Err(e) = hyper::server::conn::Http::new()
+        .serve_connection(io, service)
+        .with_upgrades()
+        .await
+    {
+        debug!("Error serving connection: {}", e);
+    }
+}
+
+/// The routes served to clients, depending on whether they authenticated.
+#[derive(Clone)]
+struct Routes {
+    authenticated: BoxedFilter<(Response<Body>,)>,
+    /// Refuses to exec into or attach to containers
+    anonymous: BoxedFilter<(Response<Body>,)>,
+}
+
+fn routes<T: Provider>(provider: Arc<T>) -> Routes {
     let health = warp::get().and(warp::path("healthz")).map(|| PING);
     let ping = warp::get().and(warp::path::end()).map(|| PING);
 
@@ -35,22 +199,78 @@ pub(crate) async fn start<T: Provider>(
         });
 
     let exec_provider = provider.clone();
-    let exec = warp::post()
-        .and(warp::path!("exec" / String / String / String))
-        .and_then(move |namespace, pod, container| {
+    let exec = warp::path!("exec" / String / String / String)
+        .and(warp::ws())
+        .and(raw_query())
+        .and(warp::header::optional::<String>(
+            SEC_WEBSOCKET_PROTOCOL.as_str(),
+        ))
+        .and_then(move |namespace, pod, container, ws, query, protocols| {
             let provider = exec_provider.clone();
-            post_exec(provider, namespace, pod, container)
+            let target = Target::new(namespace, pod, container, Action::Exec);
+            upgrade_to_websocket(provider, target, ws, query, protocols)
         });
 
-    let routes = ping.or(health).or(logs).or(exec);
+    let spdy_exec_provider = provider.clone();
+    let spdy_exec = warp::post()
+        .and(warp::path!("exec" / String / String / String))
+        .and(raw_query())
+        .and(warp::ext::optional::<SpdyUpgrade>())
+        .and(warp::header::headers_cloned())
+        .and_then(move |namespace, pod, container, query, upgrade, headers| {
+            let provider = spdy_exec_provider.clone();
+            let target = Target::new(namespace, pod, container, Action::Exec);
+            upgrade_to_spdy(provider, target, query, upgrade, headers)
+        });
 
-    warp::serve(routes)
-        .tls()
-        .cert_path(&config.cert_file)
-        .key_path(&config.private_key_file)
-        .run((config.addr, config.port))
-        .await;
-    Ok(())
+    let attach_provider = provider.clone();
+    let attach = warp::path!("attach" / String / String / String)
+        .and(warp::ws())
+        .and(raw_query())
+        .and(warp::header::optional::<String>(
+            SEC_WEBSOCKET_PROTOCOL.as_str(),
+        ))
+        .and_then(move |namespace, pod, container, ws, query, protocols| {
+            let provider = attach_provider.clone();
+            let target = Target::new(namespace, pod, container, Action::Attach);
+            upgrade_to_websocket(provider, target, ws, query, protocols)
+        });
+
+    let spdy_attach = warp::post()
+        .and(warp::path!("attach" / String / String / String))
+        .and(raw_query())
+        .and(warp::ext::optional::<SpdyUpgrade>())
+        .and(warp::header::headers_cloned())
+        .and_then(move |namespace, pod, container, query, upgrade, headers| {
+            let provider = provider.clone();
+            let target = Target::new(namespace, pod, container, Action::Attach);
+            upgrade_to_spdy(provider, target, query, upgrade, headers)
+        });
+
+    let unauthenticated = warp::path("exec").or(warp::path("attach")).unify().map(|| {
+        return_with_code(
+            StatusCode::UNAUTHORIZED,
+            "Exec and attach require authenticating with a client certificate.".to_owned(),
+        )
+    });
+
+    let public = ping.or(health).or(logs);
+    Routes {
+        authenticated: public
+            .clone()
+            .or(exec)
+            .or(spdy_exec)
+            .or(attach)
+            .or(spdy_attach)
+            .map(Reply::into_response)
+            .boxed(),
+        anonymous: public.or(unauthenticated).map(Reply::into_response).boxed(),
+    }
+}
+
+/// The request's query string, which is empty if it has none.
+fn raw_query() -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+    warp::query::raw().or(warp::any().map(String::new)).unify()
 }
 
 /// Get the logs from the running container.
@@ -89,19 +309,425 @@ async fn get_container_logs<T: Provider>(
     }
 }
 
-/// Run a pod exec command and get the output
+/// What a streaming request does once its client is connected.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Action {
+    /// Run a command in the container
+    Exec,
+    /// Attach to the container's running process
+    Attach,
+}
+
+/// The container a streaming request is for, and what it does with it.
+#[derive(Debug)]
+struct Target {
+    namespace: String,
+    pod: String,
+    container: String,
+    action: Action,
+}
+
+impl Target {
+    fn new(namespace: String, pod: String, container: String, action: Action) -> Self {
+        Target {
+            namespace,
+            pod,
+            container,
+            action,
+        }
+    }
+}
+
+/// The streams a client asked for in an exec or attach request, and the
+/// command to run for exec requests.
+#[derive(Debug, Default, PartialEq)]
+struct StreamOptions {
+    command: Vec<String>,
+    stdin: bool,
+    stdout: bool,
+    stderr: bool,
+    tty: bool,
+}
+
+impl StreamOptions {
+    fn parse(query: &str) -> Self {
+        let mut options = StreamOptions::default();
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            let enabled = value == "true" || value == "1";
+            match key.as_ref() {
+                "command" => options.command.push(value.into_owned()),
+                "stdin" => options.stdin = enabled,
+                "stdout" => options.stdout = enabled,
+                "stderr" => options.stderr = enabled,
+                "tty" => options.tty = enabled,
+                _ => (),
+            }
+        }
+        options
+    }
+
+    /// Checks that the request can be served, returning why it can't.
+    fn validate(&self, action: Action) -> Result<(), &'static str> {
+        if !(self.stdin || self.stdout || self.stderr) {
+            Err("You must specify at least one of stdin, stdout or stderr.")
+        } else if action == Action::Exec && self.command.is_empty() {
+            Err("No command given.")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The streams a SPDY client opens, by stream type, and their channels.
+    /// A terminal has no separate stderr, and a v4 client sends its size.
+    fn spdy_streams(&self, protocol: Option<&str>) -> Vec<(&'static str, u8)> {
+        let mut streams = vec![("error", ERROR_CHANNEL)];
+        if self.stdin {
+            streams.push(("stdin", STDIN_CHANNEL));
+        }
+        if self.stdout {
+            streams.push(("stdout", STDOUT_CHANNEL));
+        }
+        if self.stderr && !self.tty {
+            streams.push(("stderr", STDERR_CHANNEL));
+        }
+        if self.tty && protocol == Some(V4_STREAM_PROTOCOL) {
+            streams.push(("resize", RESIZE_CHANNEL));
+        }
+        streams
+    }
+}
+
+/// Run a pod exec command or attach to a container over a WebSocket
+///
+/// Implements the kubelet paths /exec/{namespace}/{pod}/{container} and
+/// /attach/{namespace}/{pod}/{container} for clients that speak the
+/// Kubernetes channel protocol over a WebSocket.
+async fn upgrade_to_websocket<T: Provider>(
+    provider: Arc<T>,
+    target: Target,
+    ws: Ws,
+    query: String,
+    protocols: Option<String>,
+) -> Result<Response<Body>, Infallible> {
+    let options = StreamOptions::parse(&query);
+    debug!(
+        "Got WebSocket request for {:?}. Options: {:?}.",
+        target, options
+    );
+    if let Err(message) = options.validate(target.action) {
+        return Ok(return_with_code(
+            StatusCode::BAD_REQUEST,
+            message.to_owned(),
+        ));
+    }
+
+    let protocols = protocols.unwrap_or_default();
+    let protocol = stream_protocol(protocols.split(','));
+    let mut response = ws
+        .on_upgrade(move |socket| {
+            serve_client(
+                provider,
+                target,
+                options,
+                protocol,
+                websocket_client(socket),
+            )
+        })
+        .into_response();
+    if let Some(protocol) = protocol {
+        response
+            .headers_mut()
+            .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(protocol));
+    }
+    Ok(response)
+}
+
+/// Run a pod exec command or attach to a container over SPDY
 ///
-/// Implements the kubelet path /exec/{namespace}/{pod}/{container}
-async fn post_exec<T: Provider>(
-    _provider: Arc<T>,
-    _namespace: String,
-    _pod: String,
-    _container: String,
+/// Implements the kubelet paths /exec/{namespace}/{pod}/{container} and
+/// /attach/{namespace}/{pod}/{container} for clients, such as kubectl, that
+/// speak the Kubernetes channel protocol over SPDY/3.1.
+async fn upgrade_to_spdy<T: Provider>(
+    provider: Arc<T>,
+    target: Target,
+    query: String,
+    upgrade: Option<SpdyUpgrade>,
+    headers: HeaderMap,
 ) -> Result<Response<Body>, Infallible> {
-    Ok(return_with_code(
-        StatusCode::NOT_IMPLEMENTED,
-        "Exec not implemented.".to_string(),
-    ))
+    let options = StreamOptions::parse(&query);
+    debug!("Got SPDY request for {:?}. Options: {:?}.", target, options);
+    let upgrade = match upgrade.and_then(|upgrade| upgrade.0.lock().unwrap().take()) {
+        Some(upgrade) => upgrade,
+        None => {
+            return Ok(return_with_code(
+                StatusCode::BAD_REQUEST,
+                "Streaming requires upgrading to SPDY/3.1 or a WebSocket.".to_owned(),
+            ))
+        }
+    };
+    if let Err(message) = options.validate(target.action) {
+        return Ok(return_with_code(
+            StatusCode::BAD_REQUEST,
+            message.to_owned(),
+        ));
+    }
+
+    let requested: Vec<&str> = headers
+        .get_all(STREAM_PROTOCOL_HEADER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    let protocol = stream_protocol(requested.iter().copied());
+    // Clients that don't ask for a protocol get the original one
+    if protocol.is_none() && !requested.is_empty() {
+        return Ok(return_with_code(
+            StatusCode::FORBIDDEN,
+            format!(
+                "Unable to negotiate protocol: client supports {:?}, server accepts {:?}.",
+                requested, STREAM_PROTOCOLS
+            ),
+        ));
+    }
+
+    tokio::spawn(async move {
+        let streams = options.spdy_streams(protocol);
+        let client = match upgrade.await {
+            Ok(connection) => spdy::accept(connection, &streams).await,
+            Err(e) => Err(e.into()),
+        };
+        match client {
+            Ok(client) => serve_client(provider, target, options, protocol, client).await,
+            Err(e) => error!("Error connecting SPDY client: {:?}", e),
+        }
+    });
+
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    let headers = response.headers_mut();
+    headers.insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+    headers.insert(UPGRADE, HeaderValue::from_static(spdy::UPGRADE));
+    if let Some(protocol) = protocol {
+        headers.insert(STREAM_PROTOCOL_HEADER, HeaderValue::from_static(protocol));
+    }
+    Ok(response)
+}
+
+/// Whether a request asks to upgrade the connection to `protocol`.
+fn wants_upgrade(headers: &HeaderMap, protocol: &str) -> bool {
+    headers
+        .get_all(UPGRADE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(protocol))
+}
+
+/// Picks the first of the streaming protocols requested by a client that is supported.
+fn stream_protocol<'a>(requested: impl Iterator<Item = &'a str>) -> Option<&'static str> {
+    requested.map(str::trim).find_map(|requested| {
+        STREAM_PROTOCOLS
+            .iter()
+            .find(|supported| **supported == requested)
+            .copied()
+    })
+}
+
+/// A client connected over a WebSocket or SPDY, which either way sends and
+/// receives data on the channels of the Kubernetes streaming protocols.
+struct Client {
+    /// What the client writes to stdin, until it closes stdin or disconnects
+    stdin: mpsc::Receiver<Vec<u8>>,
+    /// Sends data to the client on a channel. The connection is closed once
+    /// this is dropped, and this is closed if the client disconnects.
+    output: mpsc::Sender<(u8, Vec<u8>)>,
+}
+
+/// Connects a client over a WebSocket, where each message starts with the
+/// channel it is sent on.
+fn websocket_client(socket: WebSocket) -> Client {
+    let (mut sink, mut messages) = socket.split();
+    // TODO: ~magic~ number
+    let (stdin, stdin_receiver) = mpsc::channel(8);
+    let (output, mut output_receiver) = mpsc::channel::<(u8, Vec<u8>)>(8);
+    let (disconnected, mut detected) = oneshot::channel::<()>();
+    tokio::spawn(async move {
+        let _disconnected = disconnected;
+        while let Some(message) = messages.next().await {
+            let message = match message {
+                Ok(message) if message.is_close() => break,
+                Ok(message) => message,
+                Err(e) => {
+                    error!("Error reading from WebSocket client: {}", e);
+                    break;
+                }
+            };
+            // Messages for other channels, such as terminal resizes, are ignored
+            if let Some((&STDIN_CHANNEL, input)) = message.as_bytes().split_first() {
+                if !input.is_empty() {
+                    stdin.send(input.to_vec()).await.ok();
+                }
+            }
+        }
+    });
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                message = output_receiver.recv() => match message {
+                    Some((channel, data)) => {
+                        let mut message = vec![channel];
+                        message.extend(data);
+                        if let Err(e) = sink.send(Message::binary(message)).await {
+                            error!("Error writing to WebSocket client: {}", e);
+                            break;
+                        }
+                    }
+                    None => {
+                        sink.close().await.ok();
+                        break;
+                    }
+                },
+                _ = &mut detected => break,
+            }
+        }
+    });
+    Client {
+        stdin: stdin_receiver,
+        output,
+    }
+}
+
+/// Runs a command in or attaches to a container for a connected client,
+/// streaming output to the client and input from it, and then reports the
+/// outcome on the error channel.
+async fn serve_client<T: Provider>(
+    provider: Arc<T>,
+    target: Target,
+    options: StreamOptions,
+    protocol: Option<&'static str>,
+    mut client: Client,
+) {
+    let StreamOptions {
+        command,
+        stdin,
+        stdout,
+        stderr,
+        tty,
+    } = options;
+    // TODO: ~magic~ number
+    let (output_sender, mut output) = mpsc::channel(8);
+    let output_sender = ExecSender::new(output_sender);
+    let (mut stdin, stdin_receiver) = match stdin {
+        true => {
+            let (sender, receiver) = mpsc::channel(8);
+            (Some(sender), Some(receiver))
+        }
+        false => (None, None),
+    };
+    let Target {
+        namespace,
+        pod,
+        container,
+        action,
+    } = target;
+    let run: BoxFuture<anyhow::Result<i32>> = match action {
+        Action::Exec => Box::pin(async move {
+            provider
+                .exec_in_container(namespace, pod, container, command, output_sender)
+                .await
+        }),
+        Action::Attach => Box::pin(async move {
+            provider
+                .attach(namespace, pod, container, stdin_receiver, output_sender)
+                .await
+                .map(|_| 0)
+        }),
+    };
+    tokio::pin!(run);
+
+    // Output is streamed until there is no more of it, which is once the
+    // command has completed or the attached process has exited
+    let mut result = None;
+    let mut client_stdin_open = true;
+    loop {
+        tokio::select! {
+            outcome = &mut run, if result.is_none() => result = Some(outcome),
+            data = output.recv() => {
+                let (stream, data) = match data {
+                    Some(data) => data,
+                    None => break,
+                };
+                let channel = match stream {
+                    Stream::Stdout if stdout => STDOUT_CHANNEL,
+                    // A terminal has no separate stderr
+                    Stream::Stderr if stderr && tty => STDOUT_CHANNEL,
+                    Stream::Stderr if stderr => STDERR_CHANNEL,
+                    _ => continue,
+                };
+                if client.output.send((channel, data)).await.is_err() {
+                    break;
+                }
+            }
+            input = client.stdin.recv(), if client_stdin_open => match (input, &stdin) {
+                (Some(input), Some(sender)) => {
+                    if sender.send(input).await.is_err() {
+                        stdin = None;
+                    }
+                }
+                (Some(_), None) => (),
+                (None, _) => {
+                    client_stdin_open = false;
+                    stdin = None;
+                }
+            },
+            _ = client.output.closed() => {
+                debug!("Client disconnected.");
+                return;
+            }
+        }
+    }
+    let result = match result {
+        Some(result) => result,
+        None => run.await,
+    };
+
+    let status = match result {
+        Ok(0) => serde_json::json!({ "metadata": {}, "status": "Success" }),
+        Ok(exit_code) => serde_json::json!({
+            "metadata": {},
+            "status": "Failure",
+            "message": format!("command terminated with non-zero exit code: {}", exit_code),
+            "reason": "NonZeroExitCode",
+            "details": {
+                "causes": [{ "reason": "ExitCode", "message": exit_code.to_string() }]
+            }
+        }),
+        Err(e) => {
+            error!("Error serving {:?} request: {:?}", action, e);
+            let message = if e.is::<NotImplementedError>() {
+                format!("{:?} not implemented in provider.", action)
+            } else {
+                format!("Server error: {}", e)
+            };
+            serde_json::json!({ "metadata": {}, "status": "Failure", "message": message })
+        }
+    };
+    let error = if protocol == Some(V4_STREAM_PROTOCOL) {
+        Some(status.to_string())
+    } else {
+        // Older protocols only report errors, as plain text
+        status["message"].as_str().map(str::to_owned)
+    };
+    if let Some(error) = error {
+        if client
+            .output
+            .send((ERROR_CHANNEL, error.into_bytes()))
+            .await
+            .is_err()
+        {
+            debug!("Client disconnected before the outcome was sent.");
+        }
+    }
 }
 
 fn return_with_code(code: StatusCode, body: String) -> Response<Body> {
@@ -109,3 +735,395 @@ fn return_with_code(code: StatusCode, body: String) -> Response<Body> {
     *response.status_mut() = code;
     response
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::plugin_watcher::PluginRegistry;
+    use crate::pod::Pod;
+    use crate::pod::Status;
+    use hyper::upgrade::Upgraded;
+    use krator::ObjectState;
+    use tokio::net::TcpStream;
+    use tokio::sync::RwLock;
+
+    struct MockProvider;
+
+    struct ProviderState;
+    struct PodState;
+
+    #[async_trait::async_trait]
+    impl ObjectState for PodState {
+        type Manifest = Pod;
+        type Status = Status;
+        type SharedState = ProviderState;
+        async fn async_drop(self, _provider_state: &mut ProviderState) {}
+    }
+
+    #[async_trait::async_trait]
+    impl Provider for MockProvider {
+        type ProviderState = ProviderState;
+        type InitialState = crate::pod::state::Stub;
+        type TerminatedState = crate::pod::state::Stub;
+        type PodState = PodState;
+
+        const ARCH: &'static str = "mock";
+
+        async fn initialize_pod_state(&self, _pod: &Pod) -> anyhow::Result<Self::PodState> {
+            Ok(PodState)
+        }
+
+        fn provider_state(&self) -> krator::SharedState<ProviderState> {
+            Arc::new(RwLock::new(ProviderState {}))
+        }
+
+        fn plugin_registry(&self) -> Option<Arc<PluginRegistry>> {
+            Some(Arc::new(PluginRegistry::default()))
+        }
+
+        async fn logs(
+            &self,
+            _namespace: String,
+            _pod: String,
+            _container: String,
+            _sender: crate::log::Sender,
+        ) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        /// Echoes the command's arguments to stdout and fails.
+        async fn exec_in_container(
+            &self,
+            _namespace: String,
+            _pod: String,
+            _container: String,
+            command: Vec<String>,
+            output: ExecSender,
+        ) -> anyhow::Result<i32> {
+            output
+                .send(Stream::Stdout, command[1..].join(" ").into_bytes())
+                .await?;
+            output
+                .send(Stream::Stderr, b"unable to echo".to_vec())
+                .await?;
+            Ok(3)
+        }
+
+        /// Echoes stdin to stdout until stdin is closed.
+        async fn attach(
+            &self,
+            _namespace: String,
+            _pod: String,
+            _container: String,
+            stdin: Option<mpsc::Receiver<Vec<u8>>>,
+            output: ExecSender,
+        ) -> anyhow::Result<()> {
+            let mut stdin = stdin.ok_or_else(|| anyhow::anyhow!("no stdin"))?;
+            tokio::spawn(async move {
+                output
+                    .send(Stream::Stdout, b"attached\n".to_vec())
+                    .await
+                    .unwrap();
+                while let Some(input) = stdin.recv().await {
+                    output.send(Stream::Stdout, input).await.unwrap();
+                }
+            });
+            Ok(())
+        }
+    }
+
+    /// Makes a request to a server for the mock provider over plain HTTP, as
+    /// an authenticated client, asking to upgrade to SPDY with the given
+    /// streaming protocol.
+    async fn request_spdy(
+        path: &str,
+        protocol: &str,
+    ) -> (
+        StatusCode,
+        HeaderMap,
+        Option<spdy::test::TestClient<Upgraded>>,
+    ) {
+        let routes = routes(Arc::new(MockProvider)).authenticated;
+        request_spdy_from(routes, path, protocol).await
+    }
+
+    async fn request_spdy_from(
+        routes: BoxedFilter<(Response<Body>,)>,
+        path: &str,
+        protocol: &str,
+    ) -> (
+        StatusCode,
+        HeaderMap,
+        Option<spdy::test::TestClient<Upgraded>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve_connection(stream, routes).await;
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let request = Request::post(path)
+            .header(CONNECTION, "Upgrade")
+            .header(UPGRADE, spdy::UPGRADE)
+            .header(STREAM_PROTOCOL_HEADER, protocol)
+            .body(Body::empty())
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let client = match status {
+            StatusCode::SWITCHING_PROTOCOLS => Some(spdy::test::TestClient::new(
+                hyper::upgrade::on(response).await.unwrap(),
+            )),
+            _ => None,
+        };
+        (status, headers, client)
+    }
+
+    #[test]
+    fn stream_options_are_parsed_from_the_query() {
+        let options = StreamOptions::parse(
+            "command=echo&command=hello%20world&stdin=1&stdout=true&tty=false",
+        );
+        assert_eq!(
+            options,
+            StreamOptions {
+                command: vec!["echo".to_owned(), "hello world".to_owned()],
+                stdin: true,
+                stdout: true,
+                stderr: false,
+                tty: false,
+            }
+        );
+        assert_eq!(
+            options.spdy_streams(Some(V4_STREAM_PROTOCOL)),
+            vec![
+                ("error", ERROR_CHANNEL),
+                ("stdin", STDIN_CHANNEL),
+                ("stdout", STDOUT_CHANNEL)
+            ]
+        );
+        assert!(StreamOptions::parse("command=ls")
+            .validate(Action::Exec)
+            .is_err());
+        assert!(StreamOptions::parse("stdout=true")
+            .validate(Action::Exec)
+            .is_err());
+        assert!(StreamOptions::parse("stdout=true")
+            .validate(Action::Attach)
+            .is_ok());
+    }
+
+    #[test]
+    fn terminals_have_no_stderr_stream() {
+        let options = StreamOptions::parse("stdout=true&stderr=true&tty=true");
+        assert_eq!(
+            options.spdy_streams(Some(V4_STREAM_PROTOCOL)),
+            vec![
+                ("error", ERROR_CHANNEL),
+                ("stdout", STDOUT_CHANNEL),
+                ("resize", RESIZE_CHANNEL)
+            ]
+        );
+        assert_eq!(
+            options.spdy_streams(Some("channel.k8s.io")),
+            vec![("error", ERROR_CHANNEL), ("stdout", STDOUT_CHANNEL)]
+        );
+    }
+
+    #[tokio::test]
+    async fn exec_over_spdy_streams_output_and_exit_code() {
+        let (status, headers, client) = request_spdy(
+            "/exec/ns/pod/container?command=echo&command=hello&stdout=true&stderr=true",
+            V4_STREAM_PROTOCOL,
+        )
+        .await;
+        assert_eq!(status, StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(headers[STREAM_PROTOCOL_HEADER], V4_STREAM_PROTOCOL);
+        let mut client = client.unwrap();
+        let error = client.open("error").await;
+        let stdout = client.open("stdout").await;
+        let stderr = client.open("stderr").await;
+
+        let streams = client.read_to_end().await;
+        assert_eq!(streams[&stdout], b"hello");
+        assert_eq!(streams[&stderr], b"unable to echo");
+        let status: serde_json::Value = serde_json::from_slice(&streams[&error]).unwrap();
+        assert_eq!(status["status"], "Failure");
+        assert_eq!(status["reason"], "NonZeroExitCode");
+        assert_eq!(status["details"]["causes"][0]["reason"], "ExitCode");
+        assert_eq!(status["details"]["causes"][0]["message"], "3");
+    }
+
+    #[tokio::test]
+    async fn exec_over_older_protocols_reports_errors_as_text() {
+        let (status, _, client) = request_spdy(
+            "/exec/ns/pod/container?command=echo&command=hello&stdout=true",
+            "channel.k8s.io",
+        )
+        .await;
+        assert_eq!(status, StatusCode::SWITCHING_PROTOCOLS);
+        let mut client = client.unwrap();
+        let error = client.open("error").await;
+        let stdout = client.open("stdout").await;
+
+        let streams = client.read_to_end().await;
+        assert_eq!(streams[&stdout], b"hello");
+        assert_eq!(
+            streams[&error],
+            b"command terminated with non-zero exit code: 3"
+        );
+    }
+
+    #[tokio::test]
+    async fn attach_over_spdy_streams_input_and_output() {
+        let (status, _, client) = request_spdy(
+            "/attach/ns/pod/container?stdin=true&stdout=true",
+            V4_STREAM_PROTOCOL,
+        )
+        .await;
+        assert_eq!(status, StatusCode::SWITCHING_PROTOCOLS);
+        let mut client = client.unwrap();
+        let error = client.open("error").await;
+        let stdin = client.open("stdin").await;
+        let stdout = client.open("stdout").await;
+        client.write(stdin, b"hello").await;
+        client.close(stdin).await;
+
+        let streams = client.read_to_end().await;
+        assert_eq!(streams[&stdout], b"attached\nhello");
+        let status: serde_json::Value = serde_json::from_slice(&streams[&error]).unwrap();
+        assert_eq!(status["status"], "Success");
+    }
+
+    #[tokio::test]
+    async fn unsupported_stream_protocols_are_forbidden() {
+        let (status, _, _) =
+            request_spdy("/attach/ns/pod/container?stdout=true", "v5.channel.k8s.io").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn streaming_requests_need_a_stream() {
+        let (status, _, _) = request_spdy("/attach/ns/pod/container", V4_STREAM_PROTOCOL).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn attach_over_websocket_streams_input_and_output() {
+        let mut client = warp::test::ws()
+            .path("/attach/ns/pod/container?stdin=true&stdout=true")
+            .header(SEC_WEBSOCKET_PROTOCOL, V4_STREAM_PROTOCOL)
+            .handshake(routes(Arc::new(MockProvider)).authenticated)
+            .await
+            .unwrap();
+        client.send(Message::binary(b"\x00hello".to_vec())).await;
+
+        let message = client.recv().await.unwrap();
+        assert_eq!(message.as_bytes(), b"\x01attached\n");
+        let message = client.recv().await.unwrap();
+        assert_eq!(message.as_bytes(), b"\x01hello");
+    }
+
+    #[tokio::test]
+    async fn anonymous_clients_cannot_exec_or_attach() {
+        let anonymous = routes(Arc::new(MockProvider)).anonymous;
+        for path in &[
+            "/exec/ns/pod/container?command=echo&stdout=true",
+            "/attach/ns/pod/container?stdin=true&stdout=true",
+        ] {
+            let (status, _, _) =
+                request_spdy_from(anonymous.clone(), path, V4_STREAM_PROTOCOL).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+
+        let response = warp::test::request()
+            .path("/healthz")
+            .reply(&anonymous)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    /// A certificate for `localhost`, signed by `ca` if it is given.
+    fn certificate(ca: Option<&rcgen::Certificate>) -> (rcgen::Certificate, Vec<u8>) {
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_owned()]);
+        if ca.is_none() {
+            params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        }
+        let certificate = rcgen::Certificate::from_params(params).unwrap();
+        let der = match ca {
+            Some(ca) => certificate.serialize_der_with_signer(ca).unwrap(),
+            None => certificate.serialize_der().unwrap(),
+        };
+        (certificate, der)
+    }
+
+    #[tokio::test]
+    async fn clients_authenticate_with_certificates_from_the_client_cas() {
+        use tokio_rustls::rustls::{Certificate, ClientConfig, PrivateKey};
+        use tokio_rustls::webpki::DNSNameRef;
+        use tokio_rustls::TlsConnector;
+
+        let (ca, ca_der) = certificate(None);
+        let (server, _) = certificate(Some(&ca));
+        let (client, client_der) = certificate(Some(&ca));
+        let (untrusted_ca, _) = certificate(None);
+        let (untrusted, untrusted_der) = certificate(Some(&untrusted_ca));
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = ServerConfig {
+            addr: std::net::Ipv4Addr::LOCALHOST.into(),
+            port: 0,
+            cert_file: dir.path().join("server.crt"),
+            private_key_file: dir.path().join("server.key"),
+            client_ca_file: Some(dir.path().join("ca.crt")),
+        };
+        let server_pem = server.serialize_pem_with_signer(&ca).unwrap();
+        std::fs::write(&config.cert_file, server_pem).unwrap();
+        std::fs::write(&config.private_key_file, server.serialize_private_key_pem()).unwrap();
+        std::fs::write(
+            config.client_ca_file.as_ref().unwrap(),
+            ca.serialize_pem().unwrap(),
+        )
+        .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(tls_config(&config).unwrap()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let clients = vec![
+            Some((client_der, client.serialize_private_key_der())),
+            None,
+            Some((untrusted_der, untrusted.serialize_private_key_der())),
+        ];
+        let mut outcomes = Vec::new();
+        for identity in clients {
+            let mut client_config = ClientConfig::new();
+            client_config
+                .root_store
+                .add(&Certificate(ca_der.clone()))
+                .unwrap();
+            if let Some((certificate, key)) = identity {
+                client_config
+                    .set_single_client_cert(vec![Certificate(certificate)], PrivateKey(key))
+                    .unwrap();
+            }
+            let connector = TlsConnector::from(Arc::new(client_config));
+            let connecting = async {
+                let stream = TcpStream::connect(addr).await.unwrap();
+                let name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+                connector.connect(name, stream).await
+            };
+            let accepting = async {
+                let (stream, _) = listener.accept().await.unwrap();
+                acceptor.accept(stream).await
+            };
+            let (_client, server) = tokio::join!(connecting, accepting);
+            outcomes.push(server.map(|stream| authenticated(&stream)).ok());
+        }
+        assert_eq!(outcomes, vec![Some(true), Some(false), None]);
+    }
+}
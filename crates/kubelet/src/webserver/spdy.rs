@@ -0,0 +1,737 @@
+//! A server for the SPDY/3.1 connections that clients such as `kubectl exec`
+//! and `kubectl attach` stream over.
+//!
+//! The client opens a stream for each of the channels of the Kubernetes
+//! streaming protocols, naming its channel in a `streamType` header, and the
+//! server replies to each. Only what those protocols need is implemented: the
+//! server never opens streams of its own, and output isn't held back for the
+//! client's flow control windows, which Kubernetes clients don't enforce.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::time::Duration;
+
+use anyhow::bail;
+use miniz_oxide::inflate::core::{
+    decompress, inflate_flags, DecompressorOxide, TINFL_LZ_DICT_SIZE,
+};
+use miniz_oxide::inflate::TINFLStatus;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error};
+
+use super::{Client, STDIN_CHANNEL};
+
+/// The value of the `Upgrade` header for SPDY connections.
+pub(crate) const UPGRADE: &str = "SPDY/3.1";
+/// How long a client has to open its streams once the connection is upgraded.
+const STREAM_CREATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+const VERSION: u16 = 3;
+const SYN_STREAM: u16 = 1;
+const SYN_REPLY: u16 = 2;
+const RST_STREAM: u16 = 3;
+const PING: u16 = 6;
+const GOAWAY: u16 = 7;
+const WINDOW_UPDATE: u16 = 9;
+/// The flag that marks the last frame a peer sends on a stream.
+const FLAG_FIN: u8 = 0x01;
+/// The RST_STREAM status for streams the server won't accept.
+const REFUSED_STREAM: u32 = 3;
+/// The largest payload of a frame sent or accepted. Frames could be up to
+/// 16MiB, but the client's writes of stdin are much smaller, and accepting
+/// them would let any client make the server allocate that much per frame.
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+/// The largest header block accepted, once decompressed. The only header
+/// that matters is the stream type.
+const MAX_HEADER_BLOCK_SIZE: usize = 16 * 1024;
+
+/// The dictionary that SPDY/3 header blocks are compressed with.
+const HEADER_DICTIONARY: &[u8] = b"\
+    \x00\x00\x00\x07options\x00\x00\x00\x04head\x00\x00\x00\x04post\x00\x00\x00\x03put\
+    \x00\x00\x00\x06delete\x00\x00\x00\x05trace\x00\x00\x00\x06accept\
+    \x00\x00\x00\x0eaccept-charset\x00\x00\x00\x0faccept-encoding\
+    \x00\x00\x00\x0faccept-language\x00\x00\x00\x0daccept-ranges\x00\x00\x00\x03age\
+    \x00\x00\x00\x05allow\x00\x00\x00\x0dauthorization\x00\x00\x00\x0dcache-control\
+    \x00\x00\x00\x0aconnection\x00\x00\x00\x0ccontent-base\x00\x00\x00\x10content-encoding\
+    \x00\x00\x00\x10content-language\x00\x00\x00\x0econtent-length\
+    \x00\x00\x00\x10content-location\x00\x00\x00\x0bcontent-md5\x00\x00\x00\x0dcontent-range\
+    \x00\x00\x00\x0ccontent-type\x00\x00\x00\x04date\x00\x00\x00\x04etag\x00\x00\x00\x06expect\
+    \x00\x00\x00\x07expires\x00\x00\x00\x04from\x00\x00\x00\x04host\x00\x00\x00\x08if-match\
+    \x00\x00\x00\x11if-modified-since\x00\x00\x00\x0dif-none-match\x00\x00\x00\x08if-range\
+    \x00\x00\x00\x13if-unmodified-since\x00\x00\x00\x0dlast-modified\x00\x00\x00\x08location\
+    \x00\x00\x00\x0cmax-forwards\x00\x00\x00\x06pragma\x00\x00\x00\x12proxy-authenticate\
+    \x00\x00\x00\x13proxy-authorization\x00\x00\x00\x05range\x00\x00\x00\x07referer\
+    \x00\x00\x00\x0bretry-after\x00\x00\x00\x06server\x00\x00\x00\x02te\x00\x00\x00\x07trailer\
+    \x00\x00\x00\x11transfer-encoding\x00\x00\x00\x07upgrade\x00\x00\x00\x0auser-agent\
+    \x00\x00\x00\x04vary\x00\x00\x00\x03via\x00\x00\x00\x07warning\x00\x00\x00\x10www-authenticate\
+    \x00\x00\x00\x06method\x00\x00\x00\x03get\x00\x00\x00\x06status\x00\x00\x00\x06200 OK\
+    \x00\x00\x00\x07version\x00\x00\x00\x08HTTP/1.1\x00\x00\x00\x03url\x00\x00\x00\x06public\
+    \x00\x00\x00\x0aset-cookie\x00\x00\x00\x0akeep-alive\x00\x00\x00\x06origin\
+    100101201202205206300302303304305306307402405406407408409410411412413414415416417502504505\
+    203 Non-Authoritative Information204 No Content301 Moved Permanently400 Bad Request\
+    401 Unauthorized403 Forbidden404 Not Found500 Internal Server Error501 Not Implemented\
+    503 Service UnavailableJan Feb Mar Apr May Jun Jul Aug Sept Oct Nov Dec 00:00:00 \
+    Mon, Tue, Wed, Thu, Fri, Sat, Sun, GMTchunked,text/html,image/png,image/jpg,image/gif,\
+    application/xml,application/xhtml+xml,text/plain,text/javascript,publicprivatemax-age=\
+    gzip,deflate,sdchcharset=utf-8charset=iso-8859-1,utf-,*,enq=0.";
+
+/// A frame read from a SPDY connection.
+#[derive(Debug, PartialEq)]
+enum Frame {
+    Control {
+        kind: u16,
+        flags: u8,
+        data: Vec<u8>,
+    },
+    Data {
+        stream_id: u32,
+        flags: u8,
+        data: Vec<u8>,
+    },
+}
+
+/// Reads the next frame from a connection, or `None` if it has been closed.
+/// Frames larger than [`MAX_FRAME_SIZE`] are refused before they are read.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Option<Frame>> {
+    let mut head = [0; 8];
+    match reader.read_exact(&mut head).await {
+        Ok(_) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let flags = head[4];
+    let length = u32::from_be_bytes([0, head[5], head[6], head[7]]) as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is too large", length),
+        ));
+    }
+    let mut data = vec![0; length];
+    reader.read_exact(&mut data).await?;
+    Ok(Some(if head[0] & 0x80 != 0 {
+        Frame::Control {
+            kind: u16::from_be_bytes([head[2], head[3]]),
+            flags,
+            data,
+        }
+    } else {
+        Frame::Data {
+            stream_id: u32::from_be_bytes([head[0], head[1], head[2], head[3]]),
+            flags,
+            data,
+        }
+    }))
+}
+
+fn control_frame(kind: u16, flags: u8, data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(8 + data.len());
+    frame.extend_from_slice(&(0x8000 | VERSION).to_be_bytes());
+    frame.extend_from_slice(&kind.to_be_bytes());
+    frame.push(flags);
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes()[1..]);
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// Frames `data` as sent on a stream, splitting it up if it is too long for
+/// one frame.
+fn data_frames(stream_id: u32, flags: u8, data: &[u8]) -> Vec<u8> {
+    let mut frames = Vec::with_capacity(8 + data.len());
+    let mut rest = data;
+    loop {
+        let (chunk, next) = rest.split_at(rest.len().min(MAX_FRAME_SIZE));
+        frames.extend_from_slice(&stream_id.to_be_bytes());
+        frames.push(if next.is_empty() { flags } else { 0 });
+        frames.extend_from_slice(&(chunk.len() as u32).to_be_bytes()[1..]);
+        frames.extend_from_slice(chunk);
+        if next.is_empty() {
+            return frames;
+        }
+        rest = next;
+    }
+}
+
+/// Builds one of the control frames that hold just a stream ID and a value,
+/// such as a status code.
+fn stream_control_frame(kind: u16, stream_id: u32, value: u32) -> Vec<u8> {
+    let mut data = stream_id.to_be_bytes().to_vec();
+    data.extend_from_slice(&value.to_be_bytes());
+    control_frame(kind, 0, &data)
+}
+
+/// The stream ID that a control frame's data starts with.
+fn stream_id(data: &[u8]) -> Option<u32> {
+    let id: [u8; 4] = data.get(..4)?.try_into().ok()?;
+    Some(u32::from_be_bytes(id) & 0x7fff_ffff)
+}
+
+/// Splits a decompressed header block into its names and values.
+fn parse_headers(block: &[u8]) -> anyhow::Result<Vec<(String, String)>> {
+    fn take<'a>(block: &mut &'a [u8], n: usize) -> anyhow::Result<&'a [u8]> {
+        if block.len() < n {
+            bail!("header block is truncated");
+        }
+        let (taken, rest) = block.split_at(n);
+        *block = rest;
+        Ok(taken)
+    }
+    fn take_string(block: &mut &[u8]) -> anyhow::Result<String> {
+        let length = u32::from_be_bytes(take(block, 4)?.try_into()?) as usize;
+        Ok(String::from_utf8_lossy(take(block, length)?).into_owned())
+    }
+
+    let mut block = block;
+    let count = u32::from_be_bytes(take(&mut block, 4)?.try_into()?);
+    (0..count)
+        .map(|_| Ok((take_string(&mut block)?, take_string(&mut block)?)))
+        .collect()
+}
+
+/// Decompresses the header blocks a peer sends, which are all part of one
+/// zlib stream that starts out with [`HEADER_DICTIONARY`] as its history.
+struct HeaderDecompressor {
+    state: DecompressorOxide,
+    /// the last 32KiB of output, which later output can refer back to
+    window: Vec<u8>,
+    /// where the next output is written in `window`
+    position: usize,
+    /// the zlib header, until all of it has been read
+    header: Option<Vec<u8>>,
+}
+
+impl HeaderDecompressor {
+    fn new() -> Self {
+        // Starting out with the dictionary in the window lets the stream
+        // refer to it as though it had been output already
+        let mut window = vec![0; TINFL_LZ_DICT_SIZE];
+        window[..HEADER_DICTIONARY.len()].copy_from_slice(HEADER_DICTIONARY);
+        HeaderDecompressor {
+            state: DecompressorOxide::new(),
+            window,
+            position: HEADER_DICTIONARY.len(),
+            header: Some(Vec::new()),
+        }
+    }
+
+    fn decompress(&mut self, mut input: &[u8]) -> anyhow::Result<Vec<u8>> {
+        // The zlib header is skipped here, as the decompressor rejects headers
+        // that name a dictionary. When one does, its ID follows the flags.
+        while let (Some(header), Some((byte, rest))) = (self.header.as_mut(), input.split_first()) {
+            header.push(*byte);
+            input = rest;
+            let length = match header.get(1) {
+                Some(flags) if flags & 0x20 != 0 => 6,
+                _ => 2,
+            };
+            if header.len() == length {
+                self.header = None;
+            }
+        }
+        if self.header.is_some() {
+            return Ok(Vec::new());
+        }
+
+        let mut output = Vec::new();
+        loop {
+            let (status, read, written) = decompress(
+                &mut self.state,
+                input,
+                &mut self.window,
+                self.position,
+                inflate_flags::TINFL_FLAG_HAS_MORE_INPUT,
+            );
+            output.extend_from_slice(&self.window[self.position..self.position + written]);
+            if output.len() > MAX_HEADER_BLOCK_SIZE {
+                bail!(
+                    "header block is larger than {} bytes",
+                    MAX_HEADER_BLOCK_SIZE
+                );
+            }
+            self.position = (self.position + written) % self.window.len();
+            input = &input[read..];
+            match status {
+                TINFLStatus::HasMoreOutput => continue,
+                TINFLStatus::NeedsMoreInput | TINFLStatus::Done => return Ok(output),
+                status => bail!("unable to decompress header block: {:?}", status),
+            }
+        }
+    }
+}
+
+/// Compresses the header blocks sent to a peer as stored zlib blocks, which
+/// any peer can decompress whatever dictionary it uses.
+#[derive(Default)]
+struct HeaderCompressor {
+    started: bool,
+}
+
+impl HeaderCompressor {
+    fn compress(&mut self, block: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(block.len() + 7);
+        if !self.started {
+            output.extend_from_slice(&[0x78, 0x01]);
+            self.started = true;
+        }
+        let mut rest = block;
+        loop {
+            let (chunk, next) = rest.split_at(rest.len().min(u16::MAX as usize));
+            let length = chunk.len() as u16;
+            // A stored block that isn't the last, whose header is padded to a byte
+            output.push(0);
+            output.extend_from_slice(&length.to_le_bytes());
+            output.extend_from_slice(&(!length).to_le_bytes());
+            output.extend_from_slice(chunk);
+            if next.is_empty() {
+                return output;
+            }
+            rest = next;
+        }
+    }
+}
+
+fn header_block(headers: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = (headers.len() as u32).to_be_bytes().to_vec();
+    for value in headers.iter().flat_map(|(name, value)| vec![name, value]) {
+        block.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        block.extend_from_slice(value.as_bytes());
+    }
+    block
+}
+
+/// Waits for a client to open a stream for each of the `channels` it asked
+/// for, each named by its stream type, and connects it.
+pub(crate) async fn accept<IO>(io: IO, channels: &[(&str, u8)]) -> anyhow::Result<Client>
+where
+    IO: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(io);
+    let mut streams = HashMap::new();
+    let opening = async {
+        let mut decompressor = HeaderDecompressor::new();
+        let mut compressor = HeaderCompressor::default();
+        while streams.len() < channels.len() {
+            let (kind, data) = match read_frame(&mut reader).await? {
+                Some(Frame::Control { kind, data, .. }) => (kind, data),
+                Some(Frame::Data { .. }) => continue,
+                None => bail!("client disconnected before opening its streams"),
+            };
+            match kind {
+                SYN_STREAM if data.len() >= 10 => {
+                    let stream_id = stream_id(&data).unwrap_or_default();
+                    let headers = parse_headers(&decompressor.decompress(&data[10..])?)?;
+                    let stream_type = headers
+                        .iter()
+                        .find(|(name, _)| name.eq_ignore_ascii_case("streamType"))
+                        .map(|(_, value)| value.as_str());
+                    let channel = channels
+                        .iter()
+                        .find(|(name, _)| Some(*name) == stream_type)
+                        .map(|(_, channel)| *channel)
+                        .filter(|channel| !streams.values().any(|c| c == channel));
+                    let reply = match channel {
+                        Some(channel) => {
+                            debug!("Client opened {:?} stream {}", stream_type, stream_id);
+                            streams.insert(stream_id, channel);
+                            let mut reply = stream_id.to_be_bytes().to_vec();
+                            reply.extend(compressor.compress(&header_block(&[])));
+                            control_frame(SYN_REPLY, 0, &reply)
+                        }
+                        None => stream_control_frame(RST_STREAM, stream_id, REFUSED_STREAM),
+                    };
+                    writer.write_all(&reply).await?;
+                }
+                PING => writer.write_all(&control_frame(PING, 0, &data)).await?,
+                GOAWAY => bail!("client closed the connection before opening its streams"),
+                _ => (),
+            }
+        }
+        Ok(())
+    };
+    tokio::time::timeout(STREAM_CREATION_TIMEOUT, opening)
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out waiting for client to open its streams"))??;
+
+    // TODO: ~magic~ number
+    let (stdin, stdin_receiver) = mpsc::channel(8);
+    let (output, output_receiver) = mpsc::channel(8);
+    let (control, control_receiver) = mpsc::channel(8);
+    let (disconnected, detected) = oneshot::channel();
+    let stdin_stream = streams
+        .iter()
+        .find(|(_, channel)| **channel == STDIN_CHANNEL)
+        .map(|(stream_id, _)| *stream_id);
+    tokio::spawn(read_input(
+        reader,
+        stdin_stream,
+        stdin,
+        control,
+        disconnected,
+    ));
+    tokio::spawn(write_output(
+        writer,
+        streams,
+        output_receiver,
+        control_receiver,
+        detected,
+    ));
+    Ok(Client {
+        stdin: stdin_receiver,
+        output,
+    })
+}
+
+/// Reads the client's frames until it disconnects, forwarding what it writes
+/// to stdin and answering its pings.
+async fn read_input<R: AsyncRead + Unpin>(
+    mut reader: R,
+    stdin_stream: Option<u32>,
+    stdin: mpsc::Sender<Vec<u8>>,
+    control: mpsc::Sender<Vec<u8>>,
+    _disconnected: oneshot::Sender<()>,
+) {
+    let mut stdin = Some(stdin);
+    loop {
+        match read_frame(&mut reader).await {
+            Ok(Some(Frame::Data {
+                stream_id,
+                flags,
+                data,
+            })) if Some(stream_id) == stdin_stream => {
+                if !data.is_empty() {
+                    // Input is handed on as soon as it is read, so the client
+                    // can send more straight away
+                    let delta = data.len() as u32;
+                    for stream_id in &[stream_id, 0] {
+                        let update = stream_control_frame(WINDOW_UPDATE, *stream_id, delta);
+                        control.send(update).await.ok();
+                    }
+                    if let Some(sender) = &stdin {
+                        if sender.send(data).await.is_err() {
+                            stdin = None;
+                        }
+                    }
+                }
+                if flags & FLAG_FIN != 0 {
+                    stdin = None;
+                }
+            }
+            Ok(Some(Frame::Data { .. })) => (),
+            Ok(Some(Frame::Control { kind, data, .. })) => match kind {
+                SYN_STREAM => {
+                    if let Some(stream_id) = stream_id(&data) {
+                        let refusal = stream_control_frame(RST_STREAM, stream_id, REFUSED_STREAM);
+                        control.send(refusal).await.ok();
+                    }
+                }
+                RST_STREAM if stream_id(&data) == stdin_stream => {
+                    stdin = None;
+                }
+                PING => {
+                    control.send(control_frame(PING, 0, &data)).await.ok();
+                }
+                GOAWAY => break,
+                _ => (),
+            },
+            Ok(None) => break,
+            Err(e) => {
+                debug!("Error reading from SPDY client: {}", e);
+                break;
+            }
+        }
+    }
+    debug!("SPDY client disconnected");
+}
+
+/// Writes the output sent to the client on the stream of its channel until
+/// all of it has been sent, and then closes the connection. Stops early if
+/// the client disconnects.
+async fn write_output<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    streams: HashMap<u32, u8>,
+    mut output: mpsc::Receiver<(u8, Vec<u8>)>,
+    mut control: mpsc::Receiver<Vec<u8>>,
+    mut disconnected: oneshot::Receiver<()>,
+) {
+    let stream_ids: HashMap<u8, u32> = streams.iter().map(|(id, ch)| (*ch, *id)).collect();
+    let result: std::io::Result<()> = async {
+        loop {
+            tokio::select! {
+                message = output.recv() => match message {
+                    Some((channel, data)) => {
+                        if let Some(stream_id) = stream_ids.get(&channel) {
+                            writer.write_all(&data_frames(*stream_id, 0, &data)).await?;
+                        }
+                    }
+                    None => break,
+                },
+                Some(frame) = control.recv() => writer.write_all(&frame).await?,
+                _ = &mut disconnected => return Ok(()),
+            }
+        }
+        let mut stream_ids: Vec<&u32> = streams.keys().collect();
+        stream_ids.sort_unstable();
+        for stream_id in &stream_ids {
+            writer
+                .write_all(&data_frames(**stream_id, FLAG_FIN, &[]))
+                .await?;
+        }
+        let last_stream_id = stream_ids.last().map(|id| **id).unwrap_or_default();
+        writer
+            .write_all(&stream_control_frame(GOAWAY, last_stream_id, 0))
+            .await?;
+        writer.shutdown().await
+    }
+    .await;
+    if let Err(e) = result {
+        error!("Error writing to SPDY client: {}", e);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// The client's end of a SPDY connection.
+    pub(crate) struct TestClient<IO> {
+        io: IO,
+        compressor: HeaderCompressor,
+        next_stream_id: u32,
+    }
+
+    impl<IO: AsyncRead + AsyncWrite + Unpin> TestClient<IO> {
+        pub(crate) fn new(io: IO) -> Self {
+            TestClient {
+                io,
+                compressor: HeaderCompressor::default(),
+                next_stream_id: 1,
+            }
+        }
+
+        /// Opens a stream of the given type and waits for the server's reply.
+        pub(crate) async fn open(&mut self, stream_type: &str) -> u32 {
+            let stream_id = self.next_stream_id;
+            self.next_stream_id += 2;
+            let mut data = stream_id.to_be_bytes().to_vec();
+            data.extend_from_slice(&[0; 6]);
+            data.extend(
+                self.compressor
+                    .compress(&header_block(&[("streamtype", stream_type)])),
+            );
+            self.io
+                .write_all(&control_frame(SYN_STREAM, 0, &data))
+                .await
+                .unwrap();
+            match read_frame(&mut self.io).await.unwrap() {
+                Some(Frame::Control {
+                    kind: SYN_REPLY,
+                    data,
+                    ..
+                }) => assert_eq!(data[..4], stream_id.to_be_bytes()),
+                frame => panic!("expected a reply to stream {}, got {:?}", stream_id, frame),
+            }
+            stream_id
+        }
+
+        pub(crate) async fn write(&mut self, stream_id: u32, data: &[u8]) {
+            self.io
+                .write_all(&data_frames(stream_id, 0, data))
+                .await
+                .unwrap();
+        }
+
+        /// Tells the server that the client won't write any more to a stream.
+        pub(crate) async fn close(&mut self, stream_id: u32) {
+            self.io
+                .write_all(&data_frames(stream_id, FLAG_FIN, &[]))
+                .await
+                .unwrap();
+        }
+
+        /// Reads everything the server sends until it closes the connection,
+        /// by stream.
+        pub(crate) async fn read_to_end(&mut self) -> HashMap<u32, Vec<u8>> {
+            let mut streams: HashMap<u32, Vec<u8>> = HashMap::new();
+            while let Some(frame) = read_frame(&mut self.io).await.unwrap() {
+                if let Frame::Data {
+                    stream_id, data, ..
+                } = frame
+                {
+                    streams.entry(stream_id).or_default().extend(data);
+                }
+            }
+            streams
+        }
+    }
+
+    #[test]
+    fn dictionary_is_the_spdy3_dictionary() {
+        assert_eq!(HEADER_DICTIONARY.len(), 1423);
+        // A zlib header names its dictionary by its Adler-32 checksum
+        let (mut a, mut b) = (1u32, 0u32);
+        for byte in HEADER_DICTIONARY {
+            a = (a + *byte as u32) % 65521;
+            b = (b + a) % 65521;
+        }
+        assert_eq!((b << 16) | a, 0xe3c6_a7c2);
+    }
+
+    #[test]
+    fn header_blocks_are_decompressed_with_the_dictionary() {
+        // Two blocks compressed by zlib with the dictionary and a sync flush
+        // after each, as SPDY clients do
+        let first = [
+            0x78, 0xf9, 0xe3, 0xc6, 0xa7, 0xc2, 0x02, 0xa6, 0x23, 0x46, 0x70, 0x3a, 0x2b, 0x29,
+            0x4a, 0x4d, 0xcc, 0x85, 0x16, 0x25, 0xac, 0xa9, 0xa0, 0x80, 0x06, 0x00, 0x00, 0x00,
+            0xff, 0xff,
+        ];
+        let second = [
+            0x02, 0x65, 0x1c, 0x0c, 0x29, 0x60, 0x62, 0x4e, 0xc9, 0x07, 0x97, 0xd1, 0xe8, 0xe5,
+            0x0f, 0x17, 0xc2, 0xe1, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff,
+        ];
+        let mut decompressor = HeaderDecompressor::new();
+        let headers = parse_headers(&decompressor.decompress(&first).unwrap()).unwrap();
+        assert_eq!(headers, vec![("streamtype".into(), "error".into())]);
+        let headers = parse_headers(&decompressor.decompress(&second).unwrap()).unwrap();
+        assert_eq!(
+            headers,
+            vec![
+                ("streamtype".into(), "stdout".into()),
+                ("content-type".into(), "text/plain".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn compressed_header_blocks_can_be_decompressed() {
+        let mut compressor = HeaderCompressor::default();
+        let mut decompressor = HeaderDecompressor::new();
+        for headers in &[
+            vec![("streamtype", "stdin")],
+            vec![],
+            vec![("streamtype", "x".repeat(10000).as_str())],
+        ] {
+            let block = header_block(headers);
+            let compressed = compressor.compress(&block);
+            assert_eq!(decompressor.decompress(&compressed).unwrap(), block);
+        }
+    }
+
+    #[test]
+    fn oversized_header_blocks_are_refused() {
+        // Split over several stored blocks, as each holds at most 64KiB
+        let block = header_block(&[("streamtype", "x".repeat(70000).as_str())]);
+        let compressed = HeaderCompressor::default().compress(&block);
+        let error = HeaderDecompressor::new()
+            .decompress(&compressed)
+            .unwrap_err();
+        assert!(error.to_string().contains("larger than"), "{}", error);
+    }
+
+    #[test]
+    fn malformed_header_blocks_are_refused() {
+        // Claims a header that the block doesn't hold
+        let mut block = 1u32.to_be_bytes().to_vec();
+        block.extend_from_slice(&100u32.to_be_bytes());
+        block.extend_from_slice(b"streamtype");
+        assert!(parse_headers(&block).is_err());
+        assert!(parse_headers(&[0, 0]).is_err());
+
+        // Not a deflate stream, after the zlib header
+        let mut decompressor = HeaderDecompressor::new();
+        assert!(decompressor
+            .decompress(&[0x78, 0x01, 0xff, 0xff, 0xff])
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_are_refused_before_they_are_read() {
+        let mut head = data_frames(1, 0, &[]);
+        head[5..8].copy_from_slice(&[0xff, 0xff, 0xff]);
+        let error = read_frame(&mut head.as_slice()).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn truncated_frames_are_errors() {
+        let frame = data_frames(1, 0, b"hello");
+        let error = read_frame(&mut &frame[..10]).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn long_output_is_split_into_frames_clients_accept() {
+        let data = vec![1; MAX_FRAME_SIZE * 2 + 1];
+        let frames = data_frames(1, FLAG_FIN, &data);
+        let mut reader = frames.as_slice();
+        let mut read = Vec::new();
+        while let Some(Frame::Data { data, flags, .. }) = read_frame(&mut reader).await.unwrap() {
+            read.extend(data);
+            assert_eq!(flags & FLAG_FIN != 0, read.len() == MAX_FRAME_SIZE * 2 + 1);
+        }
+        assert_eq!(read, data);
+    }
+
+    #[tokio::test]
+    async fn frames_are_read_as_written() {
+        let mut frames = control_frame(PING, 0, &[0, 0, 0, 1]);
+        frames.extend(data_frames(3, FLAG_FIN, b"hello"));
+        let mut reader = frames.as_slice();
+        assert_eq!(
+            read_frame(&mut reader).await.unwrap(),
+            Some(Frame::Control {
+                kind: PING,
+                flags: 0,
+                data: vec![0, 0, 0, 1]
+            })
+        );
+        assert_eq!(
+            read_frame(&mut reader).await.unwrap(),
+            Some(Frame::Data {
+                stream_id: 3,
+                flags: FLAG_FIN,
+                data: b"hello".to_vec()
+            })
+        );
+        assert_eq!(read_frame(&mut reader).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn clients_are_connected_once_their_streams_are_open() {
+        let (server, client) = tokio::io::duplex(1024);
+        let accepting = tokio::spawn(async move {
+            accept(server, &[("error", 3), ("stdin", 0), ("stdout", 1)]).await
+        });
+        let mut client = TestClient::new(client);
+        let error = client.open("error").await;
+        let stdin = client.open("stdin").await;
+        let stdout = client.open("stdout").await;
+        let mut connected = accepting.await.unwrap().unwrap();
+
+        client.write(stdin, b"hello").await;
+        assert_eq!(connected.stdin.recv().await.unwrap(), b"hello");
+        connected.output.send((1, b"world".to_vec())).await.unwrap();
+        connected.output.send((3, b"done".to_vec())).await.unwrap();
+        drop(connected);
+
+        let streams = client.read_to_end().await;
+        assert_eq!(streams[&stdout], b"world");
+        assert_eq!(streams[&error], b"done");
+    }
+
+    #[tokio::test]
+    async fn clients_sending_malformed_frames_are_disconnected() {
+        let mut oversized = control_frame(SYN_STREAM, 0, &[]);
+        oversized[5..8].copy_from_slice(&[0x10, 0x00, 0x00]);
+        let mut malformed = 1u32.to_be_bytes().to_vec();
+        malformed.extend_from_slice(&[0; 6]);
+        malformed.extend_from_slice(&[0x78, 0x01, 0xff, 0xff, 0xff]);
+        for frame in &[oversized, control_frame(SYN_STREAM, 0, &malformed)] {
+            let (server, mut client) = tokio::io::duplex(1024);
+            let accepting = tokio::spawn(async move { accept(server, &[("error", 3)]).await });
+            client.write_all(frame).await.unwrap();
+            assert!(accepting.await.unwrap().is_err());
+        }
+    }
+}
@@ -0,0 +1,527 @@
+//! Compiled module cache shared by all pods running on the provider.
+//!
+//! Compiled modules are kept in memory and also persisted to disk so that they
+//! survive krustlet restarts. Artifacts on disk live in a directory named after
+//! a fingerprint of the wasmtime version and engine configuration that
+//! produced them, and directories for any other fingerprint are removed when
+//! the cache is opened. Only the most recently used modules are kept in
+//! memory, as reloading the others from disk is cheap.
+//!
+//! With the `components` feature, the cache also compiles components, which
+//! are run by a later wasmtime. Those are only kept in memory.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+use wasmtime::{Config, Engine, Module};
+
+use crate::memory::LimitedMemoryCreator;
+
+#[cfg(feature = "components")]
+use wasmtime_components::{
+    component::Component, Config as ComponentConfig, Engine as ComponentEngine,
+};
+
+/// The size in bytes of the checksum stored at the start of every artifact.
+const CHECKSUM_SIZE: usize = 32;
+const ARTIFACT_EXTENSION: &str = "cwasm";
+/// The number of compiled modules kept in memory.
+const CAPACITY: usize = 32;
+
+type ModuleKey<K> = (String, K);
+type ModuleSlot<T> = Arc<Mutex<Option<T>>>;
+
+/// The limits that a module is run with, which decide the engine that it is
+/// compiled for.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub(crate) struct EngineLimits {
+    /// whether the module consumes fuel, so that it can be throttled
+    pub metered: bool,
+    /// whether the module's memory is limited
+    pub memory: bool,
+}
+
+/// The configuration of an engine.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct EngineKey {
+    simd: bool,
+    limits: EngineLimits,
+}
+
+impl EngineKey {
+    fn all() -> Vec<Self> {
+        let flags = [false, true];
+        let mut keys = vec![];
+        for simd in flags.iter() {
+            for metered in flags.iter() {
+                for memory in flags.iter() {
+                    keys.push(EngineKey {
+                        simd: *simd,
+                        limits: EngineLimits {
+                            metered: *metered,
+                            memory: *memory,
+                        },
+                    });
+                }
+            }
+        }
+        keys
+    }
+}
+
+/// A compiled module, or the slot it is compiled into, and when it was last
+/// used.
+struct CachedModule<T> {
+    slot: ModuleSlot<T>,
+    last_used: u64,
+}
+
+/// Compiled modules of type `T` by digest and the configuration `K` of the
+/// engine they were compiled for.
+struct Modules<K, T> {
+    modules: HashMap<ModuleKey<K>, CachedModule<T>>,
+    /// counts the lookups, to tell which module was used least recently
+    uses: u64,
+}
+
+impl<K, T> Default for Modules<K, T> {
+    fn default() -> Self {
+        Modules {
+            modules: HashMap::new(),
+            uses: 0,
+        }
+    }
+}
+
+impl<K: Clone + Eq + std::hash::Hash, T> Modules<K, T> {
+    /// Gets the slot for `key`, evicting the least recently used module if
+    /// that takes the cache over `capacity`. Anyone still using the evicted
+    /// module keeps it until they are done with it.
+    fn slot(&mut self, key: ModuleKey<K>, capacity: usize) -> ModuleSlot<T> {
+        self.uses += 1;
+        let last_used = self.uses;
+        let slot = {
+            let cached = self.modules.entry(key).or_insert_with(|| CachedModule {
+                slot: Default::default(),
+                last_used,
+            });
+            cached.last_used = last_used;
+            cached.slot.clone()
+        };
+        if self.modules.len() > capacity {
+            let evicted = self
+                .modules
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(evicted) = evicted {
+                debug!("Evicting compiled module {} from memory", evicted.0);
+                self.modules.remove(&evicted);
+            }
+        }
+        slot
+    }
+}
+
+/// Owns the wasmtime engines used to run modules and caches the modules
+/// compiled with them by image digest, so that replicas and restarts of the
+/// same image only pay for compilation once.
+///
+/// Fuel metering is an engine wide setting that slows down all code compiled
+/// with it, so modules that need to be throttled get their own engine. So do
+/// modules with a memory limit, whose tables can't be allowed to grow, and
+/// modules that are run with the SIMD proposal enabled.
+#[derive(Clone)]
+pub(crate) struct ModuleCache {
+    engines: HashMap<EngineKey, Engine>,
+    /// whether the engines handed out have the SIMD proposal enabled
+    simd: bool,
+    modules: Arc<Mutex<Modules<EngineKey, Module>>>,
+    /// the engines that components are compiled for, by whether they have
+    /// the SIMD proposal enabled
+    #[cfg(feature = "components")]
+    component_engines: HashMap<bool, ComponentEngine>,
+    #[cfg(feature = "components")]
+    components: Arc<Mutex<Modules<bool, Component>>>,
+    /// the number of compiled modules kept in memory
+    capacity: usize,
+    /// directory that compiled artifacts are persisted in
+    path: PathBuf,
+}
+
+impl ModuleCache {
+    /// Creates an empty cache that persists compiled artifacts under `path`.
+    pub fn new(path: PathBuf) -> Self {
+        ModuleCache {
+            engines: EngineKey::all()
+                .into_iter()
+                .map(|key| (key, new_engine(key)))
+                .collect(),
+            simd: false,
+            modules: Default::default(),
+            #[cfg(feature = "components")]
+            component_engines: [false, true]
+                .iter()
+                .map(|simd| (*simd, new_component_engine(*simd)))
+                .collect(),
+            #[cfg(feature = "components")]
+            components: Default::default(),
+            capacity: CAPACITY,
+            path,
+        }
+    }
+
+    /// The same cache, handing out engines that do or don't have the SIMD
+    /// proposal enabled.
+    pub fn with_simd(&self, simd: bool) -> Self {
+        ModuleCache {
+            simd,
+            ..self.clone()
+        }
+    }
+
+    /// Creates the artifact directories for the current engines, removing
+    /// artifacts compiled by other wasmtime versions or engine configurations.
+    pub async fn prune(&self) -> anyhow::Result<()> {
+        let current: Vec<_> = EngineKey::all()
+            .into_iter()
+            .map(|key| self.fingerprint(key))
+            .collect();
+        tokio::fs::create_dir_all(&self.path).await?;
+        let mut entries = tokio::fs::read_dir(&self.path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !current.contains(&name) {
+                debug!("Removing stale compiled modules in {}", name);
+                tokio::fs::remove_dir_all(entry.path()).await?;
+            }
+        }
+        for fingerprint in current {
+            tokio::fs::create_dir_all(self.path.join(fingerprint)).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns the engine for modules run with `limits`.
+    pub fn engine(&self, limits: EngineLimits) -> &Engine {
+        &self.engines[&self.key(limits)]
+    }
+
+    fn key(&self, limits: EngineLimits) -> EngineKey {
+        EngineKey {
+            simd: self.simd,
+            limits,
+        }
+    }
+
+    /// Returns the compiled module for `module_data`, loading it from disk or
+    /// compiling it if this is the first time it has been seen. Concurrent
+    /// requests for the same module wait for a single compilation to finish.
+    /// This blocks while compiling, so it should only be called from a
+    /// blocking context.
+    pub fn get_or_compile(
+        &self,
+        module_data: &[u8],
+        limits: EngineLimits,
+    ) -> anyhow::Result<Module> {
+        let digest = digest(module_data);
+        let slot = self
+            .modules
+            .lock()
+            .unwrap()
+            .slot((digest.clone(), self.key(limits)), self.capacity);
+        let mut module = slot.lock().unwrap();
+        if let Some(module) = module.as_ref() {
+            debug!("Using cached module {}", digest);
+            return Ok(module.clone());
+        }
+
+        let artifact_path = self.artifact_path(&digest, limits);
+        let loaded = match std::fs::read(&artifact_path) {
+            Ok(artifact) => match self.deserialize(&artifact, limits) {
+                Ok(module) => Some(module),
+                Err(e) => {
+                    warn!(
+                        "Discarding invalid compiled module {}: {:?}",
+                        artifact_path.display(),
+                        e
+                    );
+                    std::fs::remove_file(&artifact_path).ok();
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+        let compiled = match loaded {
+            Some(loaded) => {
+                debug!("Loaded compiled module {} from disk", digest);
+                loaded
+            }
+            None => {
+                debug!("Compiling module {}", digest);
+                let compiled = Module::new(self.engine(limits), module_data)?;
+                if let Err(e) = self.persist(&compiled, &artifact_path) {
+                    warn!(
+                        "Unable to persist compiled module {}: {:?}",
+                        artifact_path.display(),
+                        e
+                    );
+                }
+                compiled
+            }
+        };
+        module.replace(compiled.clone());
+        Ok(compiled)
+    }
+
+    /// Returns the engine that components are run with. Every instance of a
+    /// component shares it, so they are interrupted through epoch deadlines
+    /// of their own rather than by the engine.
+    #[cfg(feature = "components")]
+    pub fn component_engine(&self) -> &ComponentEngine {
+        &self.component_engines[&self.simd]
+    }
+
+    /// Returns the compiled component for `component_data`, compiling it if
+    /// this is the first time it has been seen since the provider started.
+    /// Like [`ModuleCache::get_or_compile`], this blocks while compiling.
+    #[cfg(feature = "components")]
+    pub fn get_or_compile_component(&self, component_data: &[u8]) -> anyhow::Result<Component> {
+        let digest = digest(component_data);
+        let slot = self
+            .components
+            .lock()
+            .unwrap()
+            .slot((digest.clone(), self.simd), self.capacity);
+        let mut component = slot.lock().unwrap();
+        if let Some(component) = component.as_ref() {
+            debug!("Using cached component {}", digest);
+            return Ok(component.clone());
+        }
+        debug!("Compiling component {}", digest);
+        let compiled = Component::new(self.component_engine(), component_data)?;
+        component.replace(compiled.clone());
+        Ok(compiled)
+    }
+
+    fn deserialize(&self, artifact: &[u8], limits: EngineLimits) -> anyhow::Result<Module> {
+        // wasmtime rejects artifacts from other compilers, but it can't tell
+        // if an artifact has been truncated or corrupted, so check that first
+        if artifact.len() < CHECKSUM_SIZE {
+            anyhow::bail!("artifact is too short");
+        }
+        let (checksum, serialized) = artifact.split_at(CHECKSUM_SIZE);
+        if Sha256::digest(serialized).as_slice() != checksum {
+            anyhow::bail!("artifact checksum does not match");
+        }
+        Module::deserialize(self.engine(limits), serialized)
+    }
+
+    fn persist(&self, module: &Module, artifact_path: &Path) -> anyhow::Result<()> {
+        let serialized = module.serialize()?;
+        let dir = artifact_path
+            .parent()
+            .ok_or_else(|| anyhow::anyhow!("artifact path has no parent directory"))?;
+        // Write to a temporary file first so that a partially written
+        // artifact is never picked up by a later load
+        let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+        temp.write_all(Sha256::digest(&serialized).as_slice())?;
+        temp.write_all(&serialized)?;
+        temp.persist(artifact_path)?;
+        Ok(())
+    }
+
+    fn artifact_path(&self, digest: &str, limits: EngineLimits) -> PathBuf {
+        let file_name = format!(
+            "{}.{}",
+            digest.trim_start_matches("sha256:"),
+            ARTIFACT_EXTENSION
+        );
+        self.path
+            .join(self.fingerprint(self.key(limits)))
+            .join(file_name)
+    }
+
+    /// Identifies the wasmtime version and engine configuration used to
+    /// compile modules for the given engine.
+    fn fingerprint(&self, key: EngineKey) -> String {
+        let engine = &self.engines[&key];
+        let description = format!(
+            "wasmtime {} {} {} metered={} memory_limited={} simd={} {:?}",
+            wasmtime_runtime::VERSION,
+            std::env::consts::ARCH,
+            std::env::consts::OS,
+            key.limits.metered,
+            key.limits.memory,
+            key.simd,
+            engine.config()
+        );
+        format!("{:x}", Sha256::digest(description.as_bytes()))[..16].to_string()
+    }
+}
+
+/// Computes the digest of a module. A WASM image's module is its only layer,
+/// so this matches the layer digest in the image manifest.
+fn digest(module_data: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(module_data))
+}
+
+fn new_engine(key: EngineKey) -> Engine {
+    let mut config = Config::new();
+    config.interruptable(true);
+    config.consume_fuel(key.limits.metered);
+    config.wasm_simd(key.simd);
+    // Only memories count against a module's memory limit, and tables can
+    // only grow at runtime through the reference types proposal, so it is
+    // disabled to keep them at the size declared by the module
+    config.wasm_reference_types(!key.limits.memory);
+    config.with_host_memory(Arc::new(LimitedMemoryCreator));
+    Engine::new(&config)
+}
+
+#[cfg(feature = "components")]
+fn new_component_engine(simd: bool) -> ComponentEngine {
+    let mut config = ComponentConfig::new();
+    config
+        .epoch_interruption(true)
+        .wasm_simd(simd)
+        .wasm_relaxed_simd(simd);
+    ComponentEngine::new(&config).expect("component engine configuration is invalid")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MODULE: &[u8] = b"(module (memory 1))";
+
+    async fn cache(dir: &Path) -> ModuleCache {
+        let cache = ModuleCache::new(dir.to_path_buf());
+        cache.prune().await.unwrap();
+        cache
+    }
+
+    #[tokio::test]
+    async fn prune_removes_artifacts_of_other_engines() {
+        let dir = tempfile::tempdir().unwrap();
+        let stale = dir.path().join("0123456789abcdef");
+        std::fs::create_dir_all(&stale).unwrap();
+        std::fs::write(stale.join("module.cwasm"), b"stale").unwrap();
+
+        let cache = cache(dir.path()).await;
+        assert!(!stale.exists());
+        for key in EngineKey::all() {
+            assert!(dir.path().join(cache.fingerprint(key)).is_dir());
+        }
+    }
+
+    #[tokio::test]
+    async fn compiled_modules_are_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let limits = EngineLimits::default();
+        cache(dir.path())
+            .await
+            .get_or_compile(MODULE, limits)
+            .unwrap();
+
+        let cache = cache(dir.path()).await;
+        let artifact = std::fs::read(cache.artifact_path(&digest(MODULE), limits)).unwrap();
+        assert!(cache.deserialize(&artifact, limits).is_ok());
+        // Artifacts are only valid for the engine they were compiled for
+        let metered = EngineLimits {
+            metered: true,
+            ..limits
+        };
+        assert!(!cache.artifact_path(&digest(MODULE), metered).exists());
+    }
+
+    #[tokio::test]
+    async fn invalid_artifacts_are_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let limits = EngineLimits::default();
+        let cache = cache(dir.path()).await;
+        let artifact_path = cache.artifact_path(&digest(MODULE), limits);
+        cache.get_or_compile(MODULE, limits).unwrap();
+
+        let mut artifact = std::fs::read(&artifact_path).unwrap();
+        let last = artifact.len() - 1;
+        artifact[last] ^= 0xff;
+        let err = cache.deserialize(&artifact, limits).err().unwrap();
+        assert_eq!(err.to_string(), "artifact checksum does not match");
+        let err = cache
+            .deserialize(&artifact[..CHECKSUM_SIZE - 1], limits)
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "artifact is too short");
+
+        // A new cache finds the corrupted artifact and compiles the module
+        // again, replacing it
+        std::fs::write(&artifact_path, &artifact).unwrap();
+        let cache = ModuleCache::new(dir.path().to_path_buf());
+        cache.get_or_compile(MODULE, limits).unwrap();
+        let artifact = std::fs::read(&artifact_path).unwrap();
+        assert!(cache.deserialize(&artifact, limits).is_ok());
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_modules_are_evicted() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ModuleCache {
+            capacity: 2,
+            ..cache(dir.path()).await
+        };
+        let limits = EngineLimits::default();
+        let modules: Vec<&[u8]> = vec![b"(module)", b"(module (memory 1))", b"(module (func))"];
+        for module in [modules[0], modules[1], modules[0], modules[2]].iter() {
+            cache.get_or_compile(module, limits).unwrap();
+        }
+
+        let cached = cache.modules.lock().unwrap();
+        let cached = |module| {
+            cached
+                .modules
+                .contains_key(&(digest(module), cache.key(limits)))
+        };
+        assert!(cached(modules[0]));
+        assert!(!cached(modules[1]));
+        assert!(cached(modules[2]));
+    }
+
+    #[tokio::test]
+    async fn only_memory_limited_modules_lose_reference_types() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(dir.path()).await;
+        let module = b"(module (table 1 externref))";
+        assert!(cache
+            .get_or_compile(module, EngineLimits::default())
+            .is_ok());
+        let limits = EngineLimits {
+            memory: true,
+            ..Default::default()
+        };
+        assert!(cache.get_or_compile(module, limits).is_err());
+    }
+
+    #[cfg(feature = "components")]
+    #[tokio::test]
+    async fn compiled_components_are_kept_in_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(dir.path()).await;
+        let component = wat::parse_str("(component)").unwrap();
+        cache.get_or_compile_component(&component).unwrap();
+        cache
+            .with_simd(true)
+            .get_or_compile_component(&component)
+            .unwrap();
+        cache.get_or_compile_component(&component).unwrap();
+
+        let cached = cache.components.lock().unwrap();
+        assert_eq!(cached.modules.len(), 2);
+        assert!(cached.modules.contains_key(&(digest(&component), false)));
+        assert!(cached.modules.contains_key(&(digest(&component), true)));
+    }
+}
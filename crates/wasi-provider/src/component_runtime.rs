@@ -0,0 +1,799 @@
+//! Runs WebAssembly components, which the version of wasmtime that
+//! [`WasiRuntime`](crate::wasi_runtime::WasiRuntime) runs core modules with
+//! predates. Components are run from their `wasi:cli/run` export by a later
+//! wasmtime, with the same lifecycle, logging and status reporting as core
+//! modules.
+//!
+//! Components are only run when the provider is built with the `components`
+//! feature. They only import WASI, so they can't use the provider's host
+//! modules or be given network access, and as they can't be throttled, those
+//! with CPU limits are refused.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::{Arc, Mutex};
+
+use anyhow::bail;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use tempfile::NamedTempFile;
+use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+use wasmtime_components::component::{Component, Linker, ResourceTable};
+use wasmtime_components::{Engine, ResourceLimiter, Store, Trap, UpdateDeadline};
+use wasmtime_wasi_components::bindings::sync::Command;
+use wasmtime_wasi_components::{
+    DirPerms, FilePerms, I32Exit, InputStream, IoView, OutputStream, Pollable, StdinStream,
+    StdoutStream, StreamError, StreamResult, WasiCtx, WasiCtxBuilder, WasiView,
+};
+
+use kubelet::container::Handle as ContainerHandle;
+use kubelet::container::Status;
+use kubelet::exec::{AttachedOutput, Sender as ExecSender};
+
+use crate::input::StdinReader;
+use crate::lifecycle::Lifecycle;
+use crate::output::{DetachGuard, ExecOutputWriter, LogStreamWriter, Stream};
+use crate::runtime::{
+    ExitGuard, Exited, HandleFactory, Instance, ModuleRuntime, ModuleSpec, Runtime,
+};
+use crate::wasi_runtime::{failed_status, oom_message, send, Mount, Stdin};
+
+/// Runs a container's component, compiled once by the [`ModuleCache`] for
+/// an engine that all components share.
+///
+/// [`ModuleCache`]: crate::cache::ModuleCache
+pub struct ComponentRuntime {
+    name: String,
+    data: Arc<Data>,
+    /// The tempfile that the component's output is written to
+    output: Arc<NamedTempFile>,
+    status_sender: Sender<Status>,
+    lifecycle: Arc<Lifecycle>,
+}
+
+/// What every instance of the component is run with.
+struct Data {
+    engine: Engine,
+    component: Component,
+    entrypoint: Option<String>,
+    env: HashMap<String, String>,
+    args: Vec<String>,
+    stdin: Stdin,
+    dirs: HashMap<PathBuf, Mount>,
+    working_dir: Option<(PathBuf, bool)>,
+    memory_limit: Option<u64>,
+}
+
+impl ComponentRuntime {
+    /// Compiles the component of `spec`, allowing it to use the SIMD
+    /// proposal if `simd` is set.
+    pub async fn new(spec: ModuleSpec, simd: bool) -> anyhow::Result<Self> {
+        let name = spec.name;
+        if spec.network.is_some() {
+            bail!(
+                "{} is a component, which can't be given network access",
+                name
+            );
+        }
+        if spec.limits.cpu.is_some() {
+            bail!("{} is a component, whose CPU limit can't be enforced", name);
+        }
+        let module_data = spec.module_data;
+        let module_cache = spec.module_cache.with_simd(simd);
+        let (engine, component) = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+            let component = module_cache.get_or_compile_component(&module_data)?;
+            Ok((module_cache.component_engine().clone(), component))
+        })
+        .await??;
+        let log_dir = spec.log_dir;
+        let temp = tokio::task::spawn_blocking(move || -> anyhow::Result<NamedTempFile> {
+            Ok(NamedTempFile::new_in(log_dir)?)
+        })
+        .await??;
+
+        Ok(ComponentRuntime {
+            name,
+            data: Arc::new(Data {
+                engine,
+                component,
+                entrypoint: spec.entrypoint,
+                env: spec.env,
+                args: spec.args,
+                stdin: spec.stdin,
+                dirs: spec.dirs,
+                working_dir: spec.working_dir,
+                memory_limit: spec.limits.memory,
+            }),
+            output: Arc::new(temp),
+            status_sender: spec.status_sender,
+            lifecycle: spec.lifecycle,
+        })
+    }
+
+    /// Runs the component to completion on a blocking task, reporting its
+    /// status as it goes.
+    fn spawn_component(
+        &self,
+        output_write: std::fs::File,
+        stdin: Option<StdinReader>,
+        attached: AttachedOutput,
+        exited: Arc<Exited>,
+        interrupted: Arc<AtomicBool>,
+    ) -> JoinHandle<anyhow::Result<()>> {
+        let data = self.data.clone();
+        let status_sender = self.status_sender.clone();
+        let name = self.name.clone();
+        tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            let _exited = ExitGuard(exited);
+            let _detach = DetachGuard(attached.clone());
+            let output = Arc::new(Mutex::new(output_write));
+            let (stdout, stdout_writer) = ForwardedOutput::new(LogStreamWriter::new(
+                Stream::Stdout,
+                output.clone(),
+                attached.clone(),
+            ));
+            let (stderr, stderr_writer) =
+                ForwardedOutput::new(LogStreamWriter::new(Stream::Stderr, output, attached));
+            let args: Vec<String> = data
+                .entrypoint
+                .iter()
+                .chain(data.args.iter())
+                .cloned()
+                .collect();
+
+            let mut store = match data.store(&name, &args, stdout, stderr, stdin, interrupted) {
+                Ok(store) => store,
+                Err(e) => {
+                    let message = format!("unable to set up component: {}", e);
+                    error!("{} {}", &name, message);
+                    send(&status_sender, &name, Status::terminated(&message, true));
+                    return Err(e);
+                }
+            };
+            let command = match data.instantiate(&mut store) {
+                Ok(command) => command,
+                Err(e) => {
+                    // Memories are only refused while instantiating if the
+                    // component's initial memory is over the limit
+                    let oom = store.data().memory.exceeded_limit();
+                    let (message, status) = failed_status("unable to instantiate component", oom);
+                    error!("{} {}: {:?}", &name, message, e);
+                    send(&status_sender, &name, status);
+                    return Err(anyhow::anyhow!("{}: {}", message, e));
+                }
+            };
+
+            info!("{} starting run of component", &name);
+            send(
+                &status_sender,
+                &name,
+                Status::Running {
+                    timestamp: chrono::Utc::now(),
+                },
+            );
+            let result = exit_code(command.wasi_cli_run().call_run(&mut store));
+            let oom = result
+                .as_ref()
+                .err()
+                .and_then(|e| store.data().memory.caused(e));
+            // Dropping the store drops its output streams, after which their
+            // writers write out any unterminated last line of output and
+            // finish, before the exit is reported, in case the termination
+            // message is read from it
+            drop((command, store));
+            stdout_writer.join();
+            stderr_writer.join();
+            match result {
+                Ok(0) => {}
+                Ok(exit_code) => {
+                    info!("{} component exited with code {}", &name, exit_code);
+                    let message = format!("Module exited with code {}", exit_code);
+                    send(&status_sender, &name, Status::exited(&message, exit_code));
+                    return Err(anyhow::anyhow!(message));
+                }
+                Err(e) => {
+                    let (message, status) = failed_status("unable to run component", oom);
+                    error!("{} {}: {:?}", &name, message, e);
+                    send(&status_sender, &name, status);
+                    return Err(anyhow::anyhow!("{}: {}", message, e));
+                }
+            }
+
+            info!("{} component run complete", &name);
+            send(
+                &status_sender,
+                &name,
+                Status::exited("Module run completed", 0),
+            );
+            Ok(())
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl ModuleRuntime for ComponentRuntime {
+    fn log_handle_factory(&self) -> HandleFactory {
+        HandleFactory::new(self.output.clone())
+    }
+
+    async fn start(&self) -> anyhow::Result<ContainerHandle<Runtime, HandleFactory>> {
+        let temp = self.output.clone();
+        let output_write = tokio::task::spawn_blocking(move || -> anyhow::Result<std::fs::File> {
+            Ok(temp.reopen()?)
+        })
+        .await??;
+
+        let stopped = Arc::new(Notify::new());
+        let (stdin_sender, stdin) = match self.data.stdin {
+            Stdin::Closed => (None, None),
+            Stdin::Open | Stdin::Once => {
+                // TODO: ~magic~ number
+                let (tx, rx) = mpsc::channel(8);
+                (Some(tx), Some(StdinReader::new(rx, stopped.clone())))
+            }
+        };
+
+        let exited = Arc::new(Exited::default());
+        let attached = AttachedOutput::new();
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let handle = self.spawn_component(
+            output_write,
+            stdin,
+            attached.clone(),
+            exited.clone(),
+            interrupted.clone(),
+        );
+
+        let instance = ComponentInstance {
+            name: self.name.clone(),
+            data: self.data.clone(),
+            interrupted,
+        };
+        let container_handle = ContainerHandle::new(
+            Runtime::new(
+                self.name.clone(),
+                Box::new(instance),
+                handle,
+                stopped,
+                exited,
+                self.lifecycle.clone(),
+            ),
+            self.log_handle_factory(),
+        )
+        .with_attached_output(attached);
+        Ok(match stdin_sender {
+            Some(sender) => container_handle.with_stdin(sender, self.data.stdin == Stdin::Once),
+            None => container_handle,
+        })
+    }
+}
+
+/// A component running in wasmtime.
+struct ComponentInstance {
+    name: String,
+    data: Arc<Data>,
+    /// set when the instance is interrupted
+    interrupted: Arc<AtomicBool>,
+}
+
+impl Instance for ComponentInstance {
+    /// Interrupts the instance by moving the shared engine past its epoch
+    /// deadline. Every other instance reaches its deadline too, but only
+    /// this one has been marked interrupted, so the others carry on.
+    fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::SeqCst);
+        self.data.engine.increment_epoch();
+    }
+
+    /// Components only import WASI, so they can't learn that they have been
+    /// asked to stop, and are only woken up if they are waiting for input.
+    fn request_stop(&self) {}
+
+    /// Exec commands run the component from its `wasi:cli/run` export with
+    /// the whole command as its arguments.
+    fn exec(
+        &self,
+        command: Vec<String>,
+        output: ExecSender,
+    ) -> BoxFuture<'static, anyhow::Result<i32>> {
+        let name = self.name.clone();
+        let data = self.data.clone();
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || data.exec(&name, command, output)).await?
+        })
+    }
+}
+
+impl Data {
+    /// Creates a store for an instance of the component, with the given
+    /// arguments and stdio along with the component's environment and
+    /// volumes. The instance traps once `interrupted` is set.
+    fn store(
+        &self,
+        name: &str,
+        args: &[String],
+        stdout: ForwardedOutput,
+        stderr: ForwardedOutput,
+        stdin: Option<StdinReader>,
+        interrupted: Arc<AtomicBool>,
+    ) -> anyhow::Result<Store<Host>> {
+        let env: Vec<(&String, &String)> = self.env.iter().collect();
+        let mut builder = WasiCtxBuilder::new();
+        builder.args(args).envs(&env).stdout(stdout).stderr(stderr);
+        if let Some(stdin) = stdin {
+            builder.stdin(ForwardedInput(Arc::new(Mutex::new(stdin))));
+        }
+
+        let mut preopens: Vec<(&PathBuf, &Path, bool)> = self
+            .dirs
+            .iter()
+            .map(|(key, mount)| {
+                let guest_dir = mount.guest_path.as_deref().unwrap_or(key);
+                (key, guest_dir, mount.read_only)
+            })
+            .collect();
+        if let Some((working_dir, read_only)) = &self.working_dir {
+            preopens.push((working_dir, Path::new("."), *read_only));
+        }
+        for (key, guest_dir, read_only) in preopens {
+            debug!(
+                "{} mounting hostpath {} as guestpath {}{}",
+                name,
+                key.display(),
+                guest_dir.display(),
+                if read_only { " (read-only)" } else { "" }
+            );
+            let (dir_perms, file_perms) = match read_only {
+                true => (DirPerms::READ, FilePerms::READ),
+                false => (DirPerms::all(), FilePerms::all()),
+            };
+            builder.preopened_dir(key, guest_dir.to_string_lossy(), dir_perms, file_perms)?;
+        }
+
+        let mut store = Store::new(
+            &self.engine,
+            Host {
+                ctx: builder.build(),
+                table: ResourceTable::new(),
+                memory: MemoryLimit::new(self.memory_limit),
+            },
+        );
+        store.limiter(|host| &mut host.memory);
+        // The deadline has already passed, so the instance checks whether it
+        // has been interrupted as soon as it starts, in case that happened
+        // before it was created, and then again every time the engine's epoch
+        // moves on
+        store.set_epoch_deadline(0);
+        store.epoch_deadline_callback(move |_| {
+            if interrupted.load(Ordering::SeqCst) {
+                return Err(Trap::Interrupt.into());
+            }
+            Ok(UpdateDeadline::Continue(1))
+        });
+        Ok(store)
+    }
+
+    /// Instantiates the component in `store`, with WASI as its imports.
+    fn instantiate(&self, store: &mut Store<Host>) -> anyhow::Result<Command> {
+        let mut linker = Linker::new(&self.engine);
+        wasmtime_wasi_components::add_to_linker_sync(&mut linker)?;
+        Command::instantiate(store, &self.component, &linker)
+    }
+
+    // Runs an exec command to completion. See `Runtime::exec`
+    fn exec(&self, name: &str, command: Vec<String>, output: ExecSender) -> anyhow::Result<i32> {
+        info!("{} running exec command {:?}", name, command);
+        let (stdout, stdout_writer) =
+            ForwardedOutput::new(ExecOutputWriter::new(Stream::Stdout, output.clone()));
+        let (stderr, stderr_writer) =
+            ForwardedOutput::new(ExecOutputWriter::new(Stream::Stderr, output));
+        // Exec commands aren't interrupted when the container is stopped
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let mut store = self.store(name, &command, stdout, stderr, None, interrupted)?;
+        let command = self.instantiate(&mut store).map_err(|e| {
+            match store.data().memory.exceeded_limit() {
+                Some(limit) => anyhow::anyhow!(oom_message(limit)),
+                None => e,
+            }
+        })?;
+        let result = exit_code(command.wasi_cli_run().call_run(&mut store));
+        let oom = result
+            .as_ref()
+            .err()
+            .and_then(|e| store.data().memory.caused(e));
+        drop((command, store));
+        stdout_writer.join();
+        stderr_writer.join();
+
+        match oom {
+            Some(limit) => Err(anyhow::anyhow!(oom_message(limit))),
+            None => result,
+        }
+    }
+}
+
+/// The exit code of a run of a component's `wasi:cli/run` export, or the
+/// error it failed with if it didn't exit.
+fn exit_code(result: anyhow::Result<Result<(), ()>>) -> anyhow::Result<i32> {
+    match result {
+        Ok(Ok(())) => Ok(0),
+        Ok(Err(())) => Ok(1),
+        Err(e) => match e.downcast_ref::<I32Exit>() {
+            Some(exit) => Ok(exit.0),
+            None => Err(e),
+        },
+    }
+}
+
+/// What a component's store holds.
+struct Host {
+    ctx: WasiCtx,
+    table: ResourceTable,
+    memory: MemoryLimit,
+}
+
+impl IoView for Host {
+    fn table(&mut self) -> &mut ResourceTable {
+        &mut self.table
+    }
+}
+
+impl WasiView for Host {
+    fn ctx(&mut self) -> &mut WasiCtx {
+        &mut self.ctx
+    }
+}
+
+/// Refuses an instance of a component linear memory past its limit, counting
+/// all of its memories together.
+struct MemoryLimit {
+    limit: u64,
+    used: u64,
+    exceeded: bool,
+}
+
+impl MemoryLimit {
+    /// Creates a limit of `limit` bytes, or no limit if it is `None`.
+    fn new(limit: Option<u64>) -> Self {
+        MemoryLimit {
+            limit: limit.unwrap_or(u64::MAX),
+            used: 0,
+            exceeded: false,
+        }
+    }
+
+    /// The limit in bytes, if the component has attempted to use more memory
+    /// than it allows.
+    fn exceeded_limit(&self) -> Option<u64> {
+        Some(self.limit).filter(|_| self.exceeded)
+    }
+
+    /// The limit in bytes, if the component failed with `error` because it ran
+    /// out of memory. As with core modules, it did if it aborted after the
+    /// limit refused it memory.
+    fn caused(&self, error: &anyhow::Error) -> Option<u64> {
+        let aborted = error.downcast_ref::<Trap>() == Some(&Trap::UnreachableCodeReached);
+        self.exceeded_limit().filter(|_| aborted)
+    }
+}
+
+impl ResourceLimiter for MemoryLimit {
+    fn memory_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> anyhow::Result<bool> {
+        let used = (self.used + desired as u64).saturating_sub(current as u64);
+        if used > self.limit {
+            self.exceeded = true;
+            return Ok(false);
+        }
+        self.used = used;
+        Ok(true)
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: usize,
+        _desired: usize,
+        _maximum: Option<usize>,
+    ) -> anyhow::Result<bool> {
+        Ok(true)
+    }
+}
+
+/// One of a component's output streams. wasmtime writes it from within an
+/// async runtime of its own, where it can't be sent on to attached or exec
+/// clients, as that blocks, so it is written out by a thread of its own.
+#[derive(Clone)]
+struct ForwardedOutput(SyncSender<Bytes>);
+
+/// The thread writing out a [`ForwardedOutput`].
+struct OutputWriter(std::thread::JoinHandle<()>);
+
+impl ForwardedOutput {
+    /// Starts writing the stream to `writer`, which is dropped once every copy
+    /// of the stream has been.
+    fn new<W: Write + Send + 'static>(mut writer: W) -> (Self, OutputWriter) {
+        // TODO: ~magic~ number
+        let (sender, receiver) = sync_channel::<Bytes>(8);
+        let thread = std::thread::spawn(move || {
+            for bytes in receiver {
+                if writer.write_all(&bytes).is_err() {
+                    break;
+                }
+            }
+        });
+        (ForwardedOutput(sender), OutputWriter(thread))
+    }
+}
+
+impl OutputWriter {
+    /// Waits for all of the stream's output to be written out, once every
+    /// copy of it has been dropped.
+    fn join(self) {
+        if self.0.join().is_err() {
+            warn!("component output writer panicked");
+        }
+    }
+}
+
+impl StdoutStream for ForwardedOutput {
+    fn stream(&self) -> Box<dyn OutputStream> {
+        Box::new(self.clone())
+    }
+
+    fn isatty(&self) -> bool {
+        false
+    }
+}
+
+#[async_trait::async_trait]
+impl Pollable for ForwardedOutput {
+    async fn ready(&mut self) {}
+}
+
+impl OutputStream for ForwardedOutput {
+    fn write(&mut self, bytes: Bytes) -> StreamResult<()> {
+        self.0.send(bytes).map_err(|_| StreamError::Closed)
+    }
+
+    fn flush(&mut self) -> StreamResult<()> {
+        Ok(())
+    }
+
+    fn check_write(&mut self) -> StreamResult<usize> {
+        Ok(1024 * 1024)
+    }
+}
+
+/// A component's stdin, read from attached clients. Reads block until there
+/// is input, or until the component is stopped, as with core modules.
+#[derive(Clone)]
+struct ForwardedInput(Arc<Mutex<StdinReader>>);
+
+impl StdinStream for ForwardedInput {
+    fn stream(&self) -> Box<dyn InputStream> {
+        Box::new(self.clone())
+    }
+
+    fn isatty(&self) -> bool {
+        false
+    }
+}
+
+#[async_trait::async_trait]
+impl Pollable for ForwardedInput {
+    async fn ready(&mut self) {}
+}
+
+impl InputStream for ForwardedInput {
+    fn read(&mut self, size: usize) -> StreamResult<Bytes> {
+        if size == 0 {
+            return Ok(Bytes::new());
+        }
+        let mut buf = vec![0; size];
+        let read = self
+            .0
+            .lock()
+            .unwrap()
+            .read(&mut buf)
+            .map_err(|e| StreamError::LastOperationFailed(e.into()))?;
+        if read == 0 {
+            return Err(StreamError::Closed);
+        }
+        buf.truncate(read);
+        Ok(buf.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::runtime::test::module_spec;
+    use crate::runtime::Engine;
+
+    /// A component whose `wasi:cli/run` export runs `body`, which leaves 0 on
+    /// the stack for success and 1 for failure, with `pages` of memory.
+    fn component(body: &str, pages: u32) -> Vec<u8> {
+        wat::parse_str(format!(
+            r#"(component
+                (core module $m
+                    (memory (export "memory") {})
+                    (func (export "run") (result i32) {}))
+                (core instance $i (instantiate $m))
+                (func $run (result (result)) (canon lift (core func $i "run")))
+                (instance $run (export "run" (func $run)))
+                (export "wasi:cli/run@0.2.0" (instance $run)))"#,
+            pages, body
+        ))
+        .unwrap()
+    }
+
+    /// Starts running a component, returning its handle, the receiver of its
+    /// statuses and the directory it logs to.
+    async fn start(
+        component: Vec<u8>,
+        memory: Option<u64>,
+    ) -> (
+        ContainerHandle<Runtime, HandleFactory>,
+        mpsc::Receiver<Status>,
+        tempfile::TempDir,
+    ) {
+        let log_dir = tempfile::tempdir().unwrap();
+        let (mut spec, statuses) = module_spec(component, log_dir.path());
+        spec.limits.memory = memory;
+        (run(spec).await.unwrap(), statuses, log_dir)
+    }
+
+    async fn run(spec: ModuleSpec) -> anyhow::Result<ContainerHandle<Runtime, HandleFactory>> {
+        let runtime = Engine::Wasmtime { simd: false }.runtime(spec).await?;
+        runtime.start().await
+    }
+
+    #[tokio::test]
+    async fn components_report_their_exit() {
+        let (mut handle, mut statuses, _log_dir) = start(component("i32.const 0", 1), None).await;
+        handle.wait().await.unwrap();
+        assert!(matches!(
+            statuses.recv().await,
+            Some(Status::Running { .. })
+        ));
+        assert!(matches!(
+            statuses.recv().await,
+            Some(Status::Terminated {
+                failed: false,
+                exit_code: Some(0),
+                ..
+            })
+        ));
+
+        let (mut handle, mut statuses, _log_dir) = start(component("i32.const 1", 1), None).await;
+        assert!(handle.wait().await.is_err());
+        assert!(matches!(
+            statuses.recv().await,
+            Some(Status::Running { .. })
+        ));
+        assert!(matches!(
+            statuses.recv().await,
+            Some(Status::Terminated {
+                failed: true,
+                exit_code: Some(1),
+                ..
+            })
+        ));
+        // Exec commands run another instance of the component
+        assert_eq!(
+            kubelet::probe::Exec::exec(handle.handle(), vec![])
+                .await
+                .unwrap(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn components_are_interrupted_when_stopped() {
+        let (mut handle, mut statuses, _log_dir) =
+            start(component("(loop $spin (br $spin)) i32.const 0", 1), None).await;
+        assert!(matches!(
+            statuses.recv().await,
+            Some(Status::Running { .. })
+        ));
+        handle.stop().await.unwrap();
+        assert!(handle.wait().await.is_err());
+        assert!(matches!(
+            statuses.recv().await,
+            Some(Status::Terminated {
+                failed: true,
+                exit_code: None,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn stopping_a_component_leaves_other_instances_running() {
+        let spin = component("(loop $spin (br $spin)) i32.const 0", 1);
+        let log_dir = tempfile::tempdir().unwrap();
+        let (spec, mut statuses) = module_spec(spin.clone(), log_dir.path());
+        // Both instances are compiled once, for the same engine
+        let module_cache = spec.module_cache.clone();
+        let mut handle = run(spec).await.unwrap();
+        let (mut other_spec, mut other_statuses) = module_spec(spin, log_dir.path());
+        other_spec.module_cache = module_cache;
+        let mut other_handle = run(other_spec).await.unwrap();
+        for statuses in [&mut statuses, &mut other_statuses].iter_mut() {
+            assert!(matches!(
+                statuses.recv().await,
+                Some(Status::Running { .. })
+            ));
+        }
+
+        handle.stop().await.unwrap();
+        assert!(handle.wait().await.is_err());
+        assert!(matches!(
+            statuses.recv().await,
+            Some(Status::Terminated { failed: true, .. })
+        ));
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(other_statuses.try_recv().is_err());
+
+        other_handle.stop().await.unwrap();
+        assert!(other_handle.wait().await.is_err());
+        assert!(matches!(
+            other_statuses.recv().await,
+            Some(Status::Terminated { failed: true, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn components_with_cpu_limits_are_refused() {
+        let log_dir = tempfile::tempdir().unwrap();
+        let (mut spec, _statuses) = module_spec(component("i32.const 0", 1), log_dir.path());
+        spec.limits.cpu = Some(0.5);
+        let err = run(spec).await.err().unwrap();
+        assert!(err.to_string().contains("CPU limit"));
+    }
+
+    #[tokio::test]
+    async fn components_are_held_to_their_memory_limit() {
+        let (mut handle, mut statuses, _log_dir) =
+            start(component("i32.const 0", 2), Some(65536)).await;
+        assert!(handle.wait().await.is_err());
+        match statuses.recv().await {
+            Some(Status::Terminated { reason, .. }) => {
+                assert_eq!(reason.as_deref(), Some(crate::memory::OOM_KILLED))
+            }
+            status => panic!("unexpected status {:?}", status),
+        }
+    }
+
+    #[test]
+    fn component_output_is_logged() {
+        let log = tempfile::NamedTempFile::new().unwrap();
+        let output = Arc::new(Mutex::new(log.reopen().unwrap()));
+        let (mut stdout, writer) = ForwardedOutput::new(LogStreamWriter::new(
+            Stream::Stdout,
+            output,
+            AttachedOutput::new(),
+        ));
+        stdout.write(Bytes::from_static(b"hello\nwor")).unwrap();
+        stdout.write(Bytes::from_static(b"ld")).unwrap();
+        drop(stdout);
+        writer.join();
+
+        let log = std::fs::read_to_string(log.path()).unwrap();
+        let lines: Vec<_> = log
+            .lines()
+            .map(|line| line.split_once(' ').unwrap().1)
+            .collect();
+        assert_eq!(lines, vec!["stdout F hello", "stdout F world"]);
+    }
+}
@@ -0,0 +1,124 @@
+//! CPU time limiting for WASI modules.
+//!
+//! Modules with a CPU limit run on an async store that consumes fuel and
+//! yields back to us every [`FUEL_SLICE`] units. [`CpuThrottle`] drives the
+//! resulting futures and sleeps between slices so the module only gets its
+//! share of a CPU, much like the CFS quota a container runtime would apply.
+//!
+//! Modules run on a single thread, so they can never use more than one core.
+//! Limits of a core or more are always honored and need no throttle.
+
+use std::future::Future;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use tracing::trace;
+
+/// The amount of fuel a module may consume before yielding to the throttle.
+pub(crate) const FUEL_SLICE: u64 = 1_000_000;
+/// The number of slices of fuel a store is given at a time. A store traps
+/// once it has used them all, so they are topped up every time it yields.
+const FUEL_INJECTIONS: u32 = 1;
+
+/// Drives wasm futures while keeping them within a CPU limit.
+pub(crate) struct CpuThrottle {
+    /// the number of CPU cores the module may use
+    cores: f64,
+}
+
+impl CpuThrottle {
+    /// Creates a throttle for the given limit, expressed in CPU cores.
+    /// Returns `None` for limits of a core or more, which a single threaded
+    /// module can't exceed.
+    pub fn new(cores: f64) -> Option<Self> {
+        if cores > 0.0 && cores < 1.0 {
+            Some(CpuThrottle { cores })
+        } else {
+            None
+        }
+    }
+
+    /// Creates an async store on `engine`, which must consume fuel, that
+    /// yields to the throttle after every slice of fuel.
+    pub fn store(&self, engine: &wasmtime::Engine) -> anyhow::Result<wasmtime::Store> {
+        let store = wasmtime::Store::new_async(engine);
+        store.add_fuel(FUEL_SLICE)?;
+        store.out_of_fuel_async_yield(FUEL_INJECTIONS, FUEL_SLICE);
+        Ok(store)
+    }
+
+    /// Blocks the current thread until the future, which runs in `store`,
+    /// completes. Sleeps whenever the module yields for long enough to keep
+    /// its CPU usage under the limit, and refuels the store so that the
+    /// module can run for as long as it likes.
+    pub fn block_on<F: Future>(&self, store: &wasmtime::Store, future: F) -> F::Output {
+        // Wasm futures flag themselves to be polled again when they yield, so
+        // there is nothing to be woken up for
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        futures::pin_mut!(future);
+        loop {
+            let started = Instant::now();
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => {
+                    store.out_of_fuel_async_yield(FUEL_INJECTIONS, FUEL_SLICE);
+                    let busy = started.elapsed();
+                    let idle = busy.mul_f64((1.0 - self.cores) / self.cores);
+                    trace!("module ran for {:?}, throttling for {:?}", busy, idle);
+                    std::thread::sleep(idle);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn limits_of_a_core_or_more_are_not_throttled() {
+        assert!(CpuThrottle::new(0.5).is_some());
+        assert!(CpuThrottle::new(1.0).is_none());
+        assert!(CpuThrottle::new(2.0).is_none());
+        assert!(CpuThrottle::new(0.0).is_none());
+    }
+
+    #[test]
+    fn throttled_modules_are_refueled() {
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let engine = wasmtime::Engine::new(&config);
+        let throttle = CpuThrottle::new(0.9).unwrap();
+        let store = throttle.store(&engine).unwrap();
+        // Counts down from the given number, which takes many slices of fuel
+        let module = wasmtime::Module::new(
+            &engine,
+            r#"(module
+                (func $decrement (param i32) (result i32)
+                    local.get 0
+                    i32.const 1
+                    i32.sub)
+                (func (export "count") (param i32)
+                    (loop
+                        local.get 0
+                        call $decrement
+                        local.tee 0
+                        br_if 0)))"#,
+        )
+        .unwrap();
+        let instance = throttle
+            .block_on(&store, wasmtime::Instance::new_async(&store, &module, &[]))
+            .unwrap();
+        let count = instance.get_func("count").unwrap();
+        let slices = 20;
+        throttle
+            .block_on(
+                &store,
+                count.call_async(&[wasmtime::Val::I32(slices * FUEL_SLICE as i32)]),
+            )
+            .unwrap();
+        assert!(store.fuel_consumed().unwrap() > slices as u64 * FUEL_SLICE);
+    }
+}
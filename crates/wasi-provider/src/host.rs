@@ -0,0 +1,64 @@
+//! Host modules that WASI modules can import functions from, in addition to
+//! WASI itself.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use wasmtime::Linker;
+
+/// The names of the WASI snapshots, which are always provided by the runtime.
+const WASI_MODULE_NAMES: &[&str] = &["wasi_snapshot_preview1", "wasi_unstable"];
+
+/// A module of host functions that WASI modules can import, such as logging,
+/// key-value storage or an HTTP client.
+///
+/// The functions are defined separately for every instance of a WASI module,
+/// as wasmtime ties them to the instance's store.
+///
+/// Any `Fn(&str, &mut Linker) -> anyhow::Result<()>` closure can be used as a
+/// host module.
+pub trait HostModule: Send + Sync + 'static {
+    /// Defines the module's functions in `linker` under `module_name`, which is
+    /// the name WASI modules import them from.
+    fn add_to_linker(&self, module_name: &str, linker: &mut Linker) -> anyhow::Result<()>;
+}
+
+impl<F> HostModule for F
+where
+    F: Fn(&str, &mut Linker) -> anyhow::Result<()> + Send + Sync + 'static,
+{
+    fn add_to_linker(&self, module_name: &str, linker: &mut Linker) -> anyhow::Result<()> {
+        self(module_name, linker)
+    }
+}
+
+/// The host modules registered with a provider, by the name they are imported by.
+#[derive(Clone, Default)]
+pub(crate) struct HostModules {
+    modules: HashMap<String, Arc<dyn HostModule>>,
+}
+
+impl HostModules {
+    /// Registers `module` to be imported by `name`. Names can only be
+    /// registered once, and the WASI snapshots' names are reserved.
+    pub fn register(&mut self, name: String, module: Arc<dyn HostModule>) -> anyhow::Result<()> {
+        if WASI_MODULE_NAMES.contains(&name.as_str()) {
+            anyhow::bail!("host module name {} is reserved for WASI", name);
+        }
+        if self.modules.contains_key(&name) {
+            anyhow::bail!("a host module named {} is already registered", name);
+        }
+        self.modules.insert(name, module);
+        Ok(())
+    }
+
+    /// Defines the functions of every registered module in `linker`.
+    pub fn add_to_linker(&self, linker: &mut Linker) -> anyhow::Result<()> {
+        for (name, module) in self.modules.iter() {
+            module
+                .add_to_linker(name, linker)
+                .map_err(|e| anyhow::anyhow!("unable to define host module {}: {}", name, e))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+//! Feeding input from attached clients to a module's stdin.
+
+use std::io::Read;
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, Notify};
+
+/// Reads a module's stdin from the input sent by attached clients. Reads block
+/// until there is input, and reach the end of the file once the input is
+/// closed or the module is being stopped.
+pub(crate) struct StdinReader {
+    input: mpsc::Receiver<Vec<u8>>,
+    /// notified when the module is stopped, so that it isn't stuck waiting
+    stopped: Arc<Notify>,
+    /// input that has been received but not read yet
+    pending: Vec<u8>,
+    closed: bool,
+}
+
+impl StdinReader {
+    pub fn new(input: mpsc::Receiver<Vec<u8>>, stopped: Arc<Notify>) -> Self {
+        StdinReader {
+            input,
+            stopped,
+            pending: Vec::new(),
+            closed: false,
+        }
+    }
+}
+
+impl Read for StdinReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pending.is_empty() && !self.closed {
+            let input = &mut self.input;
+            let stopped = &self.stopped;
+            // Modules run on a blocking thread, so it is fine to block it here
+            let next = futures::executor::block_on(async {
+                tokio::select! {
+                    data = input.recv() => data,
+                    _ = stopped.notified() => None,
+                }
+            });
+            match next {
+                Some(data) => self.pending = data,
+                None => self.closed = true,
+            }
+        }
+        let read = buf.len().min(self.pending.len());
+        buf[..read].copy_from_slice(&self.pending[..read]);
+        self.pending.drain(..read);
+        Ok(read)
+    }
+}
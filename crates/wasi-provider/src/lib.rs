@@ -32,16 +32,35 @@
 
 #![deny(missing_docs)]
 
+mod cache;
+#[cfg(feature = "components")]
+mod component_runtime;
+mod cpu;
+mod host;
+mod input;
+mod lifecycle;
+mod memory;
+mod output;
+mod runtime;
+mod scratch;
+mod security;
+mod sockets;
+mod termination;
 mod wasi_runtime;
 
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::net::IpAddr;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use async_trait::async_trait;
+use cache::ModuleCache;
+use host::HostModules;
 use kubelet::node::Builder;
 use kubelet::plugin_watcher::PluginRegistry;
+use kubelet::pod::address::AddressPool;
 use kubelet::pod::state::prelude::SharedState;
 use kubelet::pod::{Handle, Pod, PodKey};
 use kubelet::provider::{Provider, ProviderError};
@@ -50,15 +69,22 @@ use kubelet::state::common::terminated::Terminated;
 use kubelet::state::common::{GenericProvider, GenericProviderState};
 use kubelet::store::Store;
 use kubelet::volume::Ref;
+use runtime::Runtime;
+use sockets::{PodAddresses, PodPorts};
 use tokio::sync::RwLock;
-use wasi_runtime::Runtime;
 
 mod states;
 use states::pod::PodState;
 
+pub use host::HostModule;
+/// The version of wasmtime that modules are run with, for defining host modules.
+pub use wasmtime;
+
 const TARGET_WASM32_WASI: &str = "wasm32-wasi";
 const LOG_DIR_NAME: &str = "wasi-logs";
 const VOLUME_DIR: &str = "volumes";
+const SCRATCH_DIR: &str = "scratch";
+const MODULE_CACHE_DIR: &str = "wasi-module-cache";
 
 /// WasiProvider provides a Kubelet runtime implementation that executes WASM
 /// binaries conforming to the WASI spec.
@@ -67,7 +93,7 @@ pub struct WasiProvider {
     shared: ProviderState,
 }
 
-type PodHandleMap = Arc<RwLock<HashMap<PodKey, Arc<Handle<Runtime, wasi_runtime::HandleFactory>>>>>;
+type PodHandleMap = Arc<RwLock<HashMap<PodKey, Arc<Handle<Runtime, runtime::HandleFactory>>>>>;
 
 /// Provider-level state shared between all pods
 #[derive(Clone)]
@@ -77,7 +103,14 @@ pub struct ProviderState {
     log_path: PathBuf,
     client: kube::Client,
     volume_path: PathBuf,
+    /// where the scratch directories of pods' containers are created
+    scratch_path: PathBuf,
     plugin_registry: Arc<PluginRegistry>,
+    module_cache: ModuleCache,
+    host_modules: Arc<HostModules>,
+    node_ip: IpAddr,
+    /// pod IPs, when pods aren't given the node's IP
+    pod_addresses: Option<Arc<Mutex<AddressPool>>>,
 }
 
 #[async_trait]
@@ -94,6 +127,9 @@ impl GenericProviderState for ProviderState {
     fn plugin_registry(&self) -> Option<Arc<PluginRegistry>> {
         Some(self.plugin_registry.clone())
     }
+    fn runtime_handlers(&self) -> Vec<String> {
+        runtime_handlers()
+    }
     async fn stop(&self, pod: &Pod) -> anyhow::Result<()> {
         let key = PodKey::from(pod);
         let mut handle_writer = self.handles.write().await;
@@ -103,6 +139,38 @@ impl GenericProviderState for ProviderState {
             Ok(())
         }
     }
+    async fn stop_gracefully(&self, pod: &Pod, grace_period: Duration) -> anyhow::Result<()> {
+        // Other pods' handles are needed while this one shuts down
+        let handle = {
+            let handles = self.handles.read().await;
+            handles.get(&PodKey::from(pod)).cloned()
+        };
+        match handle {
+            Some(handle) => handle.stop_gracefully(grace_period).await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl ProviderState {
+    /// Allocates the addresses of a pod, which is given the node's IP unless
+    /// there is a pod CIDR.
+    fn allocate_pod_addresses(&self, key: &PodKey) -> anyhow::Result<PodAddresses> {
+        let pod_ip = match &self.pod_addresses {
+            Some(pool) => pool.lock().unwrap().allocate(key)?,
+            None => self.node_ip,
+        };
+        Ok(PodAddresses {
+            pod_ip,
+            host_ip: self.node_ip,
+        })
+    }
+
+    fn release_pod_addresses(&self, key: &PodKey) {
+        if let Some(pool) = &self.pod_addresses {
+            pool.lock().unwrap().release(key);
+        }
+    }
 }
 
 impl WasiProvider {
@@ -115,8 +183,17 @@ impl WasiProvider {
     ) -> anyhow::Result<Self> {
         let log_path = config.data_dir.join(LOG_DIR_NAME);
         let volume_path = config.data_dir.join(VOLUME_DIR);
+        let scratch_path = config.data_dir.join(SCRATCH_DIR);
         tokio::fs::create_dir_all(&log_path).await?;
         tokio::fs::create_dir_all(&volume_path).await?;
+        // Scratch directories left behind by a previous run belong to
+        // containers that are started afresh
+        if tokio::fs::metadata(&scratch_path).await.is_ok() {
+            tokio::fs::remove_dir_all(&scratch_path).await?;
+        }
+        tokio::fs::create_dir_all(&scratch_path).await?;
+        let module_cache = ModuleCache::new(config.data_dir.join(MODULE_CACHE_DIR));
+        module_cache.prune().await?;
         let client = kube::Client::try_from(kubeconfig)?;
         Ok(Self {
             shared: ProviderState {
@@ -124,16 +201,62 @@ impl WasiProvider {
                 store,
                 log_path,
                 volume_path,
+                scratch_path,
                 client,
                 plugin_registry,
+                module_cache,
+                host_modules: Default::default(),
+                node_ip: config.node_ip,
+                pod_addresses: None,
             },
         })
     }
+
+    /// Gives pods their own IPs from `cidr`, which is also set as the node's
+    /// pod CIDR, rather than the node's IP. Ports declared by pods that have
+    /// opted in to networking are bound on the pod's IP, so the node must
+    /// accept traffic for the whole CIDR, e.g. with
+    /// `ip route add local <cidr> dev lo` on Linux.
+    pub fn set_pod_cidr(&mut self, cidr: &str) -> anyhow::Result<()> {
+        self.shared.pod_addresses = Some(Arc::new(Mutex::new(AddressPool::new(cidr)?)));
+        Ok(())
+    }
+
+    /// Registers a host module that WASI modules can import functions from by
+    /// `name`, in addition to WASI itself. Host modules must be registered
+    /// before the provider is used to start a Kubelet.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// # async fn example(mut provider: wasi_provider::WasiProvider) -> anyhow::Result<()> {
+    /// provider.register_host_module("log", |module: &str, linker: &mut wasi_provider::wasmtime::Linker| {
+    ///     linker.func(module, "flush", || tracing::info!("flushing logs"))?;
+    ///     Ok(())
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn register_host_module(
+        &mut self,
+        name: impl Into<String>,
+        module: impl HostModule,
+    ) -> anyhow::Result<()> {
+        Arc::make_mut(&mut self.shared.host_modules).register(name.into(), Arc::new(module))
+    }
 }
 
 struct ModuleRunContext {
     modules: HashMap<String, Vec<u8>>,
     volumes: HashMap<String, Ref>,
+    /// the directory holding the scratch directories of the pod's containers,
+    /// once one has been started
+    scratch: Option<Ref>,
+    /// the handler of the pod's RuntimeClass, if it has one
+    runtime_handler: Option<String>,
+    addresses: PodAddresses,
+    /// the ports bound for the pod's containers, which restarted containers
+    /// take over
+    ports: PodPorts,
 }
 
 #[async_trait::async_trait]
@@ -153,11 +276,15 @@ impl Provider for WasiProvider {
         builder.set_architecture("wasm-wasi");
         builder.add_taint("NoSchedule", "kubernetes.io/arch", Self::ARCH);
         builder.add_taint("NoExecute", "kubernetes.io/arch", Self::ARCH);
+        if let Some(pool) = &self.shared.pod_addresses {
+            builder.set_pod_cidr(&pool.lock().unwrap().cidr());
+        }
         Ok(())
     }
 
     async fn initialize_pod_state(&self, pod: &Pod) -> anyhow::Result<Self::PodState> {
-        Ok(PodState::new(pod))
+        let addresses = self.shared.allocate_pod_addresses(&PodKey::from(pod))?;
+        Ok(PodState::new(pod, addresses))
     }
 
     async fn logs(
@@ -176,6 +303,47 @@ impl Provider for WasiProvider {
         handle.output(&container_name, sender).await
     }
 
+    async fn attach(
+        &self,
+        namespace: String,
+        pod_name: String,
+        container_name: String,
+        stdin: Option<tokio::sync::mpsc::Receiver<Vec<u8>>>,
+        output: kubelet::exec::Sender,
+    ) -> anyhow::Result<()> {
+        let handles = self.shared.handles.read().await;
+        let handle = handles
+            .get(&PodKey::new(&namespace, &pod_name))
+            .ok_or_else(|| ProviderError::PodNotFound {
+                pod_name: pod_name.clone(),
+            })?;
+        handle.attach(&container_name, stdin, output).await
+    }
+
+    async fn exec_in_container(
+        &self,
+        namespace: String,
+        pod_name: String,
+        container_name: String,
+        command: Vec<String>,
+        output: kubelet::exec::Sender,
+    ) -> anyhow::Result<i32> {
+        let exec = {
+            let handles = self.shared.handles.read().await;
+            let handle = handles
+                .get(&PodKey::new(&namespace, &pod_name))
+                .ok_or_else(|| ProviderError::PodNotFound {
+                    pod_name: pod_name.clone(),
+                })?;
+            handle
+                .with_container_handle(&container_name, |container| {
+                    container.handle().exec(command, output)
+                })
+                .await?
+        };
+        exec.await
+    }
+
     fn plugin_registry(&self) -> Option<Arc<PluginRegistry>> {
         Some(self.shared.plugin_registry.clone())
     }
@@ -183,6 +351,14 @@ impl Provider for WasiProvider {
     fn volume_path(&self) -> Option<PathBuf> {
         Some(self.shared.volume_path())
     }
+
+    fn runtime_handlers(&self) -> Vec<String> {
+        runtime_handlers()
+    }
+}
+
+fn runtime_handlers() -> Vec<String> {
+    runtime::HANDLERS.iter().map(|h| h.to_string()).collect()
 }
 
 impl GenericProvider for WasiProvider {
@@ -190,8 +366,9 @@ impl GenericProvider for WasiProvider {
     type PodState = PodState;
     type RunState = crate::states::pod::initializing::Initializing;
 
-    fn validate_pod_runnable(_pod: &Pod) -> anyhow::Result<()> {
-        Ok(())
+    fn validate_pod_runnable(pod: &Pod) -> anyhow::Result<()> {
+        runtime::Engine::for_pod(pod, None)?;
+        security::validate_pod(pod)
     }
 
     fn validate_container_runnable(
@@ -202,6 +379,6 @@ impl GenericProvider for WasiProvider {
                 return Err(anyhow::anyhow!("Cannot run kube-proxy"));
             }
         }
-        Ok(())
+        security::validate_container(container)
     }
 }
@@ -0,0 +1,76 @@
+//! The `postStart` and `preStop` hooks of WASI containers, which run around
+//! starting and stopping their modules.
+
+use kubelet::lifecycle::Hooks;
+use kubelet::pod::{record_event, EventType, Pod};
+use kubelet::probe::Exec;
+use tracing::{info, warn};
+
+/// The lifecycle hooks of a container, and where to report their failures.
+pub(crate) struct Lifecycle {
+    hooks: Hooks,
+    client: kube::Client,
+    pod: Pod,
+    container_name: String,
+}
+
+impl Lifecycle {
+    pub fn new(hooks: Hooks, client: kube::Client, pod: Pod, container_name: String) -> Self {
+        Lifecycle {
+            hooks,
+            client,
+            pod,
+            container_name,
+        }
+    }
+
+    /// Runs the `postStart` hook, recording an event if it fails.
+    pub async fn post_start(&self, exec: &dyn Exec) -> anyhow::Result<()> {
+        let result = self.hooks.post_start(exec).await;
+        if let Err(e) = &result {
+            self.record_failure("FailedPostStartHook", e).await;
+        }
+        result
+    }
+
+    /// Runs the `preStop` hook, recording an event if it fails. The module
+    /// should be stopped either way.
+    pub async fn pre_stop(&self, exec: &dyn Exec) {
+        if self.hooks.has_pre_stop() {
+            info!(
+                "Running preStop hook of pod {} container {}",
+                self.pod.name(),
+                self.container_name
+            );
+        }
+        if let Err(e) = self.hooks.pre_stop(exec).await {
+            self.record_failure("FailedPreStopHook", &e).await;
+        }
+    }
+
+    async fn record_failure(&self, reason: &str, e: &anyhow::Error) {
+        warn!(
+            "Pod {} container {} {}",
+            self.pod.name(),
+            self.container_name,
+            e
+        );
+        if let Err(e) = record_event(
+            &self.client,
+            &self.pod,
+            Some(&self.container_name),
+            EventType::Warning,
+            reason,
+            &e.to_string(),
+        )
+        .await
+        {
+            warn!(
+                "Pod {} container {} event could not be recorded: {:?}",
+                self.pod.name(),
+                self.container_name,
+                e
+            );
+        }
+    }
+}
@@ -0,0 +1,304 @@
+//! Linear memory accounting for WASI modules.
+//!
+//! wasmtime does not yet offer a per-store resource limiter, so limits are
+//! enforced by handing the engine a custom [`MemoryCreator`] that refuses to
+//! allocate or grow linear memory past a container's memory limit. Engines are
+//! shared between pods, so the limit for the instance being created is picked
+//! up from the current thread (see [`with_limit`]).
+
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use wasmtime::{LinearMemory, MemoryCreator, MemoryType, Trap, TrapCode};
+use wasmtime_runtime::Mmap;
+
+/// The size of a WebAssembly page in bytes.
+const WASM_PAGE_SIZE: usize = 0x10000;
+/// The maximum number of pages a 32-bit linear memory can address.
+const WASM_MAX_PAGES: u32 = 0x10000;
+
+/// The reason reported for containers that ran out of memory.
+pub(crate) const OOM_KILLED: &str = "OOMKilled";
+
+thread_local! {
+    static CURRENT_LIMIT: RefCell<Option<Arc<MemoryLimit>>> = RefCell::new(None);
+}
+
+/// Runs `f` with any memories created on this thread counting against `limit`.
+/// Instantiation happens synchronously on the calling thread, so wrapping it in
+/// this function applies the limit to all of the instance's memories.
+pub(crate) fn with_limit<T>(limit: Option<Arc<MemoryLimit>>, f: impl FnOnce() -> T) -> T {
+    let previous = CURRENT_LIMIT.with(|current| current.replace(limit));
+    let result = f();
+    CURRENT_LIMIT.with(|current| current.replace(previous));
+    result
+}
+
+/// Tracks the linear memory used by a module instance against its limit.
+#[derive(Debug)]
+pub(crate) struct MemoryLimit {
+    limit: u64,
+    used: AtomicU64,
+    exceeded: AtomicBool,
+}
+
+impl MemoryLimit {
+    /// Creates a new limit of `limit` bytes.
+    pub fn new(limit: u64) -> Self {
+        MemoryLimit {
+            limit,
+            used: AtomicU64::new(0),
+            exceeded: AtomicBool::new(false),
+        }
+    }
+
+    /// The limit in bytes.
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// Whether the module has attempted to use more memory than the limit allows.
+    pub fn exceeded(&self) -> bool {
+        self.exceeded.load(Ordering::SeqCst)
+    }
+
+    /// Whether the module stopped with `error` because it ran out of memory.
+    /// Modules are only refused memory by `memory.grow` returning -1, which
+    /// they can handle, so they are out of memory if they abort, as Rust and
+    /// C programs do when allocations fail, after the limit has refused them.
+    /// Modules that exit by themselves never are, whatever their exit code.
+    pub fn caused(&self, error: &anyhow::Error) -> bool {
+        let aborted = error
+            .downcast_ref::<Trap>()
+            .and_then(|trap| trap.trap_code())
+            == Some(TrapCode::UnreachableCodeReached);
+        aborted && self.exceeded()
+    }
+
+    fn reserve(&self, pages: u32) -> bool {
+        let bytes = pages as u64 * WASM_PAGE_SIZE as u64;
+        let limit = self.limit;
+        let reserved = self
+            .used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(bytes).filter(|total| *total <= limit)
+            })
+            .is_ok();
+        if !reserved {
+            self.exceeded.store(true, Ordering::SeqCst);
+        }
+        reserved
+    }
+
+    fn release(&self, pages: u32) {
+        let bytes = pages as u64 * WASM_PAGE_SIZE as u64;
+        self.used.fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
+/// A [`MemoryCreator`] whose memories count against the [`MemoryLimit`] that
+/// is current on the thread creating them, if any.
+pub(crate) struct LimitedMemoryCreator;
+
+unsafe impl MemoryCreator for LimitedMemoryCreator {
+    fn new_memory(
+        &self,
+        ty: MemoryType,
+        reserved_size_in_bytes: Option<u64>,
+        guard_size_in_bytes: u64,
+    ) -> Result<Box<dyn LinearMemory>, String> {
+        let limit = CURRENT_LIMIT.with(|current| current.borrow().clone());
+        let minimum = ty.limits().min();
+        if let Some(limit) = limit.as_ref() {
+            if !limit.reserve(minimum) {
+                return Err(format!(
+                    "initial memory of {} pages exceeds the limit of {} bytes",
+                    minimum,
+                    limit.limit()
+                ));
+            }
+        }
+
+        let guard_size = guard_size_in_bytes as usize;
+        let minimum_bytes = minimum as usize * WASM_PAGE_SIZE;
+        let reserved_bytes = reserved_size_in_bytes
+            .map(|bytes| bytes as usize)
+            .unwrap_or(minimum_bytes);
+        let alloc = match Mmap::accessible_reserved(minimum_bytes, reserved_bytes + guard_size) {
+            Ok(alloc) => alloc,
+            Err(e) => {
+                if let Some(limit) = limit.as_ref() {
+                    limit.release(minimum);
+                }
+                return Err(e);
+            }
+        };
+
+        Ok(Box::new(LimitedMemory {
+            alloc: RefCell::new(alloc),
+            size: Cell::new(minimum),
+            maximum: ty.limits().max(),
+            guard_size,
+            limit,
+        }))
+    }
+}
+
+/// An mmap backed linear memory that checks every grow against its limit, if any.
+struct LimitedMemory {
+    alloc: RefCell<Mmap>,
+    // The current size in wasm pages
+    size: Cell<u32>,
+    // The maximum size in wasm pages declared by the module, if any
+    maximum: Option<u32>,
+    guard_size: usize,
+    limit: Option<Arc<MemoryLimit>>,
+}
+
+unsafe impl LinearMemory for LimitedMemory {
+    fn size(&self) -> u32 {
+        self.size.get()
+    }
+
+    fn grow(&self, delta: u32) -> Option<u32> {
+        let prev_pages = self.size.get();
+        if delta == 0 {
+            return Some(prev_pages);
+        }
+        let new_pages = prev_pages.checked_add(delta)?;
+        if new_pages >= WASM_MAX_PAGES || matches!(self.maximum, Some(max) if new_pages > max) {
+            return None;
+        }
+        if matches!(self.limit.as_ref(), Some(limit) if !limit.reserve(delta)) {
+            return None;
+        }
+
+        let prev_bytes = prev_pages as usize * WASM_PAGE_SIZE;
+        let delta_bytes = delta as usize * WASM_PAGE_SIZE;
+        let new_bytes = new_pages as usize * WASM_PAGE_SIZE;
+        let mut alloc = self.alloc.borrow_mut();
+        let grown = if new_bytes > alloc.len() - self.guard_size {
+            // The reservation is too small, so this is a dynamic memory that
+            // has to be moved to a larger mapping
+            Mmap::accessible_reserved(new_bytes, new_bytes + self.guard_size).map(|mut moved| {
+                moved.as_mut_slice()[..prev_bytes].copy_from_slice(&alloc.as_slice()[..prev_bytes]);
+                *alloc = moved;
+            })
+        } else {
+            alloc.make_accessible(prev_bytes, delta_bytes)
+        };
+        if grown.is_err() {
+            if let Some(limit) = self.limit.as_ref() {
+                limit.release(delta);
+            }
+            return None;
+        }
+
+        self.size.set(new_pages);
+        Some(prev_pages)
+    }
+
+    fn as_ptr(&self) -> *mut u8 {
+        self.alloc.borrow_mut().as_mut_ptr()
+    }
+}
+
+impl Drop for LimitedMemory {
+    fn drop(&mut self) {
+        if let Some(limit) = self.limit.as_ref() {
+            limit.release(self.size.get());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PAGE: u64 = WASM_PAGE_SIZE as u64;
+
+    fn store() -> wasmtime::Store {
+        let mut config = wasmtime::Config::new();
+        config.with_host_memory(Arc::new(LimitedMemoryCreator));
+        wasmtime::Store::new(&wasmtime::Engine::new(&config))
+    }
+
+    fn instantiate(
+        store: &wasmtime::Store,
+        wat: &str,
+        limit: &Arc<MemoryLimit>,
+    ) -> anyhow::Result<wasmtime::Instance> {
+        let module = wasmtime::Module::new(store.engine(), wat)?;
+        with_limit(Some(limit.clone()), || {
+            wasmtime::Instance::new(store, &module, &[])
+        })
+    }
+
+    const GROW: &str = r#"(module
+        (memory 1)
+        (func (export "grow") (param i32) (result i32)
+            local.get 0
+            memory.grow)
+        (func (export "abort") unreachable))"#;
+
+    fn grow(instance: &wasmtime::Instance, pages: i32) -> i32 {
+        let grow = instance
+            .get_func("grow")
+            .unwrap()
+            .get1::<i32, i32>()
+            .unwrap();
+        grow(pages).unwrap()
+    }
+
+    #[test]
+    fn initial_memory_over_limit_is_refused() {
+        let limit = Arc::new(MemoryLimit::new(PAGE));
+        let store = store();
+        assert!(instantiate(&store, "(module (memory 2))", &limit).is_err());
+        assert!(limit.exceeded());
+        assert_eq!(limit.used.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn grows_are_refused_past_limit() {
+        let limit = Arc::new(MemoryLimit::new(2 * PAGE));
+        let store = store();
+        let instance = instantiate(&store, GROW, &limit).unwrap();
+        assert_eq!(grow(&instance, 1), 1);
+        assert!(!limit.exceeded());
+        assert_eq!(grow(&instance, 1), -1);
+        assert!(limit.exceeded());
+        assert_eq!(limit.used.load(Ordering::SeqCst), 2 * PAGE);
+
+        drop((instance, store));
+        assert_eq!(limit.used.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn memories_without_limit_are_not_counted() {
+        let limit = Arc::new(MemoryLimit::new(PAGE));
+        let store = store();
+        let module = wasmtime::Module::new(store.engine(), GROW).unwrap();
+        let instance = wasmtime::Instance::new(&store, &module, &[]).unwrap();
+        assert_eq!(grow(&instance, 4), 1);
+        assert_eq!(limit.used.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn only_aborting_after_refusal_is_out_of_memory() {
+        let limit = Arc::new(MemoryLimit::new(PAGE));
+        let store = store();
+        let instance = instantiate(&store, GROW, &limit).unwrap();
+        let abort = || instance.get_func("abort").unwrap().call(&[]).unwrap_err();
+
+        // Aborting for any other reason isn't
+        assert!(!limit.caused(&abort()));
+
+        assert_eq!(grow(&instance, 1), -1);
+        // Nor is exiting after handling the refusal
+        assert!(!limit.caused(&wasmtime::Trap::i32_exit(0).into()));
+        assert!(!limit.caused(&wasmtime::Trap::i32_exit(1).into()));
+        assert!(limit.caused(&abort()));
+    }
+}
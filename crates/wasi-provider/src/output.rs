@@ -0,0 +1,182 @@
+//! Capture of a module's stdout and stderr.
+//!
+//! For the module's main run, both streams are written to the container's log file in the CRI logging
+//! format, `<timestamp> <stream> <tag> <content>`, where the tag is `F` for a
+//! full line and `P` for part of a line that was too long to buffer. This keeps
+//! the streams apart for log shippers, and kubelet decodes them back into the
+//! module's combined output when serving logs. Clients attached to the module
+//! are sent a copy of its output as it is written. The output of exec commands
+//! is streamed straight back to the client instead.
+
+use std::fs::File;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use chrono::SecondsFormat;
+use kubelet::exec::{AttachedOutput, Sender as ExecSender, Stream as ExecStream};
+
+/// The longest line that is buffered before it is written out as a partial line.
+const MAX_LINE_SIZE: usize = 16 * 1024;
+
+/// The output stream a [`LogStreamWriter`] captures.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+impl From<Stream> for ExecStream {
+    fn from(stream: Stream) -> Self {
+        match stream {
+            Stream::Stdout => ExecStream::Stdout,
+            Stream::Stderr => ExecStream::Stderr,
+        }
+    }
+}
+
+/// Writes one of a module's output streams to a log file shared with its
+/// other streams, one CRI log line at a time, and to any attached clients.
+pub(crate) struct LogStreamWriter {
+    stream: Stream,
+    output: Arc<Mutex<File>>,
+    attached: AttachedOutput,
+    /// output that hasn't been terminated by a newline yet
+    line: Vec<u8>,
+}
+
+impl LogStreamWriter {
+    pub fn new(stream: Stream, output: Arc<Mutex<File>>, attached: AttachedOutput) -> Self {
+        LogStreamWriter {
+            stream,
+            output,
+            attached,
+            line: Vec::new(),
+        }
+    }
+
+    fn write_line(&self, content: &[u8], partial: bool) -> std::io::Result<()> {
+        let mut record = format!(
+            "{} {} {} ",
+            chrono::Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true),
+            self.stream.as_str(),
+            if partial { "P" } else { "F" }
+        )
+        .into_bytes();
+        record.extend_from_slice(content);
+        record.push(b'\n');
+        // A single write keeps lines from different streams from interleaving
+        self.output.lock().unwrap().write_all(&record)
+    }
+}
+
+impl Write for LogStreamWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // Attached clients get output as it is written, rather than by line
+        self.attached.blocking_send(self.stream.into(), buf);
+        let mut rest = buf;
+        while let Some(end) = rest.iter().position(|b| *b == b'\n') {
+            self.line.extend_from_slice(&rest[..end]);
+            let line = std::mem::take(&mut self.line);
+            self.write_line(&line, false)?;
+            rest = &rest[end + 1..];
+        }
+        self.line.extend_from_slice(rest);
+        while self.line.len() >= MAX_LINE_SIZE {
+            let end = partial_line_end(&self.line);
+            let partial: Vec<u8> = self.line.drain(..end).collect();
+            self.write_line(&partial, true)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.output.lock().unwrap().flush()
+    }
+}
+
+/// Where to cut a line that is too long to buffer, backing off so that a
+/// UTF-8 encoded character isn't split across partial lines.
+fn partial_line_end(line: &[u8]) -> usize {
+    // A character is at most four bytes, so only the three bytes before the
+    // cut can start one that it would split
+    (MAX_LINE_SIZE - 3..=MAX_LINE_SIZE)
+        .rev()
+        .find(|end| !matches!(line.get(*end), Some(b) if b & 0xc0 == 0x80))
+        .unwrap_or(MAX_LINE_SIZE)
+}
+
+impl Drop for LogStreamWriter {
+    fn drop(&mut self) {
+        // Whatever is left when the module exits is the last line of output,
+        // even though it wasn't terminated
+        if !self.line.is_empty() {
+            self.write_line(&self.line, false).ok();
+        }
+    }
+}
+
+/// Disconnects the clients attached to a module's output once its run ends,
+/// however it ends.
+pub(crate) struct DetachGuard(pub AttachedOutput);
+
+impl Drop for DetachGuard {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+/// Sends one of an exec command's output streams to the client that ran it.
+pub(crate) struct ExecOutputWriter {
+    stream: Stream,
+    output: ExecSender,
+}
+
+impl ExecOutputWriter {
+    pub fn new(stream: Stream, output: ExecSender) -> Self {
+        ExecOutputWriter { stream, output }
+    }
+}
+
+impl Write for ExecOutputWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output
+            .blocking_send(self.stream.into(), buf.to_vec())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, e))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn long_lines_are_not_cut_inside_characters() {
+        let mut line = vec![b'a'; MAX_LINE_SIZE - 1];
+        line.extend_from_slice("\u{e9}tag\u{e8}re".as_bytes());
+        assert_eq!(partial_line_end(&line), MAX_LINE_SIZE - 1);
+        assert!(std::str::from_utf8(&line[..partial_line_end(&line)]).is_ok());
+
+        let line = vec![b'a'; MAX_LINE_SIZE + 1];
+        assert_eq!(partial_line_end(&line), MAX_LINE_SIZE);
+    }
+
+    #[test]
+    fn invalid_utf8_is_cut_at_the_limit() {
+        let line = vec![0x80; MAX_LINE_SIZE + 1];
+        assert_eq!(partial_line_end(&line), MAX_LINE_SIZE);
+    }
+}
@@ -0,0 +1,667 @@
+//! The engines that modules can be run with. Each engine provides a
+//! [`ModuleRuntime`] that starts a container's module, and an [`Instance`]
+//! for each run of it. Stopping a module, waiting for it and running its
+//! lifecycle hooks is the same whatever runs it, and is done by [`Runtime`].
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use tempfile::NamedTempFile;
+use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::info;
+
+use kubelet::container::Handle as ContainerHandle;
+use kubelet::container::Status;
+use kubelet::exec::Sender as ExecSender;
+use kubelet::handle::StopHandler;
+use kubelet::pod::Pod;
+use kubelet::probe::Exec;
+
+use crate::cache::ModuleCache;
+#[cfg(feature = "components")]
+use crate::component_runtime::ComponentRuntime;
+use crate::host::HostModules;
+use crate::lifecycle::Lifecycle;
+use crate::sockets::Network;
+use crate::wasi_runtime::{Mount, ResourceLimits, Stdin, WasiRuntime};
+
+/// Annotation naming the engine that a pod's modules are run with
+pub(crate) const ENGINE_ANNOTATION: &str = "alpha.wasi.krustlet.dev/engine";
+
+/// The RuntimeClass handlers that the provider can run pods with, besides
+/// its default of wasmtime without the SIMD proposal
+pub(crate) const HANDLERS: &[&str] = &["wasmtime", "wasmtime-simd"];
+
+/// The host module that running modules can import [`STOP_REQUESTED_FUNC`]
+/// from.
+pub(crate) const LIFECYCLE_MODULE: &str = "krustlet_lifecycle";
+/// A function that returns 1 once the module has been asked to stop
+/// gracefully, as a process would be sent `SIGTERM`, and 0 until then.
+/// Modules that poll it can exit by themselves within their grace period.
+/// Modules that don't are only woken up if they are waiting for input.
+pub(crate) const STOP_REQUESTED_FUNC: &str = "stop_requested";
+
+/// The magic number that every WebAssembly binary starts with
+const WASM_MAGIC: &[u8] = b"\0asm";
+/// The layer field, after the version, of core modules. Components share
+/// the magic number but are a different layer.
+const CORE_MODULE_LAYER: &[u8] = &[0, 0];
+
+/// Whether `module_data` is a component rather than a core module, going by
+/// its preamble.
+pub(crate) fn is_component(module_data: &[u8]) -> bool {
+    module_data.len() >= 8
+        && &module_data[..4] == WASM_MAGIC
+        && &module_data[6..8] != CORE_MODULE_LAYER
+}
+
+/// An engine that modules can be run with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Engine {
+    /// wasmtime, compiling modules ahead of running them
+    Wasmtime {
+        /// whether modules may use the SIMD proposal
+        simd: bool,
+    },
+    /// A runtime that doesn't run modules, for testing
+    #[cfg(test)]
+    Fake,
+}
+
+impl Engine {
+    /// The engine that the pod's modules are run with. That is the engine of
+    /// the pod's RuntimeClass `handler` if it has one, or else the one it
+    /// asks for with the [`ENGINE_ANNOTATION`], which defaults to wasmtime.
+    pub fn for_pod(pod: &Pod, handler: Option<&str>) -> anyhow::Result<Self> {
+        match handler.or_else(|| pod.annotations().get(ENGINE_ANNOTATION).map(|e| e.as_str())) {
+            None => Ok(Engine::Wasmtime { simd: false }),
+            Some(name) => Engine::from_name(name),
+        }
+    }
+
+    fn from_name(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "wasmtime" => Ok(Engine::Wasmtime { simd: false }),
+            "wasmtime-simd" => Ok(Engine::Wasmtime { simd: true }),
+            #[cfg(test)]
+            "fake" => Ok(Engine::Fake),
+            _ => anyhow::bail!("unknown engine {}", name),
+        }
+    }
+
+    /// Makes the runtime that runs the module of `spec` with this engine.
+    pub async fn runtime(self, spec: ModuleSpec) -> anyhow::Result<Box<dyn ModuleRuntime>> {
+        match self {
+            #[cfg(feature = "components")]
+            Engine::Wasmtime { simd } if is_component(&spec.module_data) => {
+                Ok(Box::new(ComponentRuntime::new(spec, simd).await?))
+            }
+            #[cfg(not(feature = "components"))]
+            Engine::Wasmtime { .. } if is_component(&spec.module_data) => anyhow::bail!(
+                "{} is a component, which this provider was built without support for",
+                spec.name
+            ),
+            Engine::Wasmtime { simd } => {
+                let runtime = WasiRuntime::new(
+                    spec.name,
+                    spec.module_data,
+                    spec.entrypoint,
+                    spec.env,
+                    spec.args,
+                    spec.stdin,
+                    spec.dirs,
+                    spec.working_dir,
+                    spec.limits,
+                    spec.module_cache.with_simd(simd),
+                    spec.host_modules,
+                    spec.network,
+                    spec.lifecycle,
+                    spec.log_dir,
+                    spec.status_sender,
+                )
+                .await?;
+                Ok(Box::new(runtime))
+            }
+            #[cfg(test)]
+            Engine::Fake => Ok(Box::new(test::FakeRuntime::new(spec).await?)),
+        }
+    }
+}
+
+/// Everything a container's module is run with, whatever engine runs it.
+/// See [`WasiRuntime::new`] for what each is.
+pub(crate) struct ModuleSpec {
+    pub name: String,
+    pub module_data: Vec<u8>,
+    pub entrypoint: Option<String>,
+    pub env: HashMap<String, String>,
+    pub args: Vec<String>,
+    pub stdin: Stdin,
+    pub dirs: HashMap<PathBuf, Mount>,
+    pub working_dir: Option<(PathBuf, bool)>,
+    pub limits: ResourceLimits,
+    pub module_cache: ModuleCache,
+    pub host_modules: Arc<HostModules>,
+    pub network: Option<Network>,
+    pub lifecycle: Arc<Lifecycle>,
+    pub log_dir: PathBuf,
+    pub status_sender: Sender<Status>,
+}
+
+/// Runs a container's module. The module is stopped and waited for through
+/// the [`Runtime`] of the handle returned by `start`.
+#[async_trait::async_trait]
+pub(crate) trait ModuleRuntime: Send + Sync {
+    /// Starts running the module.
+    async fn start(&self) -> anyhow::Result<ContainerHandle<Runtime, HandleFactory>>;
+
+    /// Creates readers of the module's log.
+    fn log_handle_factory(&self) -> HandleFactory;
+}
+
+/// The parts of a running module that depend on the engine running it.
+pub(crate) trait Instance: Send + Sync {
+    /// Stops the module immediately, whatever it is doing.
+    fn interrupt(&self);
+
+    /// Asks the running module to stop, which it learns of from
+    /// [`STOP_REQUESTED_FUNC`] if it imports it.
+    fn request_stop(&self);
+
+    /// Runs a command in a new instance of the module, with the same
+    /// environment and volumes as the running one, streaming its output to
+    /// `output`. Resolves to the command's exit code once it has completed.
+    fn exec(
+        &self,
+        command: Vec<String>,
+        output: ExecSender,
+    ) -> BoxFuture<'static, anyhow::Result<i32>>;
+}
+
+/// A running module.
+pub struct Runtime {
+    module: RunningModule,
+    handle: JoinHandle<anyhow::Result<()>>,
+    /// The container's hooks, until the preStop hook has been run
+    lifecycle: Option<Arc<Lifecycle>>,
+}
+
+/// What it takes to stop a running module, which needn't be done through its
+/// [`Runtime`].
+#[derive(Clone)]
+struct RunningModule {
+    name: String,
+    instance: Arc<dyn Instance>,
+    /// Wakes up the module if it is waiting for input when it is stopped
+    stopped: Arc<Notify>,
+    /// Set once the module has finished running, however it ended
+    exited: Arc<Exited>,
+}
+
+/// Whether a module has finished running, however it ended.
+#[derive(Default)]
+pub(crate) struct Exited {
+    exited: AtomicBool,
+    notify: Notify,
+}
+
+impl Exited {
+    fn is_set(&self) -> bool {
+        self.exited.load(Ordering::SeqCst)
+    }
+
+    /// Waits until the module has exited. Only one task may wait at a time.
+    async fn wait(&self) {
+        if !self.is_set() {
+            self.notify.notified().await;
+        }
+    }
+}
+
+/// Marks a module as exited when dropped.
+pub(crate) struct ExitGuard(pub(crate) Arc<Exited>);
+
+impl Drop for ExitGuard {
+    fn drop(&mut self) {
+        self.0.exited.store(true, Ordering::SeqCst);
+        self.0.notify.notify_one();
+    }
+}
+
+impl Runtime {
+    /// Wraps a module that `handle` is running. `stopped` is notified when it
+    /// is stopped, and `exited` is set by the engine once it has finished.
+    pub(crate) fn new(
+        name: String,
+        instance: Box<dyn Instance>,
+        handle: JoinHandle<anyhow::Result<()>>,
+        stopped: Arc<Notify>,
+        exited: Arc<Exited>,
+        lifecycle: Arc<Lifecycle>,
+    ) -> Self {
+        Runtime {
+            module: RunningModule {
+                name,
+                instance: Arc::from(instance),
+                stopped,
+                exited,
+            },
+            handle,
+            lifecycle: Some(lifecycle),
+        }
+    }
+
+    /// Stops the module gracefully by running the preStop hook, unless it
+    /// has already been run, and asking the module to stop, then waking it
+    /// up if it is waiting for input or a connection, which fails with
+    /// `EINTR`, so that it can exit by itself. Its stdin has already been
+    /// closed. Modules that are still running once the grace period is over
+    /// are interrupted.
+    fn graceful_stop(
+        &mut self,
+        grace_period: Duration,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send + 'static {
+        let module = self.module.clone();
+        let lifecycle = self.lifecycle.take();
+        async move {
+            let deadline = tokio::time::Instant::now() + grace_period;
+            let ask_to_exit = async {
+                module.pre_stop(lifecycle).await;
+                module.instance.request_stop();
+                module.stopped.notify_one();
+                module.exited.wait().await;
+            };
+            if tokio::time::timeout_at(deadline, ask_to_exit).await.is_ok() {
+                return Ok(());
+            }
+            info!(
+                "{} did not exit within its grace period of {:?}, interrupting it",
+                module.name, grace_period
+            );
+            module.instance.interrupt();
+            module.stopped.notify_one();
+            Ok(())
+        }
+    }
+}
+
+impl RunningModule {
+    /// Runs the preStop hook of `lifecycle`. As in Kubernetes, it isn't run
+    /// for modules that have already exited.
+    async fn pre_stop(&self, lifecycle: Option<Arc<Lifecycle>>) {
+        if let Some(lifecycle) = lifecycle {
+            if !self.exited.is_set() {
+                lifecycle.pre_stop(self).await;
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl StopHandler for Runtime {
+    async fn stop(&mut self) -> anyhow::Result<()> {
+        self.module.pre_stop(self.lifecycle.take()).await;
+        self.module.instance.interrupt();
+        self.module.stopped.notify_one();
+        Ok(())
+    }
+
+    /// See [`Runtime::graceful_stop`].
+    async fn stop_gracefully(&mut self, grace_period: Duration) -> anyhow::Result<()> {
+        self.graceful_stop(grace_period).await
+    }
+
+    fn stop_gracefully_detached(
+        &mut self,
+        grace_period: Duration,
+    ) -> Option<BoxFuture<'static, anyhow::Result<()>>> {
+        Some(Box::pin(self.graceful_stop(grace_period)))
+    }
+
+    async fn wait(&mut self) -> anyhow::Result<()> {
+        (&mut self.handle).await??;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Exec for Runtime {
+    async fn exec(&self, command: Vec<String>) -> anyhow::Result<i32> {
+        self.module.exec(command).await
+    }
+}
+
+#[async_trait::async_trait]
+impl Exec for RunningModule {
+    async fn exec(&self, command: Vec<String>) -> anyhow::Result<i32> {
+        self.instance.exec(command, discarded_output()).await
+    }
+}
+
+/// Makes a sender for the output of exec commands whose output isn't needed,
+/// such as probes and lifecycle hooks.
+pub(crate) fn discarded_output() -> ExecSender {
+    let (tx, mut rx) = mpsc::channel(8);
+    tokio::spawn(async move { while rx.recv().await.is_some() {} });
+    ExecSender::new(tx)
+}
+
+impl Runtime {
+    /// Runs a command in a new instance of the module, with the same
+    /// environment and volumes as the running one, streaming its output to
+    /// `output`. Returns the command's exit code once it has completed.
+    pub fn exec(
+        &self,
+        command: Vec<String>,
+        output: ExecSender,
+    ) -> impl Future<Output = anyhow::Result<i32>> {
+        self.module.instance.exec(command, output)
+    }
+}
+
+/// Holds our tempfile handle.
+#[derive(Clone, Debug)]
+pub struct HandleFactory {
+    temp: Arc<NamedTempFile>,
+}
+
+impl HandleFactory {
+    /// Reads the log that a module's output is written to in `temp`.
+    pub(crate) fn new(temp: Arc<NamedTempFile>) -> Self {
+        HandleFactory { temp }
+    }
+}
+
+impl kubelet::log::HandleFactory<tokio::fs::File> for HandleFactory {
+    /// Creates `tokio::fs::File` on demand for log reading.
+    fn new_handle(&self) -> tokio::fs::File {
+        tokio::fs::File::from_std(self.temp.reopen().unwrap())
+    }
+
+    /// The runtime writes its output in the CRI logging format.
+    fn format(&self) -> kubelet::log::Format {
+        kubelet::log::Format::Cri
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::io::Write;
+    use std::sync::atomic::AtomicUsize;
+
+    /// A deterministic runtime, which doesn't run its module but writes its
+    /// arguments to its log and exits successfully.
+    pub(crate) struct FakeRuntime {
+        name: String,
+        args: Vec<String>,
+        lifecycle: Arc<Lifecycle>,
+        output: Arc<NamedTempFile>,
+        status_sender: Sender<Status>,
+    }
+
+    impl FakeRuntime {
+        pub(crate) async fn new(spec: ModuleSpec) -> anyhow::Result<Self> {
+            Ok(FakeRuntime {
+                name: spec.name,
+                args: spec.args,
+                lifecycle: spec.lifecycle,
+                output: Arc::new(NamedTempFile::new_in(spec.log_dir)?),
+                status_sender: spec.status_sender,
+            })
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ModuleRuntime for FakeRuntime {
+        async fn start(&self) -> anyhow::Result<ContainerHandle<Runtime, HandleFactory>> {
+            let exited = Arc::new(Exited::default());
+            let instance = FakeInstance {
+                cooperative: true,
+                exited: exited.clone(),
+                stop_requests: Default::default(),
+                interrupts: Default::default(),
+            };
+            let mut output = self.output.reopen()?;
+            let args = self.args.join(" ");
+            let status_sender = self.status_sender.clone();
+            let guard = ExitGuard(exited.clone());
+            let handle = tokio::spawn(async move {
+                let _guard = guard;
+                status_sender.send(Status::running()).await?;
+                writeln!(output, "{}", args)?;
+                status_sender
+                    .send(Status::exited("Module run completed", 0))
+                    .await?;
+                Ok(())
+            });
+            Ok(ContainerHandle::new(
+                Runtime::new(
+                    self.name.clone(),
+                    Box::new(instance),
+                    handle,
+                    Arc::new(Notify::new()),
+                    exited,
+                    self.lifecycle.clone(),
+                ),
+                self.log_handle_factory(),
+            ))
+        }
+
+        fn log_handle_factory(&self) -> HandleFactory {
+            HandleFactory::new(self.output.clone())
+        }
+    }
+
+    /// A module that exits when it is interrupted, or when it is asked to
+    /// stop if it is cooperative.
+    struct FakeInstance {
+        cooperative: bool,
+        exited: Arc<Exited>,
+        stop_requests: Arc<AtomicUsize>,
+        interrupts: Arc<AtomicUsize>,
+    }
+
+    impl Instance for FakeInstance {
+        fn interrupt(&self) {
+            self.interrupts.fetch_add(1, Ordering::SeqCst);
+            drop(ExitGuard(self.exited.clone()));
+        }
+
+        fn request_stop(&self) {
+            self.stop_requests.fetch_add(1, Ordering::SeqCst);
+            if self.cooperative {
+                drop(ExitGuard(self.exited.clone()));
+            }
+        }
+
+        fn exec(
+            &self,
+            _command: Vec<String>,
+            _output: ExecSender,
+        ) -> BoxFuture<'static, anyhow::Result<i32>> {
+            Box::pin(async { Ok(0) })
+        }
+    }
+
+    /// A runtime for a fake module, and how many times it was asked to stop
+    /// and interrupted.
+    fn fake_runtime(cooperative: bool) -> (Runtime, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        let exited = Arc::new(Exited::default());
+        let stop_requests = Arc::new(AtomicUsize::new(0));
+        let interrupts = Arc::new(AtomicUsize::new(0));
+        let instance = FakeInstance {
+            cooperative,
+            exited: exited.clone(),
+            stop_requests: stop_requests.clone(),
+            interrupts: interrupts.clone(),
+        };
+        let runtime = Runtime {
+            module: RunningModule {
+                name: "fake".to_owned(),
+                instance: Arc::new(instance),
+                stopped: Arc::new(Notify::new()),
+                exited,
+            },
+            handle: tokio::spawn(async { Ok(()) }),
+            lifecycle: None,
+        };
+        (runtime, stop_requests, interrupts)
+    }
+
+    #[tokio::test]
+    async fn graceful_stops_ask_modules_to_stop() {
+        let (mut runtime, stop_requests, interrupts) = fake_runtime(true);
+        let stop = runtime
+            .stop_gracefully_detached(Duration::from_secs(30))
+            .unwrap();
+        // The stop doesn't need the runtime
+        drop(runtime);
+        stop.await.unwrap();
+        assert_eq!(stop_requests.load(Ordering::SeqCst), 1);
+        assert_eq!(interrupts.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn modules_that_do_not_stop_are_interrupted() {
+        let (mut runtime, stop_requests, interrupts) = fake_runtime(false);
+        runtime
+            .stop_gracefully(Duration::from_secs(30))
+            .await
+            .unwrap();
+        assert_eq!(stop_requests.load(Ordering::SeqCst), 1);
+        assert_eq!(interrupts.load(Ordering::SeqCst), 1);
+    }
+
+    /// A spec for running `module_data` with no arguments, environment or
+    /// volumes, logging to `log_dir`, and the receiver of its statuses.
+    pub(crate) fn module_spec(
+        module_data: Vec<u8>,
+        log_dir: &std::path::Path,
+    ) -> (ModuleSpec, mpsc::Receiver<Status>) {
+        let config = kube::Config::new("http://127.0.0.1:8080".parse().unwrap());
+        let client = kube::Client::try_from(config).unwrap();
+        let (status_sender, statuses) = mpsc::channel(8);
+        let spec = ModuleSpec {
+            name: "default:fake:app".to_owned(),
+            module_data,
+            entrypoint: None,
+            env: HashMap::new(),
+            args: Vec::new(),
+            stdin: Stdin::Closed,
+            dirs: HashMap::new(),
+            working_dir: None,
+            limits: ResourceLimits::default(),
+            module_cache: ModuleCache::new(log_dir.join("cache")),
+            host_modules: Arc::new(HostModules::default()),
+            network: None,
+            lifecycle: Arc::new(Lifecycle::new(
+                Default::default(),
+                client,
+                Pod::default(),
+                "app".to_owned(),
+            )),
+            log_dir: log_dir.to_owned(),
+            status_sender,
+        };
+        (spec, statuses)
+    }
+
+    fn pod(annotations: serde_json::Value) -> Pod {
+        serde_json::from_value(serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": {
+                "name": "fake",
+                "namespace": "default",
+                "annotations": annotations,
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn pods_select_their_engine() {
+        let fake = pod(serde_json::json!({ ENGINE_ANNOTATION: "fake" }));
+        let default = pod(serde_json::json!({}));
+        assert_eq!(Engine::for_pod(&fake, None).unwrap(), Engine::Fake);
+        assert_eq!(
+            Engine::for_pod(&default, None).unwrap(),
+            Engine::Wasmtime { simd: false }
+        );
+        // The RuntimeClass handler takes precedence over the annotation
+        assert_eq!(
+            Engine::for_pod(&fake, Some("wasmtime-simd")).unwrap(),
+            Engine::Wasmtime { simd: true }
+        );
+        assert_eq!(
+            Engine::for_pod(&default, Some("fake")).unwrap(),
+            Engine::Fake
+        );
+        assert!(Engine::for_pod(&default, Some("v8")).is_err());
+    }
+
+    #[tokio::test]
+    async fn pods_are_run_by_the_engine_they_select() {
+        let pod = pod(serde_json::json!({ ENGINE_ANNOTATION: "fake" }));
+        let log_dir = tempfile::tempdir().unwrap();
+        let (mut spec, mut statuses) = module_spec(Vec::new(), log_dir.path());
+        spec.args = vec!["hello".to_owned(), "world".to_owned()];
+
+        let runtime = Engine::for_pod(&pod, None)
+            .unwrap()
+            .runtime(spec)
+            .await
+            .unwrap();
+        let mut handle = runtime.start().await.unwrap();
+        handle.wait().await.unwrap();
+
+        assert!(matches!(
+            statuses.recv().await,
+            Some(Status::Running { .. })
+        ));
+        assert!(matches!(
+            statuses.recv().await,
+            Some(Status::Terminated {
+                exit_code: Some(0),
+                ..
+            })
+        ));
+        let mut log = String::new();
+        let mut reader = kubelet::log::HandleFactory::new_handle(&runtime.log_handle_factory());
+        tokio::io::AsyncReadExt::read_to_string(&mut reader, &mut log)
+            .await
+            .unwrap();
+        assert_eq!(log, "hello world\n");
+    }
+
+    #[test]
+    fn components_are_told_apart_from_core_modules() {
+        assert!(is_component(&wat::parse_str("(component)").unwrap()));
+        assert!(!is_component(&wat::parse_str("(module)").unwrap()));
+        assert!(!is_component(b"\0asm"));
+        assert!(!is_component(b"(component)"));
+    }
+
+    #[cfg(not(feature = "components"))]
+    #[tokio::test]
+    async fn components_are_refused_without_the_components_feature() {
+        let log_dir = tempfile::tempdir().unwrap();
+        let component = wat::parse_str("(component)").unwrap();
+        let (spec, _statuses) = module_spec(component, log_dir.path());
+        let err = Engine::Wasmtime { simd: false }
+            .runtime(spec)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("is a component"));
+    }
+}
@@ -0,0 +1,122 @@
+//! The writable root file system that modules get besides their volumes, like
+//! a container's writable layer, and the ephemeral storage limit on how much
+//! they can keep in it.
+
+use std::path::{Path, PathBuf};
+
+use krator::SharedState;
+use kubelet::container::Container;
+use kubelet::pod::Pod;
+use kubelet::volume::Ref;
+use tracing::warn;
+
+use crate::ModuleRunContext;
+
+/// Creates an empty scratch directory for the container, discarding anything
+/// a previous run of it left behind. The directories of all of a pod's
+/// containers are removed with its volumes.
+pub(crate) async fn container_dir(
+    run_context: &SharedState<ModuleRunContext>,
+    scratch_path: &Path,
+    pod: &Pod,
+    container: &Container,
+) -> anyhow::Result<PathBuf> {
+    let pod_dir = {
+        let mut run_context = run_context.write().await;
+        match &run_context.scratch {
+            Some(scratch) => scratch.to_path_buf(),
+            None => {
+                let scratch = Ref::scratch(scratch_path.join(pod_dir_name(pod))).await?;
+                let pod_dir = scratch.to_path_buf();
+                run_context.scratch = Some(scratch);
+                pod_dir
+            }
+        }
+    };
+    let dir = pod_dir.join(container.name());
+    if tokio::fs::metadata(&dir).await.is_ok() {
+        tokio::fs::remove_dir_all(&dir).await?;
+    }
+    tokio::fs::create_dir_all(&dir).await?;
+    Ok(dir)
+}
+
+/// Pods that are deleted and recreated with the same name get new UIDs, so
+/// the directory of one never outlives the other's.
+fn pod_dir_name(pod: &Pod) -> String {
+    match pod.uid() {
+        Some(uid) => uid.to_string(),
+        None => format!("{}-{}", pod.name(), pod.namespace()),
+    }
+}
+
+/// The most that a module can keep in its scratch directory, from the
+/// container's `ephemeral-storage` limit.
+#[derive(Debug)]
+pub(crate) struct StorageLimit {
+    dir: PathBuf,
+    limit: u64,
+}
+
+impl StorageLimit {
+    /// The limit on the container's scratch directory `dir`, if it has one.
+    pub fn new(container: &Container, dir: &Path) -> anyhow::Result<Option<Self>> {
+        Ok(container
+            .ephemeral_storage_limit()?
+            .map(|limit| StorageLimit {
+                dir: dir.to_path_buf(),
+                limit,
+            }))
+    }
+
+    /// The bytes stored in the scratch directory, if that is over the limit.
+    /// Like the standard kubelet, this measures usage periodically, so a
+    /// module can briefly exceed its limit before it is stopped.
+    pub async fn exceeded(&self) -> Option<u64> {
+        let dir = self.dir.clone();
+        let usage = match tokio::task::spawn_blocking(move || disk_usage(&dir)).await {
+            Ok(Ok(usage)) => usage,
+            Ok(Err(e)) => {
+                warn!(
+                    "Unable to measure scratch directory {}: {:?}",
+                    self.dir.display(),
+                    e
+                );
+                return None;
+            }
+            Err(_) => return None,
+        };
+        if usage > self.limit {
+            Some(usage)
+        } else {
+            None
+        }
+    }
+
+    /// The limit in bytes.
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+}
+
+/// The total size of the files in `dir`, not following symlinks. Files that
+/// the module removes while they are being counted are skipped.
+fn disk_usage(dir: &Path) -> std::io::Result<u64> {
+    let mut usage = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let size = entry.and_then(|entry| {
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                disk_usage(&entry.path())
+            } else {
+                Ok(metadata.len())
+            }
+        });
+        usage += match size {
+            Ok(size) => size,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+    }
+    Ok(usage)
+}
@@ -0,0 +1,109 @@
+//! Validation of pod and container security contexts. Modules have no users,
+//! groups or Linux capabilities, and can only reach the file system through
+//! their preopened directories, so most of a security context either holds
+//! trivially or can't be enforced at all. Pods asking for the latter are
+//! rejected rather than run with the settings silently ignored.
+
+use kubelet::container::Container;
+use kubelet::pod::Pod;
+
+/// Checks that the provider can honor everything the pod's security context
+/// asks for.
+pub(crate) fn validate_pod(pod: &Pod) -> anyhow::Result<()> {
+    let context = match pod.security_context() {
+        Some(context) => context,
+        None => return Ok(()),
+    };
+    let mut unsupported = vec![];
+    if context.fs_group.is_some() || context.fs_group_change_policy.is_some() {
+        unsupported.push("fsGroup");
+    }
+    if context.run_as_user.is_some() {
+        unsupported.push("runAsUser");
+    }
+    if context.run_as_group.is_some() {
+        unsupported.push("runAsGroup");
+    }
+    if context
+        .supplemental_groups
+        .iter()
+        .flatten()
+        .next()
+        .is_some()
+    {
+        unsupported.push("supplementalGroups");
+    }
+    if context.sysctls.iter().flatten().next().is_some() {
+        unsupported.push("sysctls");
+    }
+    if context.se_linux_options.is_some() {
+        unsupported.push("seLinuxOptions");
+    }
+    if context.windows_options.is_some() {
+        unsupported.push("windowsOptions");
+    }
+    if let Some(profile) = &context.seccomp_profile {
+        if !supported_seccomp_profile(&profile.type_) {
+            unsupported.push("seccompProfile");
+        }
+    }
+    check(unsupported, || format!("pod {}", pod.name()))
+}
+
+/// Checks that the provider can honor everything the container's security
+/// context asks for. `readOnlyRootFilesystem` is enforced by preopening the
+/// module's scratch root file system read-only.
+pub(crate) fn validate_container(container: &Container) -> anyhow::Result<()> {
+    let context = match container.security_context() {
+        Some(context) => context,
+        None => return Ok(()),
+    };
+    let mut unsupported = vec![];
+    if context.privileged == Some(true) {
+        unsupported.push("privileged");
+    }
+    // Modules have no capabilities, so dropping them is always honored
+    if let Some(capabilities) = &context.capabilities {
+        if capabilities.add.iter().flatten().next().is_some() {
+            unsupported.push("capabilities.add");
+        }
+    }
+    if context.run_as_user.is_some() {
+        unsupported.push("runAsUser");
+    }
+    if context.run_as_group.is_some() {
+        unsupported.push("runAsGroup");
+    }
+    if context.se_linux_options.is_some() {
+        unsupported.push("seLinuxOptions");
+    }
+    if context.windows_options.is_some() {
+        unsupported.push("windowsOptions");
+    }
+    if matches!(context.proc_mount.as_deref(), Some(proc_mount) if proc_mount != "Default") {
+        unsupported.push("procMount");
+    }
+    if let Some(profile) = &context.seccomp_profile {
+        if !supported_seccomp_profile(&profile.type_) {
+            unsupported.push("seccompProfile");
+        }
+    }
+    check(unsupported, || format!("container {}", container.name()))
+}
+
+/// Modules can't make system calls, so they already run under the strictest
+/// profile there is, but a profile on the node can't be loaded.
+fn supported_seccomp_profile(type_: &str) -> bool {
+    type_ == "RuntimeDefault" || type_ == "Unconfined"
+}
+
+fn check(unsupported: Vec<&str>, subject: impl FnOnce() -> String) -> anyhow::Result<()> {
+    if unsupported.is_empty() {
+        return Ok(());
+    }
+    anyhow::bail!(
+        "{} sets security context fields that WASI modules can't enforce: {}",
+        subject(),
+        unsupported.join(", ")
+    )
+}
@@ -0,0 +1,572 @@
+//! Experimental TCP networking for WASI modules.
+//!
+//! WASI doesn't have a way for modules to open sockets yet, so pods opt in to
+//! networking with the [`SOCKETS_ANNOTATION`]. The kubelet then preopens a
+//! listening socket for each TCP port declared by the pod's containers, after
+//! the module's preopened directories and in the order the ports are declared.
+//! The module is told where they are by the `LISTEN_FDS` and `LISTEN_FDS_START`
+//! environment variables. Connections to the port on the pod's IP, and to its
+//! host port on the node's IP if it has one, are proxied to the module's
+//! socket.
+//!
+//! Modules accept connections with `sock_accept` from `wasi_snapshot_preview1`,
+//! as it has since been added to WASI, and read and write connections like any
+//! other file. Outbound connections are made with `connect` from the
+//! `krustlet_sockets` host module, and are only allowed to the hosts listed in
+//! the [`ALLOWED_HOSTS_ANNOTATION`].
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::future::Future;
+use std::io::{IoSlice, IoSliceMut};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+use wasi_common::file::{Advice, FdFlags, FileCaps, FileType, Filestat};
+use wasi_common::snapshots::preview_1::types::Errno;
+use wasi_common::{Error, ErrorExt, SystemTimeSpec, WasiCtx, WasiFile};
+use wasmtime::{Caller, Linker};
+
+use kubelet::container::Container;
+use kubelet::pod::Pod;
+
+/// Annotation that opts a pod in to networking when set to `"true"`.
+pub(crate) const SOCKETS_ANNOTATION: &str = "alpha.wasi.krustlet.dev/sockets";
+/// Annotation listing the hosts a pod's modules may connect to, separated by
+/// commas. Entries are either `host:port`, or `host` to allow any port.
+pub(crate) const ALLOWED_HOSTS_ANNOTATION: &str = "alpha.wasi.krustlet.dev/allowed-outbound-hosts";
+
+/// The host module outbound connections are made with.
+const SOCKETS_MODULE: &str = "krustlet_sockets";
+/// The first fd after stdio.
+const FIRST_FD: u32 = 3;
+/// The longest address a module may connect to, which is a host name of up to
+/// 253 characters and a port.
+const MAX_ADDRESS_LEN: u32 = 253 + ":65535".len() as u32;
+
+/// The addresses of a pod.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PodAddresses {
+    pub pod_ip: IpAddr,
+    /// the IP of the node the pod is running on
+    pub host_ip: IpAddr,
+}
+
+/// The ports bound for a pod's containers. They stay bound for the life of
+/// the pod, so that a restarted container takes them over from its previous
+/// run, which may not have been dropped yet.
+#[derive(Debug, Default)]
+pub(crate) struct PodPorts {
+    ports: HashMap<SocketAddr, Port>,
+}
+
+#[derive(Debug)]
+struct Port {
+    listener: std::net::TcpListener,
+    /// the task proxying the port to the current run of its container
+    proxy: Option<JoinHandle<()>>,
+}
+
+impl PodPorts {
+    /// Proxies `addr` to `target`, binding it unless it is already bound, in
+    /// which case it stops being proxied to the previous target.
+    fn proxy(&mut self, addr: SocketAddr, target: SocketAddr) -> anyhow::Result<()> {
+        let port = match self.ports.entry(addr) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let listener = std::net::TcpListener::bind(addr)
+                    .map_err(|e| anyhow::anyhow!("unable to bind {}: {}", addr, e))?;
+                listener.set_nonblocking(true)?;
+                entry.insert(Port {
+                    listener,
+                    proxy: None,
+                })
+            }
+        };
+        let front = TcpListener::from_std(port.listener.try_clone()?)?;
+        if let Some(previous) = port.proxy.replace(tokio::spawn(proxy(front, target))) {
+            previous.abort();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Port {
+    fn drop(&mut self) {
+        if let Some(proxy) = &self.proxy {
+            proxy.abort();
+        }
+    }
+}
+
+/// The network access of a container that has opted in to networking.
+#[derive(Debug)]
+pub(crate) struct Network {
+    /// the sockets the module listens on, one for each port
+    listeners: Vec<std::net::TcpListener>,
+    allowed_hosts: Vec<String>,
+}
+
+impl Network {
+    /// Starts proxying the TCP ports declared by `container` to the module,
+    /// if `pod` has opted in to networking, binding them in `ports` unless
+    /// a previous run of the container already has.
+    pub fn new(
+        pod: &Pod,
+        container: &Container,
+        addresses: PodAddresses,
+        ports: &mut PodPorts,
+    ) -> anyhow::Result<Option<Self>> {
+        if pod.get_annotation(SOCKETS_ANNOTATION) != Some("true") {
+            return Ok(None);
+        }
+        let mut network = Network {
+            listeners: vec![],
+            allowed_hosts: pod
+                .get_annotation(ALLOWED_HOSTS_ANNOTATION)
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|host| !host.is_empty())
+                .map(str::to_string)
+                .collect(),
+        };
+        for port in container.ports().iter().flatten() {
+            let protocol = port.protocol.as_deref().unwrap_or("TCP");
+            if protocol != "TCP" {
+                anyhow::bail!(
+                    "port {} uses {}, but only TCP ports are supported",
+                    port.container_port,
+                    protocol
+                );
+            }
+            // The module only ever sees connections from the proxies
+            let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+            let target = listener.local_addr()?;
+            let mut bound = vec![SocketAddr::new(
+                addresses.pod_ip,
+                port.container_port as u16,
+            )];
+            if let Some(host_port) = port.host_port {
+                let host_addr = SocketAddr::new(addresses.host_ip, host_port as u16);
+                if !bound.contains(&host_addr) {
+                    bound.push(host_addr);
+                }
+            }
+            for addr in bound {
+                info!(
+                    "Proxying {} to port {} of container {}",
+                    addr,
+                    port.container_port,
+                    container.name()
+                );
+                ports.proxy(addr, target)?;
+            }
+            network.listeners.push(listener);
+        }
+        Ok(Some(network))
+    }
+}
+
+/// Forwards the connections accepted by `front` to `target`.
+async fn proxy(front: TcpListener, target: SocketAddr) {
+    loop {
+        let (inbound, peer) = match front.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Unable to accept connection for {}: {:?}", target, e);
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            if let Err(e) = forward(inbound, target).await {
+                debug!("Connection from {} to {} closed: {:?}", peer, target, e);
+            }
+        });
+    }
+}
+
+async fn forward(mut inbound: TcpStream, target: SocketAddr) -> std::io::Result<()> {
+    let mut outbound = TcpStream::connect(target).await?;
+    let (mut inbound_read, mut inbound_write) = inbound.split();
+    let (mut outbound_read, mut outbound_write) = outbound.split();
+    let to_target = async {
+        tokio::io::copy(&mut inbound_read, &mut outbound_write).await?;
+        outbound_write.shutdown().await
+    };
+    let from_target = async {
+        tokio::io::copy(&mut outbound_read, &mut inbound_write).await?;
+        inbound_write.shutdown().await
+    };
+    tokio::try_join!(to_target, from_target)?;
+    Ok(())
+}
+
+/// The sockets of one instance of a module.
+pub(crate) struct Sockets {
+    /// listening sockets by the fd they are preopened at
+    listeners: HashMap<u32, TcpListener>,
+    allowed_hosts: Vec<String>,
+    /// notified when the module is stopped, so that it isn't stuck waiting
+    stopped: Arc<Notify>,
+}
+
+impl Sockets {
+    /// Sets up networking for an instance of a module using `ctx`. Only the
+    /// module's main run `listen`s on the container's ports, so that exec
+    /// commands don't take its connections. This must be called from a
+    /// blocking task.
+    pub fn new(
+        network: &Network,
+        ctx: &mut WasiCtx,
+        listen: bool,
+        stopped: Arc<Notify>,
+    ) -> anyhow::Result<Self> {
+        let mut listeners = HashMap::new();
+        if listen && !network.listeners.is_empty() {
+            let start = free_fd(ctx);
+            for (fd, listener) in (start..).zip(network.listeners.iter()) {
+                let listener = listener.try_clone()?;
+                listener.set_nonblocking(true)?;
+                ctx.insert_file(fd, Box::new(SocketFile::Listener), FileCaps::FILESTAT_GET);
+                listeners.insert(fd, TcpListener::from_std(listener)?);
+            }
+            ctx.env
+                .push(format!("LISTEN_FDS={}", network.listeners.len()))?;
+            ctx.env.push(format!("LISTEN_FDS_START={}", start))?;
+        }
+        Ok(Sockets {
+            listeners,
+            allowed_hosts: network.allowed_hosts.clone(),
+            stopped,
+        })
+    }
+
+    /// Defines the socket functions in `linker`, for the module instance
+    /// using `ctx`.
+    pub fn add_to_linker(
+        self,
+        ctx: Rc<RefCell<WasiCtx>>,
+        linker: &mut Linker,
+    ) -> anyhow::Result<()> {
+        let sockets = Rc::new(self);
+        let (accept_sockets, accept_ctx) = (sockets.clone(), ctx.clone());
+        linker.func(
+            "wasi_snapshot_preview1",
+            "sock_accept",
+            move |caller: Caller<'_>, fd: i32, _flags: i32, result: i32| -> i32 {
+                errno(accept_sockets.accept(&accept_ctx.borrow(), &caller, fd as u32, result))
+            },
+        )?;
+        linker.func(
+            SOCKETS_MODULE,
+            "connect",
+            move |caller: Caller<'_>, address: i32, address_len: i32, result: i32| -> i32 {
+                errno(sockets.connect(&ctx.borrow(), &caller, address, address_len, result))
+            },
+        )?;
+        Ok(())
+    }
+
+    fn accept(
+        &self,
+        ctx: &WasiCtx,
+        caller: &Caller<'_>,
+        fd: u32,
+        result: i32,
+    ) -> Result<(), Errno> {
+        let listener = match self.listeners.get(&fd) {
+            Some(listener) if ctx.table().contains_key(fd) => listener,
+            _ if ctx.table().contains_key(fd) => return Err(Errno::Notsock),
+            _ => return Err(Errno::Badf),
+        };
+        let (stream, peer) = block_on(&self.stopped, listener.accept()).map_err(io_errno)?;
+        debug!("Accepted connection from {}", peer);
+        self.insert_stream(ctx, caller, stream, result)
+    }
+
+    fn connect(
+        &self,
+        ctx: &WasiCtx,
+        caller: &Caller<'_>,
+        address: i32,
+        address_len: i32,
+        result: i32,
+    ) -> Result<(), Errno> {
+        let address = read_address(&memory(caller)?, address, address_len)?;
+        let (host, _) = address.rsplit_once(':').ok_or(Errno::Inval)?;
+        if !self
+            .allowed_hosts
+            .iter()
+            .any(|allowed| allowed == &address || allowed == host)
+        {
+            info!("Refusing connection to {}, which isn't allowed", address);
+            return Err(Errno::Acces);
+        }
+        let stream =
+            block_on(&self.stopped, TcpStream::connect(address.as_str())).map_err(io_errno)?;
+        debug!("Connected to {}", address);
+        self.insert_stream(ctx, caller, stream, result)
+    }
+
+    /// Adds a connection to the module's files and writes its fd to `result`.
+    fn insert_stream(
+        &self,
+        ctx: &WasiCtx,
+        caller: &Caller<'_>,
+        stream: TcpStream,
+        result: i32,
+    ) -> Result<(), Errno> {
+        let fd = free_fd(ctx);
+        memory(caller)?
+            .write(result as u32 as usize, &fd.to_le_bytes())
+            .map_err(|_| Errno::Fault)?;
+        let file = SocketFile::Stream {
+            stream,
+            stopped: self.stopped.clone(),
+        };
+        let caps = FileCaps::READ | FileCaps::WRITE | FileCaps::FILESTAT_GET;
+        ctx.insert_file(fd, Box::new(file), caps);
+        Ok(())
+    }
+}
+
+fn memory(caller: &Caller<'_>) -> Result<wasmtime::Memory, Errno> {
+    caller
+        .get_export("memory")
+        .and_then(|export| export.into_memory())
+        .ok_or(Errno::Inval)
+}
+
+/// Reads the `host:port` address a module asked to connect to from its memory.
+/// Addresses are refused if they are longer than any host name could be, so
+/// that modules can't make the kubelet allocate however much they like.
+fn read_address(
+    memory: &wasmtime::Memory,
+    address: i32,
+    address_len: i32,
+) -> Result<String, Errno> {
+    let address_len = address_len as u32;
+    if address_len > MAX_ADDRESS_LEN {
+        return Err(Errno::Inval);
+    }
+    let mut address_bytes = vec![0; address_len as usize];
+    memory
+        .read(address as u32 as usize, &mut address_bytes)
+        .map_err(|_| Errno::Fault)?;
+    String::from_utf8(address_bytes).map_err(|_| Errno::Ilseq)
+}
+
+/// The lowest fd after stdio that isn't in use.
+fn free_fd(ctx: &WasiCtx) -> u32 {
+    let table = ctx.table();
+    (FIRST_FD..).find(|fd| !table.contains_key(*fd)).unwrap()
+}
+
+/// Waits for a socket operation on the module's thread, giving up when the
+/// module is stopped.
+fn block_on<T>(
+    stopped: &Notify,
+    operation: impl Future<Output = std::io::Result<T>>,
+) -> std::io::Result<T> {
+    // Modules run on a blocking thread, so it is fine to block it here
+    futures::executor::block_on(async {
+        tokio::select! {
+            result = operation => result,
+            _ = stopped.notified() => Err(std::io::ErrorKind::Interrupted.into()),
+        }
+    })
+}
+
+fn errno(result: Result<(), Errno>) -> i32 {
+    match result {
+        Ok(()) => Errno::Success as i32,
+        Err(errno) => errno as i32,
+    }
+}
+
+fn io_errno(error: std::io::Error) -> Errno {
+    use std::io::ErrorKind;
+    match error.kind() {
+        ErrorKind::ConnectionRefused => Errno::Connrefused,
+        ErrorKind::ConnectionReset => Errno::Connreset,
+        ErrorKind::ConnectionAborted => Errno::Connaborted,
+        ErrorKind::NotConnected => Errno::Notconn,
+        ErrorKind::AddrNotAvailable => Errno::Addrnotavail,
+        ErrorKind::TimedOut => Errno::Timedout,
+        ErrorKind::Interrupted => Errno::Intr,
+        ErrorKind::InvalidInput => Errno::Inval,
+        _ => Errno::Io,
+    }
+}
+
+/// A socket in a module's file table. Listening sockets are only placeholders
+/// that keep their fd in use, as connections are accepted from [`Sockets`].
+enum SocketFile {
+    Listener,
+    Stream {
+        stream: TcpStream,
+        stopped: Arc<Notify>,
+    },
+}
+
+impl SocketFile {
+    fn stream(&self) -> Result<(&TcpStream, &Notify), Error> {
+        match self {
+            SocketFile::Listener => Err(Error::not_supported()),
+            SocketFile::Stream { stream, stopped } => Ok((stream, stopped)),
+        }
+    }
+}
+
+impl WasiFile for SocketFile {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn datasync(&self) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+    fn sync(&self) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+    fn get_filetype(&self) -> Result<FileType, Error> {
+        Ok(FileType::SocketStream)
+    }
+    fn get_fdflags(&self) -> Result<FdFlags, Error> {
+        Ok(FdFlags::empty())
+    }
+    fn set_fdflags(&mut self, _flags: FdFlags) -> Result<(), Error> {
+        Err(Error::not_supported())
+    }
+    fn get_filestat(&self) -> Result<Filestat, Error> {
+        Ok(Filestat {
+            device_id: 0,
+            inode: 0,
+            filetype: self.get_filetype()?,
+            nlink: 0,
+            size: 0,
+            atim: None,
+            mtim: None,
+            ctim: None,
+        })
+    }
+    fn set_filestat_size(&self, _size: u64) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+    fn advise(&self, _offset: u64, _len: u64, _advice: Advice) -> Result<(), Error> {
+        Err(Error::seek_pipe())
+    }
+    fn allocate(&self, _offset: u64, _len: u64) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+    fn set_times(
+        &self,
+        _atime: Option<SystemTimeSpec>,
+        _mtime: Option<SystemTimeSpec>,
+    ) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+    fn read_vectored(&self, bufs: &mut [IoSliceMut]) -> Result<u64, Error> {
+        let (stream, stopped) = self.stream()?;
+        let buf = match bufs.iter_mut().find(|buf| !buf.is_empty()) {
+            Some(buf) => buf,
+            None => return Ok(0),
+        };
+        let read = block_on(stopped, async {
+            loop {
+                stream.readable().await?;
+                match stream.try_read(buf) {
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                    result => return result,
+                }
+            }
+        })
+        .map_err(|e| Error::io().context(e))?;
+        Ok(read.try_into()?)
+    }
+    fn read_vectored_at(&self, _bufs: &mut [IoSliceMut], _offset: u64) -> Result<u64, Error> {
+        Err(Error::seek_pipe())
+    }
+    fn write_vectored(&self, bufs: &[IoSlice]) -> Result<u64, Error> {
+        let (stream, stopped) = self.stream()?;
+        let buf = match bufs.iter().find(|buf| !buf.is_empty()) {
+            Some(buf) => buf,
+            None => return Ok(0),
+        };
+        let written = block_on(stopped, async {
+            loop {
+                stream.writable().await?;
+                match stream.try_write(buf) {
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                    result => return result,
+                }
+            }
+        })
+        .map_err(|e| Error::io().context(e))?;
+        Ok(written.try_into()?)
+    }
+    fn write_vectored_at(&self, _bufs: &[IoSlice], _offset: u64) -> Result<u64, Error> {
+        Err(Error::seek_pipe())
+    }
+    fn seek(&self, _pos: std::io::SeekFrom) -> Result<u64, Error> {
+        Err(Error::seek_pipe())
+    }
+    fn peek(&self, _buf: &mut [u8]) -> Result<u64, Error> {
+        Err(Error::seek_pipe())
+    }
+    fn num_ready_bytes(&self) -> Result<u64, Error> {
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn restarted_containers_take_over_their_ports() {
+        let addr = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut ports = PodPorts::default();
+        let previous = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        ports.proxy(addr, previous.local_addr().unwrap()).unwrap();
+        let current = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        ports.proxy(addr, current.local_addr().unwrap()).unwrap();
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        client.shutdown().await.unwrap();
+        let (mut connection, _) = current.accept().await.unwrap();
+        let mut received = String::new();
+        connection.read_to_string(&mut received).await.unwrap();
+        assert_eq!(received, "hello");
+    }
+
+    #[test]
+    fn addresses_are_read_within_bounds() {
+        let store = wasmtime::Store::default();
+        let memory_type = wasmtime::MemoryType::new(wasmtime::Limits::new(1, None));
+        let memory = wasmtime::Memory::new(&store, memory_type);
+        memory.write(16, b"example.com:80").unwrap();
+
+        assert_eq!(read_address(&memory, 16, 14).unwrap(), "example.com:80");
+        assert_eq!(read_address(&memory, 16, -1), Err(Errno::Inval));
+        assert_eq!(
+            read_address(&memory, 16, MAX_ADDRESS_LEN as i32 + 1),
+            Err(Errno::Inval)
+        );
+        assert_eq!(read_address(&memory, 65530, 14), Err(Errno::Fault));
+    }
+}
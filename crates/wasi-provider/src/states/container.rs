@@ -1,6 +1,7 @@
 use crate::ModuleRunContext;
 use crate::ProviderState;
 use krator::{ObjectState, SharedState};
+use kubelet::backoff::ExponentialBackoffStrategy;
 use kubelet::container::{Container, ContainerKey, Status};
 use kubelet::pod::Pod;
 
@@ -12,6 +13,9 @@ pub(crate) struct ContainerState {
     pod: Pod,
     container_key: ContainerKey,
     run_context: SharedState<ModuleRunContext>,
+    /// Backs off retries of starting the container when its configuration
+    /// can't be resolved
+    config_error_backoff: ExponentialBackoffStrategy,
 }
 
 impl ContainerState {
@@ -24,6 +28,7 @@ impl ContainerState {
             pod,
             container_key,
             run_context,
+            config_error_backoff: ExponentialBackoffStrategy::default(),
         }
     }
 }
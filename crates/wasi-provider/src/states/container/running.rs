@@ -1,20 +1,92 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use super::terminated::Terminated;
 use super::ContainerState;
-use crate::ProviderState;
+use crate::runtime::{discarded_output, HandleFactory};
+use crate::scratch::StorageLimit;
+use crate::termination::TerminationLog;
+use crate::{PodHandleMap, ProviderState};
 use kubelet::container::state::prelude::*;
-use tokio::sync::mpsc::Receiver;
-use tracing::{debug, warn};
+use kubelet::container::{patch_container_readiness, ContainerKey};
+use kubelet::pod::PodKey;
+use kubelet::probe::{Exec, ProbeEvent, Probes};
+use kubelet::provider::ProviderError;
+use kubelet::state::common::GenericProviderState;
+use tokio::sync::mpsc::{self, Receiver};
+use tracing::{debug, error, warn};
+
+/// How often the usage of a container's scratch directory is measured
+const STORAGE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Runs the commands of exec probes in the container, as `kubectl exec` would.
+struct ContainerExec {
+    handles: PodHandleMap,
+    pod_key: PodKey,
+    container_key: ContainerKey,
+}
+
+#[async_trait::async_trait]
+impl Exec for ContainerExec {
+    async fn exec(&self, command: Vec<String>) -> anyhow::Result<i32> {
+        let exec = {
+            let handles = self.handles.read().await;
+            let handle = handles
+                .get(&self.pod_key)
+                .ok_or_else(|| ProviderError::PodNotFound {
+                    pod_name: self.pod_key.name(),
+                })?;
+            handle
+                .with_container_handle(&self.container_key.name(), |container| {
+                    container.handle().exec(command, discarded_output())
+                })
+                .await?
+        };
+        exec.await
+    }
+}
 
 /// The container is starting.
 #[derive(Debug, TransitionTo)]
 #[transition_to(Terminated)]
 pub struct Running {
     rx: Receiver<Status>,
+    probes: Probes,
+    termination_log: TerminationLog,
+    storage_limit: Option<StorageLimit>,
+    logs: HandleFactory,
 }
 
 impl Running {
-    pub fn new(rx: Receiver<Status>) -> Self {
-        Running { rx }
+    pub(crate) fn new(
+        rx: Receiver<Status>,
+        probes: Probes,
+        termination_log: TerminationLog,
+        storage_limit: Option<StorageLimit>,
+        logs: HandleFactory,
+    ) -> Self {
+        Running {
+            rx,
+            probes,
+            termination_log,
+            storage_limit,
+            logs,
+        }
+    }
+}
+
+/// Stops the container's module, such as when it has to be killed.
+async fn stop_container(handles: &PodHandleMap, pod_key: &PodKey, state: &ContainerState) {
+    let handle = handles.read().await.get(pod_key).cloned();
+    if let Some(handle) = handle {
+        if let Err(e) = handle.stop_container(&state.container_key).await {
+            warn!(
+                "Pod {} container {} could not be stopped: {:?}",
+                state.pod.name(),
+                state.container_key,
+                e
+            );
+        }
     }
 }
 
@@ -22,25 +94,122 @@ impl Running {
 impl State<ContainerState> for Running {
     async fn next(
         mut self: Box<Self>,
-        _shared_state: SharedState<ProviderState>,
-        _state: &mut ContainerState,
+        shared_state: SharedState<ProviderState>,
+        state: &mut ContainerState,
         _container: Manifest<Container>,
     ) -> Transition<ContainerState> {
+        let (client, handles) = {
+            let provider_state = shared_state.read().await;
+            (provider_state.client(), provider_state.handles.clone())
+        };
+        let api = kube::Api::namespaced(client, state.pod.namespace());
+        let pod_key = PodKey::from(&state.pod);
+
+        // Containers without probes are ready as soon as they are running
+        let (probe_tx, mut probe_rx) = mpsc::channel(4);
+        let probes = if self.probes.is_empty() {
+            None
+        } else {
+            let exec = Arc::new(ContainerExec {
+                handles: handles.clone(),
+                pod_key: pod_key.clone(),
+                container_key: state.container_key.clone(),
+            });
+            Some(tokio::spawn(self.probes.clone().run(exec, probe_tx)))
+        };
+
+        let rx = &mut self.rx;
+        let (termination_log, logs) = (&self.termination_log, &self.logs);
+        let storage_limit = &self.storage_limit;
+        let mut storage_checks = tokio::time::interval(STORAGE_CHECK_INTERVAL);
+        let mut started = false;
         debug!("Awaiting container status updates");
-        while let Some(status) = self.rx.recv().await {
-            debug!("Got status update from WASI Runtime: {:?}", &status);
-            if let Status::Terminated {
-                failed, message, ..
-            } = status
-            {
-                return Transition::next(self, Terminated::new(message, failed));
+        let terminated = loop {
+            tokio::select! {
+                status = rx.recv() => match status {
+                    Some(status) => {
+                        debug!("Got status update from WASI Runtime: {:?}", &status);
+                        if let Status::Terminated {
+                            failed,
+                            message,
+                            exit_code,
+                            reason,
+                            ..
+                        } = status
+                        {
+                            // Like a container's, the message is whatever the
+                            // module left in its termination message file
+                            let message = termination_log
+                                .message(failed, logs)
+                                .await
+                                .unwrap_or(message);
+                            break match (reason, exit_code) {
+                                (Some(reason), _) => Terminated::with_reason(reason, message, failed),
+                                (None, Some(exit_code)) => Terminated::exited(message, exit_code),
+                                (None, None) => Terminated::new(message, failed),
+                            };
+                        }
+                    }
+                    None => {
+                        warn!("WASI Runtime hung up channel.");
+                        break Terminated::new("WASI Runtime hung up channel.".to_string(), true);
+                    }
+                },
+                _ = storage_checks.tick(), if storage_limit.is_some() => {
+                    let storage_limit = storage_limit.as_ref().unwrap();
+                    if let Some(usage) = storage_limit.exceeded().await {
+                        let message = format!(
+                            "Container {} exceeded its local ephemeral storage limit of {} bytes, using {} bytes",
+                            state.container_key,
+                            storage_limit.limit(),
+                            usage
+                        );
+                        error!("Pod {} {}, stopping it", state.pod.name(), message);
+                        stop_container(&handles, &pod_key, state).await;
+                        break Terminated::new(message, true);
+                    }
+                }
+                Some(event) = probe_rx.recv() => {
+                    let ready = match event {
+                        ProbeEvent::Started => {
+                            started = true;
+                            false
+                        }
+                        ProbeEvent::Ready(ready) => ready,
+                        ProbeEvent::Unhealthy(message) => {
+                            error!(
+                                "Pod {} container {} is unhealthy, stopping it: {}",
+                                state.pod.name(),
+                                state.container_key,
+                                message
+                            );
+                            stop_container(&handles, &pod_key, state).await;
+                            break Terminated::new(message, true);
+                        }
+                    };
+                    if let Err(e) = patch_container_readiness(
+                        &api,
+                        &state.pod,
+                        &state.container_key,
+                        started,
+                        ready,
+                    )
+                    .await
+                    {
+                        warn!(
+                            "Pod {} container {} readiness patch request returned error: {:?}",
+                            state.pod.name(),
+                            state.container_key,
+                            e
+                        );
+                    }
+                }
             }
+        };
+        if let Some(probes) = probes {
+            probes.abort();
         }
-        warn!("WASI Runtime hung up channel.");
-        Transition::next(
-            self,
-            Terminated::new("WASI Runtime hung up channel.".to_string(), true),
-        )
+        Transition::next(self, terminated)
     }
 
     async fn status(
@@ -11,11 +11,39 @@ use super::ContainerState;
 pub struct Terminated {
     message: String,
     failed: bool,
+    exit_code: Option<i32>,
+    /// why the container was terminated, such as `OOMKilled`
+    reason: Option<String>,
 }
 
 impl Terminated {
     pub fn new(message: String, failed: bool) -> Self {
-        Terminated { message, failed }
+        Terminated {
+            message,
+            failed,
+            exit_code: None,
+            reason: None,
+        }
+    }
+
+    /// Creates a terminated state for a module that was stopped for `reason`.
+    pub fn with_reason(reason: String, message: String, failed: bool) -> Self {
+        Terminated {
+            message,
+            failed,
+            exit_code: None,
+            reason: Some(reason),
+        }
+    }
+
+    /// Creates a terminated state for a module that exited with `exit_code`.
+    pub fn exited(message: String, exit_code: i32) -> Self {
+        Terminated {
+            message,
+            failed: exit_code != 0,
+            exit_code: Some(exit_code),
+            reason: None,
+        }
     }
 }
 
@@ -47,6 +75,14 @@ impl State<ContainerState> for Terminated {
         _state: &mut ContainerState,
         _container: &Container,
     ) -> anyhow::Result<Status> {
-        Ok(Status::terminated(&self.message, self.failed))
+        match (&self.reason, self.exit_code) {
+            (Some(reason), _) => Ok(Status::terminated_with_reason(
+                reason,
+                &self.message,
+                self.failed,
+            )),
+            (None, Some(exit_code)) => Ok(Status::exited(&self.message, exit_code)),
+            (None, None) => Ok(Status::terminated(&self.message, self.failed)),
+        }
     }
 }
@@ -1,17 +1,25 @@
 use std::collections::HashMap;
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 
 use tokio::sync::mpsc;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
+use kubelet::backoff::BackoffStrategy;
 use kubelet::container::state::prelude::*;
-use kubelet::pod::{Handle as PodHandle, PodKey};
+use kubelet::lifecycle::Hooks;
+use kubelet::pod::{Handle as PodHandle, Pod, PodKey};
+use kubelet::probe::Probes;
 use kubelet::state::common::GenericProviderState;
 use kubelet::volume::Ref;
 
-use crate::wasi_runtime::WasiRuntime;
+use crate::lifecycle::Lifecycle;
+use crate::runtime::{Engine, ModuleSpec};
+use crate::scratch::{self, StorageLimit};
+use crate::sockets::Network;
+use crate::termination::TerminationLog;
+use crate::wasi_runtime::{find_mount, Mount, ResourceLimits, Stdin};
 use crate::ProviderState;
 
 use super::running::Running;
@@ -21,11 +29,11 @@ use super::ContainerState;
 fn volume_path_map(
     container: &Container,
     volumes: &HashMap<String, Ref>,
-) -> anyhow::Result<HashMap<PathBuf, Option<PathBuf>>> {
+) -> anyhow::Result<HashMap<PathBuf, Mount>> {
     if let Some(volume_mounts) = container.volume_mounts().as_ref() {
         volume_mounts
             .iter()
-            .map(|vm| -> anyhow::Result<(PathBuf, Option<PathBuf>)> {
+            .map(|vm| -> anyhow::Result<(PathBuf, Mount)> {
                 // Check the volume exists first
                 let vol = volumes.get(&vm.name).ok_or_else(|| {
                     anyhow::anyhow!(
@@ -40,18 +48,99 @@ fn volume_path_map(
                 }
                 // We can safely assume that this should be valid UTF-8 because it would have
                 // been validated by the k8s API
-                Ok((vol.deref().clone(), Some(guest_path)))
+                let mount = Mount {
+                    guest_path: Some(guest_path),
+                    read_only: vm.read_only.unwrap_or(false),
+                };
+                Ok((vol.deref().clone(), mount))
             })
-            .collect::<anyhow::Result<HashMap<PathBuf, Option<PathBuf>>>>()
+            .collect::<anyhow::Result<HashMap<PathBuf, Mount>>>()
     } else {
         Ok(HashMap::default())
     }
 }
 
+/// Finds the host directory of the container's working directory, which is in
+/// its scratch root file system unless a volume is mounted over it, and is
+/// read-only if that mount is. As in a container, it is created if it doesn't
+/// exist yet.
+async fn working_dir(
+    container: &Container,
+    volumes: &HashMap<PathBuf, Mount>,
+) -> anyhow::Result<Option<(PathBuf, bool)>> {
+    let working_dir = match container.working_dir() {
+        Some(working_dir) => Path::new(working_dir),
+        None => return Ok(None),
+    };
+    let (host_dir, mount, rest) = find_mount(volumes, working_dir)
+        .ok_or_else(|| anyhow::anyhow!("{} is not an absolute path", working_dir.display()))?;
+    if !rest.components().all(|c| matches!(c, Component::Normal(_))) {
+        anyhow::bail!("{} is not a normalized path", working_dir.display());
+    }
+    let host_dir = host_dir.join(rest);
+    tokio::fs::create_dir_all(&host_dir).await?;
+    Ok(Some((host_dir, mount.read_only)))
+}
+
+fn resource_limits(container: &Container) -> anyhow::Result<ResourceLimits> {
+    Ok(ResourceLimits {
+        memory: container.memory_limit()?,
+        cpu: container.cpu_limit()?,
+    })
+}
+
+/// Splits the container's command into the export to call and the arguments to
+/// pass to it. Like a container image's entrypoint, the first item of the
+/// command picks what to run and the rest come before the container's args.
+/// Modules without the export are run from `_start` with the whole command
+/// before the container's args instead.
+fn entrypoint_and_args(container: &Container) -> (Option<String>, Vec<String>) {
+    let mut command = container.command().clone().unwrap_or_default().into_iter();
+    let entrypoint = command.next();
+    let args = command
+        .chain(container.args().clone().unwrap_or_default())
+        .collect();
+    (entrypoint, args)
+}
+
+fn stdin(container: &Container) -> Stdin {
+    match (container.stdin(), container.stdin_once()) {
+        (Some(true), Some(true)) => Stdin::Once,
+        (Some(true), _) => Stdin::Open,
+        _ => Stdin::Closed,
+    }
+}
+
 /// The container is starting.
 #[derive(Default, Debug, TransitionTo)]
-#[transition_to(Running, Terminated)]
-pub struct Waiting;
+#[transition_to(Running, Terminated, Waiting)]
+pub struct Waiting {
+    /// Why the container couldn't be configured the last time it was
+    /// started, if it couldn't
+    config_error: Option<String>,
+}
+
+impl Waiting {
+    /// The container couldn't be configured, for instance because a
+    /// ConfigMap it refers to doesn't exist, so starting it is retried after
+    /// a backoff.
+    fn config_error(message: String) -> Self {
+        Waiting {
+            config_error: Some(message),
+        }
+    }
+}
+
+/// Whether the pod is being deleted, in which case there is no point in
+/// retrying to start its containers.
+async fn pod_deleted(client: &kube::Client, pod: &Pod) -> bool {
+    let api: kube::Api<Pod> = kube::Api::namespaced(client.clone(), pod.namespace());
+    match api.get(pod.name()).await {
+        Ok(pod) => pod.deletion_timestamp().is_some(),
+        Err(kube::Error::Api(response)) => response.code == 404,
+        Err(_) => false,
+    }
+}
 
 #[async_trait::async_trait]
 impl State<ContainerState> for Waiting {
@@ -69,15 +158,40 @@ impl State<ContainerState> for Waiting {
             state.pod.name(),
         );
 
-        let (client, log_path) = {
+        let (client, log_path, scratch_path, module_cache, host_modules) = {
             let provider_state = shared.read().await;
-            (provider_state.client(), provider_state.log_path.clone())
+            (
+                provider_state.client(),
+                provider_state.log_path.clone(),
+                provider_state.scratch_path.clone(),
+                provider_state.module_cache.clone(),
+                provider_state.host_modules.clone(),
+            )
         };
 
-        let (module_data, container_volumes) = {
-            let mut run_context = state.run_context.write().await;
-            let module_data = match run_context.modules.remove(container.name()) {
-                Some(data) => data,
+        if self.config_error.is_some() {
+            state.config_error_backoff.wait().await;
+            if pod_deleted(&client, &state.pod).await {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} was deleted before container {} could be started",
+                            state.pod.name(),
+                            container.name()
+                        ),
+                        false,
+                    ),
+                );
+            }
+        }
+
+        let (module_data, mut container_volumes, addresses, runtime_handler) = {
+            let run_context = state.run_context.read().await;
+            // The module data is kept in the run context in case the
+            // container is restarted
+            let module_data = match run_context.modules.get(container.name()) {
+                Some(data) => data.clone(),
                 None => {
                     return Transition::next(
                         self,
@@ -109,11 +223,213 @@ impl State<ContainerState> for Waiting {
                     )
                 }
             };
-            (module_data, container_volumes)
+            (
+                module_data,
+                container_volumes,
+                run_context.addresses,
+                run_context.runtime_handler.clone(),
+            )
+        };
+
+        // The pod's status may not have been updated with its addresses yet,
+        // but the Downward API needs them
+        let mut pod = state.pod.clone();
+        pod.set_ips(
+            &addresses.pod_ip.to_string(),
+            &addresses.host_ip.to_string(),
+        );
+        let env = match kubelet::provider::env_vars(&container, &pod, &client).await {
+            Ok(env) => env,
+            Err(e) => {
+                warn!(
+                    "Pod {} container {} could not be configured: {}",
+                    state.pod.name(),
+                    container.name(),
+                    e
+                );
+                return Transition::next(self, Waiting::config_error(e.to_string()));
+            }
+        };
+        state.config_error_backoff.reset();
+        let (entrypoint, args) = entrypoint_and_args(&container);
+        let limits = match resource_limits(&container) {
+            Ok(limits) => limits,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} has invalid resource limits: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        };
+
+        let network = {
+            let mut run_context = state.run_context.write().await;
+            Network::new(&state.pod, &container, addresses, &mut run_context.ports)
+        };
+        let network = match network {
+            Ok(network) => network,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} failed to set up networking: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        };
+
+        let probes = match Probes::new(&container, &addresses.pod_ip.to_string()) {
+            Ok(probes) => probes,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} has invalid probes: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        };
+
+        let hooks = match Hooks::new(&container, &addresses.pod_ip.to_string()) {
+            Ok(hooks) => hooks,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} has invalid lifecycle hooks: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        };
+        let lifecycle = Arc::new(Lifecycle::new(
+            hooks,
+            client.clone(),
+            state.pod.clone(),
+            container.name().to_string(),
+        ));
+
+        let scratch_dir =
+            match scratch::container_dir(&state.run_context, &scratch_path, &state.pod, &container)
+                .await
+            {
+                Ok(scratch_dir) => scratch_dir,
+                Err(e) => {
+                    return Transition::next(
+                        self,
+                        Terminated::new(
+                            format!(
+                                "Pod {} container {} failed to create scratch directory: {:?}",
+                                state.pod.name(),
+                                container.name(),
+                                e
+                            ),
+                            true,
+                        ),
+                    )
+                }
+            };
+        let storage_limit = match StorageLimit::new(&container, &scratch_dir) {
+            Ok(storage_limit) => storage_limit,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} has an invalid ephemeral storage limit: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
         };
+        // The scratch directory is the module's root file system, which the
+        // volumes are mounted over
+        let read_only_root = container
+            .security_context()
+            .and_then(|context| context.read_only_root_filesystem)
+            .unwrap_or(false);
+        container_volumes.insert(
+            scratch_dir,
+            Mount {
+                guest_path: Some(PathBuf::from("/")),
+                read_only: read_only_root,
+            },
+        );
 
-        let env = kubelet::provider::env_vars(&container, &state.pod, &client).await;
-        let args = container.args().clone().unwrap_or_default();
+        let working_dir = match working_dir(&container, &container_volumes).await {
+            Ok(working_dir) => working_dir,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} has an invalid working directory: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        };
+
+        let termination_log =
+            match TerminationLog::new(&container, &container_volumes, &log_path).await {
+                Ok(termination_log) => termination_log,
+                Err(e) => {
+                    return Transition::next(
+                        self,
+                        Terminated::new(
+                            format!(
+                            "Pod {} container {} failed to create termination message file: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                            true,
+                        ),
+                    )
+                }
+            };
+        if let Some((host_dir, guest_dir)) = termination_log.mount() {
+            container_volumes.insert(
+                host_dir,
+                Mount {
+                    guest_path: Some(guest_dir),
+                    read_only: false,
+                },
+            );
+        }
 
         // TODO: ~magic~ number
         let (tx, rx) = mpsc::channel(8);
@@ -124,17 +440,41 @@ impl State<ContainerState> for Waiting {
             state.pod.name(),
             container.name()
         );
-        let runtime = match WasiRuntime::new(
+        let engine = match Engine::for_pod(&state.pod, runtime_handler.as_deref()) {
+            Ok(engine) => engine,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} can't be run: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        };
+        let spec = ModuleSpec {
             name,
             module_data,
+            entrypoint,
             env,
             args,
-            container_volumes,
-            log_path,
-            tx,
-        )
-        .await
-        {
+            stdin: stdin(&container),
+            dirs: container_volumes,
+            working_dir,
+            limits,
+            module_cache,
+            host_modules,
+            network,
+            lifecycle: lifecycle.clone(),
+            log_dir: log_path,
+            status_sender: tx,
+        };
+        let runtime = match engine.runtime(spec).await {
             Ok(runtime) => runtime,
             Err(e) => {
                 return Transition::next(
@@ -170,9 +510,25 @@ impl State<ContainerState> for Waiting {
             }
         };
         debug!("Container {} WASI Runtime started", container.name());
+        if let Err(e) = lifecycle.post_start(container_handle.handle()).await {
+            let mut container_handle = container_handle;
+            container_handle.stop().await.ok();
+            return Transition::next(
+                self,
+                Terminated::new(
+                    format!(
+                        "Pod {} container {} was stopped: {}",
+                        state.pod.name(),
+                        container.name(),
+                        e
+                    ),
+                    true,
+                ),
+            );
+        }
         let pod_key = PodKey::from(&state.pod);
         {
-            let provider_state = shared.write().await;
+            let provider_state = shared.read().await;
             let mut handles_writer = provider_state.handles.write().await;
             let pod_handle = handles_writer.entry(pod_key).or_insert_with(|| {
                 Arc::new(PodHandle::new(HashMap::new(), state.pod.clone(), None))
@@ -181,7 +537,11 @@ impl State<ContainerState> for Waiting {
                 .insert_container_handle(state.container_key.clone(), container_handle)
                 .await;
         }
-        Transition::next(self, Running::new(rx))
+        let logs = runtime.log_handle_factory();
+        Transition::next(
+            self,
+            Running::new(rx, probes, termination_log, storage_limit, logs),
+        )
     }
 
     async fn status(
@@ -189,6 +549,12 @@ impl State<ContainerState> for Waiting {
         _state: &mut ContainerState,
         _container: &Container,
     ) -> anyhow::Result<Status> {
-        Ok(Status::waiting("Module is starting."))
+        match &self.config_error {
+            Some(message) => Ok(Status::waiting_with_reason(
+                "CreateContainerConfigError",
+                message,
+            )),
+            None => Ok(Status::waiting("Module is starting.")),
+        }
     }
 }
@@ -1,12 +1,14 @@
+use crate::sockets::PodAddresses;
 use crate::ModuleRunContext;
 use crate::ProviderState;
 use async_trait::async_trait;
 use krator::{ObjectState, SharedState};
 use kubelet::backoff::BackoffStrategy;
 use kubelet::backoff::ExponentialBackoffStrategy;
+use kubelet::pod::state::prelude::StatusBuilder;
 use kubelet::pod::Pod;
 use kubelet::pod::PodKey;
-use kubelet::pod::Status;
+use kubelet::pod::{Phase, Status};
 use kubelet::state::common::{BackoffSequence, GenericPodState, ThresholdTrigger};
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -20,6 +22,7 @@ pub(crate) mod starting;
 /// State that is shared between pod state handlers.
 pub struct PodState {
     key: PodKey,
+    addresses: PodAddresses,
     run_context: SharedState<ModuleRunContext>,
     errors: usize,
     image_pull_backoff_strategy: ExponentialBackoffStrategy,
@@ -36,24 +39,42 @@ impl ObjectState for PodState {
             let mut handles = provider_state.handles.write().await;
             handles.remove(&self.key);
         }
+        provider_state.release_pod_addresses(&self.key);
     }
 }
 
 impl PodState {
-    pub fn new(pod: &Pod) -> Self {
+    pub(crate) fn new(pod: &Pod, addresses: PodAddresses) -> Self {
         let run_context = ModuleRunContext {
             modules: Default::default(),
             volumes: Default::default(),
+            scratch: None,
+            runtime_handler: None,
+            addresses,
+            ports: Default::default(),
         };
         let key = PodKey::from(pod);
         PodState {
             key,
+            addresses,
             run_context: Arc::new(RwLock::new(run_context)),
             errors: 0,
             image_pull_backoff_strategy: ExponentialBackoffStrategy::default(),
             crash_loop_backoff_strategy: ExponentialBackoffStrategy::default(),
         }
     }
+
+    /// Makes the status of a pod whose containers are being run, which
+    /// includes the pod's addresses.
+    pub(crate) fn running_status(&self, phase: Phase, reason: &str) -> Status {
+        StatusBuilder::new()
+            .phase(phase)
+            .reason(reason)
+            .message(reason)
+            .pod_ip(&self.addresses.pod_ip.to_string())
+            .host_ip(&self.addresses.host_ip.to_string())
+            .build()
+    }
 }
 
 #[async_trait]
@@ -62,6 +83,10 @@ impl GenericPodState for PodState {
         let mut run_context = self.run_context.write().await;
         run_context.modules = modules;
     }
+    async fn set_runtime_handler(&mut self, handler: Option<String>) {
+        let mut run_context = self.run_context.write().await;
+        run_context.runtime_handler = handler;
+    }
     async fn set_volumes(&mut self, volumes: HashMap<String, kubelet::volume::Ref>) {
         let mut run_context = self.run_context.write().await;
         run_context.volumes = volumes;
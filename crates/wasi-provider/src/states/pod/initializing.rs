@@ -45,7 +45,7 @@ impl State<PodState> for Initializing {
             // Each new init container resets the CrashLoopBackoff timer.
             pod_state.crash_loop_backoff_strategy.reset();
 
-            let initial_state = Waiting;
+            let initial_state = Waiting::default();
 
             let container_key = ContainerKey::Init(init_container.name().to_string());
             let container_state = ContainerState::new(
@@ -80,7 +80,7 @@ impl State<PodState> for Initializing {
         Transition::next(self, Starting)
     }
 
-    async fn status(&self, _pod_state: &mut PodState, _pmeod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Running, "Initializing"))
+    async fn status(&self, pod_state: &mut PodState, _pmeod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(pod_state.running_status(Phase::Running, "Initializing"))
     }
 }
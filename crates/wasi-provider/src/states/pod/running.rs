@@ -1,23 +1,42 @@
-use tokio::sync::mpsc::Receiver;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use tokio::sync::mpsc::{Receiver, Sender};
+use tracing::{info, warn};
+
+use kubelet::backoff::{BackoffStrategy, ExponentialBackoffStrategy};
+use kubelet::container::{
+    patch_container_restart_count, patch_container_status, ContainerKey, Status as ContainerStatus,
+};
 use kubelet::pod::state::prelude::*;
 use kubelet::state::common::error::Error;
 use kubelet::state::common::GenericProviderState;
 
 use super::completed::Completed;
+use super::starting::run_container;
 use crate::fail_fatal;
 use crate::{PodState, ProviderState};
 
+/// How long a container has to run for before it is no longer considered to
+/// be crash looping, as in the Kubernetes kubelet.
+const CRASH_LOOP_RESET_AFTER: Duration = Duration::from_secs(600);
+
 /// The Kubelet is running the Pod.
 #[derive(Debug, TransitionTo)]
 #[transition_to(Completed, Error<crate::WasiProvider>)]
 pub struct Running {
-    rx: Receiver<anyhow::Result<()>>,
+    tx: Sender<(ContainerKey, anyhow::Result<()>)>,
+    rx: Receiver<(ContainerKey, anyhow::Result<()>)>,
 }
 
 impl Running {
-    pub fn new(rx: Receiver<anyhow::Result<()>>) -> Self {
-        Running { rx }
+    pub fn new(
+        tx: Sender<(ContainerKey, anyhow::Result<()>)>,
+        rx: Receiver<(ContainerKey, anyhow::Result<()>)>,
+    ) -> Self {
+        Running { tx, rx }
     }
 }
 
@@ -26,15 +45,71 @@ impl State<PodState> for Running {
     async fn next(
         mut self: Box<Self>,
         provider_state: SharedState<ProviderState>,
-        _pod_state: &mut PodState,
-        pod: Manifest<Pod>,
+        pod_state: &mut PodState,
+        manifest: Manifest<Pod>,
     ) -> Transition<PodState> {
-        let pod = pod.latest();
-
+        let pod = manifest.latest();
+        let restart_policy = pod.restart_policy();
         let mut completed = 0;
         let total_containers = pod.containers().len();
+        let mut started_at: HashMap<ContainerKey, Instant> = pod
+            .containers()
+            .iter()
+            .map(|c| (ContainerKey::App(c.name().to_string()), Instant::now()))
+            .collect();
+        let mut restart_counts: HashMap<ContainerKey, i32> = HashMap::new();
+        let mut backoffs: HashMap<ContainerKey, ExponentialBackoffStrategy> = HashMap::new();
+
+        while let Some((container_key, result)) = self.rx.recv().await {
+            if restart_policy.should_restart(result.is_err()) {
+                if let Err(e) = &result {
+                    warn!(
+                        "Pod {} container {} failed, restarting: {:?}",
+                        pod.name(),
+                        container_key,
+                        e
+                    );
+                } else {
+                    info!(
+                        "Pod {} container {} completed, restarting.",
+                        pod.name(),
+                        container_key
+                    );
+                }
+
+                // Each container backs off on its own, so one crash looping
+                // doesn't hold up restarting the others
+                let backoff = backoffs.entry(container_key.clone()).or_default();
+                let ran_for = started_at
+                    .get(&container_key)
+                    .map(Instant::elapsed)
+                    .unwrap_or_default();
+                if ran_for >= CRASH_LOOP_RESET_AFTER {
+                    backoff.reset();
+                }
+                let delay = backoff.next_duration();
+                let restart_count = restart_counts.entry(container_key.clone()).or_insert(0);
+                *restart_count += 1;
+
+                started_at.insert(container_key.clone(), Instant::now() + delay);
+                let run = run_container(
+                    &provider_state,
+                    pod_state,
+                    &manifest,
+                    container_key.clone(),
+                    self.tx.clone(),
+                );
+                tokio::spawn(restart_after(
+                    Arc::clone(&provider_state),
+                    manifest.clone(),
+                    container_key,
+                    *restart_count,
+                    delay,
+                    run,
+                ));
+                continue;
+            }
 
-        while let Some(result) = self.rx.recv().await {
             match result {
                 Ok(()) => {
                     completed += 1;
@@ -45,7 +120,7 @@ impl State<PodState> for Running {
                 Err(e) => {
                     // Stop remaining containers;
                     {
-                        let provider = provider_state.write().await;
+                        let provider = provider_state.read().await;
                         provider.stop(&pod).await.ok();
                     }
                     fail_fatal!(e);
@@ -61,7 +136,50 @@ impl State<PodState> for Running {
         )
     }
 
-    async fn status(&self, _pod_state: &mut PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Running, "Running"))
+    async fn status(&self, pod_state: &mut PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(pod_state.running_status(Phase::Running, "Running"))
+    }
+}
+
+/// Restarts a container once it has backed off for `delay`, reporting it as
+/// crash looping in the meantime.
+async fn restart_after(
+    provider_state: SharedState<ProviderState>,
+    manifest: Manifest<Pod>,
+    container_key: ContainerKey,
+    restart_count: i32,
+    delay: Duration,
+    run: impl Future<Output = ()>,
+) {
+    let pod = manifest.latest();
+    let client = {
+        let provider_state = provider_state.read().await;
+        kube::Api::namespaced(provider_state.client(), pod.namespace())
+    };
+    let waiting = ContainerStatus::waiting_with_reason(
+        "CrashLoopBackOff",
+        "back-off restarting failed container",
+    );
+    if let Err(e) = patch_container_status(&client, &pod, &container_key, &waiting).await {
+        warn!(
+            "Pod {} container {} status patch request returned error: {:?}",
+            pod.name(),
+            container_key,
+            e
+        );
+    }
+    tokio::time::sleep(delay).await;
+
+    if let Err(e) =
+        patch_container_restart_count(&client, &manifest.latest(), &container_key, restart_count)
+            .await
+    {
+        warn!(
+            "Pod {} container {} restart count patch request returned error: {:?}",
+            pod.name(),
+            container_key,
+            e
+        );
     }
+    run.await
 }
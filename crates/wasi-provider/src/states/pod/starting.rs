@@ -1,5 +1,7 @@
+use std::future::Future;
 use std::sync::Arc;
 
+use tokio::sync::mpsc::Sender;
 use tracing::info;
 
 use kubelet::container::state::run_to_completion;
@@ -13,6 +15,58 @@ use crate::{PodState, ProviderState};
 
 use super::running::Running;
 
+/// Runs a container of the pod to completion on a new task, then sends its
+/// result to `tx`.
+pub(crate) fn start_container(
+    provider_state: &SharedState<ProviderState>,
+    pod_state: &PodState,
+    pod: &Manifest<Pod>,
+    container_key: ContainerKey,
+    tx: Sender<(ContainerKey, anyhow::Result<()>)>,
+) {
+    tokio::task::spawn(run_container(
+        provider_state,
+        pod_state,
+        pod,
+        container_key,
+        tx,
+    ));
+}
+
+/// Runs a container of the pod to completion, then sends its result to `tx`.
+pub(crate) fn run_container(
+    provider_state: &SharedState<ProviderState>,
+    pod_state: &PodState,
+    pod: &Manifest<Pod>,
+    container_key: ContainerKey,
+    tx: Sender<(ContainerKey, anyhow::Result<()>)>,
+) -> impl Future<Output = ()> + Send + 'static {
+    let container_state = ContainerState::new(
+        pod.latest(),
+        container_key.clone(),
+        Arc::clone(&pod_state.run_context),
+    );
+    let task_provider = Arc::clone(provider_state);
+    let task_pod = pod.clone();
+    async move {
+        let client = {
+            let provider_state = task_provider.read().await;
+            provider_state.client()
+        };
+
+        let result = run_to_completion(
+            &client,
+            Waiting::default(),
+            task_provider,
+            container_state,
+            task_pod,
+            container_key.clone(),
+        )
+        .await;
+        tx.send((container_key, result)).await.ok();
+    }
+}
+
 #[derive(Default, Debug, TransitionTo)]
 #[transition_to(Running)]
 /// The Kubelet is starting the Pod containers
@@ -26,46 +80,26 @@ impl State<PodState> for Starting {
         pod_state: &mut PodState,
         pod: Manifest<Pod>,
     ) -> Transition<PodState> {
-        let pod_rx = pod.clone();
+        let pod_manifest = pod.clone();
         let pod = pod.latest();
 
         info!("Starting containers for pod {:?}.", pod.name());
         let containers = pod.containers();
         let (tx, rx) = tokio::sync::mpsc::channel(containers.len());
         for container in containers {
-            let initial_state = Waiting;
-            let container_key = ContainerKey::App(container.name().to_string());
-            let container_state = ContainerState::new(
-                pod.clone(),
-                container_key.clone(),
-                Arc::clone(&pod_state.run_context),
+            start_container(
+                &provider_state,
+                pod_state,
+                &pod_manifest,
+                ContainerKey::App(container.name().to_string()),
+                tx.clone(),
             );
-            let task_provider = Arc::clone(&provider_state);
-            let task_tx = tx.clone();
-            let task_pod = pod_rx.clone();
-            tokio::task::spawn(async move {
-                let client = {
-                    let provider_state = task_provider.read().await;
-                    provider_state.client()
-                };
-
-                let result = run_to_completion(
-                    &client,
-                    initial_state,
-                    task_provider,
-                    container_state,
-                    task_pod,
-                    container_key,
-                )
-                .await;
-                task_tx.send(result).await
-            });
         }
         info!("All containers started for pod {:?}.", pod.name());
-        Transition::next(self, Running::new(rx))
+        Transition::next(self, Running::new(tx, rx))
     }
 
-    async fn status(&self, _pod_state: &mut PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Pending, "Starting"))
+    async fn status(&self, pod_state: &mut PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(pod_state.running_status(Phase::Pending, "Starting"))
     }
 }
@@ -0,0 +1,348 @@
+//! The termination message that a module can leave when it exits, which is
+//! reported in its container's terminated state like the standard kubelet does.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use kubelet::container::Container;
+use kubelet::log::HandleFactory as _;
+use tempfile::TempDir;
+use tracing::warn;
+
+use crate::runtime::HandleFactory;
+use crate::wasi_runtime::{find_mount, Mount};
+
+/// Where the termination message is written when the container doesn't say
+const DEFAULT_TERMINATION_MESSAGE_PATH: &str = "/dev/termination-log";
+/// The most bytes of a termination message file that are reported
+const MAX_MESSAGE_LENGTH: u64 = 4 * 1024;
+/// The most lines of output reported when falling back to the log
+const MAX_LOG_LINES: usize = 80;
+/// The most bytes of output reported when falling back to the log
+const MAX_LOG_LENGTH: usize = 2 * 1024;
+
+/// Where the message comes from when the module doesn't write one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Policy {
+    /// The message is only ever read from the file.
+    File,
+    /// The tail of the module's output is used if it failed without writing
+    /// a message.
+    FallbackToLogsOnError,
+}
+
+/// A writable file that a module's termination message is read from, at the
+/// container's `terminationMessagePath`.
+#[derive(Debug)]
+pub(crate) struct TerminationLog {
+    /// the local directory of the mount holding the file
+    dir: PathBuf,
+    /// the path of the file in `dir`
+    path: PathBuf,
+    /// a directory that is mounted at the file's parent directory when the
+    /// mount holding it is read-only, which is removed when dropped
+    mount: Option<(TempDir, PathBuf)>,
+    policy: Policy,
+}
+
+impl TerminationLog {
+    /// Creates an empty termination message file for the container in the
+    /// mount holding its path. Like a container's, the file is writable even
+    /// if that mount is read-only, in which case a directory created in
+    /// `parent_dir` needs mounting at the file's parent directory, as long as
+    /// that hides neither a mount nor the working directory.
+    pub async fn new(
+        container: &Container,
+        mounts: &HashMap<PathBuf, Mount>,
+        parent_dir: &Path,
+    ) -> anyhow::Result<Self> {
+        let policy = match container.termination_message_policy().map(|p| p.as_str()) {
+            None | Some("File") => Policy::File,
+            Some("FallbackToLogsOnError") => Policy::FallbackToLogsOnError,
+            Some(policy) => anyhow::bail!("unknown termination message policy {}", policy),
+        };
+        let path = Path::new(
+            container
+                .termination_message_path()
+                .map(|p| p.as_str())
+                .unwrap_or(DEFAULT_TERMINATION_MESSAGE_PATH),
+        );
+        let (host_dir, mount, rest) = match (path.parent(), path.file_name()) {
+            (Some(_), Some(_)) => find_mount(mounts, path),
+            _ => None,
+        }
+        .filter(|(_, _, rest)| rest.components().all(|c| matches!(c, Component::Normal(_))))
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "termination message path {} is not a normalized absolute file path",
+                path.display()
+            )
+        })?;
+
+        if !mount.read_only {
+            let (dir, path) = (host_dir.clone(), rest.to_path_buf());
+            create_file(dir.clone(), path.clone()).await?;
+            return Ok(TerminationLog {
+                dir,
+                path,
+                mount: None,
+                policy,
+            });
+        }
+
+        // Every mount at or below the parent directory would be hidden by
+        // mounting over it, as would the working directory
+        let guest_dir = path.parent().unwrap();
+        let hidden = mounts
+            .iter()
+            .map(|(host, mount)| mount.guest_path.as_ref().unwrap_or(host).as_path())
+            .chain(container.working_dir().map(Path::new))
+            .find(|hidden| hidden.starts_with(guest_dir));
+        if let Some(hidden) = hidden {
+            anyhow::bail!(
+                "termination message path {} is read-only, and mounting a writable directory at {} would hide {}",
+                path.display(),
+                guest_dir.display(),
+                hidden.display()
+            );
+        }
+        let parent_dir = parent_dir.to_path_buf();
+        let temp_dir = tokio::task::spawn_blocking(move || {
+            tempfile::Builder::new()
+                .prefix("termination-")
+                .tempdir_in(parent_dir)
+        })
+        .await??;
+        let (dir, path) = (
+            temp_dir.path().to_path_buf(),
+            PathBuf::from(path.file_name().unwrap()),
+        );
+        create_file(dir.clone(), path.clone()).await?;
+        Ok(TerminationLog {
+            dir,
+            path,
+            mount: Some((temp_dir, guest_dir.to_path_buf())),
+            policy,
+        })
+    }
+
+    /// The local directory to mount and the guest directory to mount it at,
+    /// if the file isn't writable where it is.
+    pub fn mount(&self) -> Option<(PathBuf, PathBuf)> {
+        self.mount
+            .as_ref()
+            .map(|(dir, guest_dir)| (dir.path().to_path_buf(), guest_dir.clone()))
+    }
+
+    /// The termination message of a module that exited, read from the file
+    /// or, depending on the policy, from the end of its `logs` if it failed.
+    /// Returns `None` if there isn't one.
+    pub async fn message(&self, failed: bool, logs: &HandleFactory) -> Option<String> {
+        match self.read_file().await {
+            Ok(message) if !message.is_empty() => return Some(message),
+            Ok(_) => (),
+            Err(e) => warn!("Unable to read termination message file: {:?}", e),
+        }
+        if !failed || self.policy != Policy::FallbackToLogsOnError {
+            return None;
+        }
+        match kubelet::log::read_tail(logs.new_handle(), logs.format(), MAX_LOG_LINES).await {
+            Ok(lines) if !lines.is_empty() => Some(last_bytes(lines.join("\n"), MAX_LOG_LENGTH)),
+            Ok(_) => None,
+            Err(e) => {
+                warn!("Unable to read log for termination message: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Reads the file through the mount's directory, so that links the
+    /// module left can't lead outside of it.
+    async fn read_file(&self) -> std::io::Result<String> {
+        let (dir, path) = (self.dir.clone(), self.path.clone());
+        tokio::task::spawn_blocking(move || {
+            let mut message = Vec::new();
+            open_dir(&dir)?
+                .open(&path)?
+                .into_std()
+                .take(MAX_MESSAGE_LENGTH)
+                .read_to_end(&mut message)?;
+            Ok(String::from_utf8_lossy(&message).into_owned())
+        })
+        .await?
+    }
+}
+
+fn open_dir(dir: &Path) -> std::io::Result<cap_std::fs::Dir> {
+    unsafe { cap_std::fs::Dir::open_ambient_dir(dir) }
+}
+
+/// Creates the empty file at `path` in `dir`, along with its parent
+/// directories, without following links out of `dir`.
+async fn create_file(dir: PathBuf, path: PathBuf) -> std::io::Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let dir = open_dir(&dir)?;
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            dir.create_dir_all(parent)?;
+        }
+        dir.create(&path)?;
+        Ok(())
+    })
+    .await?
+}
+
+/// Keeps at most the last `max` bytes of `s`, without splitting a character.
+fn last_bytes(s: String, max: usize) -> String {
+    if s.len() <= max {
+        return s;
+    }
+    let start = (s.len() - max..s.len())
+        .find(|i| s.is_char_boundary(*i))
+        .unwrap_or(s.len());
+    s[start..].to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use kubelet::pod::Pod;
+
+    fn container(termination_message_path: Option<&str>, working_dir: Option<&str>) -> Container {
+        let pod: Pod = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "test" },
+            "spec": {
+                "containers": [{
+                    "name": "app",
+                    "terminationMessagePath": termination_message_path,
+                    "workingDir": working_dir,
+                }]
+            }
+        }))
+        .unwrap();
+        pod.containers().remove(0)
+    }
+
+    fn mount(guest_path: &str, read_only: bool) -> Mount {
+        Mount {
+            guest_path: Some(PathBuf::from(guest_path)),
+            read_only,
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_are_left_in_the_mount_holding_their_path() {
+        let (root, data, logs) = (
+            TempDir::new().unwrap(),
+            TempDir::new().unwrap(),
+            TempDir::new().unwrap(),
+        );
+        std::fs::write(data.path().join("existing"), "data").unwrap();
+        let mut mounts = HashMap::new();
+        mounts.insert(root.path().to_path_buf(), mount("/", false));
+        mounts.insert(data.path().to_path_buf(), mount("/data", false));
+
+        let log = TerminationLog::new(&container(Some("/data/msg"), None), &mounts, logs.path())
+            .await
+            .unwrap();
+        assert_eq!(None, log.mount());
+        std::fs::write(data.path().join("msg"), "done").unwrap();
+        assert_eq!("done", log.read_file().await.unwrap());
+        assert_eq!(
+            "data",
+            std::fs::read_to_string(data.path().join("existing")).unwrap()
+        );
+
+        let log = TerminationLog::new(&container(None, None), &mounts, logs.path())
+            .await
+            .unwrap();
+        assert_eq!(None, log.mount());
+        std::fs::write(root.path().join("dev/termination-log"), "root").unwrap();
+        assert_eq!("root", log.read_file().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn read_only_mounts_get_a_writable_directory_for_the_message() {
+        let (root, logs) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let mut mounts = HashMap::new();
+        mounts.insert(root.path().to_path_buf(), mount("/", true));
+
+        let log = TerminationLog::new(&container(None, None), &mounts, logs.path())
+            .await
+            .unwrap();
+        let (host_dir, guest_dir) = log.mount().unwrap();
+        assert_eq!(Path::new("/dev"), guest_dir);
+        assert!(host_dir.starts_with(logs.path()));
+        std::fs::write(host_dir.join("termination-log"), "done").unwrap();
+        assert_eq!("done", log.read_file().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn writable_directories_are_not_mounted_over_mounts_or_the_working_directory() {
+        let (root, data, logs) = (
+            TempDir::new().unwrap(),
+            TempDir::new().unwrap(),
+            TempDir::new().unwrap(),
+        );
+        let mut mounts = HashMap::new();
+        mounts.insert(root.path().to_path_buf(), mount("/", true));
+        mounts.insert(data.path().to_path_buf(), mount("/data", true));
+
+        for (path, working_dir) in &[
+            (Some("/data/msg"), None),
+            (Some("/msg"), None),
+            (None, Some("/dev/app")),
+        ] {
+            assert!(
+                TerminationLog::new(&container(*path, *working_dir), &mounts, logs.path())
+                    .await
+                    .is_err()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn links_out_of_the_mount_are_not_followed() {
+        let (root, secrets, logs) = (
+            TempDir::new().unwrap(),
+            TempDir::new().unwrap(),
+            TempDir::new().unwrap(),
+        );
+        std::fs::write(secrets.path().join("secret"), "secret").unwrap();
+        let mut mounts = HashMap::new();
+        mounts.insert(root.path().to_path_buf(), mount("/", false));
+
+        let log = TerminationLog::new(&container(None, None), &mounts, logs.path())
+            .await
+            .unwrap();
+        let file = root.path().join("dev/termination-log");
+        std::fs::remove_file(&file).unwrap();
+        std::os::unix::fs::symlink(secrets.path().join("secret"), &file).unwrap();
+        assert!(log.read_file().await.is_err());
+
+        // Nor are links left in the mount followed when the file is created
+        std::fs::remove_dir_all(root.path().join("dev")).unwrap();
+        std::os::unix::fs::symlink(secrets.path(), root.path().join("dev")).unwrap();
+        assert!(
+            TerminationLog::new(&container(None, None), &mounts, logs.path())
+                .await
+                .is_err()
+        );
+        assert!(!secrets.path().join("termination-log").exists());
+    }
+
+    #[tokio::test]
+    async fn paths_must_be_normalized_absolute_file_paths() {
+        let (root, logs) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let mut mounts = HashMap::new();
+        mounts.insert(root.path().to_path_buf(), mount("/", false));
+
+        for path in &["termination-log", "/", "/dev/../../termination-log"] {
+            assert!(
+                TerminationLog::new(&container(Some(path), None), &mounts, logs.path())
+                    .await
+                    .is_err()
+            );
+        }
+    }
+}
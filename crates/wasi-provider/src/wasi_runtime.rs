@@ -1,39 +1,49 @@
 use anyhow::bail;
+use futures::future::BoxFuture;
+use std::any::Any;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use tracing::{debug, error, info, warn};
 
 use tempfile::NamedTempFile;
-use tokio::sync::mpsc::Sender;
-use tokio::sync::oneshot;
+use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::{oneshot, Notify};
 use tokio::task::JoinHandle;
 use wasi_cap_std_sync::WasiCtxBuilder;
+use wasi_common::dir::DirCaps;
+use wasi_common::file::FileCaps;
+use wasi_common::pipe::{ReadPipe, WritePipe};
+use wasi_common::WasiCtx;
 use wasmtime::InterruptHandle;
 use wasmtime_wasi::snapshots::preview_0::Wasi as WasiUnstable;
 use wasmtime_wasi::snapshots::preview_1::Wasi;
 
 use kubelet::container::Handle as ContainerHandle;
 use kubelet::container::Status;
-use kubelet::handle::StopHandler;
-
-pub struct Runtime {
-    handle: JoinHandle<anyhow::Result<()>>,
-    interrupt_handle: InterruptHandle,
-}
-
-#[async_trait::async_trait]
-impl StopHandler for Runtime {
-    async fn stop(&mut self) -> anyhow::Result<()> {
-        self.interrupt_handle.interrupt();
-        Ok(())
-    }
-
-    async fn wait(&mut self) -> anyhow::Result<()> {
-        (&mut self.handle).await??;
-        Ok(())
-    }
-}
+use kubelet::exec::{AttachedOutput, Sender as ExecSender};
+
+use crate::cache::{EngineLimits, ModuleCache};
+use crate::cpu::CpuThrottle;
+use crate::host::HostModules;
+use crate::input::StdinReader;
+use crate::lifecycle::Lifecycle;
+use crate::memory::{self, MemoryLimit};
+use crate::output::{DetachGuard, ExecOutputWriter, LogStreamWriter, Stream};
+use crate::runtime::{
+    ExitGuard, Exited, HandleFactory, Instance, ModuleRuntime, Runtime, LIFECYCLE_MODULE,
+    STOP_REQUESTED_FUNC,
+};
+use crate::sockets::{Network, Sockets};
+
+/// The export called to run a module when the container doesn't set a command
+const DEFAULT_ENTRYPOINT: &str = "_start";
+/// The fd of the first preopened directory, after stdio
+const FIRST_PREOPEN_FD: u32 = 3;
 
 /// WasiRuntime provides a WASI compatible runtime. A runtime should be used for
 /// each "instance" of a process and can be passed to a thread pool for running
@@ -46,31 +56,87 @@ pub struct WasiRuntime {
     output: Arc<NamedTempFile>,
     /// A channel to send status updates on the runtime
     status_sender: Sender<Status>,
+    /// The container's lifecycle hooks
+    lifecycle: Arc<Lifecycle>,
 }
 
 struct Data {
     /// binary module data to be run as a wasm module
     module_data: Vec<u8>,
+    /// the name of the exported function that runs the module, if the
+    /// container's command gives one
+    entrypoint: Option<String>,
     /// key/value environment variables made available to the wasm process
     env: HashMap<String, String>,
     /// the arguments passed as the command-line arguments list
     args: Vec<String>,
-    /// a hash map of local file system paths to optional path names in the runtime
-    /// (e.g. /tmp/foo/myfile -> /app/config). If the optional value is not given,
-    /// the same path will be allowed in the runtime
-    dirs: HashMap<PathBuf, Option<PathBuf>>,
+    /// whether the module's stdin accepts input from attached clients
+    stdin: Stdin,
+    /// a hash map of local file system paths to where they are mounted in the
+    /// runtime (e.g. /tmp/foo/myfile -> /app/config)
+    dirs: HashMap<PathBuf, Mount>,
+    /// a local file system path that is also preopened as the module's
+    /// current directory, `.`, and whether it is read-only
+    working_dir: Option<(PathBuf, bool)>,
+    /// resource limits enforced on the module
+    limits: ResourceLimits,
+    /// the shared engines and compiled modules to run the module with
+    module_cache: ModuleCache,
+    /// the host modules the module can import functions from, besides WASI
+    host_modules: Arc<HostModules>,
+    /// the ports the module listens on and hosts it can connect to, if the pod
+    /// has opted in to networking
+    network: Option<Network>,
 }
 
-/// Holds our tempfile handle.
-pub struct HandleFactory {
-    temp: Arc<NamedTempFile>,
+/// Where a local directory is mounted in a module's file system
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Mount {
+    /// the path in the runtime. If not given, the same path as the local
+    /// one is used
+    pub guest_path: Option<PathBuf>,
+    /// whether the module can only read from the directory
+    pub read_only: bool,
 }
 
-impl kubelet::log::HandleFactory<tokio::fs::File> for HandleFactory {
-    /// Creates `tokio::fs::File` on demand for log reading.
-    fn new_handle(&self) -> tokio::fs::File {
-        tokio::fs::File::from_std(self.temp.reopen().unwrap())
-    }
+/// Finds the mount holding `guest_path`, returning its local directory, the
+/// mount and the rest of the path below it. The deepest mount wins, as it
+/// hides anything mounted above it.
+pub(crate) fn find_mount<'a>(
+    mounts: &'a HashMap<PathBuf, Mount>,
+    guest_path: &'a Path,
+) -> Option<(&'a PathBuf, &'a Mount, &'a Path)> {
+    mounts
+        .iter()
+        .filter_map(|(host, mount)| {
+            let guest = mount.guest_path.as_ref().unwrap_or(host);
+            let rest = guest_path.strip_prefix(guest).ok()?;
+            Some((guest.components().count(), host, mount, rest))
+        })
+        .max_by_key(|(depth, _, _, _)| *depth)
+        .map(|(_, host, mount, rest)| (host, mount, rest))
+}
+
+/// How a module's stdin is connected
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stdin {
+    /// stdin is always at the end of the file
+    Closed,
+    /// stdin accepts input from attached clients until the module exits
+    Open,
+    /// stdin accepts input from the first client to attach, and is closed
+    /// when it detaches
+    Once,
+}
+
+/// Resource limits enforced on a running module
+#[derive(Clone, Debug, Default)]
+pub struct ResourceLimits {
+    /// the maximum number of bytes of linear memory the module may use
+    pub memory: Option<u64>,
+    /// the number of CPU cores the module may use. Modules run on a single
+    /// thread, so limits of a core or more have no effect
+    pub cpu: Option<f64>,
 }
 
 impl WasiRuntime {
@@ -79,18 +145,38 @@ impl WasiRuntime {
     /// # Arguments
     ///
     /// * `module_path` - the path to the WebAssembly binary
+    /// * `entrypoint` - the exported function to call. If the module has no such
+    ///     function, it is run from `_start` with the entrypoint as its first
+    ///     argument, as is a module without an entrypoint
     /// * `env` - a collection of key/value pairs containing the environment variables
     /// * `args` - the arguments passed as the command-line arguments list
-    /// * `dirs` - a map of local file system paths to optional path names in the runtime
-    ///     (e.g. /tmp/foo/myfile -> /app/config). If the optional value is not given,
-    ///     the same path will be allowed in the runtime
+    /// * `stdin` - whether stdin accepts input from attached clients
+    /// * `dirs` - a map of local file system paths to where they are mounted in the runtime
+    ///     (e.g. /tmp/foo/myfile -> /app/config)
+    /// * `working_dir` - a local file system path to preopen as the module's current
+    ///     directory, and whether it is read-only
+    /// * `limits` - the memory and CPU limits enforced on the module
+    /// * `module_cache` - the shared engines and compiled modules
+    /// * `host_modules` - the host modules available to import, besides WASI
+    /// * `network` - the module's network access, if it has any
+    /// * `lifecycle` - the container's hooks. The preStop hook is run when the
+    ///     module is stopped
     /// * `log_dir` - location for storing logs
+    #[allow(clippy::too_many_arguments)]
     pub async fn new<L: AsRef<Path> + Send + Sync + 'static>(
         name: String,
         module_data: Vec<u8>,
+        entrypoint: Option<String>,
         env: HashMap<String, String>,
         args: Vec<String>,
-        dirs: HashMap<PathBuf, Option<PathBuf>>,
+        stdin: Stdin,
+        dirs: HashMap<PathBuf, Mount>,
+        working_dir: Option<(PathBuf, bool)>,
+        limits: ResourceLimits,
+        module_cache: ModuleCache,
+        host_modules: Arc<HostModules>,
+        network: Option<Network>,
+        lifecycle: Arc<Lifecycle>,
         log_dir: L,
         status_sender: Sender<Status>,
     ) -> anyhow::Result<Self> {
@@ -109,16 +195,31 @@ impl WasiRuntime {
             name,
             data: Arc::new(Data {
                 module_data,
+                entrypoint,
                 env,
                 args,
+                stdin,
                 dirs,
+                working_dir,
+                limits,
+                module_cache,
+                host_modules,
+                network,
             }),
             output: Arc::new(temp),
             status_sender,
+            lifecycle,
         })
     }
+}
+
+#[async_trait::async_trait]
+impl ModuleRuntime for WasiRuntime {
+    fn log_handle_factory(&self) -> HandleFactory {
+        HandleFactory::new(self.output.clone())
+    }
 
-    pub async fn start(&self) -> anyhow::Result<ContainerHandle<Runtime, HandleFactory>> {
+    async fn start(&self) -> anyhow::Result<ContainerHandle<Runtime, HandleFactory>> {
         let temp = self.output.clone();
         // Because a reopen is blocking, run in a blocking task to get new
         // handles to the tempfile
@@ -127,27 +228,105 @@ impl WasiRuntime {
         })
         .await??;
 
-        let (interrupt_handle, handle) = self.spawn_wasmtime(output_write).await?;
-
-        let log_handle_factory = HandleFactory {
-            temp: self.output.clone(),
+        let stopped = Arc::new(Notify::new());
+        let (stdin_sender, stdin) = match self.data.stdin {
+            Stdin::Closed => (None, None),
+            Stdin::Open | Stdin::Once => {
+                // TODO: ~magic~ number
+                let (tx, rx) = mpsc::channel(8);
+                (Some(tx), Some(StdinReader::new(rx, stopped.clone())))
+            }
         };
 
-        Ok(ContainerHandle::new(
-            Runtime {
+        let exited = Arc::new(Exited::default());
+        let attached = AttachedOutput::new();
+        let stop_requested = Arc::new(AtomicBool::new(false));
+        let (interrupt_handle, handle) = self
+            .spawn_wasmtime(
+                output_write,
+                stdin,
+                attached.clone(),
+                stopped.clone(),
+                stop_requested.clone(),
+                exited.clone(),
+            )
+            .await?;
+
+        let instance = WasmtimeInstance {
+            name: self.name.clone(),
+            data: self.data.clone(),
+            interrupt_handle,
+            stop_requested,
+        };
+        let container_handle = ContainerHandle::new(
+            Runtime::new(
+                self.name.clone(),
+                Box::new(instance),
                 handle,
-                interrupt_handle,
-            },
-            log_handle_factory,
-        ))
+                stopped,
+                exited,
+                self.lifecycle.clone(),
+            ),
+            self.log_handle_factory(),
+        )
+        .with_attached_output(attached);
+        Ok(match stdin_sender {
+            Some(sender) => container_handle.with_stdin(sender, self.data.stdin == Stdin::Once),
+            None => container_handle,
+        })
+    }
+}
+
+/// A module running in wasmtime.
+struct WasmtimeInstance {
+    name: String,
+    data: Arc<Data>,
+    interrupt_handle: InterruptHandle,
+    /// What [`STOP_REQUESTED_FUNC`] returns, for the running module and its
+    /// exec commands
+    stop_requested: Arc<AtomicBool>,
+}
+
+impl Instance for WasmtimeInstance {
+    fn interrupt(&self) {
+        self.interrupt_handle.interrupt();
+    }
+
+    /// If the first item of the command names an exported function, that
+    /// function is called with the rest of the command as its arguments.
+    /// Otherwise the module is run from its `_start` export with the whole
+    /// command as its arguments.
+    fn exec(
+        &self,
+        command: Vec<String>,
+        output: ExecSender,
+    ) -> BoxFuture<'static, anyhow::Result<i32>> {
+        let name = self.name.clone();
+        let data = self.data.clone();
+        let stop_requested = self.stop_requested.clone();
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || data.exec(&name, command, output, stop_requested))
+                .await?
+        })
     }
 
+    fn request_stop(&self) {
+        self.stop_requested.store(true, Ordering::SeqCst);
+    }
+}
+
+impl WasiRuntime {
     // Spawns a running wasmtime instance with the given context and status
     // channel. Due to the Instance type not being Send safe, all of the logic
     // needs to be done within the spawned task
     async fn spawn_wasmtime(
         &self,
         output_write: std::fs::File,
+        stdin: Option<StdinReader>,
+        attached: AttachedOutput,
+        stopped: Arc<Notify>,
+        stop_requested: Arc<AtomicBool>,
+        exited: Arc<Exited>,
     ) -> anyhow::Result<(InterruptHandle, JoinHandle<anyhow::Result<()>>)> {
         // Clone the module data Arc so it can be moved
         let data = self.data.clone();
@@ -156,70 +335,31 @@ impl WasiRuntime {
 
         let name = self.name.clone();
         let handle = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
-            let env: Vec<(String, String)> = data
-                .env
-                .iter()
-                .map(|(k, v)| (k.to_string(), v.to_string()))
-                .collect();
-            let stdout = unsafe { cap_std::fs::File::from_std(output_write.try_clone()?) };
-            let stdout = wasi_cap_std_sync::file::File::from_cap_std(stdout);
-            let stderr = unsafe { cap_std::fs::File::from_std(output_write.try_clone()?) };
-            let stderr = wasi_cap_std_sync::file::File::from_cap_std(stderr);
-
-            // Build the WASI instance and then generate a list of WASI modules
-            let ctx_builder_snapshot = WasiCtxBuilder::new();
-            let mut ctx_builder_snapshot = ctx_builder_snapshot
-                .args(&data.args)?
-                .envs(&env)?
-                .stdout(Box::new(stdout))
-                .stderr(Box::new(stderr));
-
-            let stdout = unsafe { cap_std::fs::File::from_std(output_write.try_clone()?) };
-            let stdout = wasi_cap_std_sync::file::File::from_cap_std(stdout);
-            let stderr = unsafe { cap_std::fs::File::from_std(output_write.try_clone()?) };
-            let stderr = wasi_cap_std_sync::file::File::from_cap_std(stderr);
-
-            let ctx_builder_unstable = WasiCtxBuilder::new();
-            let mut ctx_builder_unstable = ctx_builder_unstable
-                .args(&data.args)?
-                .envs(&env)?
-                .stdout(Box::new(stdout))
-                .stderr(Box::new(stderr));
-
-            for (key, value) in data.dirs.iter() {
-                let guest_dir = value.as_ref().unwrap_or(key);
-                debug!(
-                    "{} mounting hostpath {} as guestpath {}",
-                    &name,
-                    key.display(),
-                    guest_dir.display()
-                );
-                let preopen_dir = unsafe { cap_std::fs::Dir::open_ambient_dir(key) }?;
-                ctx_builder_snapshot =
-                    ctx_builder_snapshot.preopened_dir(preopen_dir, guest_dir)?;
-                let preopen_dir = unsafe { cap_std::fs::Dir::open_ambient_dir(key) }?;
-                ctx_builder_unstable =
-                    ctx_builder_unstable.preopened_dir(preopen_dir, guest_dir)?;
-            }
-            let wasi_ctx_snapshot = ctx_builder_snapshot.build()?;
-            let wasi_ctx_unstable = ctx_builder_unstable.build()?;
-            let mut config = wasmtime::Config::new();
-            config.interruptable(true);
-            let engine = wasmtime::Engine::new(&config);
-            let store = wasmtime::Store::new(&engine);
+            let _exited = ExitGuard(exited);
+            let _detach = DetachGuard(attached.clone());
+            // Both WASI contexts share the same pipes, so that each stream's
+            // partial lines are buffered in one place
+            let output = Arc::new(Mutex::new(output_write));
+            let stdout = WritePipe::new(LogStreamWriter::new(
+                Stream::Stdout,
+                output.clone(),
+                attached.clone(),
+            ));
+            let stderr = WritePipe::new(LogStreamWriter::new(Stream::Stderr, output, attached));
+            let memory_limit = data
+                .limits
+                .memory
+                .map(|limit| Arc::new(MemoryLimit::new(limit)));
+            let cpu_throttle = data.limits.cpu.and_then(CpuThrottle::new);
+            let store = data.store(&cpu_throttle)?;
             let interrupt = store.interrupt_handle()?;
             tx.send(interrupt)
                 .map_err(|_| anyhow::anyhow!("Unable to send interrupt back to main thread"))?;
 
-            let wasi_snapshot = Wasi::new(
-                &store,
-                std::rc::Rc::new(std::cell::RefCell::new(wasi_ctx_snapshot)),
-            );
-            let wasi_unstable = WasiUnstable::new(
-                &store,
-                std::rc::Rc::new(std::cell::RefCell::new(wasi_ctx_unstable)),
-            );
-            let module = match wasmtime::Module::new(&engine, &data.module_data) {
+            let module = match data
+                .module_cache
+                .get_or_compile(&data.module_data, data.engine_limits(&cpu_throttle))
+            {
                 // We can't map errors here or it moves the send channel, so we
                 // do it in a match
                 Ok(m) => m,
@@ -233,43 +373,50 @@ impl WasiRuntime {
                             failed: true,
                             message: message.into(),
                             timestamp: chrono::Utc::now(),
+                            exit_code: None,
+                            reason: None,
                         },
                     );
 
                     return Err(anyhow::anyhow!("{}: {}", message, e));
                 }
             };
-            // Iterate through the module includes and resolve imports
-            let imports = module
-                .imports()
-                .map(|i| {
-                    let name = i.name().unwrap();
-                    // This is super funky logic, but it matches what is in 0.12.0
-                    let export = match i.module() {
-                        "wasi_snapshot_preview1" => wasi_snapshot.get_export(name),
-                        "wasi_unstable" => wasi_unstable.get_export(name),
-                        other => bail!("import module `{}` was not found", other),
-                    };
-                    match export {
-                        Some(export) => Ok(export.clone().into()),
-                        None => bail!("import `{}` was not found in module `{}`", name, i.module()),
-                    }
-                })
-                .collect::<Result<Vec<_>, _>>();
+            let (entrypoint, args) = match &data.entrypoint {
+                Some(entrypoint) => {
+                    let command = std::iter::once(entrypoint.clone())
+                        .chain(data.args.iter().cloned())
+                        .collect();
+                    command_entrypoint(&module, command)
+                }
+                None => (DEFAULT_ENTRYPOINT.to_string(), data.args.clone()),
+            };
+            let (mut wasi_ctx_snapshot, wasi_ctx_unstable) =
+                data.wasi_ctxs(&name, &args, stdout, stderr, stdin)?;
+            let sockets = data.sockets(&mut wasi_ctx_snapshot, true, stopped)?;
+            let imports = data.link(
+                &store,
+                &module,
+                wasi_ctx_snapshot,
+                wasi_ctx_unstable,
+                sockets,
+                stop_requested,
+            );
             let imports = match imports {
                 // We can't map errors here or it moves the send channel, so we
                 // do it in a match
                 Ok(m) => m,
                 Err(e) => {
-                    let message = "unable to load module";
-                    error!("{} {}: {:?}", &name, message, e);
+                    let message = format!("unable to load module: {}", e);
+                    error!("{} {}", &name, message);
                     send(
                         &status_sender,
                         &name,
                         Status::Terminated {
                             failed: true,
-                            message: message.into(),
+                            message,
                             timestamp: chrono::Utc::now(),
+                            exit_code: None,
+                            reason: None,
                         },
                     );
 
@@ -277,27 +424,26 @@ impl WasiRuntime {
                 }
             };
 
-            let instance = match wasmtime::Instance::new(&store, &module, &imports) {
-                // We can't map errors here or it moves the send channel, so we
-                // do it in a match
-                Ok(m) => m,
-                Err(e) => {
-                    let message = "unable to instantiate module";
-                    error!("{} {}: {:?}", &name, message, e);
-                    send(
-                        &status_sender,
-                        &name,
-                        Status::Terminated {
-                            failed: true,
-                            message: message.into(),
-                            timestamp: chrono::Utc::now(),
-                        },
-                    );
-
-                    // Converting from anyhow
-                    return Err(anyhow::anyhow!("{}: {}", message, e));
-                }
-            };
+            let instance =
+                match instantiate(&store, &module, &imports, &cpu_throttle, &memory_limit) {
+                    // We can't map errors here or it moves the send channel, so we
+                    // do it in a match
+                    Ok(m) => m,
+                    Err(e) => {
+                        // Memories are only refused while instantiating if the
+                        // module's initial memory is over the limit
+                        let oom = memory_limit
+                            .as_ref()
+                            .filter(|limit| limit.exceeded())
+                            .map(|limit| limit.limit());
+                        let (message, status) = failed_status("unable to instantiate module", oom);
+                        error!("{} {}: {:?}", &name, message, e);
+                        send(&status_sender, &name, status);
+
+                        // Converting from anyhow
+                        return Err(anyhow::anyhow!("{}: {}", message, e));
+                    }
+                };
 
             // NOTE(taylor): In the future, if we want to pass args directly, we'll
             // need to do a bit more to pass them in here.
@@ -310,43 +456,60 @@ impl WasiRuntime {
                 },
             );
 
-            let export = instance
-                .get_export("_start")
-                .ok_or_else(|| anyhow::anyhow!("_start import doesn't exist in wasm module"))?;
-            let func = match export {
-                wasmtime::Extern::Func(f) => f,
-                _ => {
-                    let message = "_start import was not a function. This is likely a problem with the module";
+            let func = match instance.get_export(&entrypoint) {
+                Some(wasmtime::Extern::Func(f)) => f,
+                export => {
+                    let message = match export {
+                        Some(_) => format!("{} export was not a function. This is likely a problem with the module", entrypoint),
+                        None => format!("{} export doesn't exist in wasm module", entrypoint),
+                    };
                     error!("{} {}", &name, message);
                     send(
                         &status_sender,
                         &name,
                         Status::Terminated {
                             failed: true,
-                            message: message.into(),
+                            message: message.clone(),
                             timestamp: chrono::Utc::now(),
+                            exit_code: None,
+                            reason: None,
                         },
                     );
 
                     return Err(anyhow::anyhow!(message));
                 }
             };
-            match func.call(&[]) {
+            let result = call(&func, &cpu_throttle);
+            // Dropping the instance's store drops its output pipes, which
+            // write out any unterminated last line of output before the exit
+            // is reported, in case the termination message is read from it
+            drop((func, instance, imports, store));
+            let exit_code = result.as_ref().err().and_then(exit_code);
+            match (result, exit_code) {
                 // We can't map errors here or it moves the send channel, so we
                 // do it in a match
-                Ok(_) => {}
-                Err(e) => {
-                    let message = "unable to run module";
+                (Ok(_), _) => {}
+                (Err(_), Some(exit_code)) => {
+                    info!("{} module exited with code {}", &name, exit_code);
+                    let message = if exit_code == 0 {
+                        "Module run completed".to_string()
+                    } else {
+                        format!("Module exited with code {}", exit_code)
+                    };
+                    send(&status_sender, &name, Status::exited(&message, exit_code));
+                    if exit_code != 0 {
+                        return Err(anyhow::anyhow!(message));
+                    }
+                    return Ok(());
+                }
+                (Err(e), None) => {
+                    let oom = memory_limit
+                        .as_ref()
+                        .filter(|limit| limit.caused(&e))
+                        .map(|limit| limit.limit());
+                    let (message, status) = failed_status("unable to run module", oom);
                     error!("{} {}: {:?}", &name, message, e);
-                    send(
-                        &status_sender,
-                        &name,
-                        Status::Terminated {
-                            failed: true,
-                            message: message.into(),
-                            timestamp: chrono::Utc::now(),
-                        },
-                    );
+                    send(&status_sender, &name, status);
 
                     return Err(anyhow::anyhow!("{}: {}", message, e));
                 }
@@ -356,11 +519,7 @@ impl WasiRuntime {
             send(
                 &status_sender,
                 &name,
-                Status::Terminated {
-                    failed: false,
-                    message: "Module run completed".into(),
-                    timestamp: chrono::Utc::now(),
-                },
+                Status::exited("Module run completed", 0),
             );
             Ok(())
         });
@@ -370,9 +529,387 @@ impl WasiRuntime {
     }
 }
 
-fn send(sender: &Sender<Status>, name: &str, status: Status) {
+impl Data {
+    /// Builds the contexts for both versions of WASI, with the given arguments
+    /// and stdio along with the module's environment and volumes.
+    fn wasi_ctxs<W: Write + Any>(
+        &self,
+        name: &str,
+        args: &[String],
+        stdout: WritePipe<W>,
+        stderr: WritePipe<W>,
+        stdin: Option<StdinReader>,
+    ) -> anyhow::Result<(WasiCtx, WasiCtx)> {
+        let env: Vec<(String, String)> = self
+            .env
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        // Build the WASI instance and then generate a list of WASI modules
+        let ctx_builder_snapshot = WasiCtxBuilder::new();
+        let mut ctx_builder_snapshot = ctx_builder_snapshot
+            .args(args)?
+            .envs(&env)?
+            .stdout(Box::new(stdout.clone()))
+            .stderr(Box::new(stderr.clone()));
+
+        let ctx_builder_unstable = WasiCtxBuilder::new();
+        let mut ctx_builder_unstable = ctx_builder_unstable
+            .args(args)?
+            .envs(&env)?
+            .stdout(Box::new(stdout))
+            .stderr(Box::new(stderr));
+
+        if let Some(stdin) = stdin {
+            let stdin = ReadPipe::new(stdin);
+            ctx_builder_snapshot = ctx_builder_snapshot.stdin(Box::new(stdin.clone()));
+            ctx_builder_unstable = ctx_builder_unstable.stdin(Box::new(stdin));
+        }
+
+        let ctx_snapshot = ctx_builder_snapshot.build()?;
+        let ctx_unstable = ctx_builder_unstable.build()?;
+
+        let mut preopens: Vec<(&PathBuf, &Path, bool)> = self
+            .dirs
+            .iter()
+            .map(|(key, mount)| {
+                let guest_dir = mount.guest_path.as_deref().unwrap_or(key);
+                (key, guest_dir, mount.read_only)
+            })
+            .collect();
+        if let Some((working_dir, read_only)) = &self.working_dir {
+            preopens.push((working_dir, Path::new("."), *read_only));
+        }
+        // The builder always preopens directories with every capability, so
+        // they are added to the contexts directly, right after stdio
+        for (fd, (key, guest_dir, read_only)) in (FIRST_PREOPEN_FD..).zip(preopens) {
+            debug!(
+                "{} mounting hostpath {} as guestpath {}{}",
+                name,
+                key.display(),
+                guest_dir.display(),
+                if read_only { " (read-only)" } else { "" }
+            );
+            let (dir_caps, file_caps) = preopen_caps(read_only);
+            for ctx in [&ctx_snapshot, &ctx_unstable].iter() {
+                let preopen_dir = unsafe { cap_std::fs::Dir::open_ambient_dir(key) }?;
+                ctx.insert_dir(
+                    fd,
+                    Box::new(wasi_cap_std_sync::dir::Dir::from_cap_std(preopen_dir)),
+                    dir_caps,
+                    file_caps,
+                    guest_dir.to_path_buf(),
+                );
+            }
+        }
+        Ok((ctx_snapshot, ctx_unstable))
+    }
+
+    /// The limits that decide which engine the module is compiled for.
+    fn engine_limits(&self, cpu_throttle: &Option<CpuThrottle>) -> EngineLimits {
+        EngineLimits {
+            metered: cpu_throttle.is_some(),
+            memory: self.limits.memory.is_some(),
+        }
+    }
+
+    /// Creates a store for an instance of the module.
+    fn store(&self, cpu_throttle: &Option<CpuThrottle>) -> anyhow::Result<wasmtime::Store> {
+        let engine = self.module_cache.engine(self.engine_limits(cpu_throttle));
+        match cpu_throttle {
+            // Throttled modules run on an async store so that they yield
+            // back to the throttle every time they use up a slice of fuel
+            Some(throttle) => throttle.store(engine),
+            None => Ok(wasmtime::Store::new(engine)),
+        }
+    }
+
+    /// Sets up the sockets of an instance of the module using the WASI
+    /// snapshot `ctx`, if the module has network access.
+    fn sockets(
+        &self,
+        ctx: &mut WasiCtx,
+        listen: bool,
+        stopped: Arc<Notify>,
+    ) -> anyhow::Result<Option<Sockets>> {
+        self.network
+            .as_ref()
+            .map(|network| Sockets::new(network, ctx, listen, stopped))
+            .transpose()
+    }
+
+    /// Resolves the module's imports from WASI, its sockets, whether it has
+    /// been asked to stop and the registered host modules.
+    fn link(
+        &self,
+        store: &wasmtime::Store,
+        module: &wasmtime::Module,
+        wasi_ctx_snapshot: WasiCtx,
+        wasi_ctx_unstable: WasiCtx,
+        sockets: Option<Sockets>,
+        stop_requested: Arc<AtomicBool>,
+    ) -> anyhow::Result<Vec<wasmtime::Extern>> {
+        let mut linker = wasmtime::Linker::new(store);
+        let wasi_ctx_snapshot = Rc::new(RefCell::new(wasi_ctx_snapshot));
+        Wasi::new(store, wasi_ctx_snapshot.clone()).add_to_linker(&mut linker)?;
+        WasiUnstable::new(store, Rc::new(RefCell::new(wasi_ctx_unstable)))
+            .add_to_linker(&mut linker)?;
+        if let Some(sockets) = sockets {
+            sockets.add_to_linker(wasi_ctx_snapshot, &mut linker)?;
+        }
+        linker.func(LIFECYCLE_MODULE, STOP_REQUESTED_FUNC, move || {
+            stop_requested.load(Ordering::SeqCst) as i32
+        })?;
+        self.host_modules.add_to_linker(&mut linker)?;
+
+        // Look up every import before failing, so that all of the missing
+        // ones are reported at once
+        let mut missing = vec![];
+        let imports: Vec<_> = module
+            .imports()
+            .filter_map(|i| match linker.get_one_by_name(i.module(), i.name()) {
+                Ok(export) => Some(export),
+                Err(_) => {
+                    missing.push(format!("{}::{}", i.module(), i.name().unwrap_or_default()));
+                    None
+                }
+            })
+            .collect();
+        if !missing.is_empty() {
+            bail!(
+                "module imports {} which the host does not provide",
+                missing.join(", ")
+            );
+        }
+        Ok(imports)
+    }
+
+    // Runs an exec command to completion. See `Runtime::exec`
+    fn exec(
+        &self,
+        name: &str,
+        command: Vec<String>,
+        output: ExecSender,
+        stop_requested: Arc<AtomicBool>,
+    ) -> anyhow::Result<i32> {
+        let memory_limit = self
+            .limits
+            .memory
+            .map(|limit| Arc::new(MemoryLimit::new(limit)));
+        let cpu_throttle = self.limits.cpu.and_then(CpuThrottle::new);
+        let module = self
+            .module_cache
+            .get_or_compile(&self.module_data, self.engine_limits(&cpu_throttle))?;
+
+        let (entrypoint, args) = command_entrypoint(&module, command);
+        info!("{} running exec command {} {:?}", name, entrypoint, args);
+
+        let stdout = WritePipe::new(ExecOutputWriter::new(Stream::Stdout, output.clone()));
+        let stderr = WritePipe::new(ExecOutputWriter::new(Stream::Stderr, output));
+        let (mut wasi_ctx_snapshot, wasi_ctx_unstable) =
+            self.wasi_ctxs(name, &args, stdout, stderr, None)?;
+        // Exec commands can't be stopped, so they are never notified
+        let sockets = self.sockets(&mut wasi_ctx_snapshot, false, Arc::new(Notify::new()))?;
+        let store = self.store(&cpu_throttle)?;
+        let imports = self.link(
+            &store,
+            &module,
+            wasi_ctx_snapshot,
+            wasi_ctx_unstable,
+            sockets,
+            stop_requested,
+        )?;
+        let instance = instantiate(&store, &module, &imports, &cpu_throttle, &memory_limit)
+            .map_err(
+                |e| match memory_limit.as_ref().filter(|limit| limit.exceeded()) {
+                    Some(limit) => anyhow::anyhow!(oom_message(limit.limit())),
+                    None => e,
+                },
+            )?;
+        let func = instance
+            .get_func(&entrypoint)
+            .ok_or_else(|| anyhow::anyhow!("{} export is not a function", entrypoint))?;
+
+        match call(&func, &cpu_throttle) {
+            Ok(_) => Ok(0),
+            Err(e) => match exit_code(&e) {
+                Some(exit_code) => Ok(exit_code),
+                None => match memory_limit.as_ref().filter(|limit| limit.caused(&e)) {
+                    Some(limit) => Err(anyhow::anyhow!(oom_message(limit.limit()))),
+                    None => Err(e),
+                },
+            },
+        }
+    }
+}
+
+/// The capabilities of a preopened directory and of the files opened in it.
+/// Read-only directories can be listed and their files read, but nothing in
+/// them can be created, written, renamed, removed or have its times changed.
+fn preopen_caps(read_only: bool) -> (DirCaps, FileCaps) {
+    if !read_only {
+        return (DirCaps::all(), FileCaps::all());
+    }
+    let dir_caps = DirCaps::OPEN
+        | DirCaps::READDIR
+        | DirCaps::READLINK
+        | DirCaps::PATH_FILESTAT_GET
+        | DirCaps::FILESTAT_GET;
+    let file_caps = FileCaps::READ
+        | FileCaps::SEEK
+        | FileCaps::TELL
+        | FileCaps::FDSTAT_SET_FLAGS
+        | FileCaps::ADVISE
+        | FileCaps::FILESTAT_GET
+        | FileCaps::POLL_READWRITE;
+    (dir_caps, file_caps)
+}
+
+/// Picks the export that runs a command. If the command's first item names an
+/// exported function, that function is called with the rest of the command as
+/// its arguments. Otherwise the module is run from its `_start` export with
+/// the whole command as its arguments.
+fn command_entrypoint(module: &wasmtime::Module, command: Vec<String>) -> (String, Vec<String>) {
+    match command.first() {
+        Some(first) if exports_func(module, first) => (command[0].clone(), command[1..].to_vec()),
+        _ => (DEFAULT_ENTRYPOINT.to_string(), command),
+    }
+}
+
+/// Whether `module` exports a function called `name`.
+fn exports_func(module: &wasmtime::Module, name: &str) -> bool {
+    matches!(module.get_export(name), Some(wasmtime::ExternType::Func(_)))
+}
+
+/// Instantiates a module, with its memories counting against `memory_limit`.
+fn instantiate(
+    store: &wasmtime::Store,
+    module: &wasmtime::Module,
+    imports: &[wasmtime::Extern],
+    cpu_throttle: &Option<CpuThrottle>,
+    memory_limit: &Option<Arc<MemoryLimit>>,
+) -> anyhow::Result<wasmtime::Instance> {
+    memory::with_limit(memory_limit.clone(), || match cpu_throttle.as_ref() {
+        Some(throttle) => {
+            throttle.block_on(store, wasmtime::Instance::new_async(store, module, imports))
+        }
+        None => wasmtime::Instance::new(store, module, imports),
+    })
+}
+
+/// Calls an entrypoint, throttling it if it has a CPU limit.
+fn call(
+    func: &wasmtime::Func,
+    cpu_throttle: &Option<CpuThrottle>,
+) -> anyhow::Result<Box<[wasmtime::Val]>> {
+    match cpu_throttle.as_ref() {
+        Some(throttle) => throttle.block_on(func.store(), func.call_async(&[])),
+        None => func.call(&[]),
+    }
+}
+
+// WASI's proc_exit unwinds the module with a trap carrying the exit code,
+// which isn't an error unless the code is non-zero
+fn exit_code(error: &anyhow::Error) -> Option<i32> {
+    error
+        .downcast_ref::<wasmtime::Trap>()
+        .and_then(|trap| trap.i32_exit_status())
+}
+
+// Makes the status of a module that failed for want of memory, if `oom`
+// is the limit in bytes that it ran into, or for the reason given in
+// `message` otherwise. Returns the status along with its message
+pub(crate) fn failed_status(message: &str, oom: Option<u64>) -> (String, Status) {
+    match oom {
+        Some(limit) => {
+            let message = oom_message(limit);
+            let status = Status::terminated_with_reason(memory::OOM_KILLED, &message, true);
+            (message, status)
+        }
+        None => (message.to_string(), Status::terminated(message, true)),
+    }
+}
+
+pub(crate) fn oom_message(limit: u64) -> String {
+    format!("module exceeded its memory limit of {} bytes", limit)
+}
+
+pub(crate) fn send(sender: &Sender<Status>, name: &str, status: Status) {
     match sender.blocking_send(status) {
         Err(e) => warn!("{} error sending wasi status: {:?}", name, e),
         Ok(_) => debug!("{} send completed.", name),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn command(items: &[&str]) -> Vec<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    #[test]
+    fn command_selects_exported_entrypoint() {
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(
+            &engine,
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "_start"))
+                (func (export "migrate")))"#,
+        )
+        .unwrap();
+
+        let (entrypoint, args) = command_entrypoint(&module, command(&["migrate", "--all"]));
+        assert_eq!(entrypoint, "migrate");
+        assert_eq!(args, command(&["--all"]));
+
+        // Commands that don't name a function run the module as a program
+        for missing in [&["server", "--port=80"][..], &["memory"][..], &[][..]].iter() {
+            let (entrypoint, args) = command_entrypoint(&module, command(missing));
+            assert_eq!(entrypoint, DEFAULT_ENTRYPOINT);
+            assert_eq!(args, command(missing));
+        }
+    }
+
+    #[tokio::test]
+    async fn modules_exit_early_when_asked_to_stop() {
+        let module = wat::parse_str(
+            r#"(module
+                (import "krustlet_lifecycle" "stop_requested" (func $stop_requested (result i32)))
+                (memory (export "memory") 1)
+                (func (export "_start")
+                    (loop $running
+                        (br_if $running (i32.eqz (call $stop_requested))))))"#,
+        )
+        .unwrap();
+        let log_dir = tempfile::tempdir().unwrap();
+        let (spec, mut statuses) = crate::runtime::test::module_spec(module, log_dir.path());
+        let runtime = crate::runtime::Engine::Wasmtime { simd: false }
+            .runtime(spec)
+            .await
+            .unwrap();
+        let mut handle = runtime.start().await.unwrap();
+        assert!(matches!(
+            statuses.recv().await,
+            Some(Status::Running { .. })
+        ));
+
+        let grace_period = std::time::Duration::from_secs(60);
+        tokio::time::timeout(grace_period / 2, handle.stop_gracefully(grace_period))
+            .await
+            .expect("module did not exit when asked to stop")
+            .unwrap();
+        handle.wait().await.unwrap();
+        assert!(matches!(
+            statuses.recv().await,
+            Some(Status::Terminated {
+                failed: false,
+                exit_code: Some(0),
+                ..
+            })
+        ));
+    }
+}
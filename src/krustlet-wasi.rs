@@ -20,7 +20,12 @@ async fn main() -> anyhow::Result<()> {
     let store = make_store(&config);
     let plugin_registry = Arc::new(PluginRegistry::new(&config.plugins_dir));
 
-    let provider = WasiProvider::new(store, &config, kubeconfig.clone(), plugin_registry).await?;
+    let mut provider =
+        WasiProvider::new(store, &config, kubeconfig.clone(), plugin_registry).await?;
+    // Pods get their own IPs from the pod CIDR, which must be routed to this node
+    if let Some(cidr) = &config.pod_cidr {
+        provider.set_pod_cidr(cidr)?;
+    }
     let kubelet = Kubelet::new(provider, kubeconfig, config).await?;
     kubelet.start().await
 }